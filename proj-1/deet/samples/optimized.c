@@ -0,0 +1,28 @@
+#include <stdio.h>
+#include <stdlib.h>
+
+struct pair { long a; long b; };
+
+__attribute__((noinline)) long sum(const int *values, int n) {
+    long total = 0;
+    for (int i = 0; i < n; i++) {
+        total += values[i] * 3;
+    }
+    return total;
+}
+
+__attribute__((noinline)) long combine(struct pair p, int scale) {
+    long result = (p.a + p.b) * scale;
+    printf("combine %ld\n", result);
+    return result;
+}
+
+int main(int argc, char **argv) {
+    int values[4] = {1, 2, 3, 4};
+    int n = argc + 3;
+    struct pair p = {n, atoi("5")};
+    long s = sum(values, n);
+    long c = combine(p, n);
+    printf("%ld %ld\n", s, c);
+    return 0;
+}
@@ -0,0 +1,47 @@
+#include <stdio.h>
+
+struct pair {
+    int first;
+    int second;
+};
+
+struct mixed {
+    double weight;
+    int count;
+};
+
+struct big {
+    long values[3];
+};
+
+float get_float(void) {
+    return 1.5f;
+}
+
+double get_double(void) {
+    return 2.25;
+}
+
+struct pair get_pair(void) {
+    struct pair p = {3, 4};
+    return p;
+}
+
+struct mixed get_mixed(void) {
+    struct mixed m = {0.5, 7};
+    return m;
+}
+
+struct big get_big(void) {
+    struct big b = {{1, 2, 3}};
+    return b;
+}
+
+int main() {
+    struct pair p = get_pair();
+    struct mixed m = get_mixed();
+    struct big b = get_big();
+    printf("%f %f %d %d %f %d %ld\n", get_float(), get_double(), p.first, p.second, m.weight,
+           m.count, b.values[2]);
+    return 0;
+}
@@ -0,0 +1,17 @@
+#include <signal.h>
+#include <stdio.h>
+
+static volatile sig_atomic_t handled = 0;
+
+static void on_usr1(int signum) {
+    (void)signum;
+    handled++;
+}
+
+int main() {
+    signal(SIGUSR1, on_usr1);
+    raise(SIGUSR1);
+    raise(SIGUSR1);
+    printf("handled %d\n", (int)handled);
+    return 0;
+}
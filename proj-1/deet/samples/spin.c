@@ -0,0 +1,11 @@
+#include <stdio.h>
+
+int main() {
+    volatile unsigned long i = 0;
+    printf("spinning\n");
+    fflush(stdout);
+    // Runs until it is interrupted
+    while (1) {
+        i++;
+    }
+}
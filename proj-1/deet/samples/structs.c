@@ -0,0 +1,51 @@
+#include <stdio.h>
+
+struct point {
+    int x;
+    int y;
+};
+
+typedef struct node {
+    int value;
+    struct node *next;
+} node_t;
+
+enum direction { NORTH, EAST = 3, SOUTH };
+
+union number {
+    int integer;
+    unsigned char bytes[4];
+};
+
+struct settings {
+    const int id;
+    volatile unsigned counter;
+    const char *const name;
+    unsigned enabled : 1;
+    int level : 4;
+    unsigned mode : 3;
+    char tag;
+    unsigned long wide : 40;
+};
+
+struct point origin = {3, 4};
+int numbers[5] = {10, 20, 30, 40, 50};
+int grid[2][2] = {{1, 2}, {3, 4}};
+char label[8] = "origin";
+enum direction heading = EAST;
+union number answer = {42};
+struct settings config = {7, 3, "fast", 1, -3, 5, 'x', 123456789012};
+
+void print_list(node_t *list, struct point *where) {
+    for (node_t *n = list; n != NULL; n = n->next) {
+        printf("%d at (%d, %d)\n", n->value, where->x, where->y);
+    }
+}
+
+int main() {
+    struct point p = {1, 2};
+    node_t second = {7, NULL};
+    node_t first = {5, &second};
+    print_list(&first, &p);
+    return 0;
+}
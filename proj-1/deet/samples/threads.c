@@ -0,0 +1,38 @@
+#include <pthread.h>
+#include <stdio.h>
+#include <sys/wait.h>
+#include <unistd.h>
+
+static int results[2];
+static volatile int created = -1;
+
+void *worker(void *arg) {
+    int id = *(int *)arg;
+    // Wait until main is done creating this thread
+    while (created < id) {
+    }
+    results[id] = (id + 1) * 10;
+    return NULL;
+}
+
+int main() {
+    pthread_t threads[2];
+    int ids[2] = {0, 1};
+    for (int i = 0; i < 2; i++) {
+        pthread_create(&threads[i], NULL, worker, &ids[i]);
+        created = i;
+        pthread_join(threads[i], NULL);
+    }
+    printf("results %d %d\n", results[0], results[1]);
+    fflush(stdout);
+
+    pid_t child = fork();
+    if (child == 0) {
+        printf("child says hello\n");
+        return 7;
+    }
+    int status;
+    waitpid(child, &status, 0);
+    printf("child exited with %d\n", WEXITSTATUS(status));
+    return 0;
+}
@@ -1,33 +1,122 @@
 use std::collections::HashMap;
+use crate::dwarf_data::Type;
 use crate::inferior::Inferior;
+use crate::target::Target;
+use nix::unistd::Pid;
+
+/// x86-64 has four debug address registers, DR0-DR3
+const NUM_WATCHPOINT_SLOTS: usize = 4;
 
 pub struct BreakpointManager {
-    breakpoint_map: HashMap<usize, Option<Breakpoint>>,
-    count: usize,
+    breakpoint_map: HashMap<usize, Breakpoint>,
+    /// Hardware watchpoints, indexed by the debug register that holds their address
+    watchpoints: [Option<Watchpoint>; NUM_WATCHPOINT_SLOTS],
+    next_id: usize,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum WatchKind {
+    Write,
+    Read,
+    Access,
+}
+
+#[derive(Clone)]
+pub struct Watchpoint {
+    pub id: usize,
+    /// What the user asked to watch, e.g. "total" or "*0x404028"
+    pub expr: String,
+    pub addr: usize,
+    pub entity_type: Type,
+    pub kind: WatchKind,
+    pub enabled: bool,
+    pub hit_count: usize,
+    /// Contents of the watched memory when we last looked, if the inferior was running
+    pub old_value: Option<Vec<u8>>,
+    /// The frame a watched local variable lives in, or None for globals and raw addresses
+    pub scope: Option<WatchScope>,
+}
+
+/// The stack frame a watchpoint on a local variable is valid in.
+#[derive(Clone, Copy)]
+pub struct WatchScope {
+    /// The thread whose stack holds the frame
+    pub tid: Pid,
+    /// The frame's canonical frame address, which the stack pointer returns to when it is popped
+    pub cfa: usize,
+    /// Where the frame returns to
+    pub return_addr: usize,
 }
 
 #[derive(Clone)]
 pub struct Breakpoint {
-    addr: usize,
-    orig_byte: u8,
+    pub id: usize,
+    pub addr: usize,
+    /// Resolved location, e.g. "in main at samples/count.c:4"
+    pub location: String,
+    pub enabled: bool,
+    pub hit_count: usize,
+    /// Expression that must be true for a hit to stop the inferior
+    pub condition: Option<String>,
+    /// Number of upcoming hits to skip
+    pub ignore_count: usize,
+    /// Commands to run each time the breakpoint stops the inferior, set with `commands`
+    pub commands: Vec<String>,
+    /// The byte that 0xcc replaced, while the breakpoint is inserted into a running inferior
+    orig_byte: Option<u8>,
 }
 impl Breakpoint {
-    pub fn new(addr: usize, orig_byte: u8) -> Breakpoint {
-        Breakpoint { addr, orig_byte }
+    pub fn new(id: usize, addr: usize, location: String) -> Breakpoint {
+        Breakpoint {
+            id,
+            addr,
+            location,
+            enabled: true,
+            hit_count: 0,
+            condition: None,
+            ignore_count: 0,
+            commands: Vec::new(),
+            orig_byte: None,
+        }
+    }
+    /// Writes 0xcc into the inferior (if enabled), remembering the byte it replaced.
+    pub fn insert(&mut self, inferior: &mut Inferior) -> Result<(), nix::Error> {
+        self.orig_byte = if self.enabled {
+            Some(inferior.write_byte(self.addr, 0xcc)?)
+        } else {
+            None
+        };
+        Ok(())
+    }
+    /// Restores the original byte if the breakpoint is currently inserted.
+    pub fn remove(&mut self, inferior: &mut Inferior) -> Result<(), nix::Error> {
+        if let Some(orig_byte) = self.orig_byte.take() {
+            inferior.write_byte(self.addr, orig_byte)?;
+        }
+        Ok(())
     }
 }
 pub enum BreakpointArgType {
     Line(usize),
+    FileLine(String, usize),
     FuncName(String),
+    FileFunc(String, String),
     Addr(usize),
     Unknown,
 }
 impl BreakpointManager {
     pub fn new() -> BreakpointManager {
-        BreakpointManager { breakpoint_map: HashMap::new(), count: 0 }
+        BreakpointManager {
+            breakpoint_map: HashMap::new(),
+            watchpoints: [None, None, None, None],
+            next_id: 0,
+        }
     }
+    /// Parses the argument of `break`: `*<addr>`, `<line>`, `<func>`, `<file>:<line>` or
+    /// `<file>:<func>`.
     pub fn parse_breakpoint_arg(raw_addr: &str) -> BreakpointArgType {
-        if raw_addr.to_lowercase().starts_with('*') {
+        if raw_addr.starts_with('*') {
+            let raw_addr = &raw_addr[1..];
             let raw_addr_without_0x = if raw_addr.to_lowercase().starts_with("0x") {
                 &raw_addr[2..]
             } else {
@@ -39,18 +128,34 @@ impl BreakpointManager {
                 }
                 None => BreakpointArgType::Unknown
             }
-        } 
+        }
+        if let Some(colon) = raw_addr.rfind(':') {
+            let (file, location) = (&raw_addr[..colon], &raw_addr[colon + 1..]);
+            if file.is_empty() || location.is_empty() {
+                return BreakpointArgType::Unknown;
+            }
+            return match usize::from_str_radix(location, 10).ok() {
+                Some(line) => BreakpointArgType::FileLine(file.to_string(), line),
+                None => BreakpointArgType::FileFunc(file.to_string(), location.to_string()),
+            };
+        }
         if let Some(line) = usize::from_str_radix(raw_addr, 10).ok() {
             return BreakpointArgType::Line(line);
         }
         BreakpointArgType::FuncName(raw_addr.to_string())
     }
     
-    pub fn iter_mut(&mut self) -> std::collections::hash_map::IterMut<'_, usize, Option<Breakpoint>>{
+    pub fn iter_mut(&mut self) -> std::collections::hash_map::IterMut<'_, usize, Breakpoint>{
         self.breakpoint_map.iter_mut()
     }
-    pub fn get_count(&self) -> usize {
-        self.count
+    /// Returns every breakpoint, ordered by id.
+    pub fn list(&self) -> Vec<&Breakpoint> {
+        let mut breakpoints: Vec<&Breakpoint> = self.breakpoint_map.values().collect();
+        breakpoints.sort_by_key(|breakpoint| breakpoint.id);
+        breakpoints
+    }
+    fn find_by_id(&mut self, id: usize) -> Option<&mut Breakpoint> {
+        self.breakpoint_map.values_mut().find(|breakpoint| breakpoint.id == id)
     }
     // set breakpoint after use unset_t
     pub fn set_t(&mut self, inferior: &mut Inferior, addr: &usize) -> Result<(), nix::Error> {
@@ -59,38 +164,228 @@ impl BreakpointManager {
     }
     // temporarily unset breakpoint
     pub fn unset_t(&mut self, inferior: &mut Inferior, addr: &usize) -> Result<(), nix::Error>{
-        if let Some((_, Some(breakpoint))) = self.breakpoint_map.get_key_value(&addr) {
-            let _ = inferior.write_byte(breakpoint.addr, breakpoint.orig_byte)?;
+        if let Some(Breakpoint { orig_byte: Some(orig_byte), .. }) = self.breakpoint_map.get(addr) {
+            let _ = inferior.write_byte(*addr, *orig_byte)?;
         }
         Ok(())
     }
-    pub fn unset(&mut self, inferior: &mut Option<Inferior>, addr: &usize) -> Result<(), nix::Error>{
-        if let Some((_, Some(breakpoint))) = self.breakpoint_map.remove_entry(&addr) {
-            match inferior.as_mut() {
-                None => {},
-                Some(i) => {
-                    let _ = i.write_byte(breakpoint.addr, breakpoint.orig_byte)?;
-                }
+    /// Deletes breakpoint `id`. Returns false if there is no such breakpoint.
+    pub fn unset(&mut self, inferior: &mut Option<Inferior>, id: usize) -> Result<bool, nix::Error>{
+        if let Some(slot) = self.find_watchpoint_slot(id) {
+            self.watchpoints[slot] = None;
+            if let Some(i) = inferior.as_mut() {
+                self.install_watchpoints(i)?;
             }
+            return Ok(true);
         }
-        Ok(())
+        let addr = match self.find_by_id(id) {
+            Some(breakpoint) => breakpoint.addr,
+            None => return Ok(false),
+        };
+        let mut breakpoint = self.breakpoint_map.remove(&addr).unwrap();
+        if let Some(i) = inferior.as_mut() {
+            breakpoint.remove(i)?;
+        }
+        Ok(true)
     }
-    pub fn get(&mut self, addr: &usize) -> Option<Breakpoint> {
-        self.breakpoint_map.get(addr)?.clone()
-    }
-    pub fn set(&mut self, inferior: &mut Option<Inferior>, addr: &usize) -> Result<bool, nix::Error> {
-        let breakpoint = match inferior.as_mut() {
-            None => {
-                None
-            },
-            Some(i) => {
-                Some(Breakpoint{ addr: *addr, orig_byte: i.write_byte(*addr, 0xcc)? })
+    /// Enables or disables breakpoint `id`, patching a running inferior accordingly. Returns false
+    /// if there is no such breakpoint.
+    pub fn set_enabled(&mut self, inferior: &mut Option<Inferior>, id: usize, enabled: bool) -> Result<bool, nix::Error> {
+        if let Some(slot) = self.find_watchpoint_slot(id) {
+            self.watchpoints[slot].as_mut().unwrap().enabled = enabled;
+            if let Some(i) = inferior.as_mut() {
+                self.install_watchpoints(i)?;
             }
+            return Ok(true);
+        }
+        let breakpoint = match self.find_by_id(id) {
+            Some(breakpoint) => breakpoint,
+            None => return Ok(false),
         };
-        if self.breakpoint_map.insert(*addr, breakpoint).is_none() {
-            self.count += 1;
+        if breakpoint.enabled == enabled {
             return Ok(true);
         }
-        Ok(false)
+        breakpoint.enabled = enabled;
+        if let Some(i) = inferior.as_mut() {
+            if enabled {
+                breakpoint.insert(i)?;
+            } else {
+                breakpoint.remove(i)?;
+            }
+        }
+        Ok(true)
+    }
+    /// Returns whether an enabled breakpoint is set at `addr`.
+    pub fn is_breakpoint(&self, addr: usize) -> bool {
+        self.breakpoint_map.get(&addr).map_or(false, |breakpoint| breakpoint.enabled)
+    }
+    /// Replaces any 0xcc we injected into `bytes`, which were read from `addr`, with the
+    /// original instruction bytes.
+    pub fn restore_original(&self, addr: usize, bytes: &mut [u8]) {
+        for breakpoint in self.breakpoint_map.values() {
+            if let Some(orig_byte) = breakpoint.orig_byte {
+                if breakpoint.addr >= addr && breakpoint.addr < addr + bytes.len() {
+                    bytes[breakpoint.addr - addr] = orig_byte;
+                }
+            }
+        }
+    }
+    /// Forgets that the breakpoints are inserted, after the program they were inserted into
+    /// was replaced by execve.
+    pub fn forget_inserted(&mut self) {
+        for breakpoint in self.breakpoint_map.values_mut() {
+            breakpoint.orig_byte = None;
+        }
+    }
+    /// Returns the breakpoint at `addr` if it is currently inserted into the inferior.
+    pub fn get(&mut self, addr: &usize) -> Option<Breakpoint> {
+        let breakpoint = self.breakpoint_map.get(addr)?;
+        breakpoint.orig_byte?;
+        Some(breakpoint.clone())
+    }
+    pub fn set_condition(&mut self, id: usize, condition: Option<String>) -> bool {
+        match self.find_by_id(id) {
+            Some(breakpoint) => {
+                breakpoint.condition = condition;
+                true
+            }
+            None => false,
+        }
+    }
+    pub fn set_ignore_count(&mut self, id: usize, count: usize) -> bool {
+        match self.find_by_id(id) {
+            Some(breakpoint) => {
+                breakpoint.ignore_count = count;
+                true
+            }
+            None => false,
+        }
+    }
+    pub fn set_commands(&mut self, id: usize, commands: Vec<String>) -> bool {
+        match self.find_by_id(id) {
+            Some(breakpoint) => {
+                breakpoint.commands = commands;
+                true
+            }
+            None => false,
+        }
+    }
+    /// Counts a hit of the breakpoint at `addr` against its ignore count. Returns true if the hit
+    /// should be ignored.
+    pub fn take_ignore(&mut self, addr: &usize) -> bool {
+        match self.breakpoint_map.get_mut(addr) {
+            Some(breakpoint) if breakpoint.ignore_count > 0 => {
+                breakpoint.ignore_count -= 1;
+                breakpoint.hit_count += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+    /// Bumps the hit count of the breakpoint at `addr`, returning its id.
+    pub fn record_hit(&mut self, addr: &usize) -> Option<usize> {
+        let breakpoint = self.breakpoint_map.get_mut(addr)?;
+        breakpoint.hit_count += 1;
+        Some(breakpoint.id)
+    }
+    /// Adds a breakpoint at `addr`, inserting it right away if the inferior is running. Returns
+    /// the new breakpoint's id, or None if there already is one at `addr`.
+    pub fn set(&mut self, inferior: &mut Option<Inferior>, addr: &usize, location: String) -> Result<Option<usize>, nix::Error> {
+        if self.breakpoint_map.contains_key(addr) {
+            return Ok(None);
+        }
+        let mut breakpoint = Breakpoint::new(self.next_id, *addr, location);
+        if let Some(i) = inferior.as_mut() {
+            breakpoint.insert(i)?;
+        }
+        self.breakpoint_map.insert(*addr, breakpoint);
+        self.next_id += 1;
+        Ok(Some(self.next_id - 1))
+    }
+    fn find_watchpoint_slot(&self, id: usize) -> Option<usize> {
+        self.watchpoints
+            .iter()
+            .position(|slot| matches!(slot, Some(watchpoint) if watchpoint.id == id))
+    }
+    /// Returns every watchpoint, ordered by id.
+    pub fn list_watchpoints(&self) -> Vec<&Watchpoint> {
+        let mut watchpoints: Vec<&Watchpoint> = self.watchpoints.iter().flatten().collect();
+        watchpoints.sort_by_key(|watchpoint| watchpoint.id);
+        watchpoints
+    }
+    pub fn get_watchpoint_mut(&mut self, slot: usize) -> Option<&mut Watchpoint> {
+        self.watchpoints.get_mut(slot)?.as_mut()
+    }
+    /// Adds a hardware watchpoint on the `entity_type.size` bytes at `addr`, programming the debug
+    /// registers right away if the inferior is running. Returns the new watchpoint's id.
+    pub fn watch(
+        &mut self,
+        inferior: &mut Option<Inferior>,
+        expr: String,
+        addr: usize,
+        entity_type: Type,
+        kind: WatchKind,
+        scope: Option<WatchScope>,
+    ) -> Result<usize, String> {
+        let len = entity_type.size;
+        if ![1, 2, 4, 8].contains(&len) || addr % len != 0 {
+            return Err(format!(
+                "Cannot watch {} bytes at {:#x}: hardware watchpoints must be 1, 2, 4 or 8 aligned bytes",
+                len, addr
+            ));
+        }
+        let slot = self
+            .watchpoints
+            .iter()
+            .position(|slot| slot.is_none())
+            .ok_or("Hardware watchpoints used exceeds limit of 4.".to_string())?;
+        let old_value = inferior.as_ref().and_then(|i| i.read_bytes(addr, len).ok());
+        self.watchpoints[slot] = Some(Watchpoint {
+            id: self.next_id,
+            expr,
+            addr,
+            entity_type,
+            kind,
+            enabled: true,
+            hit_count: 0,
+            old_value,
+            scope,
+        });
+        self.next_id += 1;
+        if let Some(i) = inferior.as_mut() {
+            self.install_watchpoints(i).map_err(|e| e.to_string())?;
+        }
+        Ok(self.next_id - 1)
+    }
+    /// Programs DR0-DR3 and DR7 from the current set of enabled watchpoints, and snapshots the
+    /// values of watchpoints that were set before the inferior started.
+    pub fn install_watchpoints(&mut self, inferior: &mut Inferior) -> Result<(), nix::Error> {
+        let mut dr7 = 0;
+        for (slot, watchpoint) in self.watchpoints.iter_mut().enumerate() {
+            let watchpoint = match watchpoint {
+                Some(watchpoint) if watchpoint.enabled => watchpoint,
+                _ => continue,
+            };
+            // x86 can't trap on reads alone, so read watchpoints trap on any access and the
+            // debugger reports them as reads
+            let rw = match watchpoint.kind {
+                WatchKind::Write => 0b01,
+                WatchKind::Read | WatchKind::Access => 0b11,
+            };
+            let len = match watchpoint.entity_type.size {
+                1 => 0b00,
+                2 => 0b01,
+                8 => 0b10,
+                _ => 0b11,
+            };
+            if watchpoint.old_value.is_none() {
+                watchpoint.old_value =
+                    inferior.read_bytes(watchpoint.addr, watchpoint.entity_type.size).ok();
+            }
+            inferior.set_debug_reg(slot, watchpoint.addr)?;
+            dr7 |= 1 << (slot * 2);
+            dr7 |= (rw | (len << 2)) << (16 + slot * 4);
+        }
+        inferior.set_debug_reg(7, dr7)
     }
 }
\ No newline at end of file
@@ -0,0 +1,62 @@
+//! Where the debugger's messages for the user go. At the `(deet)` prompt they are printed as
+//! soon as they are said; the DAP server collects them instead and sends them to the editor
+//! after each request, in order with its other events.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// Like `println!`, but says the line on a `Console`.
+macro_rules! say {
+    ($console:expr) => {
+        $console.say(String::new())
+    };
+    ($console:expr, $($arg:tt)*) => {
+        $console.say(format!($($arg)*))
+    };
+}
+
+pub struct Console {
+    /// Lines said but not yet taken, or None if lines are printed right away
+    captured: Option<RefCell<Vec<String>>>,
+}
+
+impl Console {
+    /// A console that prints to stdout.
+    pub fn terminal() -> Rc<Console> {
+        Rc::new(Console { captured: None })
+    }
+
+    /// A console that keeps what is said until `take` is called.
+    pub fn captured() -> Rc<Console> {
+        Rc::new(Console { captured: Some(RefCell::new(Vec::new())) })
+    }
+
+    pub fn say(&self, line: String) {
+        match &self.captured {
+            Some(lines) => lines.borrow_mut().push(line),
+            None => println!("{}", line),
+        }
+    }
+
+    /// Returns the lines said since the last call (always none for a terminal).
+    pub fn take(&self) -> Vec<String> {
+        match &self.captured {
+            Some(lines) => lines.replace(Vec::new()),
+            None => Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn captured_lines_are_taken_once() {
+        let console = Console::captured();
+        say!(console, "Breakpoint {} at {:#x}", 1, 0x401136);
+        say!(console);
+        assert_eq!(console.take(), vec!["Breakpoint 1 at 0x401136".to_string(), String::new()]);
+        assert!(console.take().is_empty());
+    }
+}
@@ -0,0 +1,404 @@
+//! Post-mortem debugging: the registers and memory of a crashed process, read out of its ELF
+//! core dump.
+
+use crate::target::{Mapping, Target};
+use nix::errno::Errno;
+use nix::sys::signal::Signal;
+use nix::unistd::Pid;
+use std::convert::{TryFrom, TryInto};
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::mem::size_of;
+
+const ET_CORE: u16 = 4;
+const EM_X86_64: u16 = 62;
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const NT_PRSTATUS: u32 = 1;
+const NT_PRPSINFO: u32 = 3;
+const NT_FILE: u32 = 0x4649_4c45;
+
+/// Offsets of the fields we need in `struct elf_prstatus` and `struct elf_prpsinfo` (see
+/// <linux/elfcore.h>) on x86-64.
+const PRSTATUS_CURSIG: usize = 12;
+const PRSTATUS_PID: usize = 32;
+const PRSTATUS_REGS: usize = 112;
+const PRPSINFO_PSARGS: usize = 56;
+const PSARGS_LEN: usize = 80;
+
+fn u16_at(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(data.get(offset..offset + 2)?.try_into().ok()?))
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+fn u64_at(data: &[u8], offset: usize) -> Option<usize> {
+    Some(u64::from_le_bytes(data.get(offset..offset + 8)?.try_into().ok()?) as usize)
+}
+
+/// A PT_LOAD segment: memory at `addr`, saved at `offset` in the core file. Segments the kernel
+/// didn't dump (such as read-only file mappings) have a `size` of zero.
+struct Segment {
+    addr: usize,
+    offset: usize,
+    size: usize,
+}
+
+pub struct CoreFile {
+    data: Vec<u8>,
+    segments: Vec<Segment>,
+    /// Thread id and registers of each thread, starting with the one that crashed
+    threads: Vec<(Pid, libc::user_regs_struct)>,
+    /// Index in `threads` of the thread commands look at
+    current: usize,
+    /// The signal that killed the process
+    pub signal: Option<Signal>,
+    /// The command line the process was started with
+    pub command: String,
+    mappings: Vec<Mapping>,
+}
+
+impl CoreFile {
+    /// Loads the core dump at `path`.
+    pub fn open(path: &str) -> Result<CoreFile, String> {
+        let data = fs::read(path).or(Err(format!("{}: No such file or directory.", path)))?;
+        let not_core = || format!("\"{}\" is not a core dump: file format not recognized", path);
+        if data.get(0..4) != Some(b"\x7fELF") || u16_at(&data, 0x10) != Some(ET_CORE) {
+            return Err(not_core());
+        }
+        if u16_at(&data, 0x12) != Some(EM_X86_64) {
+            return Err(format!("\"{}\": only x86-64 core dumps are supported", path));
+        }
+        let mut core = CoreFile {
+            data: Vec::new(),
+            segments: Vec::new(),
+            threads: Vec::new(),
+            current: 0,
+            signal: None,
+            command: String::new(),
+            mappings: Vec::new(),
+        };
+        let phoff = u64_at(&data, 0x20).ok_or_else(not_core)?;
+        let phentsize = u16_at(&data, 0x36).ok_or_else(not_core)? as usize;
+        let phnum = u16_at(&data, 0x38).ok_or_else(not_core)? as usize;
+        for index in 0..phnum {
+            let header = phoff + index * phentsize;
+            let kind = u32_at(&data, header).ok_or_else(not_core)?;
+            let offset = u64_at(&data, header + 8).ok_or_else(not_core)?;
+            let addr = u64_at(&data, header + 16).ok_or_else(not_core)?;
+            let size = u64_at(&data, header + 32).ok_or_else(not_core)?;
+            if offset + size > data.len() {
+                return Err(format!("\"{}\": core dump is truncated", path));
+            }
+            match kind {
+                PT_LOAD => core.segments.push(Segment { addr, offset, size }),
+                PT_NOTE => core.read_notes(&data[offset..offset + size]),
+                _ => {}
+            }
+        }
+        if core.threads.is_empty() {
+            return Err(format!("\"{}\": core dump has no registers", path));
+        }
+        core.data = data;
+        Ok(core)
+    }
+
+    /// Walks the notes in a PT_NOTE segment, picking up thread registers (NT_PRSTATUS), the
+    /// command line (NT_PRPSINFO) and the mapped files (NT_FILE).
+    fn read_notes(&mut self, notes: &[u8]) {
+        let align = |n: usize| (n + 3) & !3;
+        let mut pos = 0;
+        while let (Some(name_size), Some(desc_size), Some(kind)) =
+            (u32_at(notes, pos), u32_at(notes, pos + 4), u32_at(notes, pos + 8))
+        {
+            let desc_start = pos + 12 + align(name_size as usize);
+            let desc = match notes.get(desc_start..desc_start + desc_size as usize) {
+                Some(desc) => desc,
+                None => return,
+            };
+            match kind {
+                NT_PRSTATUS => self.read_prstatus(desc),
+                NT_PRPSINFO => {
+                    let psargs = desc.get(PRPSINFO_PSARGS..PRPSINFO_PSARGS + PSARGS_LEN);
+                    let psargs = psargs.unwrap_or_default();
+                    let len = psargs.iter().position(|&b| b == 0).unwrap_or(psargs.len());
+                    self.command = String::from_utf8_lossy(&psargs[..len]).trim().to_string();
+                }
+                NT_FILE => self.read_file_note(desc),
+                _ => {}
+            }
+            pos = desc_start + align(desc_size as usize);
+        }
+    }
+
+    fn read_prstatus(&mut self, desc: &[u8]) {
+        let regs_size = size_of::<libc::user_regs_struct>();
+        let regs = match desc.get(PRSTATUS_REGS..PRSTATUS_REGS + regs_size) {
+            // The saved registers have the same layout as user_regs_struct
+            Some(regs) => unsafe {
+                std::ptr::read_unaligned(regs.as_ptr() as *const libc::user_regs_struct)
+            },
+            None => return,
+        };
+        let pid = u32_at(desc, PRSTATUS_PID).unwrap_or(0) as i32;
+        if self.threads.is_empty() {
+            let signal = u16_at(desc, PRSTATUS_CURSIG).unwrap_or(0) as i32;
+            self.signal = Signal::try_from(signal).ok();
+        }
+        self.threads.push((Pid::from_raw(pid), regs));
+    }
+
+    /// Parses the list of mapped files: a count and page size, then (start, end, page offset)
+    /// for each file, then their names.
+    fn read_file_note(&mut self, desc: &[u8]) {
+        let count = u64_at(desc, 0).unwrap_or(0);
+        let page_size = u64_at(desc, 8).unwrap_or(0);
+        let names_start = 16 + count * 24;
+        let mut names = desc.get(names_start..).unwrap_or_default().split(|&b| b == 0);
+        for index in 0..count {
+            let entry = 16 + index * 24;
+            let (start, end, page) = match (
+                u64_at(desc, entry),
+                u64_at(desc, entry + 8),
+                u64_at(desc, entry + 16),
+            ) {
+                (Some(start), Some(end), Some(page)) => (start, end, page),
+                _ => return,
+            };
+            let path = String::from_utf8_lossy(names.next().unwrap_or_default()).to_string();
+            self.mappings.push(Mapping {
+                path,
+                start,
+                end,
+                offset: page * page_size,
+            });
+        }
+    }
+
+    /// Returns the number (from 1, in the order they were dumped), id and registers of each
+    /// thread.
+    pub fn threads(&self) -> Vec<(usize, Pid, libc::user_regs_struct)> {
+        self.threads
+            .iter()
+            .enumerate()
+            .map(|(index, (tid, regs))| (index + 1, *tid, *regs))
+            .collect()
+    }
+
+    /// Returns the id of the current thread.
+    pub fn tid(&self) -> Pid {
+        self.threads[self.current].0
+    }
+
+    /// Makes thread number `id` current, and returns its thread id.
+    pub fn switch_thread(&mut self, id: usize) -> Option<Pid> {
+        if id == 0 || id > self.threads.len() {
+            return None;
+        }
+        self.current = id - 1;
+        Some(self.tid())
+    }
+
+    /// Reads up to `len` bytes at `addr`, stopping at the end of whatever holds `addr`. Memory
+    /// the kernel didn't dump is read from the file that was mapped there.
+    fn read_chunk(&self, addr: usize, len: usize) -> Option<Vec<u8>> {
+        let segment = self
+            .segments
+            .iter()
+            .find(|segment| addr >= segment.addr && addr < segment.addr + segment.size);
+        if let Some(segment) = segment {
+            let start = segment.offset + (addr - segment.addr);
+            let len = len.min(segment.addr + segment.size - addr);
+            return Some(self.data[start..start + len].to_vec());
+        }
+        let mapping = self
+            .mappings
+            .iter()
+            .find(|mapping| addr >= mapping.start && addr < mapping.end)?;
+        let mut file = File::open(&mapping.path).ok()?;
+        file.seek(SeekFrom::Start((mapping.offset + addr - mapping.start) as u64)).ok()?;
+        let mut bytes = vec![0; len.min(mapping.end - addr)];
+        file.read_exact(&mut bytes).ok()?;
+        Some(bytes)
+    }
+}
+
+impl Target for CoreFile {
+    fn pid(&self) -> Pid {
+        self.threads[0].0
+    }
+
+    fn get_regs(&self) -> Option<libc::user_regs_struct> {
+        Some(self.threads[self.current].1)
+    }
+
+    fn read_bytes(&self, addr: usize, len: usize) -> Result<Vec<u8>, nix::Error> {
+        let mut bytes = Vec::with_capacity(len);
+        while bytes.len() < len {
+            let chunk = self
+                .read_chunk(addr + bytes.len(), len - bytes.len())
+                .ok_or(nix::Error::Sys(Errno::EIO))?;
+            bytes.extend_from_slice(&chunk);
+        }
+        Ok(bytes)
+    }
+
+    fn mappings(&self) -> Vec<Mapping> {
+        self.mappings.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    /// Checks the hard-coded struct offsets against the C library's <sys/procfs.h>.
+    #[test]
+    fn offsets_match_system_headers() {
+        let dir = std::env::temp_dir().join(format!("deet-procfs-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("offsets.c");
+        fs::write(
+            &source,
+            "#include <stdio.h>\n\
+             #include <stddef.h>\n\
+             #include <sys/procfs.h>\n\
+             int main() {\n\
+                 printf(\"%zu %zu %zu %zu %zu\\n\",\n\
+                        offsetof(struct elf_prstatus, pr_cursig),\n\
+                        offsetof(struct elf_prstatus, pr_pid),\n\
+                        offsetof(struct elf_prstatus, pr_reg),\n\
+                        offsetof(struct elf_prpsinfo, pr_psargs),\n\
+                        sizeof(((struct elf_prpsinfo *) 0)->pr_psargs));\n\
+             }\n",
+        )
+        .unwrap();
+        let binary = dir.join("offsets");
+        let status = Command::new("cc").arg("-o").arg(&binary).arg(&source).status().unwrap();
+        assert!(status.success());
+        let output = Command::new(&binary).output().unwrap();
+        let offsets: Vec<usize> = String::from_utf8_lossy(&output.stdout)
+            .split_whitespace()
+            .map(|n| n.parse().unwrap())
+            .collect();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            offsets,
+            vec![PRSTATUS_CURSIG, PRSTATUS_PID, PRSTATUS_REGS, PRPSINFO_PSARGS, PSARGS_LEN]
+        );
+    }
+
+    const SEGMENT_ADDR: usize = 0x400000;
+
+    /// Appends a note named "CORE" to `notes`.
+    fn note(notes: &mut Vec<u8>, kind: u32, desc: &[u8]) {
+        notes.extend_from_slice(&5u32.to_le_bytes());
+        notes.extend_from_slice(&(desc.len() as u32).to_le_bytes());
+        notes.extend_from_slice(&kind.to_le_bytes());
+        notes.extend_from_slice(b"CORE\0\0\0\0");
+        notes.extend_from_slice(desc);
+    }
+
+    fn prstatus(signal: u16, pid: u32, rip: u64) -> Vec<u8> {
+        let regs_size = size_of::<libc::user_regs_struct>();
+        let mut desc = vec![0; PRSTATUS_REGS + regs_size + 8];
+        desc[PRSTATUS_CURSIG..PRSTATUS_CURSIG + 2].copy_from_slice(&signal.to_le_bytes());
+        desc[PRSTATUS_PID..PRSTATUS_PID + 4].copy_from_slice(&pid.to_le_bytes());
+        // rip is the 17th register
+        let rip_offset = PRSTATUS_REGS + 16 * 8;
+        desc[rip_offset..rip_offset + 8].copy_from_slice(&rip.to_le_bytes());
+        desc
+    }
+
+    /// Builds a core dump of a process with two threads, the first of which crashed, and one
+    /// segment of memory.
+    fn core_dump() -> Vec<u8> {
+        let mut notes = Vec::new();
+        note(&mut notes, NT_PRSTATUS, &prstatus(11, 101, 0x401000));
+        note(&mut notes, NT_PRSTATUS, &prstatus(0, 100, 0x402000));
+        let mut prpsinfo = vec![0; PRPSINFO_PSARGS + PSARGS_LEN];
+        prpsinfo[PRPSINFO_PSARGS..PRPSINFO_PSARGS + 10].copy_from_slice(b"./prog arg");
+        note(&mut notes, NT_PRPSINFO, &prpsinfo);
+        let memory: Vec<u8> = (0..16).collect();
+
+        let (header_size, phentsize) = (64, 56);
+        let notes_offset = header_size + 2 * phentsize;
+        let memory_offset = notes_offset + notes.len();
+        let mut data = vec![0; header_size];
+        data[0..4].copy_from_slice(b"\x7fELF");
+        data[0x10..0x12].copy_from_slice(&ET_CORE.to_le_bytes());
+        data[0x12..0x14].copy_from_slice(&EM_X86_64.to_le_bytes());
+        data[0x20..0x28].copy_from_slice(&(header_size as u64).to_le_bytes());
+        data[0x36..0x38].copy_from_slice(&(phentsize as u16).to_le_bytes());
+        data[0x38..0x3a].copy_from_slice(&2u16.to_le_bytes());
+        for (kind, offset, addr, size) in &[
+            (PT_NOTE, notes_offset, 0, notes.len()),
+            (PT_LOAD, memory_offset, SEGMENT_ADDR, memory.len()),
+        ] {
+            let mut header = vec![0; phentsize];
+            header[0..4].copy_from_slice(&kind.to_le_bytes());
+            header[8..16].copy_from_slice(&(*offset as u64).to_le_bytes());
+            header[16..24].copy_from_slice(&(*addr as u64).to_le_bytes());
+            header[32..40].copy_from_slice(&(*size as u64).to_le_bytes());
+            data.extend_from_slice(&header);
+        }
+        data.extend_from_slice(&notes);
+        data.extend_from_slice(&memory);
+        data
+    }
+
+    /// Writes `data` to a scratch file and opens it as a core dump.
+    fn open(name: &str, data: &[u8]) -> Result<CoreFile, String> {
+        let path = std::env::temp_dir().join(format!("deet-{}-{}", name, std::process::id()));
+        fs::write(&path, data).unwrap();
+        let core = CoreFile::open(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+        core
+    }
+
+    #[test]
+    fn threads_and_registers() {
+        let mut core = open("threads", &core_dump()).unwrap();
+        assert_eq!(core.signal, Some(Signal::SIGSEGV));
+        assert_eq!(core.command, "./prog arg");
+        let threads: Vec<(usize, Pid, u64)> =
+            core.threads().into_iter().map(|(id, tid, regs)| (id, tid, regs.rip)).collect();
+        assert_eq!(
+            threads,
+            vec![(1, Pid::from_raw(101), 0x401000), (2, Pid::from_raw(100), 0x402000)]
+        );
+        assert_eq!(core.tid(), Pid::from_raw(101));
+        assert_eq!(core.get_regs().unwrap().rip, 0x401000);
+        assert_eq!(core.switch_thread(2), Some(Pid::from_raw(100)));
+        assert_eq!(core.get_regs().unwrap().rip, 0x402000);
+        assert_eq!(core.switch_thread(3), None);
+        assert_eq!(core.switch_thread(0), None);
+        assert_eq!(core.tid(), Pid::from_raw(100));
+    }
+
+    #[test]
+    fn memory() {
+        let core = open("memory", &core_dump()).unwrap();
+        assert_eq!(core.read_bytes(SEGMENT_ADDR + 4, 4), Ok(vec![4, 5, 6, 7]));
+        assert_eq!(core.read_word(SEGMENT_ADDR + 8).unwrap(), 0x0f0e0d0c0b0a0908);
+        assert!(core.read_bytes(SEGMENT_ADDR + 12, 8).is_err());
+        assert!(core.read_bytes(0, 1).is_err());
+    }
+
+    #[test]
+    fn invalid_files() {
+        let error = |name: &str, data: &[u8]| open(name, data).err().unwrap();
+        assert!(error("empty", &[]).ends_with("is not a core dump: file format not recognized"));
+        let mut data = core_dump();
+        data[0x10] = 2; // ET_EXEC
+        assert!(error("exec", &data).ends_with("is not a core dump: file format not recognized"));
+        let mut data = core_dump();
+        data[0x12] = 3; // EM_386
+        assert!(error("i386", &data).ends_with("only x86-64 core dumps are supported"));
+        let data = core_dump();
+        assert!(error("truncated", &data[..data.len() - 1]).ends_with("core dump is truncated"));
+    }
+}
@@ -0,0 +1,381 @@
+//! A Debug Adapter Protocol front-end, so that editors such as VS Code can drive deet. Requests
+//! arrive on stdin and responses and events go out on stdout, each as a JSON body preceded by a
+//! `Content-Length` header.
+//!
+//! Commands are carried out by `Debugger::execute`, just like at the `(deet)` prompt. What the
+//! debugger says is captured by its console, and what the program writes goes down a pipe; both
+//! are forwarded to the editor as `output` events, ahead of the event or response they led up
+//! to, since stdout itself belongs to the protocol.
+
+use crate::console::Console;
+use crate::debugger::Debugger;
+use crate::debugger_command::DebuggerCommand;
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::unistd::pipe;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::io::FromRawFd;
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// The `variablesReference` of the globals scope. The locals of stack frame n have reference
+/// `LOCALS_REFERENCE + n`.
+const GLOBALS_REFERENCE: u64 = 1;
+const LOCALS_REFERENCE: u64 = 2;
+
+/// The editor's end of the protocol. It is shared with the thread that forwards the program's
+/// output.
+struct Connection {
+    output: Mutex<io::Stdout>,
+    next_seq: AtomicI64,
+    /// The (nonblocking) read end of the pipe the program writes to. Whoever holds the lock is
+    /// forwarding its contents, so that output is never sent after what follows it.
+    program_output: Mutex<File>,
+}
+
+impl Connection {
+    fn send(&self, mut message: Value) {
+        message["seq"] = json!(self.next_seq.fetch_add(1, Ordering::SeqCst));
+        let body = message.to_string();
+        let mut output = self.output.lock().unwrap();
+        // If the editor went away there is no one left to tell
+        let _ = write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body);
+        let _ = output.flush();
+    }
+
+    fn event(&self, event: &str, body: Value) {
+        self.send(json!({"type": "event", "event": event, "body": body}));
+    }
+
+    /// Sends whatever the program has written so far.
+    fn forward_program_output(&self) {
+        let mut pipe = self.program_output.lock().unwrap();
+        let mut buf = [0; 4096];
+        // Reading stops with EAGAIN once the pipe is empty
+        while let Ok(len) = pipe.read(&mut buf) {
+            if len == 0 {
+                break;
+            }
+            let output = String::from_utf8_lossy(&buf[..len]);
+            self.event("output", json!({"category": "stdout", "output": output}));
+        }
+    }
+}
+
+pub struct DapServer {
+    connection: Arc<Connection>,
+    input: BufReader<io::Stdin>,
+    /// Collects what the debugger says, to be sent on as `output` events
+    console: Rc<Console>,
+    /// The write end of the program's output pipe
+    program_output: File,
+    /// Created by the `launch` request, which names the program
+    debugger: Option<Debugger>,
+    /// Arguments for the program, also from `launch`
+    args: Vec<String>,
+    /// The breakpoints set in each source file, which the next `setBreakpoints` for that file
+    /// replaces
+    breakpoints: HashMap<String, Vec<usize>>,
+}
+
+fn nix_error(err: nix::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, err)
+}
+
+impl DapServer {
+    /// Speaks the protocol on stdin and stdout. Programs launched later get a pipe for their
+    /// output, which is passed on to the editor as it arrives, and /dev/null for their input.
+    pub fn new() -> io::Result<DapServer> {
+        let (reader, writer) = pipe().map_err(nix_error)?;
+        fcntl(reader, FcntlArg::F_SETFL(OFlag::O_NONBLOCK)).map_err(nix_error)?;
+        let connection = Arc::new(Connection {
+            output: Mutex::new(io::stdout()),
+            next_seq: AtomicI64::new(1),
+            program_output: Mutex::new(unsafe { File::from_raw_fd(reader) }),
+        });
+        let forwarder = connection.clone();
+        thread::spawn(move || loop {
+            // We hold the write end, so the pipe never reaches end of file
+            let mut fds = [PollFd::new(reader, PollFlags::POLLIN)];
+            match poll(&mut fds, -1) {
+                Ok(_) => forwarder.forward_program_output(),
+                Err(nix::Error::Sys(nix::errno::Errno::EINTR)) => {}
+                Err(_) => break,
+            }
+        });
+        Ok(DapServer {
+            connection,
+            input: BufReader::new(io::stdin()),
+            console: Console::captured(),
+            program_output: unsafe { File::from_raw_fd(writer) },
+            debugger: None,
+            args: Vec::new(),
+            breakpoints: HashMap::new(),
+        })
+    }
+
+    /// Handles requests until the editor disconnects.
+    pub fn serve(&mut self) -> io::Result<()> {
+        while let Some(request) = self.read_message()? {
+            if !self.handle(&request) {
+                break;
+            }
+        }
+        if let Some(debugger) = self.debugger.as_mut() {
+            debugger.execute(DebuggerCommand::Quit);
+        }
+        Ok(())
+    }
+
+    /// Reads the next message, or returns None at the end of input.
+    fn read_message(&mut self) -> io::Result<Option<Value>> {
+        let mut length = None;
+        loop {
+            let mut header = String::new();
+            if self.input.read_line(&mut header)? == 0 {
+                return Ok(None);
+            }
+            let header = header.trim();
+            if header.is_empty() {
+                break;
+            }
+            if let Some(value) = header.splitn(2, ':').nth(1) {
+                if header.to_lowercase().starts_with("content-length:") {
+                    length = value.trim().parse::<usize>().ok();
+                }
+            }
+        }
+        let length = length.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "message without Content-Length")
+        })?;
+        let mut body = vec![0; length];
+        self.input.read_exact(&mut body)?;
+        serde_json::from_slice(&body).map(Some).map_err(|err| err.into())
+    }
+
+    /// Passes on what the program and the debugger have output since last time.
+    fn send_output(&self) {
+        self.connection.forward_program_output();
+        for line in self.console.take() {
+            let output = line + "\n";
+            self.connection.event("output", json!({"category": "console", "output": output}));
+        }
+    }
+
+    fn respond(&self, request: &Value, result: Result<Value, String>) {
+        self.send_output();
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": result.is_ok(),
+        });
+        match result {
+            Ok(body) => response["body"] = body,
+            Err(message) => response["message"] = json!(message),
+        }
+        self.connection.send(response);
+    }
+
+    /// Carries out one request. Returns false once the editor disconnects.
+    fn handle(&mut self, request: &Value) -> bool {
+        let args = &request["arguments"];
+        let command = request["command"].as_str().unwrap_or("");
+        let result = match command {
+            "initialize" => Ok(json!({"supportsConfigurationDoneRequest": true})),
+            "launch" => {
+                let result = self.launch(args);
+                let launched = result.is_ok();
+                self.respond(request, result);
+                if launched {
+                    // The editor now sends its breakpoints, then configurationDone
+                    self.connection.event("initialized", json!({}));
+                }
+                return true;
+            }
+            "setBreakpoints" => self.set_breakpoints(args),
+            "configurationDone" => {
+                let run = DebuggerCommand::Run(self.args.clone());
+                return self.resume(request, run, None);
+            }
+            "continue" => return self.resume(request, DebuggerCommand::Continue, None),
+            "next" => return self.resume(request, DebuggerCommand::Next, Some("step")),
+            "stepIn" => return self.resume(request, DebuggerCommand::Step, Some("step")),
+            "stepOut" => return self.resume(request, DebuggerCommand::Finish, Some("step")),
+            "threads" => Ok(self.threads()),
+            "stackTrace" => self.stack_trace(),
+            "scopes" => Ok(Self::scopes(args)),
+            "variables" => self.variables(args),
+            "disconnect" => {
+                self.respond(request, Ok(json!({})));
+                return false;
+            }
+            _ => Err(format!("Unsupported request \"{}\"", command)),
+        };
+        self.respond(request, result);
+        true
+    }
+
+    fn debugger(&mut self) -> Result<&mut Debugger, String> {
+        self.debugger.as_mut().ok_or_else(|| "No program has been launched".to_string())
+    }
+
+    fn launch(&mut self, args: &Value) -> Result<Value, String> {
+        let program = args["program"].as_str().ok_or("Missing \"program\" to launch")?;
+        self.args = match args["args"].as_array() {
+            Some(args) => args.iter().filter_map(|arg| arg.as_str().map(String::from)).collect(),
+            None => Vec::new(),
+        };
+        let mut debugger = Debugger::new(program, self.console.clone())?;
+        let output = self.program_output.try_clone().map_err(|err| err.to_string())?;
+        debugger.redirect_program_output(output);
+        self.debugger = Some(debugger);
+        Ok(json!({}))
+    }
+
+    /// Replaces the breakpoints in one source file.
+    fn set_breakpoints(&mut self, args: &Value) -> Result<Value, String> {
+        let path = args["source"]["path"].as_str().ok_or("Missing source path")?.to_string();
+        let old = self.breakpoints.remove(&path).unwrap_or_default();
+        let debugger = self.debugger()?;
+        for id in old {
+            debugger.execute(DebuggerCommand::Delete(id));
+        }
+        let mut ids = Vec::new();
+        let mut breakpoints = Vec::new();
+        for breakpoint in args["breakpoints"].as_array().unwrap_or(&Vec::new()) {
+            let line = breakpoint["line"].as_u64().unwrap_or(0);
+            let condition = breakpoint["condition"].as_str().map(String::from);
+            let location = format!("{}:{}", path, line);
+            breakpoints.push(match debugger.set_breakpoint(&location, condition) {
+                Ok((_, Some(id))) => {
+                    ids.push(id);
+                    json!({"verified": true, "id": id, "line": line})
+                }
+                Ok((_, None)) => json!({"verified": true, "line": line}),
+                Err(message) => json!({"verified": false, "line": line, "message": message}),
+            });
+        }
+        self.breakpoints.insert(path, ids);
+        Ok(json!({ "breakpoints": breakpoints }))
+    }
+
+    /// Responds to `request` right away, then runs `command` and reports where the inferior
+    /// stopped. `reason` is why it stopped unless it hit a breakpoint or got a signal.
+    fn resume(&mut self, request: &Value, command: DebuggerCommand, reason: Option<&str>) -> bool {
+        if self.debugger.is_none() {
+            self.respond(request, Err("No program has been launched".to_string()));
+            return true;
+        }
+        self.send_output();
+        self.connection.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": true,
+            "body": {"allThreadsContinued": true},
+        }));
+        self.debugger.as_mut().unwrap().execute(command);
+        self.send_output();
+
+        let debugger = self.debugger.as_mut().unwrap();
+        let at_breakpoint = debugger.stopped_at_breakpoint().is_some();
+        match debugger.inferior() {
+            Some(inferior) => {
+                let reason = match reason {
+                    Some(reason) => reason,
+                    None if at_breakpoint => "breakpoint",
+                    None => "exception",
+                };
+                let current = inferior.tid();
+                let thread = inferior.threads().iter().find(|thread| thread.tid == current);
+                self.connection.event(
+                    "stopped",
+                    json!({
+                        "reason": reason,
+                        "threadId": thread.map_or(1, |thread| thread.id),
+                        "allThreadsStopped": true,
+                    }),
+                );
+            }
+            None => {
+                if let Some(status) = debugger.exit_status() {
+                    self.connection.event("exited", json!({ "exitCode": status }));
+                }
+                self.connection.event("terminated", json!({}));
+            }
+        }
+        true
+    }
+
+    fn threads(&self) -> Value {
+        let threads: Vec<Value> = match self.debugger.as_ref().and_then(|d| d.inferior()) {
+            Some(inferior) => inferior
+                .threads()
+                .iter()
+                .map(|thread| json!({"id": thread.id, "name": format!("Thread {}", thread.tid)}))
+                .collect(),
+            None => Vec::new(),
+        };
+        json!({ "threads": threads })
+    }
+
+    /// Describes the frames `frame` can select, i.e. the stack from the current thread's
+    /// innermost frame out to main. Frame ids are the same numbers `frame` takes.
+    fn stack_trace(&mut self) -> Result<Value, String> {
+        let frames: Vec<Value> = self
+            .debugger()?
+            .stack_trace()
+            .into_iter()
+            .enumerate()
+            .map(|(index, (name, line))| match line {
+                Some(line) => {
+                    let file_name = Path::new(&line.file).file_name().map_or_else(
+                        || line.file.clone(),
+                        |name| name.to_string_lossy().to_string(),
+                    );
+                    json!({
+                        "id": index,
+                        "name": name,
+                        "source": {"name": file_name, "path": line.file},
+                        "line": line.number,
+                        "column": 1,
+                    })
+                }
+                None => json!({"id": index, "name": name, "line": 0, "column": 0}),
+            })
+            .collect();
+        Ok(json!({"stackFrames": frames, "totalFrames": frames.len()}))
+    }
+
+    fn scopes(args: &Value) -> Value {
+        let frame = args["frameId"].as_u64().unwrap_or(0);
+        json!({"scopes": [
+            {"name": "Locals", "variablesReference": LOCALS_REFERENCE + frame, "expensive": false},
+            {"name": "Globals", "variablesReference": GLOBALS_REFERENCE, "expensive": false},
+        ]})
+    }
+
+    fn variables(&mut self, args: &Value) -> Result<Value, String> {
+        let reference = args["variablesReference"].as_u64().unwrap_or(0);
+        let debugger = self.debugger()?;
+        let variables = match reference {
+            GLOBALS_REFERENCE => debugger.globals()?,
+            LOCALS_REFERENCE..=u64::MAX => {
+                debugger.frame_locals((reference - LOCALS_REFERENCE) as usize)?
+            }
+            _ => return Err(format!("Invalid variablesReference {}", reference)),
+        };
+        let variables: Vec<Value> = variables
+            .into_iter()
+            .map(|(name, value)| json!({"name": name, "value": value, "variablesReference": 0}))
+            .collect();
+        Ok(json!({ "variables": variables }))
+    }
+}
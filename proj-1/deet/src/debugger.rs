@@ -1,12 +1,28 @@
 use std::collections::HashMap;
+use std::fs::{self, File};
+use std::rc::Rc;
+use std::mem;
 
-use crate::breakpoint_manager::{self, BreakpointManager, BreakpointArgType};
-use crate::debugger_command::DebuggerCommand;
+use crate::console::Console;
+use crate::breakpoint_manager::{BreakpointManager, BreakpointArgType, WatchKind, WatchScope};
+use crate::debugger_command::{DebuggerCommand, ExamineFormat};
 use crate::inferior::{Inferior,Status, self};
 use nix::{sys::signal};
+use nix::unistd::Pid;
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
-use crate::dwarf_data::{DwarfData, Error as DwarfError, Line};
+use crate::dwarf_data::{
+    DwarfData, Error as DwarfError, Function, Line, Location, Member, Type, TypeKind, Variable,
+};
+use crate::disasm;
+use crate::expr;
+use crate::location::{self, Error as LocationError, Place};
+use crate::registers;
+use crate::signals::{self, SignalTable};
+use crate::unwind::{self, Frame, Module};
+use crate::value::{self, RegClass};
+use crate::core_file::CoreFile;
+use crate::target::Target;
 
 
 pub struct Debugger {
@@ -14,75 +30,662 @@ pub struct Debugger {
     history_path: String,
     readline: Editor<()>,
     inferior: Option<Inferior>,
+    /// The core dump being examined, if any. A running inferior takes precedence over it.
+    core: Option<CoreFile>,
     debug_data: DwarfData,
     breakpoints: BreakpointManager,
+    signals: SignalTable,
+    /// The frame `print`, `info locals` and friends look at; 0 is the innermost. Reset whenever
+    /// the inferior runs.
+    selected_frame: usize,
+    /// The file `list` last showed, and the line it should continue from
+    last_listed: Option<(String, usize)>,
+    /// How the last inferior ended: its exit code, or 128 plus the signal that killed it
+    exit_status: Option<i32>,
+    /// Commands created with `define`
+    user_commands: HashMap<String, Vec<String>>,
+    /// The `define` or `commands` block being typed in, if any
+    recording: Option<Recording>,
+    /// Commands of the breakpoint the inferior just stopped at, still to be run
+    pending_commands: Vec<String>,
+    /// Whether breakpoint commands are being run
+    running_commands: bool,
+    /// Bumped by every command that lets the inferior run, so that a breakpoint's command list
+    /// can tell when to stop
+    resume_count: usize,
+    /// How many `source` commands are running inside each other
+    source_depth: usize,
+    /// How many user-defined commands are running inside each other
+    user_call_depth: usize,
+    /// Where messages for the user go
+    console: Rc<Console>,
+    /// Where programs we start write their output, if not to our terminal
+    program_output: Option<File>,
 }
+/// What the lines after `define` or `commands` are for.
+enum Block {
+    Define(String),
+    Commands(usize),
+}
+/// A `define` or `commands` block being typed in, up to its "end".
+struct Recording {
+    block: Block,
+    lines: Vec<String>,
+    /// How many blocks nested inside this one are still open
+    depth: usize,
+}
+/// Evaluates expressions against a frame of the inferior's stack.
+struct FrameContext<'a> {
+    debugger: &'a Debugger,
+    frame: Frame,
+}
+
+impl expr::Context for FrameContext<'_> {
+    fn variable(&self, name: &str) -> Result<expr::Value, String> {
+        let var = match self.debugger.lookup_variable(name, self.frame.lookup_pc()) {
+            Some(var) => var,
+            None => {
+                let (enum_type, value) = self
+                    .debugger
+                    .debug_data
+                    .types()
+                    .find_enumerator(name)
+                    .ok_or(format!("No symbol \"{}\" in current context.", name))?;
+                return Ok(expr::Value {
+                    value_type: enum_type.clone(),
+                    bytes: value.to_le_bytes()[..enum_type.size.min(8)].to_vec(),
+                    address: None,
+                });
+            }
+        };
+        let (bytes, address) = self
+            .debugger
+            .read_variable(var, Some(&self.frame))
+            .map_err(|e| e.to_string())?;
+        Ok(expr::Value {
+            value_type: var.entity_type.clone(),
+            bytes,
+            address,
+        })
+    }
+
+    fn read_memory(&self, addr: usize, len: usize) -> Result<Vec<u8>, String> {
+        self.debugger
+            .debuggee()
+            .unwrap()
+            .read_bytes(addr, len)
+            .or(Err(format!("Cannot access memory at address {:#x}", addr)))
+    }
+
+    fn members(&self, id: usize) -> Vec<Member> {
+        self.debugger.debug_data.types().members(id).to_vec()
+    }
+
+    fn find_type(&self, name: &str) -> Option<Type> {
+        self.debugger.debug_data.types().find_type(name).cloned()
+    }
+
+    fn register(&self, name: &str) -> Result<i64, String> {
+        registers::get(&self.frame.regs, name)
+            .map(|value| value as i64)
+            .ok_or(format!("Invalid register \"${}\"", name))
+    }
+}
+
+/// How many lines `list` shows at a time.
+const LIST_SIZE: usize = 10;
+
+/// How deeply `source` commands may nest, so that a script sourcing itself stops with an error
+/// rather than overflowing the stack.
+const MAX_SOURCE_DEPTH: usize = 16;
+
+/// How deeply user-defined commands may call each other, like gdb's `max-user-call-depth`.
+const MAX_USER_CALL_DEPTH: usize = 1024;
+
+/// Replaces `$argc` in a line of a user-defined command with the number of `args`, and each
+/// `$argN` with the Nth of them. The digits after `$arg` are all part of the reference, so
+/// `$arg10` is never read as `$arg1` followed by "0".
+fn substitute_args(line: &str, args: &[&str]) -> Result<String, String> {
+    let mut result = String::new();
+    let mut rest = line;
+    while let Some(start) = rest.find("$arg") {
+        result.push_str(&rest[..start]);
+        let after = &rest[start + 4..];
+        let digits = after.chars().take_while(|c| c.is_ascii_digit()).count();
+        if digits > 0 {
+            let index: usize = after[..digits].parse().map_err(|_| "Invalid argument number")?;
+            let arg = args
+                .get(index)
+                .ok_or(format!("Missing argument {} in user function.", index))?;
+            result.push_str(arg);
+            rest = &after[digits..];
+        } else if after.starts_with('c') {
+            result.push_str(&args.len().to_string());
+            rest = &after[1..];
+        } else {
+            result.push_str("$arg");
+            rest = after;
+        }
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
 enum StepStatus {
     Exit,
     Ok,
 }
 impl Debugger {
-    /// Initializes the debugger.
-    pub fn new(target: &str) -> Debugger {
+    /// Initializes the debugger, which says everything it has to say on `console`.
+    pub fn new(target: &str, console: Rc<Console>) -> Result<Debugger, String> {
         let debug_data = match DwarfData::from_file(target) {
             Ok(val) => val,
             Err(DwarfError::ErrorOpeningFile) => {
-                println!("Could not open file {}", target);
-                std::process::exit(1);
+                return Err(format!("Could not open file {}", target));
             }
             Err(DwarfError::DwarfFormatError(err)) => {
-                println!("Could not debugging symbols from {}: {:?}", target, err);
-                std::process::exit(1);
+                return Err(format!("Could not debugging symbols from {}: {:?}", target, err));
             }
         };
         let history_path = format!("{}/.deet_history", std::env::var("HOME").unwrap());
         let mut readline = Editor::<()>::new();
         // Attempt to load history from ~/.deet_history if it exists
         let _ = readline.load_history(&history_path);
-        debug_data.print();
+        debug_data.print(&console);
 
-        Debugger {
+        Ok(Debugger {
             target: target.to_string(),
             history_path,
             readline,
             inferior: None,
+            core: None,
             debug_data,
             breakpoints: BreakpointManager::new(),
+            signals: SignalTable::new(),
+            selected_frame: 0,
+            last_listed: None,
+            exit_status: None,
+            user_commands: HashMap::new(),
+            recording: None,
+            pending_commands: Vec::new(),
+            running_commands: false,
+            resume_count: 0,
+            source_depth: 0,
+            user_call_depth: 0,
+            console,
+            program_output: None,
+        })
+    }
+    /// Returns what inspection commands should look at: the running inferior, or else the core
+    /// dump.
+    fn debuggee(&self) -> Option<&dyn Target> {
+        match &self.inferior {
+            Some(inferior) => Some(inferior),
+            None => self.core.as_ref().map(|core| core as &dyn Target),
+        }
+    }
+
+    /// Returns the running inferior, if any.
+    pub fn inferior(&self) -> Option<&Inferior> {
+        self.inferior.as_ref()
+    }
+
+    /// Returns how the last inferior to end did so: its exit code, or 128 plus the number of
+    /// the signal that killed it.
+    pub fn exit_status(&self) -> Option<i32> {
+        self.exit_status
+    }
+
+    /// Sends the output of programs started from now on to `output` rather than our terminal,
+    /// and gives them no input.
+    pub fn redirect_program_output(&mut self, output: File) {
+        self.program_output = Some(output);
+    }
+
+    /// Loads the core dump at `path` for post-mortem debugging, and shows where the program
+    /// died.
+    pub fn load_core(&mut self, path: &str) {
+        let core = match CoreFile::open(path) {
+            Ok(core) => core,
+            Err(e) => {
+                say!(self.console, "{}", e);
+                return;
+            }
+        };
+        say!(self.console, "Core was generated by `{}'.", core.command);
+        if let Some(signal) = core.signal {
+            say!(self.console, "Program terminated with signal {}.", signal);
         }
+        self.core = Some(core);
+        self.relocate_executable();
+        self.select_frame(None, false);
     }
-    fn parse_address(&mut self, raw_addr: &str) -> Option<usize> {
+
+    fn parse_address(&mut self, raw_addr: &str) -> Result<usize, String> {
         match BreakpointManager::parse_breakpoint_arg(raw_addr) {
-            BreakpointArgType::Line(line) => {
-                println!("Function get_addr_for_line dosen't work.");
-                self.debug_data.get_addr_for_line(None, line)
+            BreakpointArgType::Line(line) => self.resolve_line(None, line),
+            BreakpointArgType::FileLine(file, line) => self.resolve_line(Some(&file), line),
+            BreakpointArgType::FuncName(func) => self.resolve_function(None, &func),
+            BreakpointArgType::FileFunc(file, func) => self.resolve_function(Some(&file), &func),
+            BreakpointArgType::Addr(addr) => {
+                Ok(addr)
             }
-            BreakpointArgType::FuncName(func) => {
-                self.debug_data.get_addr_for_function(None, func.as_str())
+            BreakpointArgType::Unknown => Err(format!("Invalid address \"{}\"", raw_addr)),
+        }
+    }
+    /// Sets a breakpoint at `location`, which only stops the inferior when `condition` holds.
+    /// Returns its address, and its number unless there already was a breakpoint there.
+    pub fn set_breakpoint(
+        &mut self,
+        location: &str,
+        condition: Option<String>,
+    ) -> Result<(usize, Option<usize>), String> {
+        if let Some(condition) = &condition {
+            expr::parse(condition)?;
+        }
+        let addr = self.parse_address(location)?;
+        let description = self.describe_addr(addr);
+        let id = self
+            .breakpoints
+            .set(&mut self.inferior, &addr, description)
+            .map_err(|e| e.to_string())?;
+        if let Some(id) = id {
+            self.breakpoints.set_condition(id, condition);
+        }
+        Ok((addr, id))
+    }
+    fn resolve_line(&self, file: Option<&str>, line: usize) -> Result<usize, String> {
+        if let Some(file) = file {
+            if !self.debug_data.has_source_file(file) {
+                return Err(format!("No source file named {}.", file));
             }
-            BreakpointArgType::Addr(addr) => {
-                Some(addr)
+        }
+        self.debug_data.get_addr_for_line(file, line).ok_or(match file {
+            Some(file) => format!("Line {} in file \"{}\" has no code.", line, file),
+            None => format!("Line {} has no code.", line),
+        })
+    }
+    /// Resolves a function to the first address after its prologue, so that its arguments and
+    /// locals can be read as soon as the breakpoint is hit.
+    fn resolve_function(&self, file: Option<&str>, func_name: &str) -> Result<usize, String> {
+        if let Some(file) = file {
+            if !self.debug_data.has_source_file(file) {
+                return Err(format!("No source file named {}.", file));
             }
-            BreakpointArgType::Unknown => None
         }
+        let addr = self
+            .debug_data
+            .get_addr_for_function(file, func_name)
+            .ok_or(format!("Function \"{}\" not defined.", func_name))?;
+        Ok(self
+            .debug_data
+            .get_function_for_addr(addr)
+            .and_then(|func| self.debug_data.get_prologue_end(func))
+            .unwrap_or(addr))
     }
     fn resume(&mut self)  {
-        match self.inferior.as_mut().unwrap().resume().unwrap() {
+        loop {
+            // Step over the breakpoint we are sitting on (if any) before continuing
+            if let Some(addr) = self.stopped_at_breakpoint() {
+                match self.continue_breakpoint(&addr) {
+                    Ok(StepStatus::Ok) => {}
+                    Ok(StepStatus::Exit) => return,
+                    Err(e) => {
+                        say!(self.console, "{}", e);
+                        return;
+                    }
+                }
+            }
+            let scope_traps = match self.set_scope_traps() {
+                Ok(traps) => traps,
+                Err(e) => {
+                    say!(self.console, "{}", e);
+                    return;
+                }
+            };
+            let status = self.inferior.as_mut().unwrap().resume();
+            for (addr, orig_byte) in scope_traps.iter().rev() {
+                let _ = self.inferior.as_mut().map(|i| i.write_byte(*addr, *orig_byte));
+            }
+            let status = match status {
+                Ok(status) => status,
+                Err(e) => {
+                    say!(self.console, "{}", e);
+                    return;
+                }
+            };
+            if let Status::Stopped(signal::SIGTRAP, rip) = status {
+                if scope_traps.iter().any(|(addr, _)| *addr == rip - 1) {
+                    if let Err(e) = self.inferior.as_mut().unwrap().set_rip(rip - 1) {
+                        say!(self.console, "{}", e);
+                        return;
+                    }
+                    if self.leave_watchpoint_scopes() {
+                        let line = DwarfData::get_line_from_addr(&self.debug_data, rip - 1);
+                        self.print_stop_line(&line);
+                        return;
+                    }
+                    // A deeper (recursive) frame returned there; step past before going on
+                    match self.single_step() {
+                        Ok(StepStatus::Ok) => continue,
+                        Ok(StepStatus::Exit) => return,
+                        Err(e) => {
+                            say!(self.console, "{}", e);
+                            return;
+                        }
+                    }
+                }
+            }
+            if let Status::Stopped(signal, _) = status {
+                if signal != signal::SIGTRAP && !self.signal_should_stop(signal) {
+                    continue;
+                }
+            }
+            if let Status::Stopped(signal::SIGTRAP, rip) = status {
+                if self.breakpoints.get(&(rip - 1)).is_some() && !self.breakpoint_should_stop(rip - 1) {
+                    if let Err(e) = self.inferior.as_mut().unwrap().set_rip(rip - 1) {
+                        say!(self.console, "{}", e);
+                        return;
+                    }
+                    continue;
+                }
+            }
+            if let Status::Watchpoint(slot, _) = status {
+                if !self.watchpoint_should_stop(slot) {
+                    continue;
+                }
+            }
+            if let Status::Exec(_) = status {
+                self.follow_exec();
+                continue;
+            }
+            self.handle_status(status);
+            return;
+        }
+    }
+
+    /// Puts temporary breakpoints where the frames of watched locals return to, so that
+    /// continuing stops when a watchpoint goes out of scope. Returns the addresses patched and
+    /// the bytes they held, for putting back once the inferior stops.
+    fn set_scope_traps(&mut self) -> Result<Vec<(usize, u8)>, nix::Error> {
+        let inferior = self.inferior.as_mut().unwrap();
+        let return_addrs: Vec<usize> = self
+            .breakpoints
+            .list_watchpoints()
+            .iter()
+            .filter_map(|watchpoint| Some(watchpoint.scope?.return_addr))
+            .collect();
+        let mut traps = Vec::new();
+        for addr in return_addrs {
+            let trapped = traps.iter().any(|(trap, _)| *trap == addr);
+            if addr != 0 && !trapped && self.breakpoints.get(&addr).is_none() {
+                traps.push((addr, inferior.write_byte(addr, 0xcc)?));
+            }
+        }
+        Ok(traps)
+    }
+
+    /// Deletes the watchpoints on locals whose frames have returned (or whose inferior is gone),
+    /// the way gdb does. Returns true if there were any.
+    fn leave_watchpoint_scopes(&mut self) -> bool {
+        let inferior = self.inferior.as_ref();
+        let expired: Vec<usize> = self
+            .breakpoints
+            .list_watchpoints()
+            .iter()
+            .filter(|watchpoint| match watchpoint.scope {
+                Some(scope) => {
+                    let regs = inferior.and_then(|inferior| inferior.thread_regs(scope.tid));
+                    regs.map_or(true, |regs| regs.rsp as usize >= scope.cfa)
+                }
+                None => false,
+            })
+            .map(|watchpoint| watchpoint.id)
+            .collect();
+        for id in &expired {
+            if let Err(e) = self.breakpoints.unset(&mut self.inferior, *id) {
+                say!(self.console, "{}", e);
+            }
+            say!(
+                self.console,
+                "\nWatchpoint {} deleted because the program has left the block in\n\
+                 which its expression is valid.",
+                id
+            );
+        }
+        !expired.is_empty()
+    }
+
+    /// Decides whether `signal`, which just stopped the inferior, is worth stopping for,
+    /// according to its `handle` policy. Signals that don't stop are reported here if their
+    /// policy says so.
+    fn signal_should_stop(&mut self, signal: signal::Signal) -> bool {
+        let policy = self.signals.get(signal);
+        if !policy.stop {
+            if policy.print {
+                say!(self.console, "Child received signal {}", signal);
+            }
+            self.queue_signal(signal);
+        }
+        policy.stop
+    }
+
+    /// Arranges for `signal` to be delivered when the current thread resumes, if it is to be
+    /// passed to the program.
+    fn queue_signal(&mut self, signal: signal::Signal) {
+        if self.signals.get(signal).pass {
+            self.inferior.as_mut().unwrap().set_signal(Some(signal));
+        }
+    }
+
+    /// Implements `handle SIGNAL [keywords...]`.
+    fn handle_signal(&mut self, name: &str, actions: &[String]) {
+        let signal = match signals::parse_signal(name) {
+            Ok(signal) => signal,
+            Err(e) => {
+                say!(self.console, "{}", e);
+                return;
+            }
+        };
+        match self.signals.update(signal, actions) {
+            Ok(()) => self.signals.print(&self.console, &[signal]),
+            Err(e) => say!(self.console, "{}", e),
+        }
+    }
+
+    /// Implements `info signals [SIGNAL]`.
+    fn print_signals(&self, name: Option<String>) {
+        match name {
+            Some(name) => match signals::parse_signal(&name) {
+                Ok(signal) => self.signals.print(&self.console, &[signal]),
+                Err(e) => say!(self.console, "{}", e),
+            },
+            None => {
+                let signals: Vec<_> = signal::Signal::iterator().collect();
+                self.signals.print(&self.console, &signals)
+            }
+        }
+    }
+
+    /// Implements `signal SIGNAL`: resumes the inferior, delivering `name` (or no signal at all
+    /// for "0") instead of whatever signal stopped it.
+    fn continue_with_signal(&mut self, name: &str) {
+        let signal = if name == "0" {
+            None
+        } else {
+            match signals::parse_signal(name) {
+                Ok(signal) => Some(signal),
+                Err(e) => {
+                    say!(self.console, "{}", e);
+                    return;
+                }
+            }
+        };
+        match signal {
+            Some(signal) => say!(self.console, "Continuing with signal {}.", signal),
+            None => say!(self.console, "Continuing with no signal."),
+        }
+        self.inferior.as_mut().unwrap().set_signal(signal);
+        self.resume();
+    }
+
+    /// Decides whether hitting the breakpoint at `addr` should stop the inferior, by evaluating
+    /// its condition and consuming its ignore count.
+    fn breakpoint_should_stop(&mut self, addr: usize) -> bool {
+        let breakpoint = match self.breakpoints.get(&addr) {
+            Some(breakpoint) => breakpoint,
+            None => return true,
+        };
+        if let Some(condition) = &breakpoint.condition {
+            // rip is still one past the 0xcc, which may be outside the function for a 1-byte body
+            let mut regs = self.inferior.as_ref().unwrap().get_regs().unwrap();
+            regs.rip = addr as u64;
+            let ctx = FrameContext {
+                debugger: self,
+                frame: self.frame_at(regs),
+            };
+            match expr::parse(condition).and_then(|e| expr::eval(&e, &ctx)) {
+                Ok(0) => return false,
+                Ok(_) => {}
+                Err(e) => {
+                    say!(
+                        self.console,
+                        "Error in testing condition for breakpoint {}:\n{}",
+                        breakpoint.id,
+                        e
+                    );
+                    return true;
+                }
+            }
+        }
+        !self.breakpoints.take_ignore(&addr)
+    }
+
+    /// Decides whether a fired watchpoint is worth stopping for. Writes that leave the value
+    /// unchanged are ignored, and so are changes seen by a read watchpoint (x86 can only trap
+    /// reads together with writes).
+    fn watchpoint_should_stop(&mut self, slot: usize) -> bool {
+        // The stack slot of a local that went out of scope may have been reused already
+        if self.leave_watchpoint_scopes() {
+            return true;
+        }
+        let inferior = self.inferior.as_ref().unwrap();
+        let watchpoint = match self.breakpoints.get_watchpoint_mut(slot) {
+            Some(watchpoint) => watchpoint,
+            None => return true,
+        };
+        let new_value = inferior.read_bytes(watchpoint.addr, watchpoint.entity_type.size).ok();
+        let changed = new_value != watchpoint.old_value;
+        match watchpoint.kind {
+            WatchKind::Write => changed,
+            WatchKind::Read => {
+                watchpoint.old_value = new_value;
+                !changed
+            }
+            WatchKind::Access => true,
+        }
+    }
+
+    /// Prints the old and new value of the watchpoint in `slot`, which just fired.
+    fn report_watchpoint(&mut self, slot: usize) {
+        let inferior = self.inferior.as_ref().unwrap();
+        let watchpoint = match self.breakpoints.get_watchpoint_mut(slot) {
+            Some(watchpoint) => watchpoint,
+            None => return,
+        };
+        watchpoint.hit_count += 1;
+        let new_value = inferior.read_bytes(watchpoint.addr, watchpoint.entity_type.size).ok();
+        let label = match watchpoint.kind {
+            WatchKind::Write => "Hardware watchpoint",
+            WatchKind::Read => "Hardware read watchpoint",
+            WatchKind::Access => "Hardware access (read/write) watchpoint",
+        };
+        say!(self.console, "\n{} {}: {}\n", label, watchpoint.id, watchpoint.expr);
+        let entity_type = &watchpoint.entity_type;
+        let types = self.debug_data.types();
+        let format = |bytes: &Option<Vec<u8>>| match bytes {
+            Some(bytes) => value::format_value(entity_type, bytes, types),
+            None => "<unreadable>".to_string(),
+        };
+        if new_value != watchpoint.old_value && watchpoint.kind != WatchKind::Read {
+            say!(self.console, "Old value = {}", format(&watchpoint.old_value));
+            say!(self.console, "New value = {}", format(&new_value));
+        } else {
+            say!(self.console, "Value = {}", format(&new_value));
+        }
+        watchpoint.old_value = new_value;
+    }
+
+    /// Reports why the inferior stopped. If it trapped on one of our breakpoints, rip is rewound
+    /// to the breakpoint address so that the next continue executes the original instruction.
+    fn handle_status(&mut self, status: Status) {
+        if let Some(thread) = self.inferior.as_mut().and_then(|i| i.take_thread_switch()) {
+            say!(self.console, "[Switching to thread {} (Thread {})]", thread.id, thread.tid);
+        }
+        match status {
             Status::Stopped(s, rip) => {
-                println!("Child stopped (signal {})", s);
+                let mut rip = rip;
+                if s != signal::SIGTRAP {
+                    self.queue_signal(s);
+                }
+                if s == signal::SIGTRAP && self.breakpoints.get(&(rip - 1)).is_some() {
+                    rip -= 1;
+                    self.inferior.as_mut().unwrap().set_rip(rip).unwrap();
+                    self.breakpoints.record_hit(&rip);
+                    if let Some(breakpoint) = self.breakpoints.get(&rip) {
+                        self.pending_commands = breakpoint.commands;
+                    }
+                }
+                say!(self.console, "Child stopped (signal {})", s);
+                let line = DwarfData::get_line_from_addr(&self.debug_data, rip);
+                self.print_stop_line(&line);
+            }
+            Status::Watchpoint(slot, rip) => {
+                self.report_watchpoint(slot);
+                let line = DwarfData::get_line_from_addr(&self.debug_data, rip);
+                self.print_stop_line(&line);
+            }
+            Status::Exec(rip) => {
+                self.follow_exec();
                 let line = DwarfData::get_line_from_addr(&self.debug_data, rip);
-                Self::print_stop_line(&line);
+                self.print_stop_line(&line);
             }
             Status::Exited(e) => {
                 self.inferior.take();
-                println!("Child exited (status {})", e);
+                self.leave_watchpoint_scopes();
+                self.exit_status = Some(e);
+                say!(self.console, "Child exited (status {})", e);
             }
             Status::Signaled(s) => {
-                println!("Signaled {}", s);
+                self.inferior.take();
+                self.leave_watchpoint_scopes();
+                self.exit_status = Some(128 + s as i32);
+                say!(self.console, "Signaled {}", s);
             }
         }
     }
     
-    fn stopped_at_breakpoint(&mut self) -> Option<usize> {
+    /// Catches up with an inferior that just called execve. Our breakpoints went away with the
+    /// old program; they are put back if the new program is the one we are debugging.
+    fn follow_exec(&mut self) {
+        let inferior = self.inferior.as_mut().unwrap();
+        let path = fs::read_link(format!("/proc/{}/exe", inferior.pid())).unwrap_or_default();
+        say!(
+            self.console,
+            "process {} is executing new program: {}",
+            inferior.pid(),
+            path.display()
+        );
+        self.breakpoints.forget_inserted();
+        if fs::canonicalize(&self.target).ok() == Some(path) {
+            if let Err(e) = inferior.init_breakpoints(&mut self.breakpoints) {
+                say!(self.console, "{}", e);
+            }
+        }
+    }
+
+    /// Returns the address of the breakpoint the inferior is stopped at, if any.
+    pub fn stopped_at_breakpoint(&mut self) -> Option<usize> {
         let inferior = self.inferior.as_mut()?;
         let regs = inferior.get_regs()?;
         let _ = self.breakpoints.get(&(regs.rip as usize))?;
@@ -93,220 +696,1753 @@ impl Debugger {
         let inferior = self.inferior.as_mut().unwrap();
         self.breakpoints.unset_t(inferior, addr)?;
         inferior.ptrace_step()?;
-        match inferior.wait(None)? {
-            Status::Stopped(signal::SIGTRAP, _) => {
-                self.breakpoints.set_t(inferior, addr)?;
-                Ok(StepStatus::Ok)
-            }
-            Status::Exited(e) => {
-                self.inferior.take();
-                println!("Child exited (status {})", e);
-                Ok(StepStatus::Exit)
-            }
-            Status::Stopped(s, _) => {
-                println!("Child stopped (signal {})", s);
+        let status = inferior.wait()?;
+        if let Status::Exited(_) | Status::Signaled(_) | Status::Exec(_) = status {
+            self.handle_status(status);
+            return Ok(StepStatus::Exit);
+        }
+        self.breakpoints.set_t(inferior, addr)?;
+        match status {
+            Status::Stopped(signal::SIGTRAP, _) => Ok(StepStatus::Ok),
+            // The signal arrived before the instruction ran, so step it again
+            Status::Stopped(signal, _) if !self.signal_should_stop(signal) => self.single_step(),
+            Status::Watchpoint(slot, _) if !self.watchpoint_should_stop(slot) => Ok(StepStatus::Ok),
+            other => {
+                self.handle_status(other);
                 Ok(StepStatus::Exit)
             }
-            _ => {
-                panic!("Something went wrong when continue breakpoint");
-            }
         }
     }
     fn continue_normal(&mut self) -> Result<StepStatus, nix::Error> {
         let inferior = self.inferior.as_mut().unwrap();
         inferior.ptrace_step()?;
-        match inferior.wait(None)? {
+        match inferior.wait()? {
             Status::Stopped(signal::SIGTRAP, _) => {
                 Ok(StepStatus::Ok)
             }
-            Status::Exited(e) => {
-                self.inferior.take();
-                println!("Child exited (status {})", e);
-                Ok(StepStatus::Exit)
-            }
-            Status::Stopped(s, _) => {
-                println!("Child stopped (signal {})", s);
+            Status::Stopped(signal, _) if !self.signal_should_stop(signal) => self.single_step(),
+            Status::Watchpoint(slot, _) if !self.watchpoint_should_stop(slot) => Ok(StepStatus::Ok),
+            other => {
+                self.handle_status(other);
                 Ok(StepStatus::Exit)
             }
-            _ => {
-                panic!("Something went wrong when excute code");
-            }
         }
     }
     
     fn single_step(&mut self) -> Result<StepStatus, nix::Error> {
         match self.stopped_at_breakpoint() {
             Some(addr) => {
-                self.continue_breakpoint(&addr)?;
-                Ok(StepStatus::Ok)
+                self.continue_breakpoint(&addr)
             }
             None => {
                 self.continue_normal()
             }
         }
     }
-    
-    fn print_stop_line(stop_line: &Option<Line>) {
-        match stop_line {
-            None => {
-                println!("Stopped at ???");
-            }
-            Some(line) => {
-                println!("Stopped at {}", line);
-            }
-        }
-    }
-    fn current_line(&mut self) -> Option<Line> {
-        let inferior = self.inferior.as_mut()?;
-        let regs = inferior.get_regs()?;
-        let addr = regs.rip as usize;
-        self.debug_data.get_line_from_addr(addr)
-    }
-    pub fn run(&mut self) {
-        loop {
-            match self.get_next_command() {
-                DebuggerCommand::Run(args) => {
-                    // if pre inferior still alive, kill it
-                    if let Some(mut inferior) = self.inferior.take() {
-                        inferior.kill().unwrap();
-                    }
 
-                    if let Some(inferior) = Inferior::new(&self.target, &args, &mut self.breakpoints) {
-                        // Create the inferior
-                        self.inferior = Some(inferior);
-                        // TODO (milestone 1): make the inferior run
-                        // You may use self.inferior.as_mut().unwrap() to get a mutable reference
-                        // to the Inferior object
-                        self.resume();
-                    } else {
-                        println!("Error starting subprocess");
+    /// Continues until the inferior reaches `addr` with its stack pointer at or above `stack_ptr`,
+    /// using a temporary breakpoint. Hitting `addr` in a deeper (recursive) frame doesn't count.
+    /// Returns StepStatus::Exit if something else stopped the inferior first; that stop has
+    /// already been reported.
+    fn run_to(&mut self, addr: usize, stack_ptr: usize) -> Result<StepStatus, nix::Error> {
+        let user_breakpoint = self.breakpoints.get(&addr).is_some();
+        let tid = self.inferior.as_ref().unwrap().tid();
+        loop {
+            // Step off the current instruction first if it would trap straight away. If another
+            // thread was the last to stop, it gets the same treatment before we switch back.
+            loop {
+                let rip = self.inferior.as_ref().unwrap().get_regs().unwrap().rip as usize;
+                if rip == addr || self.stopped_at_breakpoint().is_some() {
+                    if let StepStatus::Exit = self.single_step()? {
+                        return Ok(StepStatus::Exit);
                     }
                 }
-                DebuggerCommand::Quit => {
-                    if let Some(mut i) = self.inferior.take() {
-                        i.kill().unwrap();
-                    }
-                    return;
+                let inferior = self.inferior.as_mut().unwrap();
+                if inferior.tid() == tid {
+                    break;
                 }
-                DebuggerCommand::Continue => {
-                    if self.inferior.is_none() {
-                        println!("No running program!");
-                        continue;
+                inferior.select_thread(tid);
+            }
+            let inferior = self.inferior.as_mut().unwrap();
+            let orig_byte = if user_breakpoint {
+                None
+            } else {
+                Some(inferior.write_byte(addr, 0xcc)?)
+            };
+            let status = inferior.resume()?;
+            match status {
+                Status::Stopped(signal::SIGTRAP, rip) if rip - 1 == addr => {
+                    if let Some(orig_byte) = orig_byte {
+                        inferior.write_byte(addr, orig_byte)?;
                     }
-                    if let Some(addr) = self.stopped_at_breakpoint() {
-                        match self.continue_breakpoint(&addr).unwrap() {
-                            StepStatus::Exit => {
-                                continue;
-                            }
-                            StepStatus::Ok => {
-
-                            }
-                        }
+                    inferior.set_rip(addr)?;
+                    let regs = inferior.get_regs().unwrap();
+                    if inferior.tid() == tid && regs.rsp as usize >= stack_ptr {
+                        return Ok(StepStatus::Ok);
+                    }
+                    if user_breakpoint && self.breakpoint_should_stop(addr) {
+                        self.handle_status(Status::Stopped(signal::SIGTRAP, rip));
+                        return Ok(StepStatus::Exit);
                     }
-                    self.resume();
                 }
-                DebuggerCommand::Breakpoint(b) => {
-                    let addr = if let Some(addr_t) = self.parse_address(b.as_str()) {
-                        addr_t
-                    } else {
-                        println!("Unknown address");
+                Status::Stopped(s, rip) => {
+                    if let Some(orig_byte) = orig_byte {
+                        inferior.write_byte(addr, orig_byte)?;
+                    }
+                    if s != signal::SIGTRAP && !self.signal_should_stop(s) {
                         continue;
-                    };
-
-                    match self.breakpoints.set(&mut self.inferior, &addr) {
-                        Ok(success) => {
-                            if success {
-                                println!("Set breakpoint {} at {:#x}", self.breakpoints.get_count() - 1, addr);
-                            } else {
-                                println!("Breakpoint {:#x} have setted before", addr);
-                            }
-                        }
-                        Err(e) => {
-                            println!("{}", e);
-                        }
                     }
-                }
-                DebuggerCommand::Backtrace => {
-                    if let Some(inferior) = self.inferior.as_mut() {
-                        match inferior.print_backtrace(&self.debug_data) {
-                            Ok(_) => {
-
-                            }
-                            Err(e) => {
-                                println!("{}", e);
-                            }
-                        }
+                    if s == signal::SIGTRAP
+                        && self.breakpoints.get(&(rip - 1)).is_some()
+                        && !self.breakpoint_should_stop(rip - 1)
+                    {
+                        self.inferior.as_mut().unwrap().set_rip(rip - 1)?;
+                        continue;
                     }
+                    self.handle_status(status);
+                    return Ok(StepStatus::Exit);
                 }
-                DebuggerCommand::Next => {
-                    if self.inferior.is_none() {
-                        println!("No running program!");
-                        continue;
+                Status::Watchpoint(slot, _) => {
+                    if let Some(orig_byte) = orig_byte {
+                        inferior.write_byte(addr, orig_byte)?;
                     }
-                    let old_line = self.current_line().unwrap();
-                    while let Ok(step_status) = self.single_step() {
-                        let now_line = &self.current_line();
-                        let mut continue_flag = match now_line {
-                            None => true,
-                            Some(now_line) => {
-                                old_line.number == now_line.number
-                                    && 
-                                old_line.file == now_line.file
-                            }
-                        };
-                        match step_status {
-                            StepStatus::Exit => continue_flag = false,
-                            StepStatus::Ok => {}
-                        }
-                        if continue_flag {
-                            continue;
-                        }
-                        Self::print_stop_line(now_line);
-                        break;
+                    if self.watchpoint_should_stop(slot) {
+                        self.handle_status(status);
+                        return Ok(StepStatus::Exit);
                     }
                 }
+                _ => {
+                    self.handle_status(status);
+                    return Ok(StepStatus::Exit);
+                }
             }
         }
     }
 
-    /// This function prompts the user to enter a command, and continues re-prompting until the user
-    /// enters a valid command. It uses DebuggerCommand::from_tokens to do the command parsing.
-    ///
-    /// You don't need to read, understand, or modify this function.
-    fn get_next_command(&mut self) -> DebuggerCommand {
+    /// If the instruction that was just single-stepped from `old_rip` was a call, returns the
+    /// return address it pushed.
+    fn called_from(&self, old_rip: usize, old_rsp: usize) -> Option<usize> {
+        let inferior = self.inferior.as_ref()?;
+        let regs = inferior.get_regs()?;
+        if regs.rsp as usize != old_rsp - 8 {
+            return None;
+        }
+        // A push also moves rsp down by a word, but it won't push an address just past itself
+        let ret_addr = inferior.read_word(regs.rsp as usize).ok()?;
+        if ret_addr > old_rip && ret_addr <= old_rip + 16 {
+            Some(ret_addr)
+        } else {
+            None
+        }
+    }
+
+    /// Single-steps until the source line changes. Calls are stepped over unless `step_into` is
+    /// set and the callee has debugging info, in which case we stop after the callee's prologue.
+    fn step_line(&mut self, step_into: bool) -> Result<StepStatus, nix::Error> {
+        let start_line = match self.current_line() {
+            Some(line) => line,
+            None => {
+                say!(self.console, "Cannot find bounds of current function");
+                return Ok(StepStatus::Exit);
+            }
+        };
         loop {
-            // Print prompt and get next line of user input
-            match self.readline.readline("(deet) ") {
-                Err(ReadlineError::Interrupted) => {
-                    // User pressed ctrl+c. We're going to ignore it
-                    println!("Type \"quit\" to exit");
-                }
-                Err(ReadlineError::Eof) => {
-                    // User pressed ctrl+d, which is the equivalent of "quit" for our purposes
-                    return DebuggerCommand::Quit;
-                }
-                Err(err) => {
-                    panic!("Unexpected I/O error: {:?}", err);
-                }
-                Ok(line) => {
-                    if line.trim().len() == 0 {
-                        continue;
+            let regs = self.inferior.as_ref().unwrap().get_regs().unwrap();
+            let (old_rip, old_rsp) = (regs.rip as usize, regs.rsp as usize);
+            if let StepStatus::Exit = self.single_step()? {
+                return Ok(StepStatus::Exit);
+            }
+            if let Some(ret_addr) = self.called_from(old_rip, old_rsp) {
+                let rip = self.inferior.as_ref().unwrap().get_regs().unwrap().rip as usize;
+                let callee = self.debug_data.get_function_for_addr(rip).cloned();
+                match callee {
+                    Some(callee) if step_into => {
+                        return self.skip_prologue(&callee);
                     }
-                    self.readline.add_history_entry(line.as_str());
-                    if let Err(err) = self.readline.save_history(&self.history_path) {
-                        println!(
-                            "Warning: failed to save history file at {}: {}",
-                            self.history_path, err
-                        );
+                    _ => {
+                        if let StepStatus::Exit = self.run_to(ret_addr, old_rsp)? {
+                            return Ok(StepStatus::Exit);
+                        }
                     }
-                    let tokens: Vec<&str> = line.split_whitespace().collect();
-                    if let Some(cmd) = DebuggerCommand::from_tokens(&tokens) {
-                        return cmd;
-                    } else {
-                        println!("Unrecognized command.");
+                }
+            }
+            match self.current_line() {
+                Some(line) => {
+                    if line.number != start_line.number || line.file != start_line.file {
+                        return Ok(StepStatus::Ok);
                     }
                 }
+                None => {
+                    // We returned into code without debugging info (e.g. out of main into libc),
+                    // so there is no line left to stop on
+                    self.resume();
+                    return Ok(StepStatus::Exit);
+                }
+            }
+        }
+    }
+
+    /// Single-steps through the prologue of `func`, which the inferior has just entered.
+    fn skip_prologue(&mut self, func: &Function) -> Result<StepStatus, nix::Error> {
+        let body_addr = match self.debug_data.get_prologue_end(func) {
+            Some(addr) => addr,
+            None => return Ok(StepStatus::Ok),
+        };
+        loop {
+            let rip = self.inferior.as_ref().unwrap().get_regs().unwrap().rip as usize;
+            if rip == body_addr || rip < func.address || rip >= func.address + func.text_length {
+                return Ok(StepStatus::Ok);
+            }
+            if let StepStatus::Exit = self.single_step()? {
+                return Ok(StepStatus::Exit);
+            }
+        }
+    }
+
+    /// Runs until the selected frame returns, then prints its return value.
+    fn finish(&mut self) -> Result<StepStatus, nix::Error> {
+        let frame = match self.selected_frame() {
+            Some(frame) => frame,
+            None => return Ok(StepStatus::Exit),
+        };
+        let func = match self.debug_data.get_function_for_addr(frame.lookup_pc()) {
+            Some(func) => func.clone(),
+            None => {
+                say!(self.console, "Cannot find bounds of current function");
+                return Ok(StepStatus::Exit);
+            }
+        };
+        if func.name == "main" {
+            say!(self.console, "\"finish\" not meaningful in the outermost frame.");
+            return Ok(StepStatus::Exit);
+        }
+        // The return address sits just below the CFA
+        let cfa = frame.cfa;
+        let ret_addr = self.inferior.as_ref().unwrap().read_word(cfa - 8)?;
+        say!(self.console, "Run till exit from {}", func.name);
+        if let StepStatus::Exit = self.run_to(ret_addr, cfa)? {
+            return Ok(StepStatus::Exit);
+        }
+        self.leave_watchpoint_scopes();
+        let line = self.current_line();
+        self.print_stop_line(&line);
+        if let Some(return_type) = &func.return_type {
+            match self.return_value(return_type)? {
+                Some(bytes) => {
+                    let value = value::format_value(return_type, &bytes, self.debug_data.types());
+                    say!(self.console, "Value returned is {}", value);
+                }
+                None => say!(
+                    self.console,
+                    "Value returned has type {} (not supported)",
+                    return_type.name
+                ),
             }
         }
+        Ok(StepStatus::Ok)
+    }
+
+    /// Reads the value a function of `return_type` has just returned from the registers the
+    /// calling convention puts it in, or returns None if it was returned in memory.
+    fn return_value(&self, return_type: &Type) -> Result<Option<Vec<u8>>, nix::Error> {
+        let classes = match value::classify(return_type, self.debug_data.types()) {
+            Some(classes) => classes,
+            None => return Ok(None),
+        };
+        let inferior = self.inferior.as_ref().unwrap();
+        let regs = inferior.get_regs().unwrap();
+        let fpregs = inferior.get_fpregs()?;
+        let mut integer = vec![regs.rax, regs.rdx].into_iter();
+        // The low eightbytes of xmm0 and xmm1
+        let mut sse = (0..2).map(|i| {
+            fpregs.xmm_space[4 * i] as u64 | (fpregs.xmm_space[4 * i + 1] as u64) << 32
+        });
+        let mut bytes = Vec::new();
+        for class in classes {
+            let eightbyte = match class {
+                Some(RegClass::Integer) => integer.next().unwrap(),
+                Some(RegClass::Sse) => sse.next().unwrap(),
+                None => 0,
+            };
+            bytes.extend_from_slice(&eightbyte.to_le_bytes());
+        }
+        bytes.truncate(return_type.resolve().size);
+        Ok(Some(bytes))
+    }
+    
+    /// Moves the executable's unwind information to where the new debuggee has it loaded.
+    fn relocate_executable(&mut self) {
+        if let Some(mappings) = self.debuggee().map(|target| target.mappings()) {
+            self.debug_data.relocate(&mappings);
+        }
+    }
+
+    /// Unwinds the inferior's stack from `regs`, through the executable and the given shared
+    /// libraries.
+    fn unwind_stack(
+        &self,
+        regs: libc::user_regs_struct,
+        libraries: &[Module],
+        limit: usize,
+    ) -> Vec<Frame> {
+        let target = match self.debuggee() {
+            Some(target) => target,
+            None => return Vec::new(),
+        };
+        let mut modules = vec![self.debug_data.module()];
+        modules.extend(libraries.iter());
+        unwind::unwind(regs, &modules, &|addr| target.read_word(addr).ok(), limit)
+    }
+
+    /// Returns the innermost frame for `regs`, falling back to the frame pointer if there is no
+    /// unwind information.
+    fn frame_at(&self, regs: libc::user_regs_struct) -> Frame {
+        match self.unwind_stack(regs, &[], 1).pop() {
+            Some(frame) => frame,
+            None => Frame {
+                regs,
+                cfa: regs.rbp as usize + 16,
+                caller: false,
+            },
+        }
+    }
+
+    /// Returns the frames that can be selected with `frame`, `up` and `down`: those of the
+    /// executable, from the innermost one out to main.
+    fn stack_frames(&self) -> Vec<Frame> {
+        let regs = match self.debuggee().and_then(|target| target.get_regs()) {
+            Some(regs) => regs,
+            None => return Vec::new(),
+        };
+        let mut frames = self.unwind_stack(regs, &[], unwind::MAX_FRAMES);
+        let main = frames.iter().position(|frame| {
+            self.debug_data.get_function_from_addr(frame.lookup_pc()).as_deref() == Some("main")
+        });
+        if let Some(main) = main {
+            frames.truncate(main + 1);
+        }
+        frames
+    }
+
+    /// Returns the function name and source line of each frame in `stack_frames`, with the
+    /// source file given as a full path.
+    pub fn stack_trace(&self) -> Vec<(String, Option<Line>)> {
+        self.stack_frames()
+            .iter()
+            .map(|frame| {
+                let pc = frame.lookup_pc();
+                let name = self.debug_data.get_function_from_addr(pc);
+                let line = self.debug_data.get_line_from_addr(pc).map(|line| Line {
+                    file: self.debug_data.source_path(&line.file).unwrap_or(line.file),
+                    ..line
+                });
+                (name.unwrap_or_else(|| "??".to_string()), line)
+            })
+            .collect()
+    }
+
+    /// Returns the frame selected with `frame`, `up` and `down`. This is needed on every stop,
+    /// so only the frames up to it are unwound.
+    fn selected_frame(&self) -> Option<Frame> {
+        let regs = self.debuggee()?.get_regs()?;
+        self.unwind_stack(regs, &[], self.selected_frame + 1).into_iter().nth(self.selected_frame)
+    }
+
+    /// Implements `frame [n]`, `up [n]` and `down [n]`: selects frame `index` and prints it.
+    /// `moving` is set for `up` and `down`, which complain about the end of the stack rather
+    /// than about the index.
+    fn select_frame(&mut self, index: Option<isize>, moving: bool) {
+        let frames = self.stack_frames();
+        if frames.is_empty() {
+            say!(self.console, "No stack.");
+            return;
+        }
+        if let Some(index) = index {
+            if !moving && index as usize >= frames.len() {
+                say!(self.console, "No frame at level {}.", index);
+                return;
+            }
+            if index < 0 {
+                say!(self.console, "Bottom (innermost) frame selected; you cannot go down.");
+                return;
+            }
+            if index as usize >= frames.len() {
+                say!(self.console, "Initial frame selected; you cannot go up.");
+                return;
+            }
+            self.selected_frame = index as usize;
+        }
+        let index = self.selected_frame.min(frames.len() - 1);
+        self.print_frame(index, &frames[index], &[]);
+        if let Some(line) = self.debug_data.get_line_from_addr(frames[index].lookup_pc()) {
+            self.print_source(&line.file, line.number, line.number);
+        }
+        self.last_listed = None;
+    }
+
+    /// Prints one line of a backtrace, e.g.
+    /// "#1  0x0000000000401189 in func1 (a=5) at samples/segfault.c:11".
+    fn print_frame(&self, index: usize, frame: &Frame, libraries: &[Module]) {
+        say!(self.console, "#{:<3}{}", index, self.describe_frame(frame, libraries));
+    }
+
+    /// Describes where `frame` is, e.g. "0x0000000000401189 in func1 (a=5) at samples/x.c:11".
+    /// The address is left out for the innermost frame of a function with debug info.
+    fn describe_frame(&self, frame: &Frame, libraries: &[Module]) -> String {
+        let pc = frame.pc();
+        let mut text = String::new();
+        let func = self.debug_data.get_function_for_addr(frame.lookup_pc());
+        if frame.caller || func.is_none() {
+            text += &format!("{:#018x} in ", pc);
+        }
+        match func {
+            Some(func) => {
+                let args: Vec<String> = func
+                    .variables
+                    .iter()
+                    .filter(|var| var.is_parameter)
+                    .map(|var| format!("{}={}", var.name, self.format_variable(var, Some(frame))))
+                    .collect();
+                text += &format!("{} ({})", func.name, args.join(", "));
+                if let Some(line) = self.debug_data.get_line_from_addr(frame.lookup_pc()) {
+                    text += &format!(" at {}", line);
+                }
+            }
+            None => {
+                let library = libraries.iter().find(|module| module.contains(pc));
+                let symbol = library
+                    .unwrap_or_else(|| self.debug_data.module())
+                    .symbol_for(frame.lookup_pc());
+                text += &format!("{} ()", symbol.unwrap_or("??"));
+                if let Some(library) = library {
+                    text += &format!(" from {}", library.path);
+                }
+            }
+        }
+        text
+    }
+
+    /// Lists the inferior's threads, or those in the core dump, marking the current one with
+    /// "*".
+    fn print_threads(&self) {
+        // The number, target id, registers (if stopped) and whether it's current of each thread
+        let threads: Vec<(usize, String, Option<libc::user_regs_struct>, bool)> =
+            match (&self.inferior, &self.core) {
+                (Some(inferior), _) => inferior
+                    .threads()
+                    .iter()
+                    .map(|thread| {
+                        let regs = inferior.thread_regs(thread.tid);
+                        let current = thread.tid == inferior.tid();
+                        (thread.id, format!("Thread {}", thread.tid), regs, current)
+                    })
+                    .collect(),
+                (None, Some(core)) => core
+                    .threads()
+                    .into_iter()
+                    .map(|(id, tid, regs)| {
+                        (id, format!("LWP {}", tid), Some(regs), tid == core.tid())
+                    })
+                    .collect(),
+                (None, None) => {
+                    say!(self.console, "No threads.");
+                    return;
+                }
+            };
+        say!(self.console, "  Id   Target Id         Frame");
+        for (id, target_id, regs, current) in threads {
+            let marker = if current { '*' } else { ' ' };
+            let frame = match regs {
+                Some(regs) => self.describe_frame(&self.frame_at(regs), &[]),
+                None => "(running)".to_string(),
+            };
+            say!(self.console, "{} {:<4} {:<17} {}", marker, id, target_id, frame);
+        }
+    }
+
+    /// Implements `thread [n]`: makes thread number `id` current, or shows the current thread.
+    fn switch_thread(&mut self, id: Option<usize>) {
+        if self.inferior.is_none() && self.core.is_some() {
+            self.switch_core_thread(id);
+            return;
+        }
+        let inferior = match self.inferior.as_mut() {
+            Some(inferior) => inferior,
+            None => {
+                say!(self.console, "No thread selected.");
+                return;
+            }
+        };
+        let id = match id {
+            Some(id) => id,
+            None => {
+                let current = inferior.tid();
+                let thread = inferior.threads().iter().find(|thread| thread.tid == current);
+                if let Some(thread) = thread {
+                    say!(self.console, "[Current thread is {} (Thread {})]", thread.id, thread.tid);
+                }
+                return;
+            }
+        };
+        match inferior.switch_thread(id) {
+            Some(thread) => {
+                say!(self.console, "[Switching to thread {} (Thread {})]", thread.id, thread.tid)
+            }
+            None => {
+                say!(self.console, "Invalid thread ID: {}", id);
+                return;
+            }
+        }
+        self.selected_frame = 0;
+        self.select_frame(None, false);
+    }
+
+    /// Like `switch_thread`, but for the threads of the core dump.
+    fn switch_core_thread(&mut self, id: Option<usize>) {
+        let core = self.core.as_mut().unwrap();
+        let id = match id {
+            Some(id) => id,
+            None => {
+                let current = core.tid();
+                let threads = core.threads();
+                let (id, _, _) = threads.iter().find(|(_, tid, _)| *tid == current).unwrap();
+                say!(self.console, "[Current thread is {} (LWP {})]", id, current);
+                return;
+            }
+        };
+        match core.switch_thread(id) {
+            Some(tid) => say!(self.console, "[Switching to thread {} (LWP {})]", id, tid),
+            None => {
+                say!(self.console, "Invalid thread ID: {}", id);
+                return;
+            }
+        }
+        self.selected_frame = 0;
+        self.select_frame(None, false);
+    }
+
+    /// Prints the stack, innermost frame first. Frames beyond main (libc's startup code) are
+    /// only shown if `past_main` is set.
+    fn print_backtrace(&self, past_main: bool) {
+        let debuggee = match self.debuggee() {
+            Some(debuggee) => debuggee,
+            None => {
+                say!(self.console, "No stack.");
+                return;
+            }
+        };
+        let regs = match debuggee.get_regs() {
+            Some(regs) => regs,
+            None => return,
+        };
+        let libraries = Module::shared_libraries(&debuggee.mappings(), &self.target);
+        let frames = self.unwind_stack(regs, &libraries, unwind::MAX_FRAMES);
+        for (index, frame) in frames.iter().enumerate() {
+            self.print_frame(index, frame, &libraries);
+            let func = self.debug_data.get_function_from_addr(frame.lookup_pc());
+            if !past_main && func.as_deref() == Some("main") {
+                break;
+            }
+        }
+    }
+
+    fn print_stop_line(&mut self, stop_line: &Option<Line>) {
+        // The next `list` should show the code around wherever we stopped
+        self.last_listed = None;
+        match stop_line {
+            None => {
+                say!(self.console, "Stopped at ???");
+            }
+            Some(line) => {
+                say!(self.console, "Stopped at {}", line);
+                self.print_source(&line.file, line.number.saturating_sub(1), line.number + 1);
+            }
+        }
+    }
+
+    /// Returns the source line of the selected frame, or of main if nothing is running.
+    fn default_source_line(&self) -> Option<Line> {
+        let addr = match self.selected_frame() {
+            Some(frame) => frame.lookup_pc(),
+            None => self.debug_data.get_addr_for_function(None, "main")?,
+        };
+        self.debug_data.get_line_from_addr(addr)
+    }
+
+    /// Prints lines `first..=last` of the source file at `path`, marking the current line with
+    /// "=>" and lines with breakpoints with "*". Returns the number of lines in the file, or
+    /// None if it can't be read.
+    fn print_source(&self, path: &str, first: usize, last: usize) -> Option<usize> {
+        let source = fs::read_to_string(path).ok()?;
+        let lines: Vec<&str> = source.lines().collect();
+        let current = self
+            .selected_frame()
+            .and_then(|frame| self.debug_data.get_line_from_addr(frame.lookup_pc()));
+        let breakpoint_lines: Vec<Line> = self
+            .breakpoints
+            .list()
+            .iter()
+            .filter(|breakpoint| breakpoint.enabled)
+            .filter_map(|breakpoint| self.debug_data.get_line_from_addr(breakpoint.addr))
+            .collect();
+        for number in first.max(1)..=last.min(lines.len()) {
+            let is_line = |line: &Line| line.file == path && line.number == number;
+            let marker = format!(
+                "{}{}",
+                if current.as_ref().map_or(false, is_line) { "=>" } else { "  " },
+                if breakpoint_lines.iter().any(is_line) { "*" } else { " " }
+            );
+            say!(self.console, "{} {}\t{}", marker, number, lines[number - 1]);
+        }
+        Some(lines.len())
+    }
+
+    /// Resolves the argument of `list` to a source file and the line to center on.
+    fn resolve_list_location(&self, arg: &str) -> Result<(String, usize), String> {
+        let func_line = |file: Option<&str>, func: &str| {
+            self.debug_data
+                .get_addr_for_function(file, func)
+                .and_then(|addr| self.debug_data.get_line_from_addr(addr))
+                .map(|line| (line.file, line.number))
+                .ok_or(format!("Function \"{}\" not defined.", func))
+        };
+        match BreakpointManager::parse_breakpoint_arg(arg) {
+            BreakpointArgType::Line(line) => {
+                let file = match &self.last_listed {
+                    Some((file, _)) => Some(file.clone()),
+                    None => self.default_source_line().map(|line| line.file),
+                };
+                Ok((file.ok_or("No symbol table is loaded.")?, line))
+            }
+            BreakpointArgType::FileLine(file, line) => match self.debug_data.source_path(&file) {
+                Some(path) => Ok((path, line)),
+                None => Err(format!("No source file named {}.", file)),
+            },
+            BreakpointArgType::FuncName(func) => func_line(None, &func),
+            BreakpointArgType::FileFunc(file, func) => func_line(Some(&file), &func),
+            BreakpointArgType::Addr(addr) => match self.debug_data.get_line_from_addr(addr) {
+                Some(line) => Ok((line.file, line.number)),
+                None => Err(format!("No line number information for address {:#x}", addr)),
+            },
+            BreakpointArgType::Unknown => Err(format!("Function \"{}\" not defined.", arg)),
+        }
+    }
+
+    /// Implements `list [func|file:line|line]`. Without an argument, lists around the current
+    /// line the first time and continues where the last listing stopped after that.
+    fn list(&mut self, arg: Option<String>) {
+        let (path, first) = match (&arg, &self.last_listed) {
+            (Some(arg), _) => match self.resolve_list_location(arg) {
+                Ok((path, center)) => (path, center.saturating_sub(LIST_SIZE / 2).max(1)),
+                Err(e) => {
+                    say!(self.console, "{}", e);
+                    return;
+                }
+            },
+            (None, Some((path, next))) => (path.clone(), *next),
+            (None, None) => match self.default_source_line() {
+                Some(line) => (line.file, line.number.saturating_sub(LIST_SIZE / 2).max(1)),
+                None => {
+                    say!(self.console, "No symbol table is loaded.");
+                    return;
+                }
+            },
+        };
+        let last = first + LIST_SIZE - 1;
+        match self.print_source(&path, first, last) {
+            Some(len) if first > len => {
+                say!(
+                    self.console,
+                    "Line number {} out of range; \"{}\" has {} lines.",
+                    first,
+                    path,
+                    len
+                )
+            }
+            Some(len) => self.last_listed = Some((path, last.min(len) + 1)),
+            None => say!(self.console, "Cannot read source file {}", path),
+        }
+    }
+    /// Works out where `var` lives in `frame` (None for globals when there is no stack).
+    fn locate_variable(
+        &self,
+        var: &Variable,
+        frame: Option<&Frame>,
+    ) -> Result<Place, LocationError> {
+        // Globals can be found before the program runs, e.g. to watch them
+        if let Location::Address(addr) = var.location {
+            return Ok(Place::Memory(addr));
+        }
+        let target = self.running_target()?;
+        let frame_base = frame
+            .and_then(|frame| self.debug_data.get_function_for_addr(frame.lookup_pc()))
+            .and_then(|func| func.frame_base.as_ref());
+        location::locate(&var.location, var.entity_type.size, frame, frame_base, target)
+    }
+
+    /// Reads the value of `var` in `frame`, and returns it with its address if it is in memory.
+    fn read_variable(
+        &self,
+        var: &Variable,
+        frame: Option<&Frame>,
+    ) -> Result<(Vec<u8>, Option<usize>), LocationError> {
+        let place = self.locate_variable(var, frame)?;
+        place.read(var.entity_type.size, self.running_target()?)
+    }
+
+    fn running_target(&self) -> Result<&dyn Target, LocationError> {
+        self.debuggee()
+            .ok_or_else(|| LocationError::Unavailable("The program is not being run.".to_string()))
+    }
+
+    /// Returns the address of `var` in `frame`, or an error if it isn't in memory.
+    fn variable_address(&self, var: &Variable, frame: Option<&Frame>) -> Result<usize, String> {
+        match self.locate_variable(var, frame) {
+            Ok(Place::Memory(addr)) => Ok(addr),
+            Ok(Place::Value(_)) => {
+                Err(format!("Can't take address of \"{}\" which isn't an lvalue.", var.name))
+            }
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    fn format_variable(&self, var: &Variable, frame: Option<&Frame>) -> String {
+        match self.read_variable(var, frame) {
+            Ok((bytes, _)) => {
+                value::format_value(&var.entity_type, &bytes, self.debug_data.types())
+            }
+            Err(LocationError::OptimizedOut) => "<optimized out>".to_string(),
+            Err(e) => format!("<error: {}>", e),
+        }
+    }
+
+    /// Finds the variable `name` is referring to when stopped at `rip`.
+    fn lookup_variable(&self, name: &str, rip: usize) -> Option<&Variable> {
+        // Locals shadow globals, just like in C
+        match self.debug_data.get_function_for_addr(rip) {
+            Some(func) => func.variables.iter().find(|var| var.name == name),
+            None => None,
+        }
+        .or_else(|| self.debug_data.get_global_variable(name))
+    }
+
+    fn print_expression(&mut self, text: &str) {
+        let frame = match self.selected_frame() {
+            Some(frame) => frame,
+            None => {
+                say!(self.console, "No running program!");
+                return;
+            }
+        };
+        let expression = match expr::parse(text) {
+            Ok(expression) => expression,
+            Err(e) => {
+                say!(self.console, "{}", e);
+                return;
+            }
+        };
+        // A variable on its own is shown even if it has been optimized out, like gdb does
+        if let expr::Expr::Var(name) = &expression {
+            if let Some(var) = self.lookup_variable(name, frame.lookup_pc()) {
+                say!(self.console, "{} = {}", text, self.format_variable(var, Some(&frame)));
+                return;
+            }
+        }
+        let ctx = FrameContext {
+            debugger: self,
+            frame,
+        };
+        match expr::evaluate(&expression, &ctx) {
+            Ok(value) => say!(
+                self.console,
+                "{} = {}",
+                text,
+                value::format_value(&value.value_type, &value.bytes, self.debug_data.types())
+            ),
+            Err(e) => say!(self.console, "{}", e),
+        }
+    }
+
+    /// Returns the name and value of each variable of the function in stack frame `index`.
+    pub fn frame_locals(&self, index: usize) -> Result<Vec<(String, String)>, String> {
+        let frame = match self.stack_frames().into_iter().nth(index) {
+            Some(frame) => frame,
+            None => return Err("No running program!".to_string()),
+        };
+        let func = self
+            .debug_data
+            .get_function_for_addr(frame.lookup_pc())
+            .ok_or("No symbol table info available.")?;
+        Ok(func
+            .variables
+            .iter()
+            .map(|var| (var.name.clone(), self.format_variable(var, Some(&frame))))
+            .collect())
+    }
+
+    fn print_locals(&mut self) {
+        match self.frame_locals(self.selected_frame) {
+            Ok(locals) if locals.is_empty() => say!(self.console, "No locals."),
+            Ok(locals) => {
+                for (name, value) in locals {
+                    say!(self.console, "{} = {}", name, value);
+                }
+            }
+            Err(e) => say!(self.console, "{}", e),
+        }
+    }
+
+    /// Returns the name and value of each global variable.
+    pub fn globals(&self) -> Result<Vec<(String, String)>, String> {
+        if self.debuggee().and_then(|target| target.get_regs()).is_none() {
+            return Err("No running program!".to_string());
+        }
+        Ok(self
+            .debug_data
+            .global_variables()
+            .iter()
+            .map(|var| (var.name.clone(), self.format_variable(var, None)))
+            .collect())
+    }
+
+    fn print_globals(&mut self) {
+        match self.globals() {
+            Ok(globals) if globals.is_empty() => say!(self.console, "No globals."),
+            Ok(globals) => {
+                for (name, value) in globals {
+                    say!(self.console, "{} = {}", name, value);
+                }
+            }
+            Err(e) => say!(self.console, "{}", e),
+        }
+    }
+
+    /// Describes `addr` for the breakpoint list, e.g. "in main at samples/count.c:4".
+    fn describe_addr(&self, addr: usize) -> String {
+        let func = self.debug_data.get_function_from_addr(addr);
+        let line = self.debug_data.get_line_from_addr(addr);
+        match (func, line) {
+            (Some(func), Some(line)) => format!("in {} at {}", func, line),
+            (Some(func), None) => format!("in {}", func),
+            (None, Some(line)) => format!("at {}", line),
+            (None, None) => String::new(),
+        }
+    }
+
+    /// Sets a watchpoint on a variable, or on the word at `*<addr>`.
+    fn set_watchpoint(&mut self, kind: WatchKind, target: String) {
+        let (addr, entity_type, scope) = if target.starts_with('*') {
+            match expr::parse_int(&target[1..]) {
+                Ok(addr) => (addr as usize, Type::new("unsigned long".to_string(), 8), None),
+                Err(e) => {
+                    say!(self.console, "{}", e);
+                    return;
+                }
+            }
+        } else {
+            let frame = self.selected_frame();
+            let var = match &frame {
+                Some(frame) => self.lookup_variable(&target, frame.lookup_pc()),
+                None => self.debug_data.get_global_variable(&target),
+            };
+            let var = match var {
+                Some(var) => var,
+                None => {
+                    say!(self.console, "No symbol \"{}\" in current context.", target);
+                    return;
+                }
+            };
+            let addr = match self.variable_address(var, frame.as_ref()) {
+                Ok(addr) => addr,
+                Err(e) => {
+                    say!(self.console, "{}", e);
+                    return;
+                }
+            };
+            // A local is only watched until its frame returns
+            let scope = match (&var.location, &frame, &self.inferior) {
+                (Location::Address(_), _, _) => None,
+                (_, Some(frame), Some(inferior)) => Some(WatchScope {
+                    tid: inferior.tid(),
+                    cfa: frame.cfa,
+                    return_addr: inferior.read_word(frame.cfa - 8).unwrap_or(0),
+                }),
+                _ => None,
+            };
+            (addr, var.entity_type.clone(), scope)
+        };
+        let watched = self.breakpoints.watch(
+            &mut self.inferior,
+            target.clone(),
+            addr,
+            entity_type,
+            kind,
+            scope,
+        );
+        match watched {
+            Ok(id) => {
+                let label = match kind {
+                    WatchKind::Write => "Hardware watchpoint",
+                    WatchKind::Read => "Hardware read watchpoint",
+                    WatchKind::Access => "Hardware access (read/write) watchpoint",
+                };
+                say!(self.console, "{} {}: {}", label, id, target);
+            }
+            Err(e) => say!(self.console, "{}", e),
+        }
+    }
+
+    fn print_breakpoints(&self) {
+        let breakpoints = self.breakpoints.list();
+        let watchpoints = self.breakpoints.list_watchpoints();
+        if breakpoints.is_empty() && watchpoints.is_empty() {
+            say!(self.console, "No breakpoints.");
+            return;
+        }
+        say!(self.console, "Num  Enb  Address             Hits  What");
+        for breakpoint in breakpoints {
+            say!(
+                self.console,
+                "{:<4} {:<4} {:#018x}  {:<5} {}",
+                breakpoint.id,
+                if breakpoint.enabled { "y" } else { "n" },
+                breakpoint.addr,
+                breakpoint.hit_count,
+                breakpoint.location
+            );
+            if let Some(condition) = &breakpoint.condition {
+                say!(self.console, "\tstop only if {}", condition);
+            }
+            if breakpoint.ignore_count > 0 {
+                say!(
+                    self.console,
+                    "\tWill ignore next {} crossings of breakpoint.",
+                    breakpoint.ignore_count
+                );
+            }
+            for command in &breakpoint.commands {
+                say!(self.console, "        {}", command);
+            }
+        }
+        for watchpoint in watchpoints {
+            let kind = match watchpoint.kind {
+                WatchKind::Write => "hw watchpoint",
+                WatchKind::Read => "read watchpoint",
+                WatchKind::Access => "acc watchpoint",
+            };
+            say!(
+                self.console,
+                "{:<4} {:<4} {:#018x}  {:<5} {} {}",
+                watchpoint.id,
+                if watchpoint.enabled { "y" } else { "n" },
+                watchpoint.addr,
+                watchpoint.hit_count,
+                kind,
+                watchpoint.expr
+            );
+        }
+    }
+
+    /// Starts debugging the already running process `pid`.
+    pub fn attach(&mut self, pid: i32) {
+        match Inferior::attach(Pid::from_raw(pid), &mut self.breakpoints, self.console.clone()) {
+            Some(inferior) => {
+                say!(self.console, "Attached to process {}", pid);
+                self.inferior = Some(inferior);
+                self.relocate_executable();
+                let line = self.current_line();
+                self.print_stop_line(&line);
+            }
+            None => say!(self.console, "Could not attach to process {}", pid),
+        }
+    }
+
+    fn detach(&mut self) {
+        let mut inferior = self.inferior.take().unwrap();
+        match inferior.detach(&mut self.breakpoints) {
+            Ok(()) => say!(self.console, "Detached from process {}", inferior.pid()),
+            Err(e) => say!(self.console, "Error detaching from process {}: {}", inferior.pid(), e),
+        }
+    }
+
+    /// Gets rid of the current inferior (if any): processes we attached to are detached from and
+    /// left running, while processes we started are killed. A loaded core dump is dropped too.
+    fn release_inferior(&mut self) {
+        self.core = None;
+        match self.inferior.as_mut() {
+            Some(inferior) if inferior.is_attached() => self.detach(),
+            Some(inferior) => {
+                inferior.kill().unwrap();
+                self.inferior = None;
+            }
+            None => {}
+        }
+    }
+
+    /// Prints all general-purpose registers, or just `name`.
+    fn print_registers(&self, name: Option<String>) {
+        let regs = match self.selected_frame() {
+            Some(frame) => frame.regs,
+            None => {
+                say!(self.console, "The program has no registers now.");
+                return;
+            }
+        };
+        let names = match &name {
+            Some(name) => vec![name.trim_start_matches('$')],
+            None => registers::NAMES.to_vec(),
+        };
+        for name in names {
+            let value = match registers::get(&regs, name) {
+                Some(value) => value,
+                None => {
+                    say!(self.console, "Invalid register `{}'", name);
+                    return;
+                }
+            };
+            let natural = match name {
+                "eflags" => registers::format_flags(value),
+                "rip" | "pc" => match self.symbolize(value as usize) {
+                    symbol if symbol.is_empty() => format!("{:#x}", value),
+                    symbol => format!("{:#x} {}", value, symbol),
+                },
+                "rbp" | "rsp" => format!("{:#x}", value),
+                _ => (value as i64).to_string(),
+            };
+            say!(self.console, "{:<15}{:<19}{}", name, format!("{:#x}", value), natural);
+        }
+    }
+
+    /// Handles `set $reg = <expr>`, writing the new value back with PTRACE_SETREGS.
+    fn set_register(&mut self, assignment: &str) {
+        let (lhs, rhs) = match assignment.find('=') {
+            Some(eq) => (assignment[..eq].trim(), assignment[eq + 1..].trim()),
+            None => {
+                say!(self.console, "Expected an assignment, e.g. set $rax = 0");
+                return;
+            }
+        };
+        if !lhs.starts_with('$') {
+            say!(self.console, "Only registers can be assigned, e.g. set $rax = 0");
+            return;
+        }
+        let regs = self.inferior.as_ref().and_then(|i| i.get_regs());
+        let (mut regs, frame) = match (regs, self.selected_frame()) {
+            (Some(regs), Some(frame)) => (regs, frame),
+            _ => {
+                say!(self.console, "The program has no registers now.");
+                return;
+            }
+        };
+        let ctx = FrameContext {
+            debugger: self,
+            frame,
+        };
+        let value = match expr::parse(rhs).and_then(|e| expr::eval(&e, &ctx)) {
+            Ok(value) => value,
+            Err(e) => {
+                say!(self.console, "{}", e);
+                return;
+            }
+        };
+        if !registers::set(&mut regs, &lhs[1..], value as u64) {
+            say!(self.console, "Invalid register `{}'", &lhs[1..]);
+            return;
+        }
+        if let Err(e) = self.inferior.as_ref().unwrap().set_regs(regs) {
+            say!(self.console, "{}", e);
+        }
+    }
+
+    /// Evaluates the address operand of `x`. `&var` and non-pointer variables give the variable's
+    /// address, pointers give the address they point to, and anything else is evaluated as an
+    /// expression, e.g. `$rsp + 8`.
+    fn eval_address(&self, text: &str, frame: Frame) -> Result<usize, String> {
+        let name = text.trim_start_matches('&');
+        if let Some(var) = self.lookup_variable(name, frame.lookup_pc()) {
+            let is_pointer = matches!(var.entity_type.resolve().kind, TypeKind::Pointer(_));
+            if text.starts_with('&') || !is_pointer {
+                return self.variable_address(var, Some(&frame));
+            }
+        } else if let Some(addr) = self.debug_data.get_addr_for_function(None, name) {
+            return Ok(addr);
+        }
+        let ctx = FrameContext {
+            debugger: self,
+            frame,
+        };
+        expr::parse(text)
+            .and_then(|e| expr::eval(&e, &ctx))
+            .map(|addr| addr as usize)
+    }
+
+    /// Names the function or global variable `addr` falls in, e.g. "<main+4>", or returns an
+    /// empty string.
+    fn symbolize(&self, addr: usize) -> String {
+        if let Some(func) = self.debug_data.get_function_for_addr(addr) {
+            return match addr - func.address {
+                0 => format!("<{}>", func.name),
+                offset => format!("<{}+{}>", func.name, offset),
+            };
+        }
+        for var in self.debug_data.global_variables() {
+            if let Location::Address(start) = var.location {
+                if addr >= start && addr < start + var.entity_type.size.max(1) {
+                    return match addr - start {
+                        0 => format!("<{}>", var.name),
+                        offset => format!("<{}+{}>", var.name, offset),
+                    };
+                }
+            }
+        }
+        String::new()
+    }
+
+    /// Labels an address at the start of a line of `x` output, e.g. "0x401126 <main>:".
+    fn address_label(&self, addr: usize) -> String {
+        match self.symbolize(addr) {
+            symbol if symbol.is_empty() => format!("{:#x}:", addr),
+            symbol => format!("{:#x} {}:", addr, symbol),
+        }
+    }
+
+    /// Formats one unit of memory for `x`.
+    fn format_unit(&self, format: char, bytes: &[u8]) -> String {
+        let unsigned = value::read_unsigned(bytes);
+        match format {
+            'd' => value::read_signed(bytes).to_string(),
+            'u' => unsigned.to_string(),
+            'o' => format!("{:#o}", unsigned),
+            't' => format!("{:0width$b}", unsigned, width = bytes.len() * 8),
+            'a' => match self.symbolize(unsigned as usize) {
+                symbol if symbol.is_empty() => format!("{:#x}", unsigned),
+                symbol => format!("{:#x} {}", unsigned, symbol),
+            },
+            'c' => {
+                let c = bytes[0];
+                if c.is_ascii_graphic() || c == b' ' {
+                    format!("{} '{}'", c as i8, c as char)
+                } else {
+                    format!("{} '\\x{:02x}'", c as i8, c)
+                }
+            }
+            _ => format!("{:#0width$x}", unsigned, width = bytes.len() * 2 + 2),
+        }
+    }
+
+    /// Reads a NUL-terminated string (of at most 200 bytes) starting at `addr`.
+    fn read_c_string(&self, addr: usize) -> Result<Vec<u8>, nix::Error> {
+        let target = self.debuggee().unwrap();
+        let mut string = Vec::new();
+        while string.len() < 200 {
+            let mut byte = target.read_bytes(addr + string.len(), 1)?;
+            self.breakpoints.restore_original(addr + string.len(), &mut byte);
+            if byte[0] == 0 {
+                break;
+            }
+            string.push(byte[0]);
+        }
+        Ok(string)
+    }
+
+    /// Implements `x/<n><fmt><unit> <addr>`.
+    fn examine(&mut self, format: ExamineFormat, text: &str) {
+        let frame = match self.selected_frame() {
+            Some(frame) => frame,
+            None => {
+                say!(self.console, "No running program!");
+                return;
+            }
+        };
+        let pc = frame.pc();
+        let mut addr = match self.eval_address(text, frame) {
+            Ok(addr) => addr,
+            Err(e) => {
+                say!(self.console, "{}", e);
+                return;
+            }
+        };
+        match format.format {
+            's' => {
+                for _ in 0..format.count {
+                    let string = match self.read_c_string(addr) {
+                        Ok(string) => string,
+                        Err(_) => {
+                            say!(self.console, "Cannot access memory at address {:#x}", addr);
+                            return;
+                        }
+                    };
+                    let escaped: String = string
+                        .iter()
+                        .map(|&b| match b {
+                            b'"' | b'\\' => format!("\\{}", b as char),
+                            b' '..=b'~' => (b as char).to_string(),
+                            b'\n' => "\\n".to_string(),
+                            b'\t' => "\\t".to_string(),
+                            _ => format!("\\{:03o}", b),
+                        })
+                        .collect();
+                    say!(self.console, "{}\t\"{}\"", self.address_label(addr), escaped);
+                    addr += string.len() + 1;
+                }
+            }
+            'i' => {
+                for _ in 0..format.count {
+                    let instruction = match self.decode_at(addr) {
+                        Some(instruction) => instruction,
+                        None => {
+                            say!(self.console, "Cannot access memory at address {:#x}", addr);
+                            return;
+                        }
+                    };
+                    let marker = if addr == pc { "=> " } else { "   " };
+                    say!(
+                        self.console,
+                        "{}{}\t{}",
+                        marker,
+                        self.address_label(addr),
+                        self.annotate(&instruction)
+                    );
+                    addr += instruction.len;
+                }
+            }
+            _ => {
+                let per_line = match (format.format, format.unit) {
+                    ('a', _) | (_, 8) => 2,
+                    ('x', 4) | ('d', 4) | ('u', 4) | ('o', 4) | ('t', _) => 4,
+                    _ => 8,
+                };
+                let target = self.debuggee().unwrap();
+                let mut bytes = match target.read_bytes(addr, format.count * format.unit) {
+                    Ok(bytes) => bytes,
+                    Err(_) => {
+                        say!(self.console, "Cannot access memory at address {:#x}", addr);
+                        return;
+                    }
+                };
+                self.breakpoints.restore_original(addr, &mut bytes);
+                let lines = bytes.chunks(format.unit * per_line).enumerate();
+                for (i, line) in lines {
+                    let mut text = self.address_label(addr + i * per_line * format.unit);
+                    for chunk in line.chunks(format.unit) {
+                        text.push('\t');
+                        text.push_str(&self.format_unit(format.format, chunk));
+                    }
+                    say!(self.console, "{}", text);
+                }
+            }
+        }
+    }
+
+    /// Reads up to `len` bytes of code at `addr`, preferring the executable's .text section so
+    /// that we never see our own 0xcc bytes.
+    fn read_code(&self, addr: usize, len: usize) -> Option<Vec<u8>> {
+        if let Some(bytes) = self.debug_data.read_text(addr, len) {
+            return Some(bytes.to_vec());
+        }
+        let target = self.debuggee()?;
+        // An instruction near the end of a mapping may be shorter than `len`
+        let mut bytes = (1..=len)
+            .rev()
+            .find_map(|len| target.read_bytes(addr, len).ok())?;
+        self.breakpoints.restore_original(addr, &mut bytes);
+        Some(bytes)
+    }
+
+    /// Decodes the instruction at `addr`.
+    fn decode_at(&self, addr: usize) -> Option<disasm::Instruction> {
+        // No x86-64 instruction is longer than 15 bytes
+        let bytes = self.read_code(addr, 15)?;
+        Some(disasm::decode(&bytes, addr))
+    }
+
+    /// Appends the symbol a call, jump or rip-relative operand refers to.
+    fn annotate(&self, instruction: &disasm::Instruction) -> String {
+        match instruction.target.map(|target| self.symbolize(target)) {
+            Some(symbol) if !symbol.is_empty() => format!("{} {}", instruction.text, symbol),
+            _ => instruction.text.clone(),
+        }
+    }
+
+    /// Implements `disassemble [func|addr]`, dumping the whole function that contains the
+    /// address (or the current rip).
+    fn disassemble(&self, target: Option<String>) {
+        let frame = self.selected_frame();
+        let rip = frame.as_ref().map(|frame| frame.pc());
+        let addr = match (&target, frame) {
+            (None, Some(frame)) => frame.lookup_pc(),
+            (None, None) => {
+                say!(self.console, "No frame selected.");
+                return;
+            }
+            (Some(target), frame) => {
+                let target = target.trim_start_matches('*');
+                let addr = match (self.debug_data.get_addr_for_function(None, target), frame) {
+                    (Some(addr), _) => Ok(addr),
+                    (None, Some(frame)) => self.eval_address(target, frame),
+                    (None, None) => expr::parse_int(target).map(|addr| addr as usize),
+                };
+                match addr {
+                    Ok(addr) => addr,
+                    Err(e) => {
+                        say!(self.console, "{}", e);
+                        return;
+                    }
+                }
+            }
+        };
+        let func = match self.debug_data.get_function_for_addr(addr) {
+            Some(func) => func,
+            None => {
+                say!(self.console, "No function contains specified address.");
+                return;
+            }
+        };
+        say!(self.console, "Dump of assembler code for function {}:", func.name);
+        let mut addr = func.address;
+        let mut last_line = None;
+        while addr < func.address + func.text_length {
+            if let Some(line) = self.debug_data.get_line_from_addr(addr) {
+                if last_line != Some((line.file.clone(), line.number)) {
+                    say!(self.console, "{}:", line);
+                    last_line = Some((line.file, line.number));
+                }
+            }
+            let instruction = match self.decode_at(addr) {
+                Some(instruction) => instruction,
+                None => break,
+            };
+            let bytes = self.read_code(addr, instruction.len).unwrap_or_default();
+            let marker = format!(
+                "{}{}",
+                if Some(addr) == rip { "=>" } else { "  " },
+                if self.breakpoints.is_breakpoint(addr) { "*" } else { " " }
+            );
+            say!(
+                self.console,
+                "{} {:#018x} <+{}>:\t{:<21}\t{}",
+                marker,
+                addr,
+                addr - func.address,
+                bytes
+                    .iter()
+                    .map(|b| format!("{:02x}", b))
+                    .collect::<Vec<String>>()
+                    .join(" "),
+                self.annotate(&instruction)
+            );
+            addr += instruction.len;
+        }
+        say!(self.console, "End of assembler dump.");
+    }
+
+    fn current_line(&mut self) -> Option<Line> {
+        let regs = self.debuggee()?.get_regs()?;
+        let addr = regs.rip as usize;
+        self.debug_data.get_line_from_addr(addr)
+    }
+    pub fn run(&mut self) {
+        loop {
+            let line = self.get_next_line();
+            if !self.execute_line(&line) {
+                return;
+            }
+        }
+    }
+
+    /// Carries out one line of input: a command, a user-defined command, or part of a `define`
+    /// or `commands` block. Returns false once the user quits.
+    pub fn execute_line(&mut self, line: &str) -> bool {
+        let line = line.trim();
+        if self.recording.is_some() {
+            self.record(line);
+            return true;
+        }
+        if line.is_empty() || line.starts_with('#') {
+            return true;
+        }
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        if let Some(cmd) = DebuggerCommand::from_tokens(&tokens) {
+            return self.execute(cmd);
+        }
+        match self.user_commands.get(tokens[0]).cloned() {
+            Some(body) => self.run_user_command(&body, &tokens[1..]),
+            None => {
+                say!(self.console, "Unrecognized command.");
+                true
+            }
+        }
+    }
+
+    /// Starts collecting the lines of a `define` or `commands` block.
+    fn start_recording(&mut self, block: Block) {
+        if nix::unistd::isatty(0).unwrap_or(false) {
+            match &block {
+                Block::Define(name) => {
+                    say!(self.console, "Type commands for definition of \"{}\".", name)
+                }
+                Block::Commands(id) => say!(self.console, "Type commands for breakpoint {}.", id),
+            }
+            say!(self.console, "End with a line saying just \"end\".");
+        }
+        self.recording = Some(Recording {
+            block,
+            lines: Vec::new(),
+            depth: 0,
+        });
+    }
+
+    /// Adds a line to the block being recorded, finishing it at its "end".
+    fn record(&mut self, line: &str) {
+        let recording = self.recording.as_mut().unwrap();
+        match line.split_whitespace().next() {
+            Some("define") | Some("commands") => recording.depth += 1,
+            Some("end") if line == "end" => {
+                if recording.depth > 0 {
+                    recording.depth -= 1;
+                } else {
+                    let recording = self.recording.take().unwrap();
+                    match recording.block {
+                        Block::Define(name) => {
+                            self.user_commands.insert(name, recording.lines);
+                        }
+                        Block::Commands(id) => {
+                            self.breakpoints.set_commands(id, recording.lines);
+                        }
+                    }
+                    return;
+                }
+            }
+            _ => {}
+        }
+        if !line.is_empty() {
+            recording.lines.push(line.to_string());
+        }
+    }
+
+    /// Runs a command made with `define`, replacing $arg0, $arg1, ... in its body with `args`
+    /// and $argc with how many there are.
+    fn run_user_command(&mut self, body: &[String], args: &[&str]) -> bool {
+        if self.user_call_depth >= MAX_USER_CALL_DEPTH {
+            say!(self.console, "Max user call depth exceeded -- command aborted.");
+            return true;
+        }
+        self.user_call_depth += 1;
+        let mut finished = true;
+        for line in body {
+            let line = match substitute_args(line, args) {
+                Ok(line) => line,
+                Err(e) => {
+                    say!(self.console, "{}", e);
+                    break;
+                }
+            };
+            if !self.execute_line(&line) {
+                finished = false;
+                break;
+            }
+        }
+        self.user_call_depth -= 1;
+        finished
+    }
+
+    /// Runs the command list of the breakpoint the inferior stopped at. A command that resumes
+    /// the inferior ends the list, but may stop at a breakpoint with commands of its own.
+    fn run_breakpoint_commands(&mut self) -> bool {
+        // Whoever is already running them picks up the new list
+        if self.running_commands {
+            return true;
+        }
+        self.running_commands = true;
+        let mut quit = false;
+        while !quit && !self.pending_commands.is_empty() {
+            let commands = mem::take(&mut self.pending_commands);
+            let resume_count = self.resume_count;
+            for line in commands {
+                if !self.execute_line(&line) {
+                    quit = true;
+                    break;
+                }
+                if self.resume_count != resume_count {
+                    break;
+                }
+            }
+        }
+        self.running_commands = false;
+        !quit
+    }
+
+    /// Carries out one command, printing its results. Returns false once the user quits.
+    pub fn execute(&mut self, cmd: DebuggerCommand) -> bool {
+        let resumes = cmd.resumes();
+        match cmd {
+            DebuggerCommand::Run(args) => {
+                // if pre inferior still alive, kill it
+                self.release_inferior();
+
+                let inferior = Inferior::new(
+                    &self.target,
+                    &args,
+                    &mut self.breakpoints,
+                    self.console.clone(),
+                    self.program_output.as_ref(),
+                );
+                if let Some(inferior) = inferior {
+                    // Create the inferior
+                    self.inferior = Some(inferior);
+                    self.relocate_executable();
+                    // TODO (milestone 1): make the inferior run
+                    // You may use self.inferior.as_mut().unwrap() to get a mutable reference
+                    // to the Inferior object
+                    self.resume();
+                } else {
+                    say!(self.console, "Error starting subprocess");
+                }
+            }
+            DebuggerCommand::Attach(pid) => {
+                self.release_inferior();
+                self.attach(pid);
+            }
+            DebuggerCommand::Detach => {
+                if self.inferior.is_none() {
+                    say!(self.console, "No running program!");
+                    return true;
+                }
+                self.detach();
+            }
+            DebuggerCommand::Quit => {
+                self.release_inferior();
+                return false;
+            }
+            DebuggerCommand::Continue => {
+                if self.inferior.is_none() {
+                    say!(self.console, "No running program!");
+                    return true;
+                }
+                self.resume();
+            }
+            DebuggerCommand::Breakpoint(b, condition) => match self.set_breakpoint(&b, condition) {
+                Ok((addr, Some(id))) => say!(self.console, "Set breakpoint {} at {:#x}", id, addr),
+                Ok((addr, None)) => say!(self.console, "Breakpoint {:#x} have setted before", addr),
+                Err(e) => say!(self.console, "{}", e),
+            },
+            DebuggerCommand::Watch(kind, target) => {
+                self.set_watchpoint(kind, target);
+            }
+            DebuggerCommand::Delete(id) => {
+                match self.breakpoints.unset(&mut self.inferior, id) {
+                    Ok(true) => say!(self.console, "Deleted breakpoint {}", id),
+                    Ok(false) => say!(self.console, "No breakpoint number {}.", id),
+                    Err(e) => say!(self.console, "{}", e),
+                }
+            }
+            DebuggerCommand::Ignore(id, count) => {
+                if self.breakpoints.set_ignore_count(id, count) {
+                    say!(
+                        self.console,
+                        "Will ignore next {} crossings of breakpoint {}.",
+                        count,
+                        id
+                    );
+                } else {
+                    say!(self.console, "No breakpoint number {}.", id);
+                }
+            }
+            DebuggerCommand::Enable(id) | DebuggerCommand::Disable(id) => {
+                let enabled = matches!(cmd, DebuggerCommand::Enable(_));
+                match self.breakpoints.set_enabled(&mut self.inferior, id, enabled) {
+                    Ok(true) => {}
+                    Ok(false) => say!(self.console, "No breakpoint number {}.", id),
+                    Err(e) => say!(self.console, "{}", e),
+                }
+            }
+            DebuggerCommand::Backtrace(past_main) => {
+                self.print_backtrace(past_main);
+            }
+            DebuggerCommand::Next | DebuggerCommand::Step => {
+                if self.inferior.is_none() {
+                    say!(self.console, "No running program!");
+                    return true;
+                }
+                let step_into = matches!(cmd, DebuggerCommand::Step);
+                match self.step_line(step_into) {
+                    Ok(StepStatus::Ok) => {
+                        self.leave_watchpoint_scopes();
+                        let line = self.current_line();
+                        self.print_stop_line(&line);
+                    }
+                    Ok(StepStatus::Exit) => {}
+                    Err(e) => say!(self.console, "{}", e),
+                }
+            }
+            DebuggerCommand::Finish => {
+                if self.inferior.is_none() {
+                    say!(self.console, "No running program!");
+                    return true;
+                }
+                if let Err(e) = self.finish() {
+                    say!(self.console, "{}", e);
+                }
+            }
+            DebuggerCommand::Print(text) => {
+                self.print_expression(&text);
+            }
+            DebuggerCommand::Info(what, arg) => match what.as_str() {
+                "locals" => self.print_locals(),
+                "globals" => self.print_globals(),
+                "b" | "break" | "breakpoints" => self.print_breakpoints(),
+                "r" | "reg" | "registers" => self.print_registers(arg),
+                "threads" => self.print_threads(),
+                "signals" | "handle" => self.print_signals(arg),
+                _ => say!(self.console, "Undefined info command: \"{}\"", what),
+            },
+            DebuggerCommand::Disassemble(target) => {
+                self.disassemble(target);
+            }
+            DebuggerCommand::Examine(format, addr) => {
+                self.examine(format, &addr);
+            }
+            DebuggerCommand::Set(assignment) => {
+                self.set_register(&assignment);
+            }
+            DebuggerCommand::List(arg) => {
+                self.list(arg);
+            }
+            DebuggerCommand::Frame(index) => {
+                self.select_frame(index.map(|index| index as isize), false);
+            }
+            DebuggerCommand::Up(count) => {
+                self.select_frame(Some(self.selected_frame as isize + count as isize), true);
+            }
+            DebuggerCommand::Down(count) => {
+                self.select_frame(Some(self.selected_frame as isize - count as isize), true);
+            }
+            DebuggerCommand::Thread(id) => {
+                self.switch_thread(id);
+            }
+            DebuggerCommand::Handle(name, actions) => {
+                self.handle_signal(&name, &actions);
+            }
+            DebuggerCommand::Signal(name) => {
+                if self.inferior.is_none() {
+                    say!(self.console, "The program is not being run.");
+                    return true;
+                }
+                self.continue_with_signal(&name);
+            }
+            DebuggerCommand::Source(path) => {
+                if !self.source(&path) {
+                    return false;
+                }
+            }
+            DebuggerCommand::Define(name) => {
+                self.start_recording(Block::Define(name));
+            }
+            DebuggerCommand::Commands(id) => {
+                let id = match id.or_else(|| self.breakpoints.list().last().map(|b| b.id)) {
+                    Some(id) => id,
+                    None => {
+                        say!(self.console, "No breakpoints specified.");
+                        return true;
+                    }
+                };
+                if self.breakpoints.list().iter().all(|breakpoint| breakpoint.id != id) {
+                    say!(self.console, "No breakpoint number {}.", id);
+                    return true;
+                }
+                self.start_recording(Block::Commands(id));
+            }
+        }
+        if resumes {
+            self.selected_frame = 0;
+            self.resume_count += 1;
+        }
+        self.run_breakpoint_commands()
+    }
+
+    /// Runs the commands in the file at `path` as if they were typed at the prompt, skipping
+    /// blank lines and "#" comments. Returns false if one of them quits.
+    pub fn source(&mut self, path: &str) -> bool {
+        if self.source_depth >= MAX_SOURCE_DEPTH {
+            say!(
+                self.console,
+                "{}: Maximum source nesting depth ({}) exceeded.",
+                path,
+                MAX_SOURCE_DEPTH
+            );
+            return true;
+        }
+        let script = match fs::read_to_string(path) {
+            Ok(script) => script,
+            Err(_) => {
+                say!(self.console, "{}: No such file or directory.", path);
+                return true;
+            }
+        };
+        self.source_depth += 1;
+        let finished = script.lines().all(|line| self.execute_line(line));
+        self.source_depth -= 1;
+        if finished && self.recording.take().is_some() {
+            say!(self.console, "{}: Missing \"end\" in command block.", path);
+        }
+        finished
+    }
+
+    /// This function prompts the user to enter a line, and continues re-prompting until the user
+    /// enters a non-empty one. Lines of a `define` or `commands` block get a ">" prompt.
+    fn get_next_line(&mut self) -> String {
+        loop {
+            // Print prompt and get next line of user input
+            let prompt = if self.recording.is_some() { ">" } else { "(deet) " };
+            match self.readline.readline(prompt) {
+                Err(ReadlineError::Interrupted) => {
+                    // User pressed ctrl+c. We're going to ignore it
+                    say!(self.console, "Type \"quit\" to exit");
+                }
+                Err(ReadlineError::Eof) => {
+                    // User pressed ctrl+d, which is the equivalent of "quit" for our purposes (or
+                    // of "end", in a block)
+                    let line = if self.recording.is_some() { "end" } else { "quit" };
+                    return line.to_string();
+                }
+                Err(err) => {
+                    panic!("Unexpected I/O error: {:?}", err);
+                }
+                Ok(line) => {
+                    if line.trim().len() == 0 {
+                        continue;
+                    }
+                    self.readline.add_history_entry(line.as_str());
+                    if let Err(err) = self.readline.save_history(&self.history_path) {
+                        say!(
+                            self.console,
+                            "Warning: failed to save history file at {}: {}",
+                            self.history_path, err
+                        );
+                    }
+                    return line;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn user_command_arguments() {
+        let args: Vec<String> = (0..11).map(|i| format!("a{}", i)).collect();
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        assert_eq!(substitute_args("print $arg1 + $arg10", &args).unwrap(), "print a1 + a10");
+        assert_eq!(substitute_args("print $argc", &args[..2]).unwrap(), "print 2");
+        assert_eq!(substitute_args("print $arg0$arg1", &args).unwrap(), "print a0a1");
+        assert_eq!(substitute_args("print $args", &args).unwrap(), "print $args");
+        assert_eq!(substitute_args("break $arg", &[]).unwrap(), "break $arg");
+        assert_eq!(
+            substitute_args("print $arg10", &args[..2]),
+            Err("Missing argument 10 in user function.".to_string())
+        );
     }
 }
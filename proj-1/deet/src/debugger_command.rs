@@ -1,16 +1,101 @@
+use crate::breakpoint_manager::WatchKind;
+
 pub enum DebuggerCommand {
     Quit,
+    Attach(i32),
+    Detach,
     Run(Vec<String>),
     Continue,
-    Backtrace,
-    Breakpoint(String),
+    Backtrace(bool),
+    Breakpoint(String, Option<String>),
+    Ignore(usize, usize),
+    Watch(WatchKind, String),
+    Delete(usize),
+    Disable(usize),
+    Enable(usize),
     Next,
+    Step,
+    Finish,
+    Print(String),
+    Info(String, Option<String>),
+    Set(String),
+    Examine(ExamineFormat, String),
+    Disassemble(Option<String>),
+    List(Option<String>),
+    Frame(Option<usize>),
+    Up(usize),
+    Down(usize),
+    Thread(Option<usize>),
+    Handle(String, Vec<String>),
+    Signal(String),
+    Source(String),
+    Define(String),
+    Commands(Option<usize>),
+}
+
+/// The `/<n><fmt><unit>` suffix of the `x` command.
+#[derive(Clone, Copy)]
+pub struct ExamineFormat {
+    pub count: usize,
+    /// One of x, d, u, o, t, a, c, s or i, as in gdb
+    pub format: char,
+    /// Bytes per unit: b (1), h (2), w (4) or g (8)
+    pub unit: usize,
+}
+
+impl ExamineFormat {
+    /// Parses the part of `x/4xw` after the slash.
+    fn parse(spec: &str) -> Option<ExamineFormat> {
+        let digits: String = spec.chars().take_while(|c| c.is_ascii_digit()).collect();
+        let count = if digits.is_empty() { 1 } else { digits.parse().ok()? };
+        let mut format = 'x';
+        let mut unit = None;
+        for c in spec[digits.len()..].chars() {
+            match c {
+                'x' | 'd' | 'u' | 'o' | 't' | 'a' | 'c' | 's' | 'i' => format = c,
+                'b' => unit = Some(1),
+                'h' => unit = Some(2),
+                'w' => unit = Some(4),
+                'g' => unit = Some(8),
+                _ => return None,
+            }
+        }
+        let unit = match format {
+            'a' => 8,
+            'c' | 's' | 'i' => 1,
+            _ => unit.unwrap_or(4),
+        };
+        Some(ExamineFormat { count, format, unit })
+    }
 }
 
 impl DebuggerCommand {
+    /// Whether the command lets the inferior run (or replaces it), which invalidates the
+    /// selected frame.
+    pub fn resumes(&self) -> bool {
+        matches!(
+            self,
+            DebuggerCommand::Run(_)
+                | DebuggerCommand::Attach(_)
+                | DebuggerCommand::Detach
+                | DebuggerCommand::Continue
+                | DebuggerCommand::Next
+                | DebuggerCommand::Step
+                | DebuggerCommand::Finish
+                | DebuggerCommand::Signal(_)
+        )
+    }
+
     pub fn from_tokens(tokens: &Vec<&str>) -> Option<DebuggerCommand> {
         match tokens[0] {
             "q" | "quit" => Some(DebuggerCommand::Quit),
+            "attach" => {
+                if tokens.len() != 2 {
+                    return None;
+                }
+                Some(DebuggerCommand::Attach(tokens[1].parse::<i32>().ok()?))
+            }
+            "detach" => Some(DebuggerCommand::Detach),
             "r" | "run" => {
                 let args = tokens[1..].to_vec();
                 Some(DebuggerCommand::Run(
@@ -20,19 +105,156 @@ impl DebuggerCommand {
             "c" | "cont" | "continue" => {
                 Some(DebuggerCommand::Continue)
             }
-            "bt" | "back" | "backtrace" => {
-                Some(DebuggerCommand::Backtrace)
-            }
+            "bt" | "back" | "backtrace" => match tokens.get(1) {
+                None => Some(DebuggerCommand::Backtrace(false)),
+                Some(&"-past-main") => Some(DebuggerCommand::Backtrace(true)),
+                Some(_) => None,
+            },
             "b" | "break" => {
-                if tokens.len() != 2 {
-                    None
+                if tokens.len() == 2 {
+                    Some(DebuggerCommand::Breakpoint(tokens[1].to_string(), None))
+                } else if tokens.len() > 3 && tokens[2] == "if" {
+                    Some(DebuggerCommand::Breakpoint(
+                        tokens[1].to_string(),
+                        Some(tokens[3..].join(" ")),
+                    ))
                 } else {
-                    Some(DebuggerCommand::Breakpoint(tokens[1].to_string()))
+                    None
+                }
+            }
+            "watch" | "rwatch" | "awatch" => {
+                if tokens.len() != 2 {
+                    return None;
+                }
+                let kind = match tokens[0] {
+                    "rwatch" => WatchKind::Read,
+                    "awatch" => WatchKind::Access,
+                    _ => WatchKind::Write,
+                };
+                Some(DebuggerCommand::Watch(kind, tokens[1].to_string()))
+            }
+            "ignore" => {
+                if tokens.len() != 3 {
+                    return None;
+                }
+                Some(DebuggerCommand::Ignore(
+                    tokens[1].parse::<usize>().ok()?,
+                    tokens[2].parse::<usize>().ok()?,
+                ))
+            }
+            "d" | "delete" | "disable" | "enable" => {
+                if tokens.len() != 2 {
+                    return None;
+                }
+                let id = tokens[1].parse::<usize>().ok()?;
+                match tokens[0] {
+                    "disable" => Some(DebuggerCommand::Disable(id)),
+                    "enable" => Some(DebuggerCommand::Enable(id)),
+                    _ => Some(DebuggerCommand::Delete(id)),
                 }
             }
             "n" | "next" => {
                 Some(DebuggerCommand::Next)
             }
+            "s" | "step" => {
+                Some(DebuggerCommand::Step)
+            }
+            "fin" | "finish" => {
+                Some(DebuggerCommand::Finish)
+            }
+            "p" | "print" => {
+                if tokens.len() < 2 {
+                    None
+                } else {
+                    Some(DebuggerCommand::Print(tokens[1..].join(" ")))
+                }
+            }
+            "i" | "info" => {
+                match tokens.len() {
+                    2 => Some(DebuggerCommand::Info(tokens[1].to_string(), None)),
+                    3 => Some(DebuggerCommand::Info(
+                        tokens[1].to_string(),
+                        Some(tokens[2].to_string()),
+                    )),
+                    _ => None,
+                }
+            }
+            cmd if cmd == "x" || cmd.starts_with("x/") => {
+                if tokens.len() < 2 {
+                    return None;
+                }
+                let format = ExamineFormat::parse(cmd.get(2..).unwrap_or(""))?;
+                Some(DebuggerCommand::Examine(format, tokens[1..].join(" ")))
+            }
+            "disas" | "disassemble" => {
+                if tokens.len() == 1 {
+                    Some(DebuggerCommand::Disassemble(None))
+                } else {
+                    Some(DebuggerCommand::Disassemble(Some(tokens[1..].join(" "))))
+                }
+            }
+            "l" | "list" => match tokens.len() {
+                1 => Some(DebuggerCommand::List(None)),
+                2 => Some(DebuggerCommand::List(Some(tokens[1].to_string()))),
+                _ => None,
+            },
+            "f" | "frame" => match tokens.get(1) {
+                None => Some(DebuggerCommand::Frame(None)),
+                Some(index) => Some(DebuggerCommand::Frame(Some(index.parse().ok()?))),
+            },
+            "up" | "down" => {
+                let count = match tokens.get(1) {
+                    None => 1,
+                    Some(count) => count.parse().ok()?,
+                };
+                if tokens[0] == "up" {
+                    Some(DebuggerCommand::Up(count))
+                } else {
+                    Some(DebuggerCommand::Down(count))
+                }
+            }
+            "thread" => match tokens.len() {
+                1 => Some(DebuggerCommand::Thread(None)),
+                2 => Some(DebuggerCommand::Thread(Some(tokens[1].parse().ok()?))),
+                _ => None,
+            },
+            "handle" => {
+                if tokens.len() < 2 {
+                    return None;
+                }
+                let actions = tokens[2..].iter().map(|s| s.to_string()).collect();
+                Some(DebuggerCommand::Handle(tokens[1].to_string(), actions))
+            }
+            "signal" => {
+                if tokens.len() != 2 {
+                    return None;
+                }
+                Some(DebuggerCommand::Signal(tokens[1].to_string()))
+            }
+            "source" => {
+                if tokens.len() < 2 {
+                    return None;
+                }
+                Some(DebuggerCommand::Source(tokens[1..].join(" ")))
+            }
+            "define" => {
+                if tokens.len() != 2 {
+                    return None;
+                }
+                Some(DebuggerCommand::Define(tokens[1].to_string()))
+            }
+            "commands" => match tokens.len() {
+                1 => Some(DebuggerCommand::Commands(None)),
+                2 => Some(DebuggerCommand::Commands(Some(tokens[1].parse().ok()?))),
+                _ => None,
+            },
+            "set" => {
+                if tokens.len() < 2 {
+                    None
+                } else {
+                    Some(DebuggerCommand::Set(tokens[1..].join(" ")))
+                }
+            }
             // Default case:
             _ => None,
         }
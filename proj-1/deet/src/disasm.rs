@@ -0,0 +1,783 @@
+//! A decoder for the subset of x86-64 that gcc emits for C programs (general-purpose integer
+//! instructions and scalar SSE), printed in AT&T syntax like gdb and objdump do. Anything else
+//! decodes as a one-byte "(bad)" instruction.
+
+use crate::value::read_signed;
+
+pub struct Instruction {
+    pub len: usize,
+    pub text: String,
+    /// The destination of a direct call or jump, or the address a rip-relative operand refers to
+    pub target: Option<usize>,
+}
+
+const REGS64: [&str; 16] = [
+    "rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi", "r8", "r9", "r10", "r11", "r12", "r13",
+    "r14", "r15",
+];
+const REGS32: [&str; 16] = [
+    "eax", "ecx", "edx", "ebx", "esp", "ebp", "esi", "edi", "r8d", "r9d", "r10d", "r11d", "r12d",
+    "r13d", "r14d", "r15d",
+];
+const REGS16: [&str; 16] = [
+    "ax", "cx", "dx", "bx", "sp", "bp", "si", "di", "r8w", "r9w", "r10w", "r11w", "r12w", "r13w",
+    "r14w", "r15w",
+];
+const REGS8: [&str; 16] = [
+    "al", "cl", "dl", "bl", "spl", "bpl", "sil", "dil", "r8b", "r9b", "r10b", "r11b", "r12b",
+    "r13b", "r14b", "r15b",
+];
+// Without a REX prefix, byte registers 4-7 are the high halves of ax, cx, dx and bx
+const LEGACY_REGS8: [&str; 4] = ["ah", "ch", "dh", "bh"];
+
+const ALU_OPS: [&str; 8] = ["add", "or", "adc", "sbb", "and", "sub", "xor", "cmp"];
+const SHIFT_OPS: [&str; 8] = ["rol", "ror", "rcl", "rcr", "shl", "shr", "shl", "sar"];
+const CONDITIONS: [&str; 16] = [
+    "o", "no", "b", "ae", "e", "ne", "be", "a", "s", "ns", "p", "np", "l", "ge", "le", "g",
+];
+
+enum Operand {
+    Reg(String),
+    /// An immediate and the size of the operation, which determines how it is masked
+    Imm(i64, usize),
+    Mem(String),
+    Addr(usize),
+}
+
+impl Operand {
+    fn render(&self) -> String {
+        match self {
+            Operand::Reg(name) => format!("%{}", name),
+            Operand::Imm(value, size) => {
+                let mask = if *size >= 8 { !0 } else { (1 << (size * 8)) - 1 };
+                format!("$0x{:x}", *value as u64 & mask)
+            }
+            Operand::Mem(text) => text.clone(),
+            Operand::Addr(addr) => format!("{:#x}", addr),
+        }
+    }
+}
+
+/// A decoded instruction. Operands are kept in Intel order (destination first) and reversed when
+/// rendered.
+struct Insn {
+    mnemonic: String,
+    operands: Vec<Operand>,
+    /// Operation size, used to add an AT&T size suffix when no register operand implies it. Zero
+    /// means the mnemonic never takes a suffix.
+    size: usize,
+}
+
+fn insn(mnemonic: &str, operands: Vec<Operand>, size: usize) -> Option<Insn> {
+    Some(Insn {
+        mnemonic: mnemonic.to_string(),
+        operands,
+        size,
+    })
+}
+
+struct ModRm {
+    md: u8,
+    /// The reg field, extended by REX.R
+    reg: u8,
+    /// The raw rm field
+    rm: u8,
+}
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    addr: usize,
+    rex: u8,
+    opsize16: bool,
+    /// An f2 or f3 prefix, which selects between SSE instructions
+    rep: Option<u8>,
+    /// The displacement of a rip-relative memory operand
+    rip_disp: Option<i64>,
+}
+
+/// Decodes the instruction at the start of `bytes`, which were read from `addr`.
+pub fn decode(bytes: &[u8], addr: usize) -> Instruction {
+    let mut decoder = Decoder {
+        bytes,
+        pos: 0,
+        addr,
+        rex: 0,
+        opsize16: false,
+        rep: None,
+        rip_disp: None,
+    };
+    let insn = match decoder.decode() {
+        Some(insn) => insn,
+        None => {
+            return Instruction {
+                len: 1,
+                text: "(bad)".to_string(),
+                target: None,
+            }
+        }
+    };
+    let len = decoder.pos;
+    let mut mnemonic = insn.mnemonic;
+    let has_reg = insn.operands.iter().any(|op| matches!(op, Operand::Reg(_)));
+    if insn.size != 0 && !insn.operands.is_empty() && !has_reg {
+        mnemonic.push(match insn.size {
+            1 => 'b',
+            2 => 'w',
+            4 => 'l',
+            _ => 'q',
+        });
+    }
+    let mut text = if insn.operands.is_empty() {
+        mnemonic
+    } else {
+        let operands: Vec<String> = insn.operands.iter().rev().map(Operand::render).collect();
+        format!("{:<6} {}", mnemonic, operands.join(","))
+    };
+    let mut target = insn.operands.iter().find_map(|op| match op {
+        Operand::Addr(addr) => Some(*addr),
+        _ => None,
+    });
+    if let Some(disp) = decoder.rip_disp {
+        let addr = (addr + len).wrapping_add(disp as usize);
+        text = format!("{:<31} # {:#x}", text, addr);
+        target = Some(addr);
+    }
+    Instruction { len, text, target }
+}
+
+impl Decoder<'_> {
+    fn byte(&mut self) -> Option<u8> {
+        let byte = *self.bytes.get(self.pos)?;
+        self.pos += 1;
+        Some(byte)
+    }
+
+    /// Reads a sign-extended immediate of `size` bytes.
+    fn imm(&mut self, size: usize) -> Option<i64> {
+        let bytes = self.bytes.get(self.pos..self.pos + size)?;
+        self.pos += size;
+        Some(read_signed(bytes))
+    }
+
+    /// Reads a branch displacement of `size` bytes and returns the address it points to.
+    fn rel(&mut self, size: usize) -> Option<Operand> {
+        let rel = self.imm(size)?;
+        Some(Operand::Addr((self.addr + self.pos).wrapping_add(rel as usize)))
+    }
+
+    fn rex_w(&self) -> bool {
+        self.rex & 8 != 0
+    }
+
+    fn rex_bit(&self, bit: u8) -> u8 {
+        ((self.rex >> bit) & 1) << 3
+    }
+
+    /// The size of a "v" operand: 8 with REX.W, 2 with an operand-size prefix, otherwise 4.
+    fn op_size(&self) -> usize {
+        if self.rex_w() {
+            8
+        } else if self.opsize16 {
+            2
+        } else {
+            4
+        }
+    }
+
+    /// Immediates are at most 4 bytes, sign-extended for 64-bit operations.
+    fn imm_size(size: usize) -> usize {
+        size.min(4)
+    }
+
+    /// Names general-purpose register `num` at the given size. Size 0 means an SSE register.
+    fn reg(&self, num: u8, size: usize) -> Operand {
+        let num = num as usize;
+        Operand::Reg(match size {
+            0 => format!("xmm{}", num),
+            1 if self.rex == 0 && (4..8).contains(&num) => LEGACY_REGS8[num - 4].to_string(),
+            1 => REGS8[num].to_string(),
+            2 => REGS16[num].to_string(),
+            4 => REGS32[num].to_string(),
+            _ => REGS64[num].to_string(),
+        })
+    }
+
+    fn modrm(&mut self) -> Option<ModRm> {
+        let byte = self.byte()?;
+        Some(ModRm {
+            md: byte >> 6,
+            reg: ((byte >> 3) & 7) | self.rex_bit(2),
+            rm: byte & 7,
+        })
+    }
+
+    /// Decodes the r/m operand as a register of `size` bytes or a memory reference.
+    fn rm(&mut self, modrm: &ModRm, size: usize) -> Option<Operand> {
+        if modrm.md == 3 {
+            Some(self.reg(modrm.rm | self.rex_bit(0), size))
+        } else {
+            self.mem(modrm)
+        }
+    }
+
+    fn mem(&mut self, modrm: &ModRm) -> Option<Operand> {
+        if modrm.md == 3 {
+            return None;
+        }
+        let mut base = Some(modrm.rm | self.rex_bit(0));
+        let mut index = None;
+        let mut disp_size = match modrm.md {
+            1 => 1,
+            2 => 4,
+            _ => 0,
+        };
+        if modrm.rm == 4 {
+            let sib = self.byte()?;
+            let index_reg = ((sib >> 3) & 7) | self.rex_bit(1);
+            if index_reg != 4 {
+                index = Some((index_reg, 1 << (sib >> 6)));
+            }
+            base = Some((sib & 7) | self.rex_bit(0));
+            if sib & 7 == 5 && modrm.md == 0 {
+                base = None;
+                disp_size = 4;
+            }
+        } else if modrm.rm == 5 && modrm.md == 0 {
+            self.rip_disp = Some(self.imm(4)?);
+            return Some(Operand::Mem(format!(
+                "{}(%rip)",
+                Self::format_disp(self.rip_disp.unwrap())
+            )));
+        }
+        let disp = if disp_size == 0 { 0 } else { self.imm(disp_size)? };
+        let mut text = if disp_size == 0 {
+            String::new()
+        } else {
+            Self::format_disp(disp)
+        };
+        match (base, index) {
+            (Some(base), None) => text += &format!("(%{})", REGS64[base as usize]),
+            (Some(base), Some((index, scale))) => {
+                let (base, index) = (REGS64[base as usize], REGS64[index as usize]);
+                text += &format!("(%{},%{},{})", base, index, scale)
+            }
+            (None, Some((index, scale))) => {
+                text += &format!("(,%{},{})", REGS64[index as usize], scale)
+            }
+            (None, None) => {}
+        }
+        Some(Operand::Mem(text))
+    }
+
+    fn format_disp(disp: i64) -> String {
+        if disp < 0 {
+            format!("-{:#x}", (disp as u64).wrapping_neg())
+        } else {
+            format!("{:#x}", disp)
+        }
+    }
+
+    /// Decodes "op r/m, reg" (or "op reg, r/m" if `reverse`) with both operands of `size` bytes.
+    fn rm_reg(&mut self, mnemonic: &str, size: usize, reverse: bool) -> Option<Insn> {
+        let modrm = self.modrm()?;
+        let rm = self.rm(&modrm, size)?;
+        let reg = self.reg(modrm.reg, size);
+        if reverse {
+            insn(mnemonic, vec![reg, rm], size)
+        } else {
+            insn(mnemonic, vec![rm, reg], size)
+        }
+    }
+
+    /// The suffix scalar/packed SSE instructions take, selected by their mandatory prefix.
+    fn sse_suffix(&self) -> &'static str {
+        match (self.rep, self.opsize16) {
+            (Some(0xf3), _) => "ss",
+            (Some(0xf2), _) => "sd",
+            (None, true) => "pd",
+            _ => "ps",
+        }
+    }
+
+    fn decode(&mut self) -> Option<Insn> {
+        let mut op = self.byte()?;
+        loop {
+            match op {
+                0x66 => self.opsize16 = true,
+                0xf2 | 0xf3 => self.rep = Some(op),
+                // Segment overrides and lock don't change how we print the instruction
+                0x26 | 0x2e | 0x36 | 0x3e | 0x64 | 0x65 | 0xf0 => {}
+                _ => break,
+            }
+            op = self.byte()?;
+        }
+        if op & 0xf0 == 0x40 {
+            self.rex = op;
+            op = self.byte()?;
+        }
+        if op == 0x0f {
+            return self.decode_two_byte();
+        }
+        let v = self.op_size();
+        match op {
+            0x00..=0x3d if op & 7 < 6 => {
+                let mnemonic = ALU_OPS[(op >> 3) as usize];
+                match op & 7 {
+                    0 => self.rm_reg(mnemonic, 1, false),
+                    1 => self.rm_reg(mnemonic, v, false),
+                    2 => self.rm_reg(mnemonic, 1, true),
+                    3 => self.rm_reg(mnemonic, v, true),
+                    4 => {
+                        let imm = self.imm(1)?;
+                        insn(mnemonic, vec![self.reg(0, 1), Operand::Imm(imm, 1)], 1)
+                    }
+                    _ => {
+                        let imm = self.imm(Self::imm_size(v))?;
+                        insn(mnemonic, vec![self.reg(0, v), Operand::Imm(imm, v)], v)
+                    }
+                }
+            }
+            0x50..=0x57 => insn("push", vec![self.reg((op & 7) | self.rex_bit(0), 8)], 8),
+            0x58..=0x5f => insn("pop", vec![self.reg((op & 7) | self.rex_bit(0), 8)], 8),
+            0x63 => {
+                let modrm = self.modrm()?;
+                let rm = self.rm(&modrm, 4)?;
+                // Only sign-extends with REX.W; otherwise it's a plain 32-bit (or 16-bit) move
+                let mnemonic = if self.rex_w() { "movslq" } else { "movsxd" };
+                insn(mnemonic, vec![self.reg(modrm.reg, v), rm], 0)
+            }
+            0x68 | 0x6a => {
+                let imm = self.imm(if op == 0x68 { 4 } else { 1 })?;
+                insn("push", vec![Operand::Imm(imm, 8)], 0)
+            }
+            0x69 | 0x6b => {
+                let modrm = self.modrm()?;
+                let rm = self.rm(&modrm, v)?;
+                let imm = self.imm(if op == 0x69 { Self::imm_size(v) } else { 1 })?;
+                insn("imul", vec![self.reg(modrm.reg, v), rm, Operand::Imm(imm, v)], v)
+            }
+            0x70..=0x7f => {
+                let target = self.rel(1)?;
+                insn(&format!("j{}", CONDITIONS[(op & 0xf) as usize]), vec![target], 0)
+            }
+            0x80 | 0x81 | 0x83 => {
+                let size = if op == 0x80 { 1 } else { v };
+                let modrm = self.modrm()?;
+                let rm = self.rm(&modrm, size)?;
+                let imm = self.imm(if op == 0x81 { Self::imm_size(v) } else { 1 })?;
+                insn(ALU_OPS[(modrm.reg & 7) as usize], vec![rm, Operand::Imm(imm, size)], size)
+            }
+            0x84 | 0x85 => self.rm_reg("test", if op & 1 == 0 { 1 } else { v }, false),
+            0x86 | 0x87 => self.rm_reg("xchg", if op & 1 == 0 { 1 } else { v }, false),
+            0x88..=0x8b => self.rm_reg("mov", if op & 1 == 0 { 1 } else { v }, op & 2 != 0),
+            0x8d => {
+                let modrm = self.modrm()?;
+                let mem = self.mem(&modrm)?;
+                insn("lea", vec![self.reg(modrm.reg, v), mem], 0)
+            }
+            0x8f => {
+                let modrm = self.modrm()?;
+                let rm = self.rm(&modrm, 8)?;
+                insn("pop", vec![rm], 8)
+            }
+            0x90 if self.rep == Some(0xf3) => insn("pause", vec![], 0),
+            0x90 if self.rex_bit(0) == 0 => insn("nop", vec![], 0),
+            0x90..=0x97 => insn(
+                "xchg",
+                vec![self.reg((op & 7) | self.rex_bit(0), v), self.reg(0, v)],
+                v,
+            ),
+            0x98 => insn(
+                match v {
+                    8 => "cltq",
+                    2 => "cbtw",
+                    _ => "cwtl",
+                },
+                vec![],
+                0,
+            ),
+            0x99 => insn(
+                match v {
+                    8 => "cqto",
+                    2 => "cwtd",
+                    _ => "cltd",
+                },
+                vec![],
+                0,
+            ),
+            0xa8 => {
+                let imm = self.imm(1)?;
+                insn("test", vec![self.reg(0, 1), Operand::Imm(imm, 1)], 1)
+            }
+            0xa9 => {
+                let imm = self.imm(Self::imm_size(v))?;
+                insn("test", vec![self.reg(0, v), Operand::Imm(imm, v)], v)
+            }
+            0xb0..=0xb7 => {
+                let imm = self.imm(1)?;
+                let reg = self.reg((op & 7) | self.rex_bit(0), 1);
+                insn("mov", vec![reg, Operand::Imm(imm, 1)], 1)
+            }
+            0xb8..=0xbf => {
+                let imm = self.imm(v)?;
+                let reg = self.reg((op & 7) | self.rex_bit(0), v);
+                insn(if v == 8 { "movabs" } else { "mov" }, vec![reg, Operand::Imm(imm, v)], v)
+            }
+            0xc0 | 0xc1 | 0xd0..=0xd3 => {
+                let size = if op & 1 == 0 { 1 } else { v };
+                let modrm = self.modrm()?;
+                let rm = self.rm(&modrm, size)?;
+                let mut operands = vec![rm];
+                match op {
+                    0xc0 | 0xc1 => operands.push(Operand::Imm(self.imm(1)?, 1)),
+                    0xd2 | 0xd3 => operands.push(Operand::Reg("cl".to_string())),
+                    _ => {}
+                }
+                insn(SHIFT_OPS[(modrm.reg & 7) as usize], operands, size)
+            }
+            0xc2 => {
+                let imm = self.imm(2)?;
+                insn("ret", vec![Operand::Imm(imm, 2)], 0)
+            }
+            0xc3 => insn("ret", vec![], 0),
+            0xc6 | 0xc7 => {
+                let size = if op == 0xc6 { 1 } else { v };
+                let modrm = self.modrm()?;
+                let rm = self.rm(&modrm, size)?;
+                let imm = self.imm(Self::imm_size(size))?;
+                insn("mov", vec![rm, Operand::Imm(imm, size)], size)
+            }
+            0xc9 => insn("leave", vec![], 0),
+            0xcc => insn("int3", vec![], 0),
+            0xe8 => {
+                let target = self.rel(4)?;
+                insn("call", vec![target], 0)
+            }
+            0xe9 | 0xeb => {
+                let target = self.rel(if op == 0xe9 { 4 } else { 1 })?;
+                insn("jmp", vec![target], 0)
+            }
+            0xf4 => insn("hlt", vec![], 0),
+            0xf6 | 0xf7 => {
+                let size = if op == 0xf6 { 1 } else { v };
+                let modrm = self.modrm()?;
+                let rm = self.rm(&modrm, size)?;
+                match modrm.reg & 7 {
+                    0 | 1 => {
+                        let imm = self.imm(Self::imm_size(size))?;
+                        insn("test", vec![rm, Operand::Imm(imm, size)], size)
+                    }
+                    2 => insn("not", vec![rm], size),
+                    3 => insn("neg", vec![rm], size),
+                    4 => insn("mul", vec![rm], size),
+                    5 => insn("imul", vec![rm], size),
+                    6 => insn("div", vec![rm], size),
+                    _ => insn("idiv", vec![rm], size),
+                }
+            }
+            0xfe | 0xff => {
+                let size = if op == 0xfe { 1 } else { v };
+                let modrm = self.modrm()?;
+                match (op, modrm.reg & 7) {
+                    (_, 0) => insn("inc", vec![self.rm(&modrm, size)?], size),
+                    (_, 1) => insn("dec", vec![self.rm(&modrm, size)?], size),
+                    (0xff, 2) | (0xff, 4) => {
+                        let rm = self.rm(&modrm, 8)?;
+                        let mnemonic = if modrm.reg & 7 == 2 { "call" } else { "jmp" };
+                        insn(mnemonic, vec![Operand::Mem(format!("*{}", rm.render()))], 0)
+                    }
+                    (0xff, 6) => insn("push", vec![self.rm(&modrm, 8)?], 8),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    fn decode_two_byte(&mut self) -> Option<Insn> {
+        let op = self.byte()?;
+        let v = self.op_size();
+        let gp_size = if self.rex_w() { 8 } else { 4 };
+        match op {
+            0x05 => insn("syscall", vec![], 0),
+            0x0b => insn("ud2", vec![], 0),
+            0xa2 => insn("cpuid", vec![], 0),
+            0x1e if self.rep == Some(0xf3) => match self.byte()? {
+                0xfa => insn("endbr64", vec![], 0),
+                0xfb => insn("endbr32", vec![], 0),
+                _ => None,
+            },
+            0x1f => {
+                let modrm = self.modrm()?;
+                let rm = self.rm(&modrm, v)?;
+                insn("nop", vec![rm], v)
+            }
+            0x10 | 0x11 | 0x28 | 0x29 => {
+                let modrm = self.modrm()?;
+                let rm = self.rm(&modrm, 0)?;
+                let reg = self.reg(modrm.reg, 0);
+                let mnemonic = if op < 0x28 {
+                    format!("mov{}", self.sse_suffix())
+                } else {
+                    format!("mova{}", self.sse_suffix())
+                };
+                if op & 1 == 0 {
+                    insn(&mnemonic, vec![reg, rm], 0)
+                } else {
+                    insn(&mnemonic, vec![rm, reg], 0)
+                }
+            }
+            0x2a => {
+                let modrm = self.modrm()?;
+                let rm = self.rm(&modrm, gp_size)?;
+                let mut mnemonic = format!("cvtsi2{}", self.sse_suffix());
+                if let Operand::Mem(_) = rm {
+                    mnemonic.push(if gp_size == 8 { 'q' } else { 'l' });
+                }
+                insn(&mnemonic, vec![self.reg(modrm.reg, 0), rm], 0)
+            }
+            0x2c | 0x2d => {
+                let modrm = self.modrm()?;
+                let rm = self.rm(&modrm, 0)?;
+                let truncate = if op == 0x2c { "t" } else { "" };
+                let mnemonic = format!("cvt{}{}2si", truncate, self.sse_suffix());
+                insn(&mnemonic, vec![self.reg(modrm.reg, gp_size), rm], 0)
+            }
+            0x2e | 0x2f => {
+                let modrm = self.modrm()?;
+                let rm = self.rm(&modrm, 0)?;
+                let unordered = if op == 0x2e { "u" } else { "" };
+                let precision = if self.opsize16 { "d" } else { "s" };
+                let mnemonic = format!("{}comis{}", unordered, precision);
+                insn(&mnemonic, vec![self.reg(modrm.reg, 0), rm], 0)
+            }
+            0x51 | 0x54 | 0x57 | 0x58 | 0x59 | 0x5c | 0x5d | 0x5e | 0x5f => {
+                let base = match op {
+                    0x51 => "sqrt",
+                    0x54 => "and",
+                    0x57 => "xor",
+                    0x58 => "add",
+                    0x59 => "mul",
+                    0x5c => "sub",
+                    0x5d => "min",
+                    0x5e => "div",
+                    _ => "max",
+                };
+                let modrm = self.modrm()?;
+                let rm = self.rm(&modrm, 0)?;
+                let mnemonic = format!("{}{}", base, self.sse_suffix());
+                insn(&mnemonic, vec![self.reg(modrm.reg, 0), rm], 0)
+            }
+            0x5a => {
+                let mnemonic = match self.sse_suffix() {
+                    "ss" => "cvtss2sd",
+                    "sd" => "cvtsd2ss",
+                    "pd" => "cvtpd2ps",
+                    _ => "cvtps2pd",
+                };
+                let modrm = self.modrm()?;
+                let rm = self.rm(&modrm, 0)?;
+                insn(mnemonic, vec![self.reg(modrm.reg, 0), rm], 0)
+            }
+            0x6e | 0x7e if self.opsize16 => {
+                let modrm = self.modrm()?;
+                let rm = self.rm(&modrm, gp_size)?;
+                let mnemonic = if gp_size == 8 { "movq" } else { "movd" };
+                let xmm = self.reg(modrm.reg, 0);
+                if op == 0x6e {
+                    insn(mnemonic, vec![xmm, rm], 0)
+                } else {
+                    insn(mnemonic, vec![rm, xmm], 0)
+                }
+            }
+            0x7e if self.rep == Some(0xf3) => {
+                let modrm = self.modrm()?;
+                let rm = self.rm(&modrm, 0)?;
+                insn("movq", vec![self.reg(modrm.reg, 0), rm], 0)
+            }
+            0xd6 if self.opsize16 => {
+                let modrm = self.modrm()?;
+                let rm = self.rm(&modrm, 0)?;
+                insn("movq", vec![rm, self.reg(modrm.reg, 0)], 0)
+            }
+            0x6f | 0x7f if self.opsize16 || self.rep == Some(0xf3) => {
+                let modrm = self.modrm()?;
+                let rm = self.rm(&modrm, 0)?;
+                let reg = self.reg(modrm.reg, 0);
+                let mnemonic = if self.opsize16 { "movdqa" } else { "movdqu" };
+                if op == 0x6f {
+                    insn(mnemonic, vec![reg, rm], 0)
+                } else {
+                    insn(mnemonic, vec![rm, reg], 0)
+                }
+            }
+            0x70 if self.opsize16 => {
+                let modrm = self.modrm()?;
+                let rm = self.rm(&modrm, 0)?;
+                let imm = self.imm(1)?;
+                insn("pshufd", vec![self.reg(modrm.reg, 0), rm, Operand::Imm(imm, 1)], 0)
+            }
+            0x60..=0x62 | 0x6c | 0x74..=0x76 | 0xd4 | 0xdb | 0xeb | 0xef | 0xfa | 0xfe
+                if self.opsize16 =>
+            {
+                let mnemonic = match op {
+                    0x60 => "punpcklbw",
+                    0x61 => "punpcklwd",
+                    0x62 => "punpckldq",
+                    0x6c => "punpcklqdq",
+                    0x74 => "pcmpeqb",
+                    0x75 => "pcmpeqw",
+                    0x76 => "pcmpeqd",
+                    0xd4 => "paddq",
+                    0xdb => "pand",
+                    0xeb => "por",
+                    0xef => "pxor",
+                    0xfa => "psubd",
+                    _ => "paddd",
+                };
+                let modrm = self.modrm()?;
+                let rm = self.rm(&modrm, 0)?;
+                insn(mnemonic, vec![self.reg(modrm.reg, 0), rm], 0)
+            }
+            0x40..=0x4f => {
+                let mnemonic = format!("cmov{}", CONDITIONS[(op & 0xf) as usize]);
+                self.rm_reg(&mnemonic, v, true)
+            }
+            0x80..=0x8f => {
+                let target = self.rel(4)?;
+                insn(&format!("j{}", CONDITIONS[(op & 0xf) as usize]), vec![target], 0)
+            }
+            0x90..=0x9f => {
+                let modrm = self.modrm()?;
+                let rm = self.rm(&modrm, 1)?;
+                insn(&format!("set{}", CONDITIONS[(op & 0xf) as usize]), vec![rm], 0)
+            }
+            0xaf => self.rm_reg("imul", v, true),
+            0xb6 | 0xb7 | 0xbe | 0xbf => {
+                let src_size = if op & 1 == 0 { 1 } else { 2 };
+                let modrm = self.modrm()?;
+                let rm = self.rm(&modrm, src_size)?;
+                let mnemonic = format!(
+                    "mov{}{}{}",
+                    if op < 0xbe { "z" } else { "s" },
+                    if src_size == 1 { "b" } else { "w" },
+                    match v {
+                        8 => "q",
+                        2 => "w",
+                        _ => "l",
+                    }
+                );
+                insn(&mnemonic, vec![self.reg(modrm.reg, v), rm], 0)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::decode;
+
+    const ADDR: usize = 0x1000;
+
+    /// Decodes `bytes` at ADDR, checking that the whole sequence is one instruction.
+    fn text(bytes: &[u8]) -> String {
+        let instruction = decode(bytes, ADDR);
+        assert_eq!(instruction.len, bytes.len(), "{}", instruction.text);
+        instruction.text
+    }
+
+    #[test]
+    fn rex_registers() {
+        assert_eq!(text(&[0x48, 0x89, 0xe5]), "mov    %rsp,%rbp");
+        assert_eq!(text(&[0x41, 0x55]), "push   %r13");
+        assert_eq!(text(&[0x4c, 0x89, 0xe7]), "mov    %r12,%rdi");
+        assert_eq!(text(&[0x48, 0xc1, 0xe0, 0x03]), "shl    $0x3,%rax");
+    }
+
+    #[test]
+    fn byte_registers() {
+        // ah without REX, sil with it
+        assert_eq!(text(&[0x88, 0xe0]), "mov    %ah,%al");
+        assert_eq!(text(&[0x40, 0x88, 0xf0]), "mov    %sil,%al");
+    }
+
+    #[test]
+    fn memory_operands() {
+        assert_eq!(text(&[0x8b, 0x45, 0xfc]), "mov    -0x4(%rbp),%eax");
+        assert_eq!(text(&[0x48, 0x8b, 0x85, 0xf8, 0xfe, 0xff, 0xff]), "mov    -0x108(%rbp),%rax");
+        assert_eq!(text(&[0x8b, 0x04, 0x87]), "mov    (%rdi,%rax,4),%eax");
+        assert_eq!(text(&[0x48, 0x8b, 0x44, 0x24, 0x08]), "mov    0x8(%rsp),%rax");
+        assert_eq!(text(&[0x43, 0x8b, 0x44, 0x8d, 0x08]), "mov    0x8(%r13,%r9,4),%eax");
+        assert_eq!(
+            text(&[0x42, 0x8b, 0x04, 0xa5, 0x00, 0x10, 0x00, 0x00]),
+            "mov    0x1000(,%r12,4),%eax"
+        );
+    }
+
+    #[test]
+    fn size_suffixes() {
+        // Without a register operand the size goes on the mnemonic
+        assert_eq!(text(&[0xc7, 0x45, 0xfc, 0, 0, 0, 0]), "movl   $0x0,-0x4(%rbp)");
+        assert_eq!(text(&[0x83, 0x7d, 0xfc, 0x04]), "cmpl   $0x4,-0x4(%rbp)");
+        assert_eq!(text(&[0x48, 0x83, 0xec, 0x10]), "sub    $0x10,%rsp");
+        assert_eq!(text(&[0x0f, 0xb6, 0x45, 0xff]), "movzbl -0x1(%rbp),%eax");
+    }
+
+    #[test]
+    fn rip_relative() {
+        let instruction = decode(&[0x48, 0x8d, 0x05, 0x10, 0x00, 0x00, 0x00], ADDR);
+        assert_eq!(instruction.len, 7);
+        assert_eq!(instruction.text, format!("{:<31} # 0x1017", "lea    0x10(%rip),%rax"));
+        assert_eq!(instruction.target, Some(0x1017));
+        let instruction = decode(&[0xff, 0x15, 0xfa, 0xff, 0xff, 0xff], ADDR);
+        assert_eq!(instruction.text, format!("{:<31} # 0x1000", "call   *-0x6(%rip)"));
+    }
+
+    #[test]
+    fn branches() {
+        let instruction = decode(&[0xe8, 0x00, 0x00, 0x00, 0x00], ADDR);
+        assert_eq!(instruction.text, "call   0x1005");
+        assert_eq!(instruction.target, Some(0x1005));
+        assert_eq!(text(&[0xeb, 0xfe]), "jmp    0x1000");
+        assert_eq!(text(&[0x0f, 0x84, 0x10, 0x00, 0x00, 0x00]), "je     0x1016");
+        assert_eq!(text(&[0x7c, 0xf0]), "jl     0xff2");
+    }
+
+    #[test]
+    fn prefixes() {
+        assert_eq!(text(&[0x66, 0x89, 0x07]), "mov    %ax,(%rdi)");
+        assert_eq!(text(&[0xf3, 0x0f, 0x10, 0x45, 0xf8]), "movss  -0x8(%rbp),%xmm0");
+        assert_eq!(text(&[0xf2, 0x0f, 0x59, 0xc1]), "mulsd  %xmm1,%xmm0");
+        assert_eq!(text(&[0x66, 0x0f, 0xef, 0xc0]), "pxor   %xmm0,%xmm0");
+        assert_eq!(text(&[0xf3, 0x0f, 0x1e, 0xfa]), "endbr64");
+        assert_eq!(text(&[0xf2, 0x48, 0x0f, 0x2a, 0xc0]), "cvtsi2sd %rax,%xmm0");
+    }
+
+    #[test]
+    fn movsxd() {
+        assert_eq!(text(&[0x48, 0x63, 0xc1]), "movslq %ecx,%rax");
+        assert_eq!(text(&[0x63, 0xc1]), "movsxd %ecx,%eax");
+        assert_eq!(text(&[0x63, 0x07]), "movsxd (%rdi),%eax");
+    }
+
+    #[test]
+    fn bad_instructions() {
+        let bad = |bytes: &[u8]| {
+            let instruction = decode(bytes, ADDR);
+            (instruction.len, instruction.text, instruction.target)
+        };
+        let expected = (1, "(bad)".to_string(), None);
+        assert_eq!(bad(&[]), expected);
+        // Truncated ModRM, displacement and immediate
+        assert_eq!(bad(&[0x48, 0x8b]), expected);
+        assert_eq!(bad(&[0x8b, 0x85, 0x00]), expected);
+        assert_eq!(bad(&[0xc7, 0x45, 0xfc, 0x00]), expected);
+        // lea needs a memory operand
+        assert_eq!(bad(&[0x8d, 0xc0]), expected);
+        // x87 isn't supported
+        assert_eq!(bad(&[0xd9, 0xc0]), expected);
+        assert_eq!(bad(&[0x0f, 0xff]), expected);
+    }
+}
@@ -1,6 +1,10 @@
+use crate::console::Console;
 use crate::gimli_wrapper;
+use crate::target::Mapping;
+use crate::unwind::Module;
 use addr2line::Context;
-use object::Object;
+use object::{Object, ObjectSection};
+use std::collections::HashMap;
 use std::convert::TryInto;
 use std::{fmt, fs};
 
@@ -12,7 +16,13 @@ pub enum Error {
 
 pub struct DwarfData {
     files: Vec<File>,
+    types: Types,
     addr2line: Context<addr2line::gimli::EndianRcSlice<addr2line::gimli::RunTimeEndian>>,
+    /// Contents and load address of the .text section, for the disassembler
+    text: Vec<u8>,
+    text_addr: usize,
+    /// Call frame information for unwinding through the executable
+    module: Module,
 }
 
 impl fmt::Debug for DwarfData {
@@ -38,35 +48,90 @@ impl DwarfData {
         } else {
             gimli::RunTimeEndian::Big
         };
+        let text = object
+            .section_data_by_name(".text")
+            .map(|data| data.to_vec())
+            .unwrap_or_default();
+        let text_addr = object
+            .section_by_name(".text")
+            .map(|section| section.address() as usize)
+            .unwrap_or(0);
+        let module = Module::from_object(path, &object, text_addr, text_addr + text.len(), 0);
+        let (files, types) = gimli_wrapper::load_file(&object, endian)?;
         Ok(DwarfData {
-            files: gimli_wrapper::load_file(&object, endian)?,
+            files,
+            types,
             addr2line: Context::new(&object).or_else(|e| Err(gimli_wrapper::Error::from(e)))?,
+            text,
+            text_addr,
+            module,
         })
     }
 
-    #[allow(dead_code)]
+    /// Returns whether `name` (a compilation unit's file name) refers to `file`, which may be
+    /// given as a path suffix such as "count.c" or "samples/count.c", or as a full path such as
+    /// "/home/user/deet/samples/count.c".
+    fn file_matches(name: &str, file: &str) -> bool {
+        name == file
+            || name.ends_with(&format!("/{}", file.trim_start_matches("./")))
+            || file.ends_with(&format!("/{}", name.trim_start_matches("./")))
+    }
+
     fn get_target_file(&self, file: &str) -> Option<&File> {
-        self.files.iter().find(|f| {
-            f.name == file || (!file.contains("/") && f.name.ends_with(&format!("/{}", file)))
-        })
+        self.files.iter().find(|f| Self::file_matches(&f.name, file))
+    }
+
+    pub fn has_source_file(&self, file: &str) -> bool {
+        self.get_target_file(file).is_some()
+    }
+
+    /// Returns the path of the source file for `file`, joining the compilation directory to
+    /// relative names.
+    pub fn source_path(&self, file: &str) -> Option<String> {
+        let file = self.get_target_file(file)?;
+        if file.name.starts_with('/') || file.comp_dir.is_empty() {
+            Some(file.name.clone())
+        } else {
+            Some(format!("{}/{}", file.comp_dir, file.name))
+        }
+    }
+
+    pub fn module(&self) -> &Module {
+        &self.module
+    }
+
+    /// Tells the executable's module where the inferior loaded it.
+    pub fn relocate(&mut self, mappings: &[Mapping]) {
+        self.module.relocate(mappings);
     }
 
-    #[allow(dead_code)]
+    /// Returns up to `len` bytes of the executable's .text section starting at `addr`, if `addr`
+    /// lies inside it. Unlike inferior memory, these never contain our breakpoints.
+    pub fn read_text(&self, addr: usize, len: usize) -> Option<&[u8]> {
+        let start = addr.checked_sub(self.text_addr)?;
+        if start >= self.text.len() {
+            return None;
+        }
+        Some(&self.text[start..(start + len).min(self.text.len())])
+    }
+
+    /// Returns the lowest statement address generated for exactly `line_number`, searching the
+    /// compilation units for `file` (or all of them), or None if the line has no code.
     pub fn get_addr_for_line(&self, file: Option<&str>, line_number: usize) -> Option<usize> {
-        let target_file = match file {
-            Some(filename) => self.get_target_file(filename)?,
-            None => self.files.get(0)?,
-        };
-        Some(
-            target_file
-                .lines
-                .iter()
-                .find(|line| line.number >= line_number)?
-                .address,
-        )
+        let lines = self
+            .files
+            .iter()
+            .filter(|f| match file {
+                Some(filename) => Self::file_matches(&f.name, filename),
+                None => true,
+            })
+            .flat_map(|f| f.lines.iter());
+        lines
+            .filter(|line| line.number == line_number)
+            .map(|line| line.address)
+            .min()
     }
 
-    #[allow(dead_code)]
     pub fn get_addr_for_function(&self, file: Option<&str>, func_name: &str) -> Option<usize> {
         match file {
             Some(filename) => Some(
@@ -87,7 +152,6 @@ impl DwarfData {
         }
     }
 
-    #[allow(dead_code)]
     pub fn get_line_from_addr(&self, curr_addr: usize) -> Option<Line> {
         let location = self
             .addr2line
@@ -100,7 +164,6 @@ impl DwarfData {
         })
     }
 
-    #[allow(dead_code)]
     pub fn get_function_from_addr(&self, curr_addr: usize) -> Option<String> {
         let frame = self
             .addr2line
@@ -111,38 +174,79 @@ impl DwarfData {
         Some(frame.function?.raw_name().ok()?.to_string())
     }
 
-    #[allow(dead_code)]
-    pub fn print(&self) {
+    /// Returns the function whose text contains `curr_addr`, along with its variables.
+    pub fn get_function_for_addr(&self, curr_addr: usize) -> Option<&Function> {
+        self.files
+            .iter()
+            .flat_map(|file| file.functions.iter())
+            .find(|func| func.address <= curr_addr && curr_addr < func.address + func.text_length)
+    }
+
+    /// Returns the address of the first line-table row in `func` after its opening line, i.e.
+    /// where its body starts once the prologue has set up the frame.
+    pub fn get_prologue_end(&self, func: &Function) -> Option<usize> {
+        self.files
+            .iter()
+            .flat_map(|file| file.lines.iter())
+            .filter(|line| {
+                line.address > func.address && line.address < func.address + func.text_length
+            })
+            .map(|line| line.address)
+            .min()
+    }
+
+    /// Returns the global variable called `name`, searching every compilation unit.
+    pub fn get_global_variable(&self, name: &str) -> Option<&Variable> {
+        self.files
+            .iter()
+            .flat_map(|file| file.global_variables.iter())
+            .find(|var| var.name == name)
+    }
+
+    /// Returns every type in the debug info.
+    pub fn types(&self) -> &Types {
+        &self.types
+    }
+
+    /// Returns the global variables of every compilation unit.
+    pub fn global_variables(&self) -> Vec<&Variable> {
+        self.files
+            .iter()
+            .flat_map(|file| file.global_variables.iter())
+            .collect()
+    }
+
+    pub fn print(&self, console: &Console) {
         for file in &self.files {
-            println!("------");
-            println!("{}", file.name);
-            println!("------");
+            say!(console, "------");
+            say!(console, "{}", file.name);
+            say!(console, "------");
 
-            println!("Global variables:");
+            say!(console, "Global variables:");
             for var in &file.global_variables {
-                println!(
+                say!(console, 
                     "  * {} ({}, located at {}, declared at line {})",
                     var.name, var.entity_type.name, var.location, var.line_number
                 );
             }
 
-            println!("Functions:");
+            say!(console, "Functions:");
             for func in &file.functions {
-                println!(
+                say!(console, 
                     "  * {} (declared on line {}, located at {:#x}, {} bytes long)",
                     func.name, func.line_number, func.address, func.text_length
                 );
                 for var in &func.variables {
-                    println!(
+                    say!(console, 
                         "    * Variable: {} ({}, located at {}, declared at line {})",
                         var.name, var.entity_type.name, var.location, var.line_number
                     );
                 }
             }
 
-            println!("Line numbers:");
+            say!(console, "Line numbers:");
             for line in &file.lines {
-                println!("  * {} (at {:#x})", line.number, line.address);
+                say!(console, "  * {} (at {:#x})", line.number, line.address);
             }
         }
     }
@@ -152,28 +256,237 @@ impl DwarfData {
 pub struct Type {
     pub name: String,
     pub size: usize,
+    pub kind: TypeKind,
+}
+
+/// How the bytes of a base type are to be read (DW_AT_encoding).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Signed,
+    Unsigned,
+    SignedChar,
+    UnsignedChar,
+    Boolean,
+    Float,
+}
+
+impl Encoding {
+    /// Guesses the encoding of a C base type from its name, for types that don't come from the
+    /// debug info.
+    pub fn from_name(name: &str, size: usize) -> Encoding {
+        if name == "_Bool" {
+            Encoding::Boolean
+        } else if name == "float" || name.ends_with("double") {
+            Encoding::Float
+        } else if name.contains("char") && size == 1 {
+            if name.contains("unsigned") {
+                Encoding::UnsignedChar
+            } else {
+                Encoding::SignedChar
+            }
+        } else if name.contains("unsigned") {
+            Encoding::Unsigned
+        } else {
+            Encoding::Signed
+        }
+    }
+
+    pub fn is_signed(self) -> bool {
+        match self {
+            Encoding::Signed | Encoding::SignedChar | Encoding::Float => true,
+            Encoding::Unsigned | Encoding::UnsignedChar | Encoding::Boolean => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum TypeKind {
+    /// An integer, character or floating point number
+    Base(Encoding),
+    Void,
+    Pointer(Box<Type>),
+    /// The element type, and the number of elements if known. Arrays of arrays are nested.
+    Array(Box<Type>, Option<usize>),
+    /// A struct, identified by the offset of its DIE. Its members are looked up with
+    /// `Types::members`, which is what lets a struct contain pointers to itself.
+    Struct(usize),
+    /// Like `Struct`, but every member is at offset 0
+    Union(usize),
+    /// An enum's enumerators, and whether its values are signed
+    Enum(Vec<(String, i64)>, bool),
+    /// Another name for the given type
+    Typedef(Box<Type>),
+    Const(Box<Type>),
+    Volatile(Box<Type>),
+}
+
+impl Default for TypeKind {
+    fn default() -> Self {
+        TypeKind::Base(Encoding::Signed)
+    }
 }
 
 impl Type {
+    /// Creates a base type, with the encoding guessed from the name.
     pub fn new(name: String, size: usize) -> Self {
         Type {
-            name: name,
-            size: size,
+            kind: TypeKind::Base(Encoding::from_name(&name, size)),
+            name,
+            size,
+        }
+    }
+
+    pub fn void() -> Self {
+        Type {
+            name: "void".to_string(),
+            size: 0,
+            kind: TypeKind::Void,
         }
     }
+
+    /// Returns the type of a pointer to this type.
+    pub fn pointer_to(&self) -> Type {
+        let name = if self.name.ends_with('*') {
+            format!("{}*", self.name)
+        } else {
+            format!("{} *", self.name)
+        };
+        Type {
+            name,
+            size: std::mem::size_of::<usize>(),
+            kind: TypeKind::Pointer(Box::new(self.clone())),
+        }
+    }
+
+    /// Looks through typedefs and qualifiers to the type they stand for.
+    pub fn resolve(&self) -> &Type {
+        match &self.kind {
+            TypeKind::Typedef(target) | TypeKind::Const(target) | TypeKind::Volatile(target) => {
+                target.resolve()
+            }
+            _ => self,
+        }
+    }
+
+    /// Whether values of this type are read as signed numbers.
+    pub fn is_signed(&self) -> bool {
+        match &self.resolve().kind {
+            TypeKind::Base(encoding) => encoding.is_signed(),
+            TypeKind::Enum(_, signed) => *signed,
+            _ => false,
+        }
+    }
+}
+
+/// A field of a struct or union.
+#[derive(Debug, Clone)]
+pub struct Member {
+    pub name: String,
+    /// Offset from the start of the struct, in bytes. For a bit-field, the byte holding its
+    /// lowest bit.
+    pub offset: usize,
+    pub member_type: Type,
+    /// For a bit-field, its first bit within the byte at `offset` and its width in bits
+    pub bit_field: Option<(usize, usize)>,
+}
+
+impl Member {
+    /// Returns the bytes of this member's value, taken from the bytes of the struct holding it.
+    /// A bit-field is shifted and masked out, then sign-extended if its type is signed, into a
+    /// value of its declared type. Returns None if `bytes` is too short.
+    pub fn extract(&self, bytes: &[u8]) -> Option<Vec<u8>> {
+        let (shift, width) = match self.bit_field {
+            Some(bit_field) => bit_field,
+            None => {
+                let end = self.offset + self.member_type.size;
+                return bytes.get(self.offset..end).map(|bytes| bytes.to_vec());
+            }
+        };
+        let len = (shift + width + 7) / 8;
+        let mut raw = [0; 16];
+        raw[..len].copy_from_slice(bytes.get(self.offset..self.offset + len)?);
+        let mut value = u128::from_le_bytes(raw) >> shift;
+        if width < 128 {
+            value &= (1 << width) - 1;
+            if self.member_type.is_signed() && width > 0 && value >> (width - 1) & 1 == 1 {
+                value |= !0 << width;
+            }
+        }
+        Some(value.to_le_bytes()[..self.member_type.size.min(16)].to_vec())
+    }
+}
+
+/// Every type in the debug info, and the members of every struct and union, by DIE offset.
+#[derive(Debug, Default)]
+pub struct Types {
+    pub types: HashMap<usize, Type>,
+    pub members: HashMap<usize, Vec<Member>>,
+}
+
+impl Types {
+    /// Returns the members of the struct or union whose DIE is at `id`.
+    pub fn members(&self, id: usize) -> &[Member] {
+        self.members.get(&id).map_or(&[], |members| members.as_slice())
+    }
+
+    /// Finds a type by the name C code would use for it, e.g. "unsigned int" or "struct point".
+    pub fn find_type(&self, name: &str) -> Option<&Type> {
+        let name = name.split_whitespace().collect::<Vec<&str>>().join(" ");
+        self.types.values().find(|t| t.name == name)
+    }
+
+    /// Finds the enum that declares the enumerator `name`, and the enumerator's value.
+    pub fn find_enumerator(&self, name: &str) -> Option<(&Type, i64)> {
+        self.types.values().find_map(|t| match &t.kind {
+            TypeKind::Enum(enumerators, _) => enumerators
+                .iter()
+                .find(|(enumerator, _)| enumerator == name)
+                .map(|(_, value)| (t, *value)),
+            _ => None,
+        })
+    }
+}
+
+/// A DWARF expression, along with the encoding of the unit it came from, which is needed to
+/// parse it.
+#[derive(Clone)]
+pub struct Expression {
+    pub bytes: Vec<u8>,
+    pub encoding: gimli::Encoding,
 }
 
 #[derive(Clone)]
 pub enum Location {
     Address(usize),
+    /// Relative to the function's frame base (`DW_OP_fbreg`)
     FramePointerOffset(isize),
+    /// Any other expression, evaluated in the variable's frame (see `location::locate`)
+    Expression(Expression),
+    /// Where the variable is depends on rip: (start, end, expression) for each range of
+    /// addresses. Outside them the variable has been optimized out.
+    List(Vec<(usize, usize, Expression)>),
+    OptimizedOut,
 }
 
 impl fmt::Display for Location {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match *self {
+        match self {
             Location::Address(addr) => write!(f, "Address({:#x})", addr),
             Location::FramePointerOffset(offset) => write!(f, "FramePointerOffset({})", offset),
+            Location::Expression(expr) => {
+                write!(f, "Expression({})", gimli_wrapper::format_expression(expr))
+            }
+            Location::List(entries) => {
+                let entries: Vec<String> = entries
+                    .iter()
+                    .map(|(start, end, expr)| {
+                        let expr = gimli_wrapper::format_expression(expr);
+                        format!("{:#x}-{:#x}: {}", start, end, expr)
+                    })
+                    .collect();
+                write!(f, "List({})", entries.join("; "))
+            }
+            Location::OptimizedOut => write!(f, "OptimizedOut"),
         }
     }
 }
@@ -191,6 +504,7 @@ pub struct Variable {
     pub entity_type: Type,
     pub location: Location,
     pub line_number: usize, // Line number in source file
+    pub is_parameter: bool,
 }
 
 #[derive(Debug, Default, Clone)]
@@ -200,11 +514,16 @@ pub struct Function {
     pub text_length: usize,
     pub line_number: usize, // Line number in source file
     pub variables: Vec<Variable>,
+    pub return_type: Option<Type>,
+    /// What `DW_OP_fbreg` is relative to (`DW_AT_frame_base`)
+    pub frame_base: Option<Location>,
 }
 
 #[derive(Debug, Default, Clone)]
 pub struct File {
     pub name: String,
+    /// The directory the compiler ran in, which relative names are relative to
+    pub comp_dir: String,
     pub global_variables: Vec<Variable>,
     pub functions: Vec<Function>,
     pub lines: Vec<Line>,
@@ -223,4 +542,43 @@ impl fmt::Display for Line {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::value;
+
+    /// A bit-field of `width` bits, starting `shift` bits into the byte at `offset`.
+    fn bit_field(name: &str, size: usize, offset: usize, shift: usize, width: usize) -> Member {
+        Member {
+            name: String::new(),
+            offset,
+            member_type: Type::new(name.to_string(), size),
+            bit_field: Some((shift, width)),
+        }
+    }
+
+    #[test]
+    fn extract_members() {
+        // unsigned a : 1; int b : 4; unsigned c : 3; char d; unsigned long e : 40;
+        // holding 1, -3, 5, 'x' and 0x1234567890
+        let bytes = [0xbb, b'x', 0x90, 0x78, 0x56, 0x34, 0x12, 0, 0, 0];
+        let a = bit_field("unsigned int", 4, 0, 0, 1).extract(&bytes);
+        assert_eq!(a, Some(vec![1, 0, 0, 0]));
+        let b = bit_field("int", 4, 0, 1, 4).extract(&bytes).unwrap();
+        assert_eq!(value::read_signed(&b), -3);
+        let c = bit_field("unsigned int", 4, 0, 5, 3).extract(&bytes);
+        assert_eq!(c, Some(vec![5, 0, 0, 0]));
+        let e = bit_field("unsigned long", 8, 2, 0, 40).extract(&bytes).unwrap();
+        assert_eq!(value::read_unsigned(&e), 0x1234567890);
 
+        let plain = Member {
+            name: String::new(),
+            offset: 1,
+            member_type: Type::new("char".to_string(), 1),
+            bit_field: None,
+        };
+        assert_eq!(plain.extract(&bytes), Some(vec![b'x']));
+        // Members past the end of the bytes can't be read
+        assert_eq!(bit_field("unsigned long", 8, 8, 4, 40).extract(&bytes), None);
+    }
+}
@@ -0,0 +1,1052 @@
+//! A small C-like expression language, used by `print` and for breakpoint conditions. Variables,
+//! registers, memory and types are resolved through a `Context`, so this module knows nothing
+//! about the inferior itself.
+
+use crate::dwarf_data::{Encoding, Member, Type, TypeKind};
+use crate::value;
+use std::cmp::Ordering;
+use std::convert::TryInto;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BinOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    And,
+    Or,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+}
+
+/// The type in a cast, e.g. `(struct node *)` has base "struct node" and one pointer.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TypeName {
+    pub base: String,
+    pub pointers: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Int(i64),
+    Var(String),
+    Reg(String),
+    Not(Box<Expr>),
+    Neg(Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+    Deref(Box<Expr>),
+    AddrOf(Box<Expr>),
+    Index(Box<Expr>, Box<Expr>),
+    /// `s.f`; `p->f` is parsed as `(*p).f`
+    Member(Box<Expr>, String),
+    Cast(TypeName, Box<Expr>),
+}
+
+/// The result of evaluating an expression: a typed value, and where it lives in the inferior's
+/// memory if it is an lvalue.
+#[derive(Debug, Clone)]
+pub struct Value {
+    pub value_type: Type,
+    pub bytes: Vec<u8>,
+    pub address: Option<usize>,
+}
+
+impl Value {
+    fn integer(value: i64) -> Value {
+        Value {
+            value_type: Type::new("long".to_string(), 8),
+            bytes: value.to_le_bytes().to_vec(),
+            address: None,
+        }
+    }
+
+    fn boolean(value: bool) -> Value {
+        Value {
+            value_type: Type::new("int".to_string(), 4),
+            bytes: (value as i32).to_le_bytes().to_vec(),
+            address: None,
+        }
+    }
+
+    /// An integer of the given type, truncated to its size.
+    fn integer_of(value_type: Type, value: i64) -> Value {
+        Value {
+            bytes: value.to_le_bytes()[..value_type.size.min(8)].to_vec(),
+            value_type,
+            address: None,
+        }
+    }
+
+    fn double(value: f64) -> Value {
+        Value {
+            value_type: Type::new("double".to_string(), 8),
+            bytes: value.to_le_bytes().to_vec(),
+            address: None,
+        }
+    }
+
+    fn pointer(value_type: Type, addr: usize) -> Value {
+        Value {
+            bytes: addr.to_le_bytes()[..value_type.size.min(8)].to_vec(),
+            value_type,
+            address: None,
+        }
+    }
+
+    fn is_float(&self) -> bool {
+        matches!(self.value_type.resolve().kind, TypeKind::Base(Encoding::Float))
+    }
+
+    /// Arrays in memory turn into pointers to their first element, like they do in C.
+    fn decay(self) -> Value {
+        match (&self.value_type.resolve().kind, self.address) {
+            (TypeKind::Array(element, _), Some(addr)) => Value::pointer(element.pointer_to(), addr),
+            _ => self,
+        }
+    }
+
+    fn to_i64(&self) -> Result<i64, String> {
+        if self.is_float() {
+            return Ok(self.to_f64()? as i64);
+        }
+        match self.value_type.resolve().kind {
+            TypeKind::Base(_) | TypeKind::Pointer(_) | TypeKind::Enum(..) => {
+                Ok(value::to_integer(&self.value_type, &self.bytes))
+            }
+            _ => Err("Argument to arithmetic operation not a number or boolean.".to_string()),
+        }
+    }
+
+    fn to_f64(&self) -> Result<f64, String> {
+        if !self.is_float() {
+            return Ok(self.to_i64()? as f64);
+        }
+        match self.bytes.len() {
+            4 => Ok(f32::from_le_bytes(self.bytes[..].try_into().unwrap()) as f64),
+            8 => Ok(f64::from_le_bytes(self.bytes[..].try_into().unwrap())),
+            _ => Err("Cannot convert value to a number.".to_string()),
+        }
+    }
+}
+
+/// Resolves the names an expression refers to.
+pub trait Context {
+    fn variable(&self, name: &str) -> Result<Value, String>;
+    fn register(&self, name: &str) -> Result<i64, String>;
+    fn read_memory(&self, addr: usize, len: usize) -> Result<Vec<u8>, String>;
+    /// Returns the members of a struct or union (see `TypeKind::Struct`).
+    fn members(&self, id: usize) -> Vec<Member>;
+    fn find_type(&self, name: &str) -> Option<Type>;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Int(i64),
+    Ident(String),
+    Reg(String),
+    Op(&'static str),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Int(value) => write!(f, "{}", value),
+            Token::Ident(name) => write!(f, "{}", name),
+            Token::Reg(name) => write!(f, "${}", name),
+            Token::Op(op) => write!(f, "{}", op),
+        }
+    }
+}
+
+// Longest operators first, so that "<=" isn't lexed as "<" followed by "="
+const OPERATORS: [&str; 21] = [
+    "==", "!=", "<=", ">=", "&&", "||", "->", "<", ">", "!", "+", "-", "*", "/", "%", "&", "(",
+    ")", "[", "]", ".",
+];
+
+/// Words that can only start a type name, which is how a cast is told apart from a
+/// parenthesized expression.
+const TYPE_KEYWORDS: [&str; 15] = [
+    "void", "char", "short", "int", "long", "float", "double", "signed", "unsigned", "_Bool",
+    "struct", "union", "enum", "const", "volatile",
+];
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c.is_ascii_digit() {
+            let start = i;
+            while i < chars.len() && chars[i].is_ascii_alphanumeric() {
+                i += 1;
+            }
+            let literal: String = chars[start..i].iter().collect();
+            tokens.push(Token::Int(parse_int(&literal)?));
+        } else if c.is_alphabetic() || c == '_' || c == '$' {
+            let start = i;
+            i += 1;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            let name: String = chars[start..i].iter().collect();
+            if c == '$' {
+                tokens.push(Token::Reg(name[1..].to_string()));
+            } else {
+                tokens.push(Token::Ident(name));
+            }
+        } else {
+            let rest: String = chars[i..].iter().collect();
+            match OPERATORS.iter().find(|op| rest.starts_with(*op)) {
+                Some(op) => {
+                    tokens.push(Token::Op(op));
+                    i += op.len();
+                }
+                None => return Err(format!("Invalid character '{}' in expression.", c)),
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+/// Parses a decimal, hex (0x) or octal (leading 0) integer literal.
+pub fn parse_int(literal: &str) -> Result<i64, String> {
+    let lower = literal.to_lowercase();
+    let parsed = if lower.starts_with("0x") {
+        i64::from_str_radix(&lower[2..], 16)
+    } else if lower.len() > 1 && lower.starts_with('0') {
+        i64::from_str_radix(&lower[1..], 8)
+    } else {
+        lower.parse::<i64>()
+    };
+    parsed.or(Err(format!("Invalid number \"{}\".", literal)))
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn eat(&mut self, op: &str) -> bool {
+        match self.peek() {
+            Some(Token::Op(next)) if *next == op => {
+                self.pos += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Parses one precedence level of left-associative binary operators.
+    fn binary(
+        &mut self,
+        ops: &[(&str, BinOp)],
+        next: fn(&mut Parser) -> Result<Expr, String>,
+    ) -> Result<Expr, String> {
+        let mut lhs = next(self)?;
+        'outer: loop {
+            for (op, bin_op) in ops {
+                if self.eat(op) {
+                    let rhs = next(self)?;
+                    lhs = Expr::Binary(bin_op.clone(), Box::new(lhs), Box::new(rhs));
+                    continue 'outer;
+                }
+            }
+            return Ok(lhs);
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr, String> {
+        self.binary(&[("||", BinOp::Or)], Parser::and)
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        self.binary(&[("&&", BinOp::And)], Parser::equality)
+    }
+
+    fn equality(&mut self) -> Result<Expr, String> {
+        self.binary(&[("==", BinOp::Eq), ("!=", BinOp::Ne)], Parser::relational)
+    }
+
+    fn relational(&mut self) -> Result<Expr, String> {
+        self.binary(
+            &[("<=", BinOp::Le), (">=", BinOp::Ge), ("<", BinOp::Lt), (">", BinOp::Gt)],
+            Parser::additive,
+        )
+    }
+
+    fn additive(&mut self) -> Result<Expr, String> {
+        self.binary(&[("+", BinOp::Add), ("-", BinOp::Sub)], Parser::multiplicative)
+    }
+
+    fn multiplicative(&mut self) -> Result<Expr, String> {
+        self.binary(
+            &[("*", BinOp::Mul), ("/", BinOp::Div), ("%", BinOp::Mod)],
+            Parser::unary,
+        )
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        if self.eat("!") {
+            Ok(Expr::Not(Box::new(self.unary()?)))
+        } else if self.eat("-") {
+            Ok(Expr::Neg(Box::new(self.unary()?)))
+        } else if self.eat("*") {
+            Ok(Expr::Deref(Box::new(self.unary()?)))
+        } else if self.eat("&") {
+            Ok(Expr::AddrOf(Box::new(self.unary()?)))
+        } else if let Some(type_name) = self.cast() {
+            Ok(Expr::Cast(type_name, Box::new(self.unary()?)))
+        } else {
+            self.postfix()
+        }
+    }
+
+    /// Parses `(type)` if the tokens ahead look like a cast. A type name either starts with a C
+    /// type keyword, ends in `*`, or is a single name followed by something to cast, e.g.
+    /// `(size_t) x`.
+    fn cast(&mut self) -> Option<TypeName> {
+        if self.peek() != Some(&Token::Op("(")) {
+            return None;
+        }
+        let mut pos = self.pos + 1;
+        let mut words = Vec::new();
+        while let Some(Token::Ident(word)) = self.tokens.get(pos) {
+            words.push(word.clone());
+            pos += 1;
+        }
+        let mut pointers = 0;
+        while self.tokens.get(pos) == Some(&Token::Op("*")) {
+            pointers += 1;
+            pos += 1;
+        }
+        if words.is_empty() || self.tokens.get(pos) != Some(&Token::Op(")")) {
+            return None;
+        }
+        let is_type = TYPE_KEYWORDS.contains(&words[0].as_str())
+            || pointers > 0
+            || matches!(
+                self.tokens.get(pos + 1),
+                Some(Token::Int(_)) | Some(Token::Ident(_)) | Some(Token::Reg(_))
+                    | Some(Token::Op("("))
+            );
+        if !is_type {
+            return None;
+        }
+        self.pos = pos + 1;
+        Some(TypeName {
+            base: canonical_type_name(&words),
+            pointers,
+        })
+    }
+
+    fn postfix(&mut self) -> Result<Expr, String> {
+        let mut expr = self.primary()?;
+        loop {
+            if self.eat("[") {
+                let index = self.or()?;
+                if !self.eat("]") {
+                    return Err("Expected ']' in expression.".to_string());
+                }
+                expr = Expr::Index(Box::new(expr), Box::new(index));
+            } else if self.eat(".") {
+                expr = Expr::Member(Box::new(expr), self.member_name()?);
+            } else if self.eat("->") {
+                expr = Expr::Member(Box::new(Expr::Deref(Box::new(expr))), self.member_name()?);
+            } else {
+                return Ok(expr);
+            }
+        }
+    }
+
+    fn member_name(&mut self) -> Result<String, String> {
+        let token = self.peek().cloned();
+        self.pos += 1;
+        match token {
+            Some(Token::Ident(name)) => Ok(name),
+            Some(token) => Err(format!("Unexpected \"{}\" in expression.", token)),
+            None => Err("Unexpected end of expression.".to_string()),
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr, String> {
+        if self.eat("(") {
+            let expr = self.or()?;
+            if !self.eat(")") {
+                return Err("Expected ')' in expression.".to_string());
+            }
+            return Ok(expr);
+        }
+        let token = self.peek().cloned();
+        self.pos += 1;
+        match token {
+            Some(Token::Int(value)) => Ok(Expr::Int(value)),
+            Some(Token::Ident(name)) => Ok(Expr::Var(name)),
+            Some(Token::Reg(name)) => Ok(Expr::Reg(name)),
+            Some(token) => Err(format!("Unexpected \"{}\" in expression.", token)),
+            None => Err("Unexpected end of expression.".to_string()),
+        }
+    }
+}
+
+pub fn parse(text: &str) -> Result<Expr, String> {
+    let mut parser = Parser {
+        tokens: tokenize(text)?,
+        pos: 0,
+    };
+    let expr = parser.or()?;
+    match parser.peek() {
+        None => Ok(expr),
+        Some(token) => Err(format!("Unexpected \"{}\" in expression.", token)),
+    }
+}
+
+/// Spells integer types the way gcc names them in the debug info, e.g. "unsigned long" becomes
+/// "long unsigned int". Qualifiers are dropped, and other names are left alone.
+fn canonical_type_name(words: &[String]) -> String {
+    let words: Vec<&str> = words
+        .iter()
+        .map(|word| word.as_str())
+        .filter(|word| *word != "const" && *word != "volatile")
+        .collect();
+    let integer_words = ["signed", "unsigned", "short", "long", "int", "char"];
+    if words.is_empty() || !words.iter().all(|word| integer_words.contains(word)) {
+        return words.join(" ");
+    }
+    let unsigned = words.contains(&"unsigned");
+    let longs = words.iter().filter(|word| **word == "long").count();
+    if words.contains(&"char") {
+        return match (unsigned, words.contains(&"signed")) {
+            (true, _) => "unsigned char",
+            (false, true) => "signed char",
+            (false, false) => "char",
+        }
+        .to_string();
+    }
+    let size = if words.contains(&"short") {
+        "short "
+    } else if longs == 1 {
+        "long "
+    } else if longs > 1 {
+        "long long "
+    } else {
+        ""
+    };
+    format!("{}{}int", size, if unsigned { "unsigned " } else { "" })
+}
+
+/// The sizes of C's base types, for casts to types the program itself never uses.
+fn builtin_type(name: &str) -> Option<Type> {
+    let size = match name {
+        "char" | "signed char" | "unsigned char" | "_Bool" => 1,
+        "short int" | "short unsigned int" => 2,
+        "int" | "unsigned int" | "float" => 4,
+        "long int" | "long unsigned int" | "long long int" | "long long unsigned int" => 8,
+        "double" => 8,
+        _ => return None,
+    };
+    Some(Type::new(name.to_string(), size))
+}
+
+fn lookup_type(type_name: &TypeName, ctx: &dyn Context) -> Result<Type, String> {
+    let mut result = if type_name.base == "void" {
+        Type::void()
+    } else {
+        ctx.find_type(&type_name.base)
+            .or_else(|| builtin_type(&type_name.base))
+            .ok_or(format!("No symbol \"{}\" in current context.", type_name.base))?
+    };
+    for _ in 0..type_name.pointers {
+        result = result.pointer_to();
+    }
+    Ok(result)
+}
+
+/// Reads the value `pointer` points to.
+fn deref(pointer: Value, ctx: &dyn Context) -> Result<Value, String> {
+    let pointer = pointer.decay();
+    let target = match &pointer.value_type.resolve().kind {
+        TypeKind::Pointer(target) => match target.resolve().kind {
+            TypeKind::Void => None,
+            _ => Some(target.as_ref().clone()),
+        },
+        _ => None,
+    };
+    let target =
+        target.ok_or_else(|| "Attempt to take contents of a non-pointer value.".to_string())?;
+    let addr = pointer.to_i64()? as usize;
+    Ok(Value {
+        bytes: ctx.read_memory(addr, target.size)?,
+        value_type: target,
+        address: Some(addr),
+    })
+}
+
+fn cast(value: Value, target: Type) -> Result<Value, String> {
+    let value = value.decay();
+    let is_scalar = |t: &Type| {
+        matches!(t.resolve().kind, TypeKind::Base(_) | TypeKind::Pointer(_) | TypeKind::Enum(..))
+    };
+    if !is_scalar(&target) || !is_scalar(&value.value_type) {
+        return Err("Invalid cast.".to_string());
+    }
+    let converted = Value {
+        value_type: target.clone(),
+        bytes: Vec::new(),
+        address: None,
+    };
+    let bytes = if converted.is_float() && target.size == 4 {
+        (value.to_f64()? as f32).to_le_bytes().to_vec()
+    } else if converted.is_float() {
+        value.to_f64()?.to_le_bytes().to_vec()
+    } else {
+        value.to_i64()?.to_le_bytes()[..target.size.min(8)].to_vec()
+    };
+    Ok(Value {
+        bytes,
+        ..converted
+    })
+}
+
+fn arithmetic(op: &BinOp, lhs: Value, rhs: Value) -> Result<Value, String> {
+    let (lhs, rhs) = (lhs.decay(), rhs.decay());
+    let pointee_size = |value: &Value| match &value.value_type.resolve().kind {
+        TypeKind::Pointer(target) => Some(target.size.max(1) as i64),
+        _ => None,
+    };
+    // Pointer arithmetic counts in elements, like C
+    match (op, pointee_size(&lhs), pointee_size(&rhs)) {
+        (BinOp::Add, Some(size), None) | (BinOp::Sub, Some(size), None) => {
+            let offset = rhs.to_i64()?.wrapping_mul(size);
+            let offset = if *op == BinOp::Sub { offset.wrapping_neg() } else { offset };
+            let addr = lhs.to_i64()?.wrapping_add(offset);
+            return Ok(Value::pointer(lhs.value_type, addr as usize));
+        }
+        (BinOp::Add, None, Some(size)) => {
+            let addr = rhs.to_i64()?.wrapping_add(lhs.to_i64()?.wrapping_mul(size));
+            return Ok(Value::pointer(rhs.value_type, addr as usize));
+        }
+        (BinOp::Sub, Some(size), Some(_)) => {
+            return Ok(Value::integer(lhs.to_i64()?.wrapping_sub(rhs.to_i64()?) / size));
+        }
+        _ => {}
+    }
+
+    if lhs.is_float() || rhs.is_float() {
+        let (lhs, rhs) = (lhs.to_f64()?, rhs.to_f64()?);
+        return Ok(match op {
+            BinOp::Add => Value::double(lhs + rhs),
+            BinOp::Sub => Value::double(lhs - rhs),
+            BinOp::Mul => Value::double(lhs * rhs),
+            BinOp::Div => Value::double(lhs / rhs),
+            BinOp::Mod => return Err("Integer only operation %.".to_string()),
+            BinOp::Eq => Value::boolean(lhs == rhs),
+            BinOp::Ne => Value::boolean(lhs != rhs),
+            BinOp::Lt => Value::boolean(lhs < rhs),
+            BinOp::Le => Value::boolean(lhs <= rhs),
+            BinOp::Gt => Value::boolean(lhs > rhs),
+            BinOp::Ge => Value::boolean(lhs >= rhs),
+            BinOp::And | BinOp::Or => Value::boolean(rhs != 0.0),
+        });
+    }
+
+    let result_type = common_integer_type(&lhs, &rhs);
+    let unsigned = !result_type.is_signed();
+    let mask = if result_type.size >= 8 { !0 } else { (1 << (result_type.size * 8)) - 1 };
+    let (lhs, rhs) = (lhs.to_i64()?, rhs.to_i64()?);
+    // Adding, subtracting and multiplying wrap the same way whatever the signedness; only
+    // comparisons and division need the unsigned values
+    let (ulhs, urhs) = (lhs as u64 & mask, rhs as u64 & mask);
+    let ordering = if unsigned { ulhs.cmp(&urhs) } else { lhs.cmp(&rhs) };
+    Ok(match op {
+        BinOp::Add => Value::integer_of(result_type, lhs.wrapping_add(rhs)),
+        BinOp::Sub => Value::integer_of(result_type, lhs.wrapping_sub(rhs)),
+        BinOp::Mul => Value::integer_of(result_type, lhs.wrapping_mul(rhs)),
+        BinOp::Div | BinOp::Mod if rhs == 0 => return Err("Division by zero".to_string()),
+        BinOp::Div if unsigned => Value::integer_of(result_type, (ulhs / urhs) as i64),
+        BinOp::Div => Value::integer_of(result_type, lhs.wrapping_div(rhs)),
+        BinOp::Mod if unsigned => Value::integer_of(result_type, (ulhs % urhs) as i64),
+        BinOp::Mod => Value::integer_of(result_type, lhs.wrapping_rem(rhs)),
+        BinOp::Eq => Value::boolean(ordering == Ordering::Equal),
+        BinOp::Ne => Value::boolean(ordering != Ordering::Equal),
+        BinOp::Lt => Value::boolean(ordering == Ordering::Less),
+        BinOp::Le => Value::boolean(ordering != Ordering::Greater),
+        BinOp::Gt => Value::boolean(ordering == Ordering::Greater),
+        BinOp::Ge => Value::boolean(ordering != Ordering::Less),
+        BinOp::And | BinOp::Or => Value::boolean(rhs != 0),
+    })
+}
+
+/// The type C's usual arithmetic conversions give an operation on two integers: anything
+/// narrower than an int is promoted to int, and the result is as wide as the wider operand and
+/// unsigned if that operand is. Pointers count as unsigned longs.
+fn common_integer_type(lhs: &Value, rhs: &Value) -> Type {
+    let promoted = |value: &Value| {
+        let value_type = value.value_type.resolve();
+        (value_type.size.max(4), value_type.size >= 4 && !value_type.is_signed())
+    };
+    let (lhs_size, lhs_unsigned) = promoted(lhs);
+    let (rhs_size, rhs_unsigned) = promoted(rhs);
+    let size = lhs_size.max(rhs_size);
+    let unsigned = (lhs_size == size && lhs_unsigned) || (rhs_size == size && rhs_unsigned);
+    let name = match (size, unsigned) {
+        (4, false) => "int",
+        (4, true) => "unsigned int",
+        (_, false) => "long int",
+        (_, true) => "long unsigned int",
+    };
+    Type::new(name.to_string(), size)
+}
+
+pub fn evaluate(expr: &Expr, ctx: &dyn Context) -> Result<Value, String> {
+    Ok(match expr {
+        // Like C, a literal is an int unless it's too big for one
+        Expr::Int(value) if *value as i32 as i64 == *value => {
+            Value::integer_of(Type::new("int".to_string(), 4), *value)
+        }
+        Expr::Int(value) => Value::integer(*value),
+        Expr::Var(name) => ctx.variable(name)?,
+        Expr::Reg(name) => Value::integer(ctx.register(name)?),
+        Expr::Not(inner) => {
+            let inner = evaluate(inner, ctx)?.decay();
+            Value::boolean(inner.to_f64()? == 0.0)
+        }
+        Expr::Neg(inner) => {
+            let inner = evaluate(inner, ctx)?;
+            if inner.is_float() {
+                Value::double(-inner.to_f64()?)
+            } else {
+                let result_type = common_integer_type(&inner, &inner);
+                Value::integer_of(result_type, inner.to_i64()?.wrapping_neg())
+            }
+        }
+        Expr::Binary(op, lhs, rhs) => {
+            let lhs = evaluate(lhs, ctx)?;
+            // && and || short-circuit like they do in C
+            if *op == BinOp::And || *op == BinOp::Or {
+                let truth = lhs.clone().decay().to_f64()? != 0.0;
+                match op {
+                    BinOp::And if !truth => return Ok(Value::boolean(false)),
+                    BinOp::Or if truth => return Ok(Value::boolean(true)),
+                    _ => {}
+                }
+            }
+            arithmetic(op, lhs, evaluate(rhs, ctx)?)?
+        }
+        Expr::Deref(inner) => deref(evaluate(inner, ctx)?, ctx)?,
+        Expr::AddrOf(inner) => {
+            let inner = evaluate(inner, ctx)?;
+            let addr = inner.address.ok_or_else(|| {
+                "Attempt to take address of value not located in memory.".to_string()
+            })?;
+            Value::pointer(inner.value_type.pointer_to(), addr)
+        }
+        Expr::Index(base, index) => {
+            let base = evaluate(base, ctx)?;
+            let index = evaluate(index, ctx)?;
+            if let TypeKind::Array(..) | TypeKind::Pointer(_) = base.value_type.resolve().kind {
+                deref(arithmetic(&BinOp::Add, base, index)?, ctx)?
+            } else {
+                return Err(format!(
+                    "cannot subscript something of type `{}'",
+                    base.value_type.name
+                ));
+            }
+        }
+        Expr::Member(base, name) => {
+            let base = evaluate(base, ctx)?;
+            let id = match base.value_type.resolve().kind {
+                TypeKind::Struct(id) | TypeKind::Union(id) => id,
+                _ => {
+                    return Err("Attempt to extract a component of a value that is not a \
+                                structure."
+                        .to_string())
+                }
+            };
+            let member = ctx
+                .members(id)
+                .into_iter()
+                .find(|member| member.name == *name)
+                .ok_or(format!("There is no member named {}.", name))?;
+            let bytes = member
+                .extract(&base.bytes)
+                .ok_or("Cannot access member outside of its struct.")?;
+            // Bit-fields have no address of their own
+            let address = match member.bit_field {
+                Some(_) => None,
+                None => base.address.map(|addr| addr + member.offset),
+            };
+            Value {
+                bytes,
+                address,
+                value_type: member.member_type,
+            }
+        }
+        Expr::Cast(type_name, inner) => cast(evaluate(inner, ctx)?, lookup_type(type_name, ctx)?)?,
+    })
+}
+
+/// Evaluates an expression to an integer, e.g. for a breakpoint condition.
+pub fn eval(expr: &Expr, ctx: &dyn Context) -> Result<i64, String> {
+    evaluate(expr, ctx)?.decay().to_i64()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Where the mock's memory starts: `arr` is at MEMORY, `pt` right after it.
+    const MEMORY: usize = 0x1000;
+    const POINT: usize = MEMORY + 16;
+    const POINT_ID: usize = 1;
+
+    fn int_type() -> Type {
+        Type::new("int".to_string(), 4)
+    }
+
+    fn point_type() -> Type {
+        Type {
+            name: "struct point".to_string(),
+            size: 8,
+            kind: TypeKind::Struct(POINT_ID),
+        }
+    }
+
+    fn scalar(name: &str, size: usize, value: i64) -> Value {
+        Value {
+            value_type: Type::new(name.to_string(), size),
+            bytes: value.to_le_bytes()[..size].to_vec(),
+            address: None,
+        }
+    }
+
+    fn in_memory(value_type: Type, addr: usize) -> Value {
+        Value {
+            bytes: MockContext.read_memory(addr, value_type.size).unwrap(),
+            value_type,
+            address: Some(addr),
+        }
+    }
+
+    struct MockContext;
+
+    impl Context for MockContext {
+        fn variable(&self, name: &str) -> Result<Value, String> {
+            let array = Type {
+                name: "int [4]".to_string(),
+                size: 16,
+                kind: TypeKind::Array(Box::new(int_type()), Some(4)),
+            };
+            Ok(match name {
+                "a" => scalar("int", 4, 2),
+                "b" => scalar("int", 4, 3),
+                "c" => scalar("int", 4, 4),
+                "zero" => scalar("int", 4, 0),
+                "u" => scalar("unsigned int", 4, 0),
+                "big" => scalar("long unsigned int", 8, -1),
+                "d" => Value::double(2.5),
+                "arr" => in_memory(array, MEMORY),
+                "p" => Value::pointer(int_type().pointer_to(), MEMORY + 4),
+                "q" => Value::pointer(int_type().pointer_to(), MEMORY + 12),
+                "pt" => in_memory(point_type(), POINT),
+                "pp" => Value::pointer(point_type().pointer_to(), POINT),
+                _ => return Err(format!("No symbol \"{}\" in current context.", name)),
+            })
+        }
+
+        fn register(&self, name: &str) -> Result<i64, String> {
+            match name {
+                "rax" => Ok(42),
+                "rip" => Ok(0x401000),
+                _ => Err(format!("Invalid register ${}", name)),
+            }
+        }
+
+        fn read_memory(&self, addr: usize, len: usize) -> Result<Vec<u8>, String> {
+            // arr = {10, 20, 30, 40}, then pt = {x = 5, y = 6}
+            let memory: Vec<u8> = [10, 20, 30, 40, 5, 6]
+                .iter()
+                .flat_map(|n: &i32| n.to_le_bytes().to_vec())
+                .collect();
+            addr.checked_sub(MEMORY)
+                .and_then(|start| memory.get(start..start + len))
+                .map(|bytes| bytes.to_vec())
+                .ok_or(format!("Cannot access memory at address {:#x}", addr))
+        }
+
+        fn members(&self, id: usize) -> Vec<Member> {
+            assert_eq!(id, POINT_ID);
+            let member = |name: &str, offset| Member {
+                name: name.to_string(),
+                offset,
+                member_type: int_type(),
+                bit_field: None,
+            };
+            vec![member("x", 0), member("y", 4)]
+        }
+
+        fn find_type(&self, name: &str) -> Option<Type> {
+            match name {
+                "struct point" => Some(point_type()),
+                _ => None,
+            }
+        }
+    }
+
+    fn var(name: &str) -> Box<Expr> {
+        Box::new(Expr::Var(name.to_string()))
+    }
+
+    fn binary(op: BinOp, lhs: Box<Expr>, rhs: Box<Expr>) -> Expr {
+        Expr::Binary(op, lhs, rhs)
+    }
+
+    fn value(text: &str) -> Result<Value, String> {
+        evaluate(&parse(text)?, &MockContext)
+    }
+
+    /// Evaluates `text`, returning the result as an integer of its type.
+    fn int(text: &str) -> i64 {
+        let value = value(text).unwrap();
+        value::to_integer(&value.value_type, &value.bytes)
+    }
+
+    fn error(text: &str) -> String {
+        value(text).unwrap_err()
+    }
+
+    #[test]
+    fn tokenize_errors() {
+        assert_eq!(error("a @ b"), "Invalid character '@' in expression.");
+        assert_eq!(error("0x"), "Invalid number \"0x\".");
+        assert_eq!(error("09"), "Invalid number \"09\".");
+        assert_eq!(error("(a"), "Expected ')' in expression.");
+        assert_eq!(error("arr[1"), "Expected ']' in expression.");
+        assert_eq!(error("a +"), "Unexpected end of expression.");
+        assert_eq!(error("a b"), "Unexpected \"b\" in expression.");
+        assert_eq!(parse_int("0x1F"), Ok(31));
+        assert_eq!(parse_int("017"), Ok(15));
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(
+            parse("a + b * c"),
+            Ok(binary(BinOp::Add, var("a"), Box::new(binary(BinOp::Mul, var("b"), var("c")))))
+        );
+        assert_eq!(int("a + b * c"), 14);
+        assert_eq!(int("(a + b) * c"), 20);
+        assert_eq!(int("a - b - c"), -5);
+        assert_eq!(int("c / a * b"), 6);
+        assert_eq!(int("a + b == 5 && c > a"), 1);
+        assert_eq!(parse("-*p"), Ok(Expr::Neg(Box::new(Expr::Deref(var("p"))))));
+        assert_eq!(int("-*p"), -20);
+        assert_eq!(
+            parse("&arr[1]"),
+            Ok(Expr::AddrOf(Box::new(Expr::Index(var("arr"), Box::new(Expr::Int(1))))))
+        );
+        let addr = value("&arr[1]").unwrap();
+        assert_eq!(addr.value_type.name, "int *");
+        assert_eq!(value::read_unsigned(&addr.bytes), MEMORY as u64 + 4);
+        assert_eq!(
+            parse("pp->y"),
+            Ok(Expr::Member(Box::new(Expr::Deref(var("pp"))), "y".to_string()))
+        );
+    }
+
+    #[test]
+    fn logical_and_vs_address_of() {
+        assert_eq!(parse("a&&b"), Ok(binary(BinOp::And, var("a"), var("b"))));
+        let and_address_of = binary(BinOp::And, var("a"), Box::new(Expr::AddrOf(var("b"))));
+        assert_eq!(parse("a && &b"), Ok(and_address_of.clone()));
+        assert_eq!(parse("a &&& b"), Ok(and_address_of));
+        // There is no bitwise and
+        assert_eq!(parse("a & b"), Err("Unexpected \"&\" in expression.".to_string()));
+    }
+
+    /// Breakpoint conditions are evaluated to an integer with `eval`.
+    fn condition(text: &str) -> Result<i64, String> {
+        eval(&parse(text)?, &MockContext)
+    }
+
+    #[test]
+    fn conditions() {
+        assert_eq!(condition("a == 2"), Ok(1));
+        assert_eq!(condition("a != 2"), Ok(0));
+        assert_eq!(condition("a < b && b <= 3"), Ok(1));
+        assert_eq!(condition("a >= b || c > b"), Ok(1));
+        assert_eq!(condition("!(a < b)"), Ok(0));
+        assert_eq!(condition("!zero"), Ok(1));
+        assert_eq!(condition("a == 1 || a == 2 && b == 4"), Ok(0));
+        // && binds tighter than ||
+        assert_eq!(
+            parse("a || b && c"),
+            Ok(binary(BinOp::Or, var("a"), Box::new(binary(BinOp::And, var("b"), var("c")))))
+        );
+        // Arrays are true, like pointers to them
+        assert_eq!(condition("arr && p"), Ok(1));
+        assert_eq!(condition("a =="), Err("Unexpected end of expression.".to_string()));
+    }
+
+    #[test]
+    fn short_circuit() {
+        // The right side isn't evaluated, so the unknown variable doesn't matter
+        assert_eq!(condition("zero && missing"), Ok(0));
+        assert_eq!(condition("a || missing"), Ok(1));
+        assert_eq!(condition("zero && *(p + 100)"), Ok(0));
+        let missing = Err("No symbol \"missing\" in current context.".to_string());
+        assert_eq!(condition("a && missing"), missing);
+        assert_eq!(condition("zero || missing"), missing);
+    }
+
+    #[test]
+    fn registers() {
+        assert_eq!(parse("$rax"), Ok(Expr::Reg("rax".to_string())));
+        assert_eq!(condition("$rax == 42"), Ok(1));
+        assert_eq!(condition("$rip"), Ok(0x401000));
+        assert_eq!(condition("$rax - a * 20 == 2 && $rip > 0x400000"), Ok(1));
+        assert_eq!(condition("$nope"), Err("Invalid register $nope".to_string()));
+    }
+
+    #[test]
+    fn casts() {
+        let type_name = |base: &str, pointers| TypeName {
+            base: base.to_string(),
+            pointers,
+        };
+        // A parenthesized variable followed by an operator isn't a cast...
+        assert_eq!(parse("(a) - 1"), Ok(binary(BinOp::Sub, var("a"), Box::new(Expr::Int(1)))));
+        assert_eq!(int("(a) - 1"), 1);
+        assert_eq!(parse("(a)"), Ok(Expr::Var("a".to_string())));
+        // ...but a type keyword always starts one
+        assert_eq!(
+            parse("(long) -1"),
+            Ok(Expr::Cast(type_name("long int", 0), Box::new(Expr::Neg(Box::new(Expr::Int(1))))))
+        );
+        assert_eq!(int("(long) -1"), -1);
+        assert_eq!(parse("(size_t) a"), Ok(Expr::Cast(type_name("size_t", 0), var("a"))));
+        assert_eq!(
+            parse("(struct point *) p"),
+            Ok(Expr::Cast(type_name("struct point", 1), var("p")))
+        );
+        assert_eq!(int("((struct point *) p)->x"), 20);
+        assert_eq!(int("(unsigned char) 300"), 44);
+        assert_eq!(int("(char) 255"), -1);
+        assert_eq!(int("(int) d"), 2);
+        assert_eq!(error("(struct point) a"), "Invalid cast.");
+        assert_eq!(
+            error("(struct nothing *) p"),
+            "No symbol \"struct nothing\" in current context."
+        );
+    }
+
+    #[test]
+    fn canonical_type_names() {
+        let name = |words: &[&str]| {
+            canonical_type_name(&words.iter().map(|word| word.to_string()).collect::<Vec<_>>())
+        };
+        assert_eq!(name(&["unsigned", "long"]), "long unsigned int");
+        assert_eq!(name(&["long", "unsigned", "long"]), "long long unsigned int");
+        assert_eq!(name(&["long", "long", "int"]), "long long int");
+        assert_eq!(name(&["short"]), "short int");
+        assert_eq!(name(&["unsigned"]), "unsigned int");
+        assert_eq!(name(&["signed"]), "int");
+        assert_eq!(name(&["const", "char"]), "char");
+        assert_eq!(name(&["signed", "char"]), "signed char");
+        assert_eq!(name(&["char", "unsigned"]), "unsigned char");
+        assert_eq!(name(&["struct", "point"]), "struct point");
+        assert_eq!(name(&["volatile", "double"]), "double");
+    }
+
+    #[test]
+    fn pointer_arithmetic() {
+        let pointer = |text: &str| {
+            let value = value(text).unwrap();
+            (value.value_type.name.clone(), value::read_unsigned(&value.bytes) as usize)
+        };
+        assert_eq!(pointer("p + 1"), ("int *".to_string(), MEMORY + 8));
+        assert_eq!(pointer("1 + p"), ("int *".to_string(), MEMORY + 8));
+        assert_eq!(pointer("p - 1"), ("int *".to_string(), MEMORY));
+        assert_eq!(pointer("arr + 3"), ("int *".to_string(), MEMORY + 12));
+        assert_eq!(int("q - p"), 2);
+        assert_eq!(int("p - q"), -2);
+        assert_eq!(int("&arr[3] - arr"), 3);
+        assert_eq!(int("*(arr + 2)"), 30);
+        assert_eq!(int("p[-1]"), 10);
+        assert_eq!(int("q > p"), 1);
+        assert_eq!(error("a[1]"), "cannot subscript something of type `int'");
+        assert_eq!(error("*(p + 100)"), "Cannot access memory at address 0x1194");
+    }
+
+    #[test]
+    fn structs() {
+        assert_eq!(int("pt.x"), 5);
+        assert_eq!(int("pp->y"), 6);
+        assert_eq!(int("(*pp).y * 2"), 12);
+        assert_eq!(int("&pt.y - &pt.x"), 1);
+        assert_eq!(error("pt.z"), "There is no member named z.");
+        assert_eq!(
+            error("a.x"),
+            "Attempt to extract a component of a value that is not a structure."
+        );
+        assert_eq!(error("*a"), "Attempt to take contents of a non-pointer value.");
+        assert_eq!(error("&(a + 1)"), "Attempt to take address of value not located in memory.");
+    }
+
+    #[test]
+    fn division() {
+        assert_eq!(int("c / a"), 2);
+        assert_eq!(int("-7 / 2"), -3);
+        assert_eq!(int("-7 % 2"), -1);
+        assert_eq!(error("a / 0"), "Division by zero");
+        assert_eq!(error("a % zero"), "Division by zero");
+        assert_eq!(error("d % 2"), "Integer only operation %.");
+        let quotient = value("d / 0").unwrap();
+        assert_eq!(quotient.to_f64(), Ok(std::f64::INFINITY));
+    }
+
+    #[test]
+    fn floats() {
+        assert_eq!(value("d * 2").unwrap().to_f64(), Ok(5.0));
+        assert_eq!(int("d > 2"), 1);
+        assert_eq!(int("-d < 0"), 1);
+    }
+
+    /// Unsigned values above i64::MAX compare and divide as unsigned, like C.
+    #[test]
+    fn unsigned_arithmetic() {
+        assert_eq!(int("big > 0"), 1);
+        assert_eq!(int("big < 1"), 0);
+        // -1 converts to the same unsigned long
+        assert_eq!(int("big == -1"), 1);
+        let half = value("big / 2").unwrap();
+        assert_eq!(half.value_type.name, "long unsigned int");
+        assert_eq!(value::read_unsigned(&half.bytes), std::u64::MAX / 2);
+        assert_eq!(int("big % 10"), 5);
+        // unsigned int arithmetic wraps at 32 bits
+        assert_eq!(int("u - 1"), 4294967295);
+        assert_eq!(int("u - 1 > 0"), 1);
+        assert_eq!(int("-1 < u"), 0);
+        assert_eq!(int("u - 1 > -1"), 0);
+        // An unsigned int with a long is a long, so stays signed
+        assert_eq!(int("u - 1 > (long) -1"), 1);
+        assert_eq!(int("(long) u - 1"), -1);
+        // Signed ints wrap at 32 bits too, and bigger literals are longs
+        assert_eq!(int("2147483647 + 1"), -2147483648);
+        assert_eq!(int("2147483648 + 1"), 2147483649);
+    }
+}
@@ -0,0 +1,293 @@
+//! A gdbserver: serves the GDB Remote Serial Protocol over TCP, so that gdb (or any other
+//! frontend that speaks it) can drive an inferior through deet's ptrace engine.
+//!
+//! Only the packets needed for basic debugging are supported: `?`, `qSupported`, `g`/`G`,
+//! `m`/`M`, `c`/`C`, `s`/`S`, `Z0`/`z0`, `H`, `k` and `D`. Anything else gets the empty reply,
+//! which tells the client the packet is unsupported.
+
+use crate::breakpoint_manager::BreakpointManager;
+use crate::console::Console;
+use crate::inferior::{Inferior, Status};
+use crate::registers;
+use crate::target::Target;
+use nix::errno::Errno;
+use nix::sys::signal::{self, Signal};
+use std::convert::TryFrom;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+/// gdb's x86-64 `g` packet starts with the 64-bit registers, followed by eflags and the segment
+/// registers at 32 bits each. We leave out the floating point and vector registers.
+const WIDE_REGISTERS: usize = 17;
+
+pub struct GdbServer {
+    inferior: Option<Inferior>,
+    breakpoints: BreakpointManager,
+    /// The reply to `?`, which is also what the last `c` or `s` returned
+    last_stop: String,
+}
+
+/// Adds the `#xx` checksum to a packet body.
+fn frame(body: &str) -> String {
+    let checksum = body.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+    format!("${}#{:02x}", body, checksum)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Parses the "addr,length" part of `m` and `M` packets.
+fn parse_range(text: &str) -> Option<(usize, usize)> {
+    let mut parts = text.split(',');
+    let addr = usize::from_str_radix(parts.next()?, 16).ok()?;
+    let len = usize::from_str_radix(parts.next()?, 16).ok()?;
+    Some((addr, len))
+}
+
+impl GdbServer {
+    /// Starts `target` with `args`, stopped at its first instruction.
+    pub fn new(target: &str, args: &Vec<String>) -> Option<GdbServer> {
+        let mut breakpoints = BreakpointManager::new();
+        let inferior = Inferior::new(target, args, &mut breakpoints, Console::terminal(), None)?;
+        Some(GdbServer {
+            inferior: Some(inferior),
+            breakpoints,
+            last_stop: format!("S{:02x}", Signal::SIGTRAP as i32),
+        })
+    }
+
+    /// Waits for a client on `addr` and serves it until it kills or detaches from the
+    /// inferior, or disconnects.
+    pub fn serve(&mut self, addr: &str) -> io::Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        println!("Listening on {}", listener.local_addr()?);
+        let (stream, client) = listener.accept()?;
+        println!("Remote debugging from host {}", client);
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = stream;
+        while let Some(packet) = Self::read_packet(&mut reader, &mut writer)? {
+            let reply = self.handle(&packet);
+            writer.write_all(frame(&reply).as_bytes())?;
+            if self.inferior.is_none() {
+                break;
+            }
+        }
+        if let Some(mut inferior) = self.inferior.take() {
+            inferior.kill()?;
+        }
+        Ok(())
+    }
+
+    /// Reads the next `$body#xx` packet and acknowledges it. Returns None once the client
+    /// hangs up.
+    fn read_packet(reader: &mut impl Read, writer: &mut TcpStream) -> io::Result<Option<String>> {
+        let mut byte = [0u8];
+        loop {
+            // Skip acks for our own packets, and the interrupt byte we can't act on
+            loop {
+                if reader.read(&mut byte)? == 0 {
+                    return Ok(None);
+                }
+                if byte[0] == b'$' {
+                    break;
+                }
+            }
+            let mut body = Vec::new();
+            loop {
+                if reader.read(&mut byte)? == 0 {
+                    return Ok(None);
+                }
+                if byte[0] == b'#' {
+                    break;
+                }
+                body.push(byte[0]);
+            }
+            let mut checksum = [0u8; 2];
+            reader.read_exact(&mut checksum)?;
+            let expected = body.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+            let checksum = std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|text| u8::from_str_radix(text, 16).ok());
+            if checksum == Some(expected) {
+                writer.write_all(b"+")?;
+                return Ok(Some(String::from_utf8_lossy(&body).to_string()));
+            }
+            writer.write_all(b"-")?;
+        }
+    }
+
+    /// Carries out one packet and returns the reply body.
+    fn handle(&mut self, packet: &str) -> String {
+        let (command, rest) = match (packet.get(..1), packet.get(1..)) {
+            (Some(command), Some(rest)) => (command, rest),
+            _ => return String::new(),
+        };
+        let result = match command {
+            "?" => Some(self.last_stop.clone()),
+            "q" if rest.starts_with("Supported") => Some("PacketSize=4000".to_string()),
+            "g" => self.read_registers(),
+            "G" => self.write_registers(rest),
+            "m" => self.read_memory(rest),
+            "M" => self.write_memory(rest),
+            "c" | "s" => Some(self.resume(command == "s", None)),
+            "C" | "S" => {
+                let number = rest.split(';').next().unwrap_or("");
+                let signal = u8::from_str_radix(number, 16)
+                    .ok()
+                    .and_then(|number| Signal::try_from(number as i32).ok());
+                Some(self.resume(command == "S", signal))
+            }
+            "Z" | "z" => self.set_breakpoint(rest, command == "Z"),
+            // We only debug one thread, so any thread is fine
+            "H" => Some("OK".to_string()),
+            "k" => {
+                if let Some(mut inferior) = self.inferior.take() {
+                    let _ = inferior.kill();
+                }
+                Some("OK".to_string())
+            }
+            "D" => {
+                if let Some(mut inferior) = self.inferior.take() {
+                    let _ = inferior.detach(&mut self.breakpoints);
+                }
+                Some("OK".to_string())
+            }
+            _ => Some(String::new()),
+        };
+        result.unwrap_or_else(|| "E01".to_string())
+    }
+
+    fn read_registers(&self) -> Option<String> {
+        let regs = self.inferior.as_ref()?.get_regs()?;
+        let mut bytes = Vec::new();
+        for (index, name) in registers::NAMES.iter().enumerate() {
+            let value = registers::get(&regs, name)?.to_le_bytes();
+            let size = if index < WIDE_REGISTERS { 8 } else { 4 };
+            bytes.extend_from_slice(&value[..size]);
+        }
+        Some(to_hex(&bytes))
+    }
+
+    fn write_registers(&mut self, hex: &str) -> Option<String> {
+        let inferior = self.inferior.as_ref()?;
+        let mut regs = inferior.get_regs()?;
+        let bytes = from_hex(hex)?;
+        let mut offset = 0;
+        for (index, name) in registers::NAMES.iter().enumerate() {
+            let size = if index < WIDE_REGISTERS { 8 } else { 4 };
+            let mut value = [0u8; 8];
+            value[..size].copy_from_slice(bytes.get(offset..offset + size)?);
+            registers::set(&mut regs, name, u64::from_le_bytes(value));
+            offset += size;
+        }
+        inferior.set_regs(regs).ok()?;
+        Some("OK".to_string())
+    }
+
+    /// Reads memory, showing the original bytes under our breakpoints.
+    fn read_memory(&self, args: &str) -> Option<String> {
+        let (addr, len) = parse_range(args)?;
+        let mut bytes = self.inferior.as_ref()?.read_bytes(addr, len).ok()?;
+        self.breakpoints.restore_original(addr, &mut bytes);
+        Some(to_hex(&bytes))
+    }
+
+    fn write_memory(&mut self, args: &str) -> Option<String> {
+        let mut parts = args.splitn(2, ':');
+        let (addr, len) = parse_range(parts.next()?)?;
+        let bytes = from_hex(parts.next()?)?;
+        if bytes.len() != len {
+            return None;
+        }
+        let inferior = self.inferior.as_mut()?;
+        for (i, byte) in bytes.iter().enumerate() {
+            inferior.write_byte(addr + i, *byte).ok()?;
+        }
+        Some("OK".to_string())
+    }
+
+    /// Handles `Z0,addr,kind` and `z0,addr,kind`. Only software breakpoints are supported.
+    fn set_breakpoint(&mut self, args: &str, insert: bool) -> Option<String> {
+        let mut parts = args.split(',');
+        if parts.next()? != "0" {
+            return Some(String::new());
+        }
+        let addr = usize::from_str_radix(parts.next()?, 16).ok()?;
+        if insert {
+            let location = format!("{:#x}", addr);
+            self.breakpoints.set(&mut self.inferior, &addr, location).ok()?;
+        } else if let Some(breakpoint) = self.breakpoints.get(&addr) {
+            self.breakpoints.unset(&mut self.inferior, breakpoint.id).ok()?;
+        }
+        Some("OK".to_string())
+    }
+
+    /// Continues or single-steps, delivering `signal`, and returns the stop reply.
+    fn resume(&mut self, step: bool, signal: Option<Signal>) -> String {
+        match self.resume_inferior(step, signal) {
+            Ok(status) => {
+                self.last_stop = match status {
+                    Status::Stopped(signal, _) => format!("S{:02x}", signal as i32),
+                    Status::Watchpoint(..) | Status::Exec(_) => {
+                        format!("S{:02x}", Signal::SIGTRAP as i32)
+                    }
+                    Status::Exited(code) => {
+                        self.inferior = None;
+                        format!("W{:02x}", code as u8)
+                    }
+                    Status::Signaled(signal) => {
+                        self.inferior = None;
+                        format!("X{:02x}", signal as i32)
+                    }
+                };
+                self.last_stop.clone()
+            }
+            Err(_) => "E01".to_string(),
+        }
+    }
+
+    fn resume_inferior(
+        &mut self,
+        step: bool,
+        signal: Option<Signal>,
+    ) -> Result<Status, nix::Error> {
+        let inferior = self.inferior.as_mut().ok_or(nix::Error::Sys(Errno::ESRCH))?;
+        inferior.set_signal(signal);
+        // Step over the breakpoint we are sitting on (if any) first
+        let rip = inferior.get_regs().map_or(0, |regs| regs.rip as usize);
+        if self.breakpoints.get(&rip).is_some() {
+            self.breakpoints.unset_t(inferior, &rip)?;
+            inferior.ptrace_step()?;
+            let status = inferior.wait()?;
+            if let Status::Exited(_) | Status::Signaled(_) | Status::Exec(_) = status {
+                return Ok(status);
+            }
+            self.breakpoints.set_t(inferior, &rip)?;
+            if step {
+                return Ok(status);
+            }
+        } else if step {
+            inferior.ptrace_step()?;
+            return inferior.wait();
+        }
+        let status = inferior.resume()?;
+        // Report breakpoints at their own address, as gdb expects
+        if let Status::Stopped(signal::SIGTRAP, rip) = status {
+            if self.breakpoints.get(&(rip - 1)).is_some() {
+                inferior.set_rip(rip - 1)?;
+            }
+        }
+        Ok(status)
+    }
+}
@@ -10,13 +10,379 @@ use gimli::{UnitOffset, UnitSectionOffset};
 use object::Object;
 use std::borrow;
 //use std::io::{BufWriter, Write};
-use crate::dwarf_data::{File, Function, Line, Location, Type, Variable};
+use crate::dwarf_data::{
+    Encoding, Expression, File, Function, Line, Location, Member, Type, TypeKind, Types, Variable,
+};
 use std::collections::HashMap;
 use std::convert::TryInto;
 use std::fmt::Write;
 use std::{io, path};
 
-pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<Vec<File>, Error> {
+/// A type DIE as it appears in the debug info. Types refer to each other by section offset, and
+/// the referenced DIE may come later (or be in another unit), so these are collected first and
+/// turned into `Type`s once they have all been seen.
+enum RawType {
+    Base(String, usize, Encoding),
+    Pointer(Option<usize>, usize),
+    /// Element type, and the element count of each dimension if known
+    Array(Option<usize>, Vec<Option<usize>>),
+    /// Whether it is a union, name, size, and (name, offset in bits, bit-field width, type) of
+    /// each member
+    Struct(bool, Option<String>, usize, Vec<RawMember>),
+    Enum(Option<String>, usize, Vec<(String, i64)>),
+    Typedef(String, Option<usize>),
+    Const(Option<usize>),
+    Volatile(Option<usize>),
+}
+
+type RawMember = (String, usize, Option<usize>, Option<usize>);
+
+/// Returns the offset of a DIE from the start of .debug_info, which is what DW_AT_type
+/// references are resolved to.
+fn section_offset<R: Reader>(offset: UnitOffset, unit: &gimli::Unit<R>) -> usize {
+    match offset.to_unit_section_offset(unit) {
+        UnitSectionOffset::DebugInfoOffset(offset) => offset.0,
+        UnitSectionOffset::DebugTypesOffset(offset) => offset.0,
+    }
+}
+
+fn get_name<R: Reader>(
+    entry: &gimli::DebuggingInformationEntry<R>,
+    unit: &gimli::Unit<R>,
+    dwarf: &gimli::Dwarf<R>,
+) -> Option<String> {
+    match get_attr_value(&entry.attr(gimli::DW_AT_name).ok()??, unit, dwarf) {
+        Ok(DebugValue::Str(name)) => Some(name),
+        _ => None,
+    }
+}
+
+fn get_number<R: Reader>(
+    entry: &gimli::DebuggingInformationEntry<R>,
+    name: gimli::DwAt,
+    unit: &gimli::Unit<R>,
+    dwarf: &gimli::Dwarf<R>,
+) -> Option<usize> {
+    let attr = entry.attr(name).ok()??;
+    match get_attr_value(&attr, unit, dwarf) {
+        Ok(DebugValue::Uint(value)) => value.try_into().ok(),
+        Ok(DebugValue::Int(value)) => value.try_into().ok(),
+        _ => attr.udata_value().and_then(|value| value.try_into().ok()),
+    }
+}
+
+fn get_type_offset<R: Reader>(
+    entry: &gimli::DebuggingInformationEntry<R>,
+    unit: &gimli::Unit<R>,
+    dwarf: &gimli::Dwarf<R>,
+) -> Option<usize> {
+    match get_attr_value(&entry.attr(gimli::DW_AT_type).ok()??, unit, dwarf) {
+        Ok(DebugValue::Size(offset)) => Some(offset),
+        _ => None,
+    }
+}
+
+/// Member offsets are usually a constant, but older compilers emit `DW_OP_plus_uconst n`.
+fn get_member_offset<R: Reader>(
+    entry: &gimli::DebuggingInformationEntry<R>,
+    unit: &gimli::Unit<R>,
+) -> usize {
+    let attr = match entry.attr(gimli::DW_AT_data_member_location) {
+        Ok(Some(attr)) => attr,
+        _ => return 0,
+    };
+    if let gimli::AttributeValue::Exprloc(ref data) = attr.value() {
+        let mut pc = data.0.clone();
+        if let Ok(gimli::Operation::PlusConstant { value }) =
+            gimli::Operation::parse(&mut pc, unit.encoding())
+        {
+            return value.try_into().unwrap_or(0);
+        }
+        return 0;
+    }
+    attr.udata_value()
+        .and_then(|value| value.try_into().ok())
+        .unwrap_or(0)
+}
+
+/// Returns where a member starts, in bits from the start of its struct, and its width in bits if
+/// it is a bit-field.
+fn get_member_bits<R: Reader>(
+    entry: &gimli::DebuggingInformationEntry<R>,
+    unit: &gimli::Unit<R>,
+    dwarf: &gimli::Dwarf<R>,
+) -> (usize, Option<usize>) {
+    let byte_offset = get_member_offset(entry, unit);
+    let width = match get_number(entry, gimli::DW_AT_bit_size, unit, dwarf) {
+        Some(width) => width,
+        None => return (byte_offset * 8, None),
+    };
+    if let Some(bit_offset) = get_number(entry, gimli::DW_AT_data_bit_offset, unit, dwarf) {
+        return (bit_offset, Some(width));
+    }
+    // DWARF 2 and 3 count DW_AT_bit_offset from the most significant bit of a storage unit of
+    // DW_AT_byte_size bytes, which on a little-endian machine is its last bit
+    let storage = get_number(entry, gimli::DW_AT_byte_size, unit, dwarf).unwrap_or(0) * 8;
+    let from_msb = get_number(entry, gimli::DW_AT_bit_offset, unit, dwarf).unwrap_or(0);
+    let bit_offset = (byte_offset * 8 + storage).saturating_sub(from_msb + width);
+    (bit_offset, Some(width))
+}
+
+fn get_encoding<R: Reader>(entry: &gimli::DebuggingInformationEntry<R>) -> Option<Encoding> {
+    match entry.attr_value(gimli::DW_AT_encoding).ok()?? {
+        gimli::AttributeValue::Encoding(encoding) => match encoding {
+            gimli::DW_ATE_signed => Some(Encoding::Signed),
+            gimli::DW_ATE_unsigned => Some(Encoding::Unsigned),
+            gimli::DW_ATE_signed_char => Some(Encoding::SignedChar),
+            gimli::DW_ATE_unsigned_char => Some(Encoding::UnsignedChar),
+            gimli::DW_ATE_boolean => Some(Encoding::Boolean),
+            gimli::DW_ATE_float => Some(Encoding::Float),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Enumerator values are signed only if the compiler used a signed form for them.
+fn get_enumerator_value<R: Reader>(entry: &gimli::DebuggingInformationEntry<R>) -> i64 {
+    match entry.attr_value(gimli::DW_AT_const_value) {
+        Ok(Some(gimli::AttributeValue::Sdata(value))) => value,
+        Ok(Some(value)) => value.udata_value().unwrap_or(0) as i64,
+        _ => 0,
+    }
+}
+
+/// Collects the type DIEs of every unit.
+fn load_raw_types<R: Reader>(dwarf: &gimli::Dwarf<R>) -> Result<HashMap<usize, RawType>, Error> {
+    let mut raw_types = HashMap::new();
+    let mut iter = dwarf.units();
+    while let Some(header) = iter.next()? {
+        let unit = dwarf.unit(header)?;
+        // The offsets of the entries enclosing the current one, to find the struct a member
+        // belongs to and the array a subrange belongs to
+        let mut parents: Vec<usize> = Vec::new();
+        let mut depth: isize = 0;
+        let mut entries = unit.entries();
+        while let Some((delta_depth, entry)) = entries.next_dfs()? {
+            depth += delta_depth;
+            parents.truncate(depth.try_into().unwrap_or(0));
+            let offset = section_offset(entry.offset(), &unit);
+            let parent = parents.last().cloned();
+            parents.push(offset);
+
+            let size = get_number(entry, gimli::DW_AT_byte_size, &unit, dwarf);
+            let target = get_type_offset(entry, &unit, dwarf);
+            let raw_type = match entry.tag() {
+                gimli::DW_TAG_base_type => {
+                    let name =
+                        get_name(entry, &unit, dwarf).unwrap_or_else(|| "<unknown>".to_string());
+                    let size = size.unwrap_or(0);
+                    let encoding =
+                        get_encoding(entry).unwrap_or_else(|| Encoding::from_name(&name, size));
+                    RawType::Base(name, size, encoding)
+                }
+                gimli::DW_TAG_pointer_type => {
+                    RawType::Pointer(target, size.unwrap_or(std::mem::size_of::<usize>()))
+                }
+                gimli::DW_TAG_array_type => RawType::Array(target, Vec::new()),
+                gimli::DW_TAG_structure_type | gimli::DW_TAG_union_type => RawType::Struct(
+                    entry.tag() == gimli::DW_TAG_union_type,
+                    get_name(entry, &unit, dwarf),
+                    size.unwrap_or(0),
+                    Vec::new(),
+                ),
+                gimli::DW_TAG_enumeration_type => {
+                    RawType::Enum(get_name(entry, &unit, dwarf), size.unwrap_or(0), Vec::new())
+                }
+                gimli::DW_TAG_typedef => RawType::Typedef(
+                    get_name(entry, &unit, dwarf).unwrap_or_else(|| "<unknown>".to_string()),
+                    target,
+                ),
+                gimli::DW_TAG_const_type => RawType::Const(target),
+                gimli::DW_TAG_volatile_type => RawType::Volatile(target),
+                gimli::DW_TAG_subrange_type => {
+                    let count = match get_number(entry, gimli::DW_AT_count, &unit, dwarf) {
+                        Some(count) => Some(count),
+                        None => get_number(entry, gimli::DW_AT_upper_bound, &unit, dwarf)
+                            .map(|bound| bound + 1),
+                    };
+                    // A multi-dimensional array has one subrange per dimension
+                    if let Some(RawType::Array(_, dimensions)) =
+                        parent.and_then(|parent| raw_types.get_mut(&parent))
+                    {
+                        dimensions.push(count);
+                    }
+                    continue;
+                }
+                gimli::DW_TAG_member => {
+                    let name = get_name(entry, &unit, dwarf).unwrap_or_default();
+                    let (bit_offset, width) = get_member_bits(entry, &unit, dwarf);
+                    if let Some(RawType::Struct(_, _, _, members)) =
+                        parent.and_then(|parent| raw_types.get_mut(&parent))
+                    {
+                        members.push((name, bit_offset, width, target));
+                    }
+                    continue;
+                }
+                gimli::DW_TAG_enumerator => {
+                    let name = get_name(entry, &unit, dwarf).unwrap_or_default();
+                    let value = get_enumerator_value(entry);
+                    if let Some(RawType::Enum(_, _, enumerators)) =
+                        parent.and_then(|parent| raw_types.get_mut(&parent))
+                    {
+                        enumerators.push((name, value));
+                    }
+                    continue;
+                }
+                _ => continue,
+            };
+            raw_types.insert(offset, raw_type);
+        }
+    }
+    Ok(raw_types)
+}
+
+/// Turns the raw type at `offset` (or void, if there is none) into a `Type`, resolving the types
+/// it refers to along the way.
+fn resolve_type(
+    offset: Option<usize>,
+    raw_types: &HashMap<usize, RawType>,
+    types: &mut HashMap<usize, Type>,
+) -> Type {
+    let offset = match offset {
+        Some(offset) => offset,
+        None => return Type::void(),
+    };
+    if let Some(resolved) = types.get(&offset) {
+        return resolved.clone();
+    }
+    let resolved = match raw_types.get(&offset) {
+        Some(RawType::Base(name, size, encoding)) => Type {
+            name: name.clone(),
+            size: *size,
+            kind: TypeKind::Base(*encoding),
+        },
+        Some(RawType::Pointer(target, size)) => {
+            let mut pointer = resolve_type(*target, raw_types, types).pointer_to();
+            pointer.size = *size;
+            pointer
+        }
+        Some(RawType::Array(target, dimensions)) => {
+            // int[2][3] is an array of 2 int[3]s, so build it from the innermost dimension out
+            let mut array = resolve_type(*target, raw_types, types);
+            for count in dimensions.iter().rev() {
+                array = array_of(array, *count);
+            }
+            array
+        }
+        Some(RawType::Struct(is_union, name, size, _)) => Type {
+            name: format!(
+                "{} {}",
+                if *is_union { "union" } else { "struct" },
+                name.as_ref().map_or("{...}", |name| name.as_str())
+            ),
+            size: *size,
+            kind: if *is_union {
+                TypeKind::Union(offset)
+            } else {
+                TypeKind::Struct(offset)
+            },
+        },
+        Some(RawType::Enum(name, size, enumerators)) => Type {
+            name: format!("enum {}", name.as_ref().map_or("{...}", |name| name.as_str())),
+            size: *size,
+            kind: TypeKind::Enum(
+                enumerators.clone(),
+                enumerators.iter().any(|(_, value)| *value < 0),
+            ),
+        },
+        Some(RawType::Typedef(name, target)) => {
+            let target = resolve_type(*target, raw_types, types);
+            Type {
+                name: name.clone(),
+                size: target.size,
+                kind: TypeKind::Typedef(Box::new(target)),
+            }
+        }
+        Some(RawType::Const(target)) => {
+            let target = resolve_type(*target, raw_types, types);
+            Type {
+                name: qualified_name("const", &target),
+                size: target.size,
+                kind: TypeKind::Const(Box::new(target)),
+            }
+        }
+        Some(RawType::Volatile(target)) => {
+            let target = resolve_type(*target, raw_types, types);
+            Type {
+                name: qualified_name("volatile", &target),
+                size: target.size,
+                kind: TypeKind::Volatile(Box::new(target)),
+            }
+        }
+        None => Type::void(),
+    };
+    types.insert(offset, resolved.clone());
+    resolved
+}
+
+/// Returns the type of an array of `count` `element`s.
+fn array_of(element: Type, count: Option<usize>) -> Type {
+    let bound = count.map_or(String::new(), |count| count.to_string());
+    let name = match element.name.find('[') {
+        Some(index) => format!(
+            "{}[{}]{}",
+            &element.name[..index],
+            bound,
+            &element.name[index..]
+        ),
+        None => format!("{} [{}]", element.name, bound),
+    };
+    Type {
+        name,
+        size: element.size * count.unwrap_or(0),
+        kind: TypeKind::Array(Box::new(element), count),
+    }
+}
+
+/// Names a const or volatile type the way C declarations read, e.g. "const char *" for a
+/// pointer to const chars and "char * const" for a const pointer.
+fn qualified_name(qualifier: &str, target: &Type) -> String {
+    // gcc makes an array of const chars const as well, but C doesn't say so twice
+    if target.name.starts_with(&format!("{} ", qualifier)) {
+        target.name.clone()
+    } else if let TypeKind::Pointer(_) = target.kind {
+        format!("{} {}", target.name, qualifier)
+    } else {
+        format!("{} {}", qualifier, target.name)
+    }
+}
+
+/// Resolves every type DIE, and the members of every struct and union.
+fn resolve_types(raw_types: &HashMap<usize, RawType>) -> Types {
+    let mut types = Types::default();
+    for (offset, raw_type) in raw_types.iter() {
+        resolve_type(Some(*offset), raw_types, &mut types.types);
+        if let RawType::Struct(_, _, _, members) = raw_type {
+            let members = members
+                .iter()
+                .map(|(name, bit_offset, width, target)| Member {
+                    name: name.clone(),
+                    offset: bit_offset / 8,
+                    member_type: resolve_type(*target, raw_types, &mut types.types),
+                    bit_field: width.map(|width| (bit_offset % 8, width)),
+                })
+                .collect();
+            types.members.insert(*offset, members);
+        }
+    }
+    types
+}
+
+pub fn load_file(
+    object: &object::File,
+    endian: gimli::RunTimeEndian,
+) -> Result<(Vec<File>, Types), Error> {
     // Load a section and return as `Cow<[u8]>`.
     let load_section = |id: gimli::SectionId| -> Result<borrow::Cow<[u8]>, gimli::Error> {
         Ok(object
@@ -40,7 +406,8 @@ pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<
     let dwarf = dwarf_cow.borrow(&borrow_section);
 
     // Define a mapping from type offsets to type structs
-    let mut offset_to_type: HashMap<usize, Type> = HashMap::new();
+    let types = resolve_types(&load_raw_types(&dwarf)?);
+    let offset_to_type = &types.types;
 
     let mut compilation_units: Vec<File> = Vec::new();
 
@@ -52,11 +419,22 @@ pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<
         // Iterate over the Debugging Information Entries (DIEs) in the unit.
         let mut depth = 0;
         let mut entries = unit.entries();
+        // The depth of the inlined subroutine we are in, if any. Its variables belong to the
+        // inlined function rather than the one we are reading, so they are left out.
+        let mut inlined_depth: Option<isize> = None;
         while let Some((delta_depth, entry)) = entries.next_dfs()? {
             depth += delta_depth;
+            if inlined_depth.map_or(false, |inlined_depth| depth <= inlined_depth) {
+                inlined_depth = None;
+            }
             // Update the offset_to_type mapping for types
             // Update the variable list for formal params/variables
             match entry.tag() {
+                gimli::DW_TAG_inlined_subroutine => {
+                    if inlined_depth.is_none() {
+                        inlined_depth = Some(depth);
+                    }
+                }
                 gimli::DW_TAG_compile_unit => {
                     let name = if let Ok(Some(attr)) = entry.attr(gimli::DW_AT_name) {
                         if let Ok(DebugValue::Str(name)) = get_attr_value(&attr, &unit, &dwarf) {
@@ -67,40 +445,18 @@ pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<
                     } else {
                         "<unknown>".to_string()
                     };
+                    let comp_dir = match &unit.comp_dir {
+                        Some(comp_dir) => gimli::Reader::to_string_lossy(comp_dir)?.to_string(),
+                        None => String::new(),
+                    };
                     compilation_units.push(File {
                         name,
+                        comp_dir,
                         global_variables: Vec::new(),
                         functions: Vec::new(),
                         lines: Vec::new(),
                     });
                 }
-                gimli::DW_TAG_base_type => {
-                    let name = if let Ok(Some(attr)) = entry.attr(gimli::DW_AT_name) {
-                        if let Ok(DebugValue::Str(name)) = get_attr_value(&attr, &unit, &dwarf) {
-                            name
-                        } else {
-                            "<unknown>".to_string()
-                        }
-                    } else {
-                        "<unknown>".to_string()
-                    };
-                    let byte_size = if let Ok(Some(attr)) = entry.attr(gimli::DW_AT_byte_size) {
-                        if let Ok(DebugValue::Uint(byte_size)) =
-                            get_attr_value(&attr, &unit, &dwarf)
-                        {
-                            byte_size
-                        } else {
-                            // TODO: report error?
-                            0
-                        }
-                    } else {
-                        // TODO: report error?
-                        0
-                    };
-                    let type_offset = entry.offset().0;
-                    offset_to_type
-                        .insert(type_offset, Type::new(name, byte_size.try_into().unwrap()));
-                }
                 gimli::DW_TAG_subprogram => {
                     let mut func: Function = Default::default();
                     let mut attrs = entry.attrs();
@@ -129,16 +485,38 @@ pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<
                                     func.line_number = line_number.try_into().unwrap();
                                 }
                             }
+                            gimli::DW_AT_type => {
+                                if let Ok(DebugValue::Size(offset)) = val {
+                                    func.return_type = offset_to_type.get(&offset).cloned();
+                                }
+                            }
+                            gimli::DW_AT_frame_base => {
+                                func.frame_base = get_location(&attr, &unit, &dwarf);
+                            }
+                            gimli::DW_AT_abstract_origin => {
+                                // An out-of-line copy of an inlined function, which gets its
+                                // name and type from the original
+                                if let Some(origin) = get_origin(&attr, &unit) {
+                                    func.name =
+                                        get_name(&origin, &unit, &dwarf).unwrap_or_default();
+                                    func.return_type = get_type_offset(&origin, &unit, &dwarf)
+                                        .and_then(|offset| offset_to_type.get(&offset).cloned());
+                                }
+                            }
                             _ => {}
                         }
                     }
                     compilation_units.last_mut().unwrap().functions.push(func);
                 }
-                gimli::DW_TAG_formal_parameter | gimli::DW_TAG_variable => {
+                gimli::DW_TAG_formal_parameter | gimli::DW_TAG_variable
+                    if inlined_depth.is_none() =>
+                {
                     let mut name = String::new();
                     let mut entity_type: Option<Type> = None;
-                    let mut location: Option<Location> = None;
+                    // Variables without a location have been optimized out entirely
+                    let mut location = Location::OptimizedOut;
                     let mut line_number = 0;
+                    let mut is_declaration = false;
                     let mut attrs = entry.attrs();
                     while let Some(attr) = attrs.next()? {
                         let val = get_attr_value(&attr, &unit, &dwarf);
@@ -151,14 +529,24 @@ pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<
                             }
                             gimli::DW_AT_type => {
                                 if let Ok(DebugValue::Size(offset)) = val {
-                                    if let Some(dtype) = offset_to_type.get(&offset).clone() {
+                                    if let Some(dtype) = offset_to_type.get(&offset) {
                                         entity_type = Some(dtype.clone());
                                     }
                                 }
                             }
                             gimli::DW_AT_location => {
-                                if let Some(loc) = get_location(&attr, &unit) {
-                                    location = Some(loc);
+                                if let Some(loc) = get_location(&attr, &unit, &dwarf) {
+                                    location = loc;
+                                }
+                            }
+                            gimli::DW_AT_declaration => {
+                                is_declaration = true;
+                            }
+                            gimli::DW_AT_abstract_origin => {
+                                if let Some(origin) = get_origin(&attr, &unit) {
+                                    name = get_name(&origin, &unit, &dwarf).unwrap_or_default();
+                                    entity_type = get_type_offset(&origin, &unit, &dwarf)
+                                        .and_then(|offset| offset_to_type.get(&offset).cloned());
                                 }
                             }
                             gimli::DW_AT_decl_line => {
@@ -169,12 +557,18 @@ pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<
                             _ => {}
                         }
                     }
-                    if entity_type.is_some() && location.is_some() {
+                    // Declarations (of externs) have no storage, and unnamed parameters belong to
+                    // function pointer types
+                    if is_declaration || name.is_empty() {
+                        continue;
+                    }
+                    if let Some(entity_type) = entity_type {
                         let var = Variable {
                             name,
-                            entity_type: entity_type.unwrap(),
-                            location: location.unwrap(),
+                            entity_type,
+                            location,
                             line_number: line_number.try_into().unwrap(),
+                            is_parameter: entry.tag() == gimli::DW_TAG_formal_parameter,
                         };
                         if depth == 1 {
                             compilation_units
@@ -182,15 +576,10 @@ pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<
                                 .unwrap()
                                 .global_variables
                                 .push(var);
-                        } else if depth > 1 {
-                            compilation_units
-                                .last_mut()
-                                .unwrap()
-                                .functions
-                                .last_mut()
-                                .unwrap()
-                                .variables
-                                .push(var);
+                        } else if let Some(func) =
+                            compilation_units.last_mut().unwrap().functions.last_mut()
+                        {
+                            func.variables.push(var);
                         }
                     }
                 }
@@ -205,7 +594,8 @@ pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<
             // Iterate over the line program rows.
             let mut rows = program.rows();
             while let Some((header, row)) = rows.next_row()? {
-                if !row.end_sequence() {
+                // Only statement boundaries are good places for breakpoints
+                if !row.end_sequence() && row.is_stmt() {
                     // Determine the path. Real applications should cache this for performance.
                     let mut path = path::PathBuf::new();
                     if let Some(file) = row.file(header) {
@@ -240,7 +630,7 @@ pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<
             }
         }
     }
-    Ok(compilation_units)
+    Ok((compilation_units, types))
 }
 
 #[derive(Debug, Clone)]
@@ -291,25 +681,77 @@ impl<'input, Endian> Reader for gimli::EndianSlice<'input, Endian> where
 
 trait Reader: gimli::Reader<Offset = usize> + Send + Sync {}
 
-fn get_location<R: Reader>(attr: &gimli::Attribute<R>, unit: &gimli::Unit<R>) -> Option<Location> {
-    if let gimli::AttributeValue::Exprloc(ref data) = attr.value() {
-        let encoding = unit.encoding();
-        let mut pc = data.0.clone();
-        if pc.len() > 0 {
-            if let Ok(op) = gimli::Operation::parse(&mut pc, encoding) {
-                match op {
-                    gimli::Operation::FrameOffset { offset } => {
-                        return Some(Location::FramePointerOffset(offset.try_into().unwrap()));
-                    }
-                    gimli::Operation::Address { address } => {
-                        return Some(Location::Address(address.try_into().unwrap()));
+fn get_expression<R: Reader>(
+    data: &gimli::Expression<R>,
+    unit: &gimli::Unit<R>,
+) -> Option<Expression> {
+    Some(Expression {
+        bytes: data.0.to_slice().ok()?.to_vec(),
+        encoding: unit.encoding(),
+    })
+}
+
+fn get_location<R: Reader>(
+    attr: &gimli::Attribute<R>,
+    unit: &gimli::Unit<R>,
+    dwarf: &gimli::Dwarf<R>,
+) -> Option<Location> {
+    match attr.value() {
+        gimli::AttributeValue::Exprloc(ref data) => {
+            let encoding = unit.encoding();
+            let mut pc = data.0.clone();
+            // A lone DW_OP_fbreg or DW_OP_addr, which is all gcc emits without optimization
+            if pc.len() > 0 {
+                if let Ok(op) = gimli::Operation::parse(&mut pc, encoding) {
+                    match op {
+                        gimli::Operation::FrameOffset { offset } if pc.len() == 0 => {
+                            return Some(Location::FramePointerOffset(offset.try_into().unwrap()));
+                        }
+                        gimli::Operation::Address { address } if pc.len() == 0 => {
+                            return Some(Location::Address(address.try_into().unwrap()));
+                        }
+                        _ => {}
                     }
-                    _ => {}
                 }
             }
+            Some(Location::Expression(get_expression(data, unit)?))
         }
+        gimli::AttributeValue::LocationListsRef(offset) => {
+            let mut entries = Vec::new();
+            let mut locations = dwarf.locations(unit, offset).ok()?;
+            while let Ok(Some(entry)) = locations.next() {
+                let expression = get_expression(&entry.data, unit)?;
+                entries.push((
+                    entry.range.begin.try_into().unwrap(),
+                    entry.range.end.try_into().unwrap(),
+                    expression,
+                ));
+            }
+            Some(Location::List(entries))
+        }
+        _ => None,
+    }
+}
+
+/// Returns the DIE a DW_AT_abstract_origin attribute refers to.
+fn get_origin<'unit, R: Reader>(
+    attr: &gimli::Attribute<R>,
+    unit: &'unit gimli::Unit<R>,
+) -> Option<gimli::DebuggingInformationEntry<'unit, 'unit, R>> {
+    match attr.value() {
+        gimli::AttributeValue::UnitRef(offset) => unit.entry(offset).ok(),
+        _ => None,
+    }
+}
+
+/// Renders a DWARF expression the way dwarfdump does, e.g. "DW_OP_breg7 +8".
+pub fn format_expression(expression: &Expression) -> String {
+    let data = gimli::EndianSlice::new(&expression.bytes, gimli::RunTimeEndian::Little);
+    let mut text = String::new();
+    match dump_exprloc(&mut text, expression.encoding, &gimli::Expression(data)) {
+        Ok(()) => text,
+        Err(_) => "<invalid expression>".to_string(),
     }
-    None
 }
 
 // based on dwarf_dump.rs
@@ -1,35 +1,132 @@
 use addr2line::gimli::LineInstruction;
+use nix::errno::Errno;
 use nix::sys::ptrace;
 use nix::sys::signal;
 use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
 use nix::unistd::Pid;
-use std::process::{Child, Command};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
 use std::os::unix::process::CommandExt;
-use crate::breakpoint_manager::Breakpoint;
+use std::fs::File;
+use std::rc::Rc;
 use crate::breakpoint_manager::BreakpointManager;
-use crate::dwarf_data::{DwarfData};
+use crate::console::Console;
+use crate::target::{Mapping, Target};
 use std::mem::size_of;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+
+/// Offset of `u_debugreg` in `struct user` (see <sys/user.h>), for PTRACE_PEEKUSER/POKEUSER.
+const DEBUG_REGS_OFFSET: usize = 848;
+
+/// The inferior Ctrl-C should stop: set while `resume` waits for it, 0 otherwise.
+static RUNNING_PID: AtomicI32 = AtomicI32::new(0);
+/// Set once the SIGINT handler has sent SIGSTOP to RUNNING_PID.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// SIGINT handler for the debugger itself. Pressing Ctrl-C while the inferior runs stops the
+/// inferior's main thread, so that `resume` returns to the prompt. Otherwise it does nothing:
+/// rustyline reports Ctrl-C at the prompt on its own.
+pub extern "C" fn interrupt(_: libc::c_int) {
+    let pid = RUNNING_PID.load(Ordering::SeqCst);
+    if pid != 0 {
+        // Only async-signal-safe calls are allowed here
+        unsafe {
+            libc::syscall(libc::SYS_tgkill, pid, pid, libc::SIGSTOP);
+        }
+        INTERRUPTED.store(true, Ordering::SeqCst);
+    }
+}
 
 fn align_addr_to_word(addr: usize) -> usize {
     addr & (-(size_of::<usize>() as isize) as usize)
 }
+
+/// Replaces the byte at `addr` in the memory of `pid`, returning the byte that was there.
+fn poke_byte(pid: Pid, addr: usize, val: u8) -> Result<u8, nix::Error> {
+    let aligned_addr = align_addr_to_word(addr);
+    let byte_offset = addr - aligned_addr;
+    let word = ptrace::read(pid, aligned_addr as ptrace::AddressType)? as u64;
+    let orig_byte = (word >> 8 * byte_offset) & 0xff;
+    let masked_word = word & !(0xff << 8 * byte_offset);
+    let updated_word = masked_word | ((val as u64) << 8 * byte_offset);
+    ptrace::write(
+        pid,
+        aligned_addr as ptrace::AddressType,
+        updated_word as *mut std::ffi::c_void,
+    )?;
+    Ok(orig_byte as u8)
+}
+
 impl Inferior {
+    /// Writes a byte of inferior memory, returning the byte it replaced. Bytes replaced with
+    /// 0xcc are remembered, so that they can be taken back out of forked children.
     pub fn write_byte(&mut self, addr: usize, val: u8) -> Result<u8, nix::Error> {
-        let aligned_addr = align_addr_to_word(addr);
-        let byte_offset = addr - aligned_addr;
-        let word = ptrace::read(self.pid(), aligned_addr as ptrace::AddressType)? as u64;
-        let orig_byte = (word >> 8 * byte_offset) & 0xff;
-        let masked_word = word & !(0xff << 8 * byte_offset);
-        let updated_word = masked_word | ((val as u64) << 8 * byte_offset);
-        ptrace::write(
-            self.pid(),
-            aligned_addr as ptrace::AddressType,
-            updated_word as *mut std::ffi::c_void,
-        )?;
-        Ok(orig_byte as u8)
+        let orig_byte = poke_byte(self.tid(), addr, val)?;
+        if val == 0xcc {
+            self.patched.entry(addr).or_insert(orig_byte);
+        } else {
+            self.patched.remove(&addr);
+        }
+        Ok(orig_byte)
+    }
+    /// Reads x86-64 debug register DR`index` of the current thread.
+    pub fn get_debug_reg(&self, index: usize) -> Result<usize, nix::Error> {
+        let offset = DEBUG_REGS_OFFSET + index * size_of::<usize>();
+        // nix's PEEKUSER wrapper throws the peeked value away, so call ptrace ourselves. A
+        // register may legitimately hold -1, so errno is what tells us about failure.
+        let value = unsafe {
+            Errno::clear();
+            libc::ptrace(
+                libc::PTRACE_PEEKUSER,
+                self.tid().as_raw(),
+                offset,
+                std::ptr::null_mut::<std::ffi::c_void>(),
+            )
+        };
+        match Errno::result(value) {
+            Ok(_) | Err(nix::Error::Sys(Errno::UnknownErrno)) => Ok(value as usize),
+            Err(e) => Err(e),
+        }
+    }
+    /// Reads the floating point and vector registers of the current thread.
+    pub fn get_fpregs(&self) -> Result<libc::user_fpregs_struct, nix::Error> {
+        let mut regs = std::mem::MaybeUninit::<libc::user_fpregs_struct>::uninit();
+        let ret = unsafe {
+            libc::ptrace(
+                libc::PTRACE_GETFPREGS,
+                self.tid().as_raw(),
+                std::ptr::null_mut::<std::ffi::c_void>(),
+                regs.as_mut_ptr(),
+            )
+        };
+        Errno::result(ret)?;
+        Ok(unsafe { regs.assume_init() })
+    }
+    /// Writes x86-64 debug register DR`index` of every thread, since watchpoints apply to the
+    /// whole process.
+    pub fn set_debug_reg(&mut self, index: usize, value: usize) -> Result<(), nix::Error> {
+        for thread in &self.threads {
+            Self::poke_debug_reg(thread.tid, index, value)?;
+        }
+        self.debug_regs[index] = value;
+        Ok(())
     }
+    fn poke_debug_reg(tid: Pid, index: usize, value: usize) -> Result<(), nix::Error> {
+        let offset = DEBUG_REGS_OFFSET + index * size_of::<usize>();
+        let ret = unsafe { libc::ptrace(libc::PTRACE_POKEUSER, tid.as_raw(), offset, value) };
+        Errno::result(ret).map(drop)
+    }
+    /// Single-steps the current thread, delivering its pending signal. The other threads stay
+    /// stopped.
     pub fn ptrace_step(&mut self) -> Result<(), nix::Error> {
-        ptrace::step(self.pid(), None)
+        let tid = self.tid();
+        let signal = self.thread_mut(tid).and_then(|thread| thread.pending_signal.take());
+        ptrace::step(tid, signal)?;
+        if let Some(thread) = self.thread_mut(tid) {
+            thread.running = true;
+        }
+        Ok(())
     }
 }
 
@@ -38,6 +135,14 @@ pub enum Status {
     /// current instruction pointer that it is stopped at.
     Stopped(signal::Signal, usize),
 
+    /// Indicates inferior stopped because a hardware watchpoint fired. Contains the debug
+    /// register slot of the watchpoint and the instruction pointer after the access.
+    Watchpoint(usize, usize),
+
+    /// Indicates the inferior replaced itself with a new program through execve. Contains the
+    /// new program's entry point.
+    Exec(usize),
+
     /// Indicates inferior exited normally. Contains the exit status code.
     Exited(i32),
 
@@ -55,89 +160,588 @@ fn child_traceme() -> Result<(), std::io::Error> {
     )))
 }
 
+/// Reports new threads, forks and execs to us. Forked children are detached as soon as they
+/// appear, so only the original process is followed.
+fn trace_options() -> ptrace::Options {
+    ptrace::Options::PTRACE_O_TRACECLONE
+        | ptrace::Options::PTRACE_O_TRACEFORK
+        | ptrace::Options::PTRACE_O_TRACEVFORK
+        | ptrace::Options::PTRACE_O_TRACEVFORKDONE
+        | ptrace::Options::PTRACE_O_TRACEEXEC
+}
+
+/// A thread of the inferior.
+pub struct Thread {
+    /// The number `info threads` and `thread <n>` use, starting at 1 for the main thread
+    pub id: usize,
+    pub tid: Pid,
+    /// Whether we let the thread run and haven't seen it stop since
+    running: bool,
+    /// Whether a SIGSTOP is on its way that should be swallowed rather than reported. New
+    /// threads start with one, and we send them to stop the other threads when one stops.
+    stop_expected: bool,
+    /// A signal the thread received while we were stopping it, delivered when it resumes
+    pending_signal: Option<signal::Signal>,
+}
+
+impl Thread {
+    fn new(id: usize, tid: Pid) -> Thread {
+        Thread {
+            id,
+            tid,
+            running: false,
+            stop_expected: false,
+            pending_signal: None,
+        }
+    }
+}
+
 pub struct Inferior {
-    child: Child,
+    pid: Pid,
+    /// The process we spawned, or None if we attached to an existing process
+    child: Option<Child>,
+    /// Every live thread, in the order they appeared. All of them are stopped whenever the
+    /// debugger has control.
+    threads: Vec<Thread>,
+    next_thread_id: usize,
+    /// The thread registers are read from and single-steps apply to
+    current: Pid,
+    /// The thread the user last saw stopped or selected
+    reported: Pid,
+    /// Original bytes of the addresses we wrote 0xcc to
+    patched: HashMap<usize, u8>,
+    /// Debug register values, so that new threads can be given the same watchpoints
+    debug_regs: [usize; 8],
+    /// Forked children that stopped before their parent reported the fork
+    early_forks: HashSet<Pid>,
+    /// Whether a SIGINT from the terminal is still to come for a Ctrl-C already reported
+    sigint_expected: bool,
+    /// Where thread and fork notices go
+    console: Rc<Console>,
 }
 
 impl Inferior {
+    fn from_pid(pid: Pid, child: Option<Child>, console: Rc<Console>) -> Inferior {
+        Inferior {
+            pid,
+            child,
+            threads: vec![Thread::new(1, pid)],
+            next_thread_id: 2,
+            current: pid,
+            reported: pid,
+            patched: HashMap::new(),
+            debug_regs: [0; 8],
+            early_forks: HashSet::new(),
+            sigint_expected: false,
+            console,
+        }
+    }
+
     /// Attempts to start a new inferior process. Returns Some(Inferior) if successful, or None if
     /// an error is encountered.
-    pub fn new(target: &str, args: &Vec<String>, breakpoints: &mut BreakpointManager) -> Option<Inferior> {
+    ///
+    /// The program's stdout and stderr go to `output` if given, in which case its stdin is
+    /// /dev/null; otherwise it shares our terminal.
+    pub fn new(
+        target: &str,
+        args: &Vec<String>,
+        breakpoints: &mut BreakpointManager,
+        console: Rc<Console>,
+        output: Option<&File>,
+    ) -> Option<Inferior> {
         let mut cmd = Command::new(target);
         cmd.args(args);
+        if let Some(output) = output {
+            cmd.stdin(Stdio::null());
+            cmd.stdout(output.try_clone().ok()?);
+            cmd.stderr(output.try_clone().ok()?);
+        }
         unsafe {
             cmd.pre_exec(child_traceme);
         }
 
-        let mut child = Inferior{child: cmd.spawn().ok()?};
-        child.init_breakpoints(breakpoints).ok()?;
-        let inferior = match child.wait(None).ok()? {
+        let child = cmd.spawn().ok()?;
+        let mut child = Inferior::from_pid(Pid::from_raw(child.id() as i32), Some(child), console);
+        // The child can only be poked once it has stopped at the exec
+        let mut inferior = match child.wait().ok()? {
             Status::Stopped(signal::SIGTRAP, _) => child,
             _ => return None,
         };
-        
+        ptrace::setoptions(inferior.pid(), trace_options()).ok()?;
+        inferior.init_breakpoints(breakpoints).ok()?;
+
         Some(inferior)
     }
-    fn init_breakpoints(&mut self, breakpoints: &mut BreakpointManager) -> Result<(), nix::Error> {
-        for (addr, breakpoint) in breakpoints.iter_mut() {
-            let orig_byte = self.write_byte(*addr, 0xcc)?;
-            *breakpoint = Some(Breakpoint::new(*addr, orig_byte));
+    /// Attaches to the already running process `pid` and all of its threads. Returns
+    /// Some(Inferior) if successful, or None if an error is encountered.
+    pub fn attach(
+        pid: Pid,
+        breakpoints: &mut BreakpointManager,
+        console: Rc<Console>,
+    ) -> Option<Inferior> {
+        let mut inferior = Inferior::from_pid(pid, None, console);
+        let mut tids: Vec<i32> = std::fs::read_dir(format!("/proc/{}/task", pid))
+            .ok()?
+            .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+            .filter(|tid| *tid != pid.as_raw())
+            .collect();
+        tids.sort();
+        tids.insert(0, pid.as_raw());
+        for tid in tids.into_iter().map(Pid::from_raw) {
+            // A thread that exits in the meantime just isn't attached
+            if ptrace::attach(tid).is_err() {
+                if tid == pid {
+                    return None;
+                }
+                continue;
+            }
+            match waitpid(tid, Some(WaitPidFlag::__WALL)).ok()? {
+                WaitStatus::Stopped(_, signal::SIGSTOP) => {}
+                _ => return None,
+            }
+            ptrace::setoptions(tid, trace_options()).ok()?;
+            if tid != pid {
+                inferior.add_thread(tid);
+            }
+        }
+        inferior.init_breakpoints(breakpoints).ok()?;
+        Some(inferior)
+    }
+    /// Returns true if we attached to this process rather than spawning it.
+    pub fn is_attached(&self) -> bool {
+        self.child.is_none()
+    }
+    /// Removes every breakpoint and watchpoint from the process and lets it run free.
+    pub fn detach(&mut self, breakpoints: &mut BreakpointManager) -> Result<(), nix::Error> {
+        for (_, breakpoint) in breakpoints.iter_mut() {
+            breakpoint.remove(self)?;
+        }
+        self.set_debug_reg(7, 0)?;
+        // Each thread gets the signal it was going to be resumed with. The main thread goes
+        // last, since detaching it is the one that has to succeed.
+        let mut main_signal = None;
+        for thread in &self.threads {
+            if thread.tid == self.pid {
+                main_signal = thread.pending_signal;
+            } else {
+                let _ = ptrace::detach(thread.tid, thread.pending_signal);
+            }
+        }
+        ptrace::detach(self.pid(), main_signal)
+    }
+    /// Inserts every breakpoint and watchpoint into the process.
+    pub fn init_breakpoints(
+        &mut self,
+        breakpoints: &mut BreakpointManager,
+    ) -> Result<(), nix::Error> {
+        for (_, breakpoint) in breakpoints.iter_mut() {
+            breakpoint.insert(self)?;
+        }
+        breakpoints.install_watchpoints(self)
+    }
+    pub fn thread_regs(&self, tid: Pid) -> Option<libc::user_regs_struct> {
+        ptrace::getregs(tid).ok()
+    }
+    pub fn set_regs(&self, regs: libc::user_regs_struct) -> Result<(), nix::Error> {
+        ptrace::setregs(self.tid(), regs)
+    }
+    pub fn set_rip(&self, rip: usize) -> Result<(), nix::Error> {
+        let mut regs = ptrace::getregs(self.tid())?;
+        regs.rip = rip as u64;
+        self.set_regs(regs)
+    }
+    pub fn kill(&mut self) -> std::io::Result<()> {
+        say!(self.console, "Killing running inferior (pid {})", self.pid());
+        match self.child.as_mut() {
+            Some(child) => child.kill(),
+            None => {
+                signal::kill(self.pid(), signal::SIGKILL)
+                    .or(Err(std::io::Error::from(std::io::ErrorKind::Other)))?;
+                let _ = waitpid(self.pid(), None);
+                Ok(())
+            }
+        }
+    }
+    
+    /// Lets every thread run until one of them stops.
+    pub fn resume(&mut self) -> Result<Status, nix::Error> {
+        let current = self.tid();
+        for thread in self.threads.iter_mut().filter(|thread| !thread.running) {
+            let result = ptrace::cont(thread.tid, thread.pending_signal.take());
+            // Threads can vanish under us (e.g. when another thread calls exit_group)
+            if thread.tid == current {
+                result?;
+            }
+            thread.running = true;
+        }
+        RUNNING_PID.store(self.pid.as_raw(), Ordering::SeqCst);
+        let status = self.wait();
+        RUNNING_PID.store(0, Ordering::SeqCst);
+        status
+    }
+
+    /// Returns the thread id of the current thread.
+    pub fn tid(&self) -> Pid {
+        self.current
+    }
+
+    pub fn threads(&self) -> &[Thread] {
+        &self.threads
+    }
+
+    fn thread_mut(&mut self, tid: Pid) -> Option<&mut Thread> {
+        self.threads.iter_mut().find(|thread| thread.tid == tid)
+    }
+
+    /// Sets the signal the current thread will receive when it next resumes, replacing the
+    /// one that stopped it (if any).
+    pub fn set_signal(&mut self, signal: Option<signal::Signal>) {
+        let tid = self.tid();
+        if let Some(thread) = self.thread_mut(tid) {
+            thread.pending_signal = signal;
+        }
+    }
+
+    /// Makes thread `tid` the current thread, without telling the user.
+    pub fn select_thread(&mut self, tid: Pid) {
+        self.current = tid;
+    }
+
+    /// Implements `thread <n>`: makes thread number `id` the current thread.
+    pub fn switch_thread(&mut self, id: usize) -> Option<&Thread> {
+        let tid = self.threads.iter().find(|thread| thread.id == id)?.tid;
+        self.current = tid;
+        self.reported = tid;
+        self.threads.iter().find(|thread| thread.id == id)
+    }
+
+    /// Returns the current thread if it is not the one the user last heard about, so that the
+    /// switch can be announced.
+    pub fn take_thread_switch(&mut self) -> Option<&Thread> {
+        if self.current == self.reported {
+            return None;
+        }
+        self.reported = self.current;
+        let current = self.current;
+        self.threads.iter().find(|thread| thread.tid == current)
+    }
+
+    fn add_thread(&mut self, tid: Pid) -> &mut Thread {
+        self.threads.push(Thread::new(self.next_thread_id, tid));
+        self.next_thread_id += 1;
+        self.threads.last_mut().unwrap()
+    }
+
+    /// Gives a thread that just reported its first stop the watchpoints the rest of the
+    /// process has.
+    fn adopt_thread(&self, tid: Pid) -> Result<(), nix::Error> {
+        if self.debug_regs[7] == 0 {
+            return Ok(());
+        }
+        for &index in &[0, 1, 2, 3, 7] {
+            Self::poke_debug_reg(tid, index, self.debug_regs[index])?;
         }
         Ok(())
     }
-    pub fn get_regs(&self) -> Option<libc::user_regs_struct> {
-        Some(ptrace::getregs(self.pid()).ok()?)
+
+    fn remove_thread(&mut self, tid: Pid) {
+        self.threads.retain(|thread| thread.tid != tid);
+        if self.current == tid {
+            if let Some(thread) = self.threads.first() {
+                self.current = thread.tid;
+            }
+        }
     }
-    pub fn print_backtrace(&self, debug_data: &DwarfData) -> Result<(), nix::Error> {
-        let regs= ptrace::getregs(self.pid())?;
-        let mut instruction_ptr = regs.rip as usize;
-        let mut base_ptr = regs.rbp as usize;
+
+    /// Whether `tid` is a thread of our process (as opposed to a forked child).
+    fn is_own_thread(&self, tid: Pid) -> bool {
+        Path::new(&format!("/proc/{}/task/{}", self.pid, tid)).exists()
+    }
+
+    /// Whether a SIGINT is waiting to be delivered to the process or any of its threads.
+    fn sigint_pending(&self) -> bool {
+        let bit = 1u64 << (libc::SIGINT - 1);
+        self.threads.iter().any(|thread| {
+            let path = format!("/proc/{}/task/{}/status", self.pid, thread.tid);
+            std::fs::read_to_string(path)
+                .unwrap_or_default()
+                .lines()
+                .filter(|line| line.starts_with("SigPnd:") || line.starts_with("ShdPnd:"))
+                .filter_map(|line| u64::from_str_radix(line[7..].trim(), 16).ok())
+                .any(|pending| pending & bit != 0)
+        })
+    }
+
+    /// Takes our breakpoints out of a freshly forked child, which inherited them, and lets it
+    /// go. Otherwise it would die of SIGTRAP the first time it ran into one.
+    fn release_fork(&mut self, child: Pid) -> Result<(), nix::Error> {
+        for (addr, orig_byte) in &self.patched {
+            poke_byte(child, *addr, *orig_byte)?;
+        }
+        say!(self.console, "[Detaching after fork from child process {}]", child);
+        ptrace::detach(child, None)
+    }
+
+    /// Handles a clone, fork or vfork reported by thread `tid`, which stays stopped.
+    fn handle_event(&mut self, tid: Pid, event: i32) -> Result<(), nix::Error> {
+        match event {
+            libc::PTRACE_EVENT_CLONE => {
+                let new_tid = Pid::from_raw(ptrace::getevent(tid)? as i32);
+                if !self.threads.iter().any(|thread| thread.tid == new_tid) {
+                    say!(self.console, "[New Thread {}]", new_tid);
+                    // It starts out in a stop of its own which we haven't seen yet
+                    let thread = self.add_thread(new_tid);
+                    thread.running = true;
+                    thread.stop_expected = true;
+                }
+            }
+            libc::PTRACE_EVENT_FORK | libc::PTRACE_EVENT_VFORK => {
+                let child = Pid::from_raw(ptrace::getevent(tid)? as i32);
+                if !self.early_forks.remove(&child) {
+                    waitpid(child, Some(WaitPidFlag::__WALL))?;
+                }
+                self.release_fork(child)?;
+            }
+            libc::PTRACE_EVENT_VFORK_DONE => {
+                // A vfork child shares our memory, so releasing it took the breakpoints out of
+                // our own code as well
+                for addr in self.patched.keys() {
+                    poke_byte(tid, *addr, 0xcc)?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Stops every thread that is still running, so that the whole process holds still while
+    /// the debugger has control. Threads that stop for some other reason in the meantime keep
+    /// what happened for later: a breakpoint is rewound so it will be hit again, and a signal
+    /// is delivered when the thread resumes.
+    fn stop_others(&mut self) -> Result<(), nix::Error> {
+        let running: Vec<Pid> = self
+            .threads
+            .iter()
+            .filter(|thread| thread.running)
+            .map(|thread| thread.tid)
+            .collect();
+        for tid in &running {
+            unsafe {
+                libc::syscall(libc::SYS_tgkill, self.pid.as_raw(), tid.as_raw(), libc::SIGSTOP);
+            }
+            self.thread_mut(*tid).unwrap().stop_expected = true;
+        }
+        for tid in running {
+            let status = waitpid(tid, Some(WaitPidFlag::__WALL))?;
+            match status {
+                WaitStatus::Stopped(_, signal::SIGSTOP) => {
+                    self.adopt_thread(tid)?;
+                    self.thread_mut(tid).unwrap().stop_expected = false;
+                }
+                WaitStatus::Stopped(_, signal::SIGTRAP) => {
+                    Self::poke_debug_reg(tid, 6, 0)?;
+                    let mut regs = ptrace::getregs(tid)?;
+                    if self.patched.contains_key(&(regs.rip as usize - 1)) {
+                        regs.rip -= 1;
+                        ptrace::setregs(tid, regs)?;
+                    }
+                }
+                WaitStatus::Stopped(_, signal) => {
+                    self.thread_mut(tid).unwrap().pending_signal = Some(signal);
+                }
+                WaitStatus::PtraceEvent(_, _, event) => self.handle_event(tid, event)?,
+                WaitStatus::Exited(..) | WaitStatus::Signaled(..) => {
+                    self.remove_thread(tid);
+                    continue;
+                }
+                _ => {}
+            }
+            if let Some(thread) = self.thread_mut(tid) {
+                thread.running = false;
+            }
+        }
+        // Threads created while we were at it are stopped too, but still have to report it
+        let new: Vec<Pid> = self
+            .threads
+            .iter()
+            .filter(|thread| thread.running)
+            .map(|thread| thread.tid)
+            .collect();
+        for tid in new {
+            waitpid(tid, Some(WaitPidFlag::__WALL))?;
+            self.adopt_thread(tid)?;
+            let thread = self.thread_mut(tid).unwrap();
+            thread.stop_expected = false;
+            thread.running = false;
+        }
+        Ok(())
+    }
+
+    /// Waits until some thread of the inferior stops for a reason worth reporting, and returns
+    /// a Status to indicate the state of the process. Thread creation, forks and the SIGSTOPs
+    /// we send ourselves are dealt with here. Once a thread stops, the current thread is
+    /// switched to it and all other threads are stopped as well.
+    pub fn wait(&mut self) -> Result<Status, nix::Error> {
         loop {
-            let func = debug_data.get_function_from_addr(instruction_ptr);
-            let line = debug_data.get_line_from_addr(instruction_ptr);
-            match (func, line) {
-                (Some(func_name), Some(line_info)) => {
-                    println!("{} ({})", func_name, line_info);
-                    if func_name == "main" {
-                        break;
+            let status = waitpid(Pid::from_raw(-1), Some(WaitPidFlag::__WALL))?;
+            let tid = match status.pid() {
+                Some(tid) => tid,
+                None => continue,
+            };
+            match status {
+                WaitStatus::Exited(_, exit_code) if tid == self.pid => {
+                    return Ok(Status::Exited(exit_code));
+                }
+                WaitStatus::Signaled(_, signal, _core_dumped) if tid == self.pid => {
+                    return Ok(Status::Signaled(signal));
+                }
+                WaitStatus::Exited(..) | WaitStatus::Signaled(..) => {
+                    if self.threads.iter().any(|thread| thread.tid == tid) {
+                        say!(self.console, "[Thread {} exited]", tid);
+                        self.remove_thread(tid);
+                    }
+                    continue;
+                }
+                WaitStatus::PtraceEvent(_, _, libc::PTRACE_EVENT_EXEC) => {
+                    // The execing thread took over the thread group leader's id, and every other
+                    // thread is gone along with the old program
+                    let pid = self.pid;
+                    self.threads.retain(|thread| thread.tid == pid);
+                    if let Some(thread) = self.threads.first_mut() {
+                        thread.running = false;
+                    }
+                    self.current = pid;
+                    self.patched.clear();
+                    self.debug_regs = [0; 8];
+                    let regs = ptrace::getregs(pid)?;
+                    return Ok(Status::Exec(regs.rip as usize));
+                }
+                WaitStatus::PtraceEvent(_, _, event) => {
+                    self.handle_event(tid, event)?;
+                    ptrace::cont(tid, None)?;
+                    continue;
+                }
+                WaitStatus::Stopped(_, signal::SIGSTOP)
+                    if !self.threads.iter().any(|thread| thread.tid == tid) =>
+                {
+                    if self.is_own_thread(tid) {
+                        // A new thread that beat its parent's clone event here
+                        say!(self.console, "[New Thread {}]", tid);
+                        self.add_thread(tid).running = true;
+                        self.adopt_thread(tid)?;
+                        ptrace::cont(tid, None)?;
+                    } else {
+                        self.early_forks.insert(tid);
+                    }
+                    continue;
+                }
+                WaitStatus::Stopped(_, signal::SIGSTOP)
+                    if self.thread_mut(tid).map_or(false, |thread| thread.stop_expected) =>
+                {
+                    self.adopt_thread(tid)?;
+                    self.thread_mut(tid).unwrap().stop_expected = false;
+                    ptrace::cont(tid, None)?;
+                    continue;
+                }
+                WaitStatus::Stopped(_, signal::SIGINT) if self.sigint_expected => {
+                    self.sigint_expected = false;
+                    ptrace::cont(tid, None)?;
+                    continue;
+                }
+                WaitStatus::Stopped(_, signal) => {
+                    match self.thread_mut(tid) {
+                        Some(thread) => thread.running = false,
+                        None => continue,
+                    }
+                    self.current = tid;
+                    self.stop_others()?;
+                    let regs = ptrace::getregs(tid)?;
+                    if INTERRUPTED.swap(false, Ordering::SeqCst) {
+                        if tid == self.pid && signal == signal::SIGSTOP {
+                            // Report the user's Ctrl-C the way a terminal would have sent it. If
+                            // the terminal did send it, the program has it too, and since our
+                            // SIGSTOP was sent to a thread it came first.
+                            self.sigint_expected = self.sigint_pending();
+                            return Ok(Status::Stopped(signal::SIGINT, regs.rip as usize));
+                        }
+                        // Something else stopped the process first; don't let the SIGSTOP we
+                        // sent surprise us later. (If the main thread was stopped along with
+                        // the others, that already took care of it.)
+                        if tid == self.pid {
+                            self.thread_mut(tid).unwrap().stop_expected = true;
+                        }
+                    }
+                    if signal == signal::SIGTRAP {
+                        // DR6 bits 0-3 say which debug register triggered. The CPU never clears
+                        // them, so we have to.
+                        let dr6 = self.get_debug_reg(6)?;
+                        if let Some(slot) = (0..4).find(|slot| dr6 & (1 << slot) != 0) {
+                            Self::poke_debug_reg(tid, 6, 0)?;
+                            return Ok(Status::Watchpoint(slot, regs.rip as usize));
+                        }
                     }
+                    return Ok(Status::Stopped(signal, regs.rip as usize));
                 }
+                // We never ask for syscall stops, and nothing else can be reported with __WALL
                 _ => {
-                    println!("unknown func (unknown position)");
-                    break;
+                    let _ = ptrace::cont(tid, None);
+                    continue;
                 }
             }
-            instruction_ptr = ptrace::read(self.pid(), (base_ptr + 8) as ptrace::AddressType)? as usize;
-            base_ptr = ptrace::read(self.pid(), base_ptr as ptrace::AddressType)? as usize;
         }
-        Ok(())
     }
-    pub fn kill(&mut self) -> std::io::Result<()> {
-        println!("Killing running inferior (pid {})", self.pid());
-        self.child.kill()
+}
+
+impl Target for Inferior {
+    /// Returns the pid of this inferior.
+    fn pid(&self) -> Pid {
+        self.pid
     }
-    
-    pub fn resume(&mut self) -> Result<Status, nix::Error> {
-        ptrace::cont(self.pid(), None)?;
-        self.wait(None)
+
+    fn get_regs(&self) -> Option<libc::user_regs_struct> {
+        self.thread_regs(self.tid())
     }
 
-    /// Returns the pid of this inferior.
-    pub fn pid(&self) -> Pid {
-        nix::unistd::Pid::from_raw(self.child.id() as i32)
+    /// Reads `len` bytes of inferior memory starting at `addr`, one word at a time.
+    fn read_bytes(&self, addr: usize, len: usize) -> Result<Vec<u8>, nix::Error> {
+        let mut bytes = Vec::with_capacity(len);
+        let mut aligned_addr = align_addr_to_word(addr);
+        while aligned_addr < addr + len {
+            let word = ptrace::read(self.tid(), aligned_addr as ptrace::AddressType)? as u64;
+            for (i, byte) in word.to_le_bytes().iter().enumerate() {
+                let byte_addr = aligned_addr + i;
+                if byte_addr >= addr && byte_addr < addr + len {
+                    bytes.push(*byte);
+                }
+            }
+            aligned_addr += size_of::<usize>();
+        }
+        Ok(bytes)
     }
 
-    /// Calls waitpid on this inferior and returns a Status to indicate the state of the process
-    /// after the waitpid call.
-    pub fn wait(&self, options: Option<WaitPidFlag>) -> Result<Status, nix::Error> {
-        Ok(match waitpid(self.pid(), options)? {
-            WaitStatus::Exited(_pid, exit_code) => Status::Exited(exit_code),
-            WaitStatus::Signaled(_pid, signal, _core_dumped) => Status::Signaled(signal),
-            WaitStatus::Stopped(_pid, signal) => {
-                let regs = ptrace::getregs(self.pid())?;
-                Status::Stopped(signal, regs.rip as usize)
+    /// Lists the files mapped into the process, according to /proc/<pid>/maps.
+    fn mappings(&self) -> Vec<Mapping> {
+        let maps = match std::fs::read_to_string(format!("/proc/{}/maps", self.pid)) {
+            Ok(maps) => maps,
+            Err(_) => return Vec::new(),
+        };
+        let mut mappings = Vec::new();
+        for line in maps.lines() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 6 || !fields[5].starts_with('/') {
+                continue;
             }
-            other => panic!("waitpid returned unexpected status: {:?}", other),
-        })
+            let mut range = fields[0].split('-');
+            let start = usize::from_str_radix(range.next().unwrap_or(""), 16).unwrap_or(0);
+            let end = usize::from_str_radix(range.next().unwrap_or(""), 16).unwrap_or(0);
+            let offset = usize::from_str_radix(fields[2], 16).unwrap_or(0);
+            mappings.push(Mapping {
+                path: fields[5].to_string(),
+                start,
+                end,
+                offset,
+            });
+        }
+        mappings
     }
 }
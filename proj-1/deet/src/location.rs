@@ -0,0 +1,429 @@
+//! Finds where variables live by evaluating their DWARF location descriptions against a stack
+//! frame. Without optimization a variable is at a fixed address or at a fixed offset in its
+//! frame, but in optimized code it may be in a register, somewhere computed by a DWARF
+//! expression, in different places depending on rip (a location list), or nowhere at all.
+
+use crate::dwarf_data::{Expression, Location};
+use crate::registers::{self, DWARF_NAMES};
+use crate::target::Target;
+use crate::unwind::Frame;
+use crate::value;
+use gimli::{EndianSlice, EvaluationResult, RunTimeEndian};
+use std::fmt;
+
+/// DWARF numbers of the registers a callee preserves: rbx, rbp, rsp, r12-r15 and rip. These are
+/// the only ones unwinding recovers for a caller's frame.
+const CALLEE_SAVED: [u16; 8] = [3, 6, 7, 12, 13, 14, 15, 16];
+
+/// Give up on expressions that are still running after this many operations.
+const MAX_ITERATIONS: u32 = 10_000;
+
+type Piece<'a> = gimli::Piece<EndianSlice<'a, RunTimeEndian>>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The variable doesn't exist at this point of the program
+    OptimizedOut,
+    Unavailable(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::OptimizedOut => write!(f, "value has been optimized out"),
+            Error::Unavailable(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+/// Where a variable's value is.
+pub enum Place {
+    Memory(usize),
+    /// Not in memory (in registers, or computed by the expression), so only its bytes are known
+    Value(Vec<u8>),
+}
+
+impl Place {
+    /// Reads `size` bytes of the value, and returns them along with its address if it has one.
+    pub fn read(
+        self,
+        size: usize,
+        target: &dyn Target,
+    ) -> Result<(Vec<u8>, Option<usize>), Error> {
+        match self {
+            Place::Memory(addr) => Ok((read_memory(target, addr, size)?, Some(addr))),
+            Place::Value(mut bytes) => {
+                bytes.resize(size, 0);
+                Ok((bytes, None))
+            }
+        }
+    }
+}
+
+fn no_frame() -> Error {
+    Error::Unavailable("No frame selected.".to_string())
+}
+
+fn invalid(err: gimli::Error) -> Error {
+    Error::Unavailable(format!("Invalid DWARF expression: {}", err))
+}
+
+fn read_memory(target: &dyn Target, addr: usize, len: usize) -> Result<Vec<u8>, Error> {
+    target
+        .read_bytes(addr, len)
+        .or(Err(Error::Unavailable(format!("Cannot access memory at address {:#x}", addr))))
+}
+
+/// Returns the value of DWARF register `number` in `frame`.
+fn register(frame: Option<&Frame>, number: u16) -> Result<u64, Error> {
+    let frame = frame.ok_or_else(no_frame)?;
+    // The rest of a caller's registers were clobbered by the calls it made
+    if frame.caller && !CALLEE_SAVED.contains(&number) {
+        return Err(Error::OptimizedOut);
+    }
+    DWARF_NAMES
+        .get(number as usize)
+        .and_then(|name| registers::get(&frame.regs, name))
+        .ok_or_else(|| Error::Unavailable(format!("Unsupported DWARF register {}", number)))
+}
+
+/// Works out where a variable of `size` bytes at `location` is in `frame`. `frame` is None when
+/// the program has no stack, which is fine for variables at fixed addresses. `frame_base` is the
+/// frame base of the variable's function; without one, the CFA is used like gcc does.
+pub fn locate(
+    location: &Location,
+    size: usize,
+    frame: Option<&Frame>,
+    frame_base: Option<&Location>,
+    target: &dyn Target,
+) -> Result<Place, Error> {
+    match location {
+        Location::Address(addr) => Ok(Place::Memory(*addr)),
+        Location::FramePointerOffset(offset) => {
+            let base = frame_base_address(frame, frame_base, target)?;
+            Ok(Place::Memory((base as isize + offset) as usize))
+        }
+        Location::Expression(expression) => {
+            evaluate(expression, size, frame, frame_base, target)
+        }
+        Location::List(entries) => {
+            evaluate(select(entries, frame)?, size, frame, frame_base, target)
+        }
+        Location::OptimizedOut => Err(Error::OptimizedOut),
+    }
+}
+
+/// Picks the expression of a location list that applies at `frame`'s pc.
+fn select<'a>(
+    entries: &'a [(usize, usize, Expression)],
+    frame: Option<&Frame>,
+) -> Result<&'a Expression, Error> {
+    let pc = frame.ok_or_else(no_frame)?.lookup_pc();
+    entries
+        .iter()
+        .find(|(start, end, _)| pc >= *start && pc < *end)
+        .map(|(_, _, expression)| expression)
+        .ok_or(Error::OptimizedOut)
+}
+
+/// Computes the address `DW_OP_fbreg` offsets are relative to.
+fn frame_base_address(
+    frame: Option<&Frame>,
+    frame_base: Option<&Location>,
+    target: &dyn Target,
+) -> Result<u64, Error> {
+    let expression = match frame_base {
+        None => return Ok(frame.ok_or_else(no_frame)?.cfa as u64),
+        Some(Location::Address(addr)) => return Ok(*addr as u64),
+        Some(Location::Expression(expression)) => expression,
+        Some(Location::List(entries)) => select(entries, frame)?,
+        Some(_) => return Err(Error::Unavailable("Unsupported frame base".to_string())),
+    };
+    // The frame base is an address, or a register holding one (DW_OP_reg6 means rbp's value)
+    match pieces(expression, frame, None, target)?.as_slice() {
+        [Piece {
+            location: gimli::Location::Address { address },
+            ..
+        }] => Ok(*address),
+        [Piece {
+            location: gimli::Location::Register { register: number },
+            ..
+        }] => register(frame, number.0),
+        _ => Err(Error::Unavailable("Unsupported frame base".to_string())),
+    }
+}
+
+/// Runs a DWARF expression, supplying the registers, memory and frame addresses it asks for.
+fn pieces<'a>(
+    expression: &'a Expression,
+    frame: Option<&Frame>,
+    frame_base: Option<&Location>,
+    target: &dyn Target,
+) -> Result<Vec<Piece<'a>>, Error> {
+    let data = EndianSlice::new(&expression.bytes, RunTimeEndian::Little);
+    let mut evaluation = gimli::Expression(data).evaluation(expression.encoding);
+    evaluation.set_max_iterations(MAX_ITERATIONS);
+    let mut result = evaluation.evaluate().map_err(invalid)?;
+    loop {
+        result = match result {
+            EvaluationResult::Complete => return Ok(evaluation.result()),
+            EvaluationResult::RequiresMemory { address, size, .. } => {
+                let bytes = read_memory(target, address as usize, size as usize)?;
+                evaluation.resume_with_memory(gimli::Value::Generic(value::read_unsigned(&bytes)))
+            }
+            EvaluationResult::RequiresRegister { register: number, .. } => {
+                let value = register(frame, number.0)?;
+                evaluation.resume_with_register(gimli::Value::Generic(value))
+            }
+            EvaluationResult::RequiresFrameBase => {
+                let base = frame_base_address(frame, frame_base, target)?;
+                evaluation.resume_with_frame_base(base)
+            }
+            EvaluationResult::RequiresCallFrameCfa => {
+                let cfa = frame.ok_or_else(no_frame)?.cfa as u64;
+                evaluation.resume_with_call_frame_cfa(cfa)
+            }
+            EvaluationResult::RequiresRelocatedAddress(address) => {
+                evaluation.resume_with_relocated_address(address)
+            }
+            // The value a parameter had on entry is gone by now, unless the caller happens to
+            // have kept it somewhere, which we have no way of knowing
+            EvaluationResult::RequiresEntryValue(_) | EvaluationResult::RequiresParameterRef(_) => {
+                return Err(Error::OptimizedOut)
+            }
+            _ => return Err(Error::Unavailable("Unsupported DWARF expression".to_string())),
+        }
+        .map_err(invalid)?;
+    }
+}
+
+/// Evaluates the location expression of a variable of `size` bytes.
+fn evaluate(
+    expression: &Expression,
+    size: usize,
+    frame: Option<&Frame>,
+    frame_base: Option<&Location>,
+    target: &dyn Target,
+) -> Result<Place, Error> {
+    // An empty location description means the variable isn't anywhere
+    if expression.bytes.is_empty() {
+        return Err(Error::OptimizedOut);
+    }
+    let pieces = pieces(expression, frame, frame_base, target)?;
+    // The usual case: the whole variable is in memory
+    if let [Piece {
+        size_in_bits: None,
+        location: gimli::Location::Address { address },
+        ..
+    }] = pieces.as_slice()
+    {
+        return Ok(Place::Memory(*address as usize));
+    }
+    if pieces.is_empty() {
+        return Err(Error::OptimizedOut);
+    }
+    // Otherwise put the value together from its pieces (just one, if it isn't split up)
+    let mut bytes = Vec::new();
+    for piece in pieces {
+        let len = piece.size_in_bits.map_or(size, |bits| (bits / 8) as usize);
+        let piece_bytes = match piece.location {
+            gimli::Location::Empty => return Err(Error::OptimizedOut),
+            gimli::Location::Address { address } => read_memory(target, address as usize, len)?,
+            gimli::Location::Register { register: number } => {
+                register(frame, number.0)?.to_le_bytes().to_vec()
+            }
+            gimli::Location::Value { value } => {
+                value.to_u64(!0).map_err(invalid)?.to_le_bytes().to_vec()
+            }
+            gimli::Location::Bytes { value } => value.slice().to_vec(),
+            gimli::Location::ImplicitPointer { .. } => {
+                return Err(Error::Unavailable("Implicit pointers are not supported".to_string()))
+            }
+        };
+        bytes.extend(piece_bytes.into_iter().chain(std::iter::repeat(0)).take(len));
+    }
+    Ok(Place::Value(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::target::Mapping;
+    use nix::unistd::Pid;
+
+    /// Where the mock's memory starts. It holds the address of its second word, then 0x2a.
+    const MEMORY: usize = 0x1000;
+    const RAX: u64 = 0x1111_2222_3333_4444;
+    const RBX: u64 = 0x5555_6666_7777_8888;
+    const RBP: u64 = 0x7ffe_0100;
+    const RSP: u64 = 0x7ffe_00e0;
+
+    struct MockTarget;
+
+    impl Target for MockTarget {
+        fn pid(&self) -> Pid {
+            Pid::from_raw(1)
+        }
+
+        fn get_regs(&self) -> Option<libc::user_regs_struct> {
+            None
+        }
+
+        fn read_bytes(&self, addr: usize, len: usize) -> Result<Vec<u8>, nix::Error> {
+            let mut memory = (MEMORY as u64 + 8).to_le_bytes().to_vec();
+            memory.extend_from_slice(&0x2au64.to_le_bytes());
+            addr.checked_sub(MEMORY)
+                .and_then(|start| memory.get(start..start + len))
+                .map(|bytes| bytes.to_vec())
+                .ok_or(nix::Error::Sys(nix::errno::Errno::EIO))
+        }
+
+        fn mappings(&self) -> Vec<Mapping> {
+            Vec::new()
+        }
+    }
+
+    fn frame(pc: u64, caller: bool) -> Frame {
+        let mut regs: libc::user_regs_struct = unsafe { std::mem::zeroed() };
+        regs.rax = RAX;
+        regs.rbx = RBX;
+        regs.rbp = RBP;
+        regs.rsp = RSP;
+        regs.rip = pc;
+        Frame { regs, cfa: RBP as usize + 16, caller }
+    }
+
+    fn expression(bytes: &[u8]) -> Expression {
+        let encoding = gimli::Encoding {
+            format: gimli::Format::Dwarf32,
+            version: 4,
+            address_size: 8,
+        };
+        Expression { bytes: bytes.to_vec(), encoding }
+    }
+
+    /// Locates a variable of `size` bytes at `location` in `frame` and reads it, returning its
+    /// bytes and address.
+    fn read(
+        location: &Location,
+        size: usize,
+        frame: Option<&Frame>,
+        frame_base: Option<&Location>,
+    ) -> Result<(Vec<u8>, Option<usize>), Error> {
+        locate(location, size, frame, frame_base, &MockTarget)?.read(size, &MockTarget)
+    }
+
+    fn address_of(bytes: &[u8], frame_base: Option<&Location>) -> Option<usize> {
+        let location = Location::Expression(expression(bytes));
+        match locate(&location, 8, Some(&frame(0x401000, false)), frame_base, &MockTarget) {
+            Ok(Place::Memory(addr)) => Some(addr),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn registers() {
+        let frame = frame(0x401000, false);
+        // DW_OP_reg0 (rax): a register holds the value itself
+        let rax = Location::Expression(expression(&[0x50]));
+        let (bytes, addr) = read(&rax, 8, Some(&frame), None).unwrap();
+        assert_eq!((bytes, addr), (RAX.to_le_bytes().to_vec(), None));
+        // An int in rbx is the register's low bytes
+        let rbx = Location::Expression(expression(&[0x53]));
+        let (bytes, _) = read(&rbx, 4, Some(&frame), None).unwrap();
+        assert_eq!(bytes, (RBX as u32).to_le_bytes());
+        // Registers need a frame; addresses don't
+        let error = read(&rax, 8, None, None).err().unwrap();
+        assert_eq!(error.to_string(), "No frame selected.");
+        let global = Location::Address(MEMORY + 8);
+        let (bytes, addr) = read(&global, 8, None, None).unwrap();
+        assert_eq!((bytes, addr), (0x2au64.to_le_bytes().to_vec(), Some(MEMORY + 8)));
+    }
+
+    #[test]
+    fn caller_registers() {
+        // A caller only gets its callee-saved registers back
+        let caller = frame(0x401000, true);
+        let rax = Location::Expression(expression(&[0x50]));
+        assert!(matches!(read(&rax, 8, Some(&caller), None), Err(Error::OptimizedOut)));
+        let rbx = Location::Expression(expression(&[0x53]));
+        assert_eq!(read(&rbx, 8, Some(&caller), None).unwrap().0, RBX.to_le_bytes());
+    }
+
+    #[test]
+    fn memory_locations() {
+        // DW_OP_breg7 (rsp) 8, and DW_OP_breg6 (rbp) -8
+        assert_eq!(address_of(&[0x77, 0x08], None), Some(RSP as usize + 8));
+        assert_eq!(address_of(&[0x76, 0x78], None), Some(RBP as usize - 8));
+        // DW_OP_fbreg -16, with the frame base in rbp (DW_OP_reg6) or defaulting to the CFA
+        let rbp_base = Location::Expression(expression(&[0x56]));
+        assert_eq!(address_of(&[0x91, 0x70], Some(&rbp_base)), Some(RBP as usize - 16));
+        assert_eq!(address_of(&[0x91, 0x70], None), Some(RBP as usize + 16 - 16));
+        // DW_OP_call_frame_cfa
+        assert_eq!(address_of(&[0x9c], None), Some(RBP as usize + 16));
+        // DW_OP_addr MEMORY; DW_OP_deref follows the pointer stored there
+        let mut deref = vec![0x03];
+        deref.extend_from_slice(&(MEMORY as u64).to_le_bytes());
+        deref.push(0x06);
+        assert_eq!(address_of(&deref, None), Some(MEMORY + 8));
+
+        let frame = frame(0x401000, false);
+        let offset = Location::FramePointerOffset(-20);
+        let place = locate(&offset, 4, Some(&frame), None, &MockTarget).ok();
+        assert!(matches!(place, Some(Place::Memory(addr)) if addr == RBP as usize + 16 - 20));
+    }
+
+    #[test]
+    fn stack_values() {
+        let frame = frame(0x401000, false);
+        // DW_OP_lit5; DW_OP_stack_value
+        let five = Location::Expression(expression(&[0x35, 0x9f]));
+        assert_eq!(read(&five, 4, Some(&frame), None).unwrap(), (vec![5, 0, 0, 0], None));
+        // DW_OP_breg3 (rbx) 2; DW_OP_stack_value: rbx + 2, which has no address
+        let sum = Location::Expression(expression(&[0x73, 0x02, 0x9f]));
+        let (bytes, addr) = read(&sum, 8, Some(&frame), None).unwrap();
+        assert_eq!((bytes, addr), ((RBX + 2).to_le_bytes().to_vec(), None));
+    }
+
+    #[test]
+    fn pieces() {
+        let frame = frame(0x401000, false);
+        // A struct of two ints: DW_OP_reg0; DW_OP_piece 4; DW_OP_reg3; DW_OP_piece 4
+        let split = Location::Expression(expression(&[0x50, 0x93, 0x04, 0x53, 0x93, 0x04]));
+        let (bytes, addr) = read(&split, 8, Some(&frame), None).unwrap();
+        let mut expected = (RAX as u32).to_le_bytes().to_vec();
+        expected.extend_from_slice(&(RBX as u32).to_le_bytes());
+        assert_eq!((bytes, addr), (expected, None));
+        // A piece with no location at all means part of the value is gone
+        let partial = Location::Expression(expression(&[0x93, 0x04, 0x53, 0x93, 0x04]));
+        assert!(matches!(read(&partial, 8, Some(&frame), None), Err(Error::OptimizedOut)));
+        // So does an empty expression
+        let empty = Location::Expression(expression(&[]));
+        assert!(matches!(read(&empty, 8, Some(&frame), None), Err(Error::OptimizedOut)));
+    }
+
+    #[test]
+    fn location_lists() {
+        let list = Location::List(vec![
+            (0x401000, 0x401010, expression(&[0x50])),
+            (0x401010, 0x401020, expression(&[0x53])),
+        ]);
+        let value = |frame: &Frame| read(&list, 8, Some(frame), None).map(|(bytes, _)| bytes);
+        assert_eq!(value(&frame(0x401004, false)).unwrap(), RAX.to_le_bytes());
+        assert_eq!(value(&frame(0x401010, false)).unwrap(), RBX.to_le_bytes());
+        // Past the last range the variable is gone
+        assert!(matches!(value(&frame(0x401020, false)), Err(Error::OptimizedOut)));
+        // A caller's return address may be past the range its call is in
+        assert_eq!(value(&frame(0x401020, true)).unwrap(), RBX.to_le_bytes());
+        assert!(matches!(read(&list, 8, None, None), Err(Error::Unavailable(_))));
+    }
+
+    #[test]
+    fn entry_values() {
+        // DW_OP_entry_value(DW_OP_reg5); DW_OP_stack_value: rdi as it was on entry
+        let entry = Location::Expression(expression(&[0xa3, 0x01, 0x55, 0x9f]));
+        let frame = frame(0x401000, false);
+        assert!(matches!(read(&entry, 8, Some(&frame), None), Err(Error::OptimizedOut)));
+        assert!(matches!(read(&Location::OptimizedOut, 8, None, None), Err(Error::OptimizedOut)));
+    }
+}
@@ -1,25 +1,135 @@
+#[macro_use]
+mod console;
 mod debugger;
 mod debugger_command;
 mod inferior;
 mod dwarf_data;
 mod gimli_wrapper;
 mod  breakpoint_manager;
+mod value;
+mod expr;
+mod location;
+mod registers;
+mod disasm;
+mod unwind;
+mod signals;
+mod target;
+mod core_file;
+mod gdbserver;
+mod dap;
 
+use crate::console::Console;
+use crate::dap::DapServer;
 use crate::debugger::Debugger;
-use nix::sys::signal::{signal, SigHandler, Signal};
+use crate::debugger_command::DebuggerCommand;
+use crate::gdbserver::GdbServer;
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
 use std::env;
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        println!("Usage: {} <target program>", args[0]);
+    let mut args: Vec<String> = env::args().collect();
+    if args.len() == 2 && args[1] == "--dap" {
+        serve_dap();
+    }
+    if args.len() >= 4 && args[1] == "--gdbserver" {
+        serve(&args[2], &args[3], &args[4..].to_vec());
+    }
+    // Options come before the target
+    let mut scripts = Vec::new();
+    let mut batch = false;
+    while args.len() > 1 {
+        match args[1].as_str() {
+            "-x" if args.len() > 2 => {
+                scripts.push(args.remove(2));
+                args.remove(1);
+            }
+            "--batch" => {
+                batch = true;
+                args.remove(1);
+            }
+            _ => break,
+        }
+    }
+    let (target, attach_pid, core) = if args.len() == 2 {
+        (args[1].clone(), None, None)
+    } else if args.len() == 3 && args[1] == "-p" {
+        let pid = match args[2].parse::<i32>() {
+            Ok(pid) => pid,
+            Err(_) => {
+                println!("Invalid pid {}", args[2]);
+                std::process::exit(1);
+            }
+        };
+        // Debug symbols come from the binary the process is running
+        (format!("/proc/{}/exe", pid), Some(pid), None)
+    } else if args.len() == 3 {
+        (args[1].clone(), None, Some(args[2].clone()))
+    } else {
+        println!("Usage: {} [--batch] [-x <script>]... <target program>", args[0]);
+        println!("       {} [--batch] [-x <script>]... <target program> <core file>", args[0]);
+        println!("       {} [--batch] [-x <script>]... -p <pid>", args[0]);
+        println!("       {} --dap", args[0]);
+        println!("       {} --gdbserver <host:port> <target program> [args...]", args[0]);
         std::process::exit(1);
+    };
+
+    // Ctrl+c shouldn't kill the debugger; while the inferior runs, it stops the inferior
+    // instead. The handler is reset in child processes when they exec, unlike SIG_IGN.
+    let action = SigAction::new(
+        SigHandler::Handler(inferior::interrupt),
+        SaFlags::SA_RESTART,
+        SigSet::empty(),
+    );
+    unsafe { sigaction(Signal::SIGINT, &action) }.expect("Error installing SIGINT handler");
+
+    let mut debugger = match Debugger::new(&target, Console::terminal()) {
+        Ok(debugger) => debugger,
+        Err(message) => {
+            println!("{}", message);
+            std::process::exit(1);
+        }
+    };
+    if let Some(pid) = attach_pid {
+        debugger.attach(pid);
+    }
+    if let Some(core) = core {
+        debugger.load_core(&core);
+    }
+    // Scripts run in order, unless one of them quits
+    let finished = scripts.iter().all(|script| debugger.source(script));
+    if batch {
+        if finished {
+            debugger.execute(DebuggerCommand::Quit);
+        }
+        std::process::exit(debugger.exit_status().unwrap_or(0));
     }
-    let target = &args[1];
+    if finished {
+        debugger.run();
+    }
+}
 
-    // Disable handling of ctrl+c in this process (so that ctrl+c only gets delivered to child
-    // processes)
-    unsafe { signal(Signal::SIGINT, SigHandler::SigIgn) }.expect("Error disabling SIGINT handling");
+/// Runs `target` under a gdbserver listening on `addr`, then exits.
+fn serve(addr: &str, target: &str, args: &Vec<String>) -> ! {
+    let mut server = match GdbServer::new(target, args) {
+        Some(server) => server,
+        None => {
+            println!("Error starting subprocess");
+            std::process::exit(1);
+        }
+    };
+    if let Err(err) = server.serve(addr) {
+        println!("gdbserver: {}", err);
+        std::process::exit(1);
+    }
+    std::process::exit(0);
+}
 
-    Debugger::new(target).run();
+/// Speaks the Debug Adapter Protocol on stdin and stdout, then exits.
+fn serve_dap() -> ! {
+    let result = DapServer::new().and_then(|mut server| server.serve());
+    if let Err(err) = result {
+        eprintln!("dap: {}", err);
+        std::process::exit(1);
+    }
+    std::process::exit(0);
 }
@@ -0,0 +1,111 @@
+//! What to do when the inferior receives a signal, as configured with `handle`.
+
+use crate::console::Console;
+use nix::sys::signal::Signal;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::str::FromStr;
+
+#[derive(Clone, Copy)]
+pub struct Policy {
+    /// Whether to return to the prompt
+    pub stop: bool,
+    /// Whether to mention that the signal arrived
+    pub print: bool,
+    /// Whether the program gets to see the signal when it resumes
+    pub pass: bool,
+}
+
+impl Policy {
+    /// The same defaults as gdb: signals that are part of a program's normal operation go
+    /// straight through, and those the debugger uses itself are not passed on. (gdb does pass
+    /// SIGSTOP, but re-injecting it just stops the inferior again.)
+    fn default_for(signal: Signal) -> Policy {
+        let (stop, print, pass) = match signal {
+            Signal::SIGALRM
+            | Signal::SIGURG
+            | Signal::SIGCHLD
+            | Signal::SIGWINCH
+            | Signal::SIGIO
+            | Signal::SIGVTALRM
+            | Signal::SIGPROF => (false, false, true),
+            Signal::SIGINT | Signal::SIGTRAP | Signal::SIGSTOP => (true, true, false),
+            _ => (true, true, true),
+        };
+        Policy { stop, print, pass }
+    }
+}
+
+pub struct SignalTable {
+    /// Policies changed with `handle`; every other signal has its default
+    policies: HashMap<Signal, Policy>,
+}
+
+impl SignalTable {
+    pub fn new() -> SignalTable {
+        SignalTable {
+            policies: HashMap::new(),
+        }
+    }
+
+    pub fn get(&self, signal: Signal) -> Policy {
+        match self.policies.get(&signal) {
+            Some(policy) => *policy,
+            None => Policy::default_for(signal),
+        }
+    }
+
+    /// Applies the keywords of a `handle` command to `signal`. As in gdb, `stop` implies `print`
+    /// and `noprint` implies `nostop`.
+    pub fn update(&mut self, signal: Signal, actions: &[String]) -> Result<(), String> {
+        let mut policy = self.get(signal);
+        for action in actions {
+            match action.as_str() {
+                "stop" => {
+                    policy.stop = true;
+                    policy.print = true;
+                }
+                "nostop" => policy.stop = false,
+                "print" => policy.print = true,
+                "noprint" => {
+                    policy.print = false;
+                    policy.stop = false;
+                }
+                "pass" | "noignore" => policy.pass = true,
+                "nopass" | "ignore" => policy.pass = false,
+                _ => return Err(format!("Unrecognized or ambiguous flag word: \"{}\".", action)),
+            }
+        }
+        self.policies.insert(signal, policy);
+        Ok(())
+    }
+
+    /// Prints how each of `signals` is handled, as a table.
+    pub fn print(&self, console: &Console, signals: &[Signal]) {
+        let yes_no = |flag| if flag { "Yes" } else { "No" };
+        say!(console, "Signal        Stop\tPrint\tPass to program");
+        for signal in signals {
+            let policy = self.get(*signal);
+            say!(
+                console,
+                "{:<14}{}\t{}\t{}",
+                signal.as_str(),
+                yes_no(policy.stop),
+                yes_no(policy.print),
+                yes_no(policy.pass)
+            );
+        }
+    }
+}
+
+/// Parses a signal given by name ("SIGUSR1", "sigusr1" or "USR1") or number.
+pub fn parse_signal(name: &str) -> Result<Signal, String> {
+    let signal = match name.parse::<i32>() {
+        Ok(number) => Signal::try_from(number).ok(),
+        Err(_) if name.to_uppercase().starts_with("SIG") => {
+            Signal::from_str(&name.to_uppercase()).ok()
+        }
+        Err(_) => Signal::from_str(&format!("SIG{}", name.to_uppercase())).ok(),
+    };
+    signal.ok_or(format!("Unknown signal \"{}\".", name))
+}
@@ -0,0 +1,36 @@
+//! Read access to the program being debugged, which is either a live process (`Inferior`) or a
+//! core dump (`CoreFile`). Everything that only inspects the program goes through this trait,
+//! so that it works post-mortem as well.
+
+use nix::unistd::Pid;
+use std::convert::TryInto;
+use std::mem::size_of;
+
+/// A file mapped into the program's address space.
+#[derive(Clone)]
+pub struct Mapping {
+    pub path: String,
+    pub start: usize,
+    pub end: usize,
+    /// Offset in the file of the byte mapped at `start`
+    pub offset: usize,
+}
+
+pub trait Target {
+    fn pid(&self) -> Pid;
+
+    /// Returns the registers of the current thread.
+    fn get_regs(&self) -> Option<libc::user_regs_struct>;
+
+    /// Reads `len` bytes of memory starting at `addr`.
+    fn read_bytes(&self, addr: usize, len: usize) -> Result<Vec<u8>, nix::Error>;
+
+    /// Returns the files mapped into memory, in address order.
+    fn mappings(&self) -> Vec<Mapping>;
+
+    /// Reads a single word of memory.
+    fn read_word(&self, addr: usize) -> Result<usize, nix::Error> {
+        let bytes = self.read_bytes(addr, size_of::<usize>())?;
+        Ok(usize::from_le_bytes(bytes.as_slice().try_into().unwrap()))
+    }
+}
@@ -0,0 +1,438 @@
+//! Stack unwinding driven by DWARF call frame information (.eh_frame and .debug_frame), so that
+//! backtraces work without frame pointers, inside libc, and in the middle of a prologue.
+
+use crate::registers::{self, DWARF_NAMES};
+use crate::target::Mapping;
+use gimli::{
+    BaseAddresses, CfaRule, DebugFrame, EhFrame, LittleEndian, Register, RegisterRule,
+    UninitializedUnwindContext, UnwindSection,
+};
+use object::{Object, ObjectSection, ObjectSegment, SymbolKind};
+use std::fs;
+
+const RETURN_ADDRESS: u16 = 16;
+
+/// Files are mapped a page at a time, so a mapping may start up to a page before its segment.
+const PAGE_SIZE: usize = 4096;
+
+/// Give up on stacks deeper than this, in case the unwind information leads us in circles.
+pub const MAX_FRAMES: usize = 1024;
+
+/// An ELF file mapped into the inferior, with the call frame information and symbols needed to
+/// unwind through it and name its functions.
+pub struct Module {
+    pub path: String,
+    start: usize,
+    end: usize,
+    /// Difference between run-time and link-time addresses (zero for non-PIE executables)
+    bias: usize,
+    /// (link-time address, file offset) of each loadable segment
+    segments: Vec<(usize, usize)>,
+    eh_frame: Vec<u8>,
+    eh_frame_addr: u64,
+    debug_frame: Vec<u8>,
+    text_addr: u64,
+    /// (address, size, name) of every function symbol, at link-time addresses
+    symbols: Vec<(usize, usize, String)>,
+}
+
+/// What the CFI says about one instruction: how to find the CFA and where the caller's registers
+/// were saved, as offsets from the CFA.
+struct Row {
+    cfa_register: u16,
+    cfa_offset: i64,
+    saved: Vec<(u16, i64)>,
+}
+
+/// One frame of a backtrace.
+#[derive(Clone)]
+pub struct Frame {
+    /// Registers as they were in this frame. Only rip, rsp and callee-saved registers are
+    /// recovered for callers; the rest keep the innermost frame's values.
+    pub regs: libc::user_regs_struct,
+    /// The canonical frame address: the value of rsp just before the call into this frame
+    pub cfa: usize,
+    /// Whether this frame is a caller, whose rip is a return address
+    pub caller: bool,
+}
+
+impl Frame {
+    pub fn pc(&self) -> usize {
+        self.regs.rip as usize
+    }
+
+    /// The address to use when looking up this frame's function and line. A caller's return
+    /// address may belong to the next line, or even the next function if the call was the last
+    /// instruction, so look at the call instead.
+    pub fn lookup_pc(&self) -> usize {
+        if self.caller {
+            self.pc() - 1
+        } else {
+            self.pc()
+        }
+    }
+}
+
+impl Module {
+    /// Collects the unwind information and symbols of `object`, which is mapped at
+    /// `start..end` in the inferior.
+    pub fn from_object(
+        path: &str,
+        object: &object::File,
+        start: usize,
+        end: usize,
+        bias: usize,
+    ) -> Module {
+        let eh_frame = object
+            .section_data_by_name(".eh_frame")
+            .map(|data| data.to_vec())
+            .unwrap_or_default();
+        let debug_frame = object
+            .section_data_by_name(".debug_frame")
+            .map(|data| data.to_vec())
+            .unwrap_or_default();
+        let address_of = |name| object.section_by_name(name).map_or(0, |s| s.address());
+        let symbols = object
+            .symbols()
+            .chain(object.dynamic_symbols())
+            .filter(|(_, symbol)| symbol.kind() == SymbolKind::Text && symbol.address() != 0)
+            .filter_map(|(_, symbol)| {
+                let name = symbol.name()?.to_string();
+                Some((symbol.address() as usize, symbol.size() as usize, name))
+            })
+            .collect();
+        let segments = object
+            .segments()
+            .map(|segment| (segment.address() as usize, segment.file_range().0 as usize))
+            .collect();
+        Module {
+            path: path.to_string(),
+            start,
+            end,
+            bias,
+            segments,
+            eh_frame,
+            eh_frame_addr: address_of(".eh_frame"),
+            debug_frame,
+            text_addr: address_of(".text"),
+            symbols,
+        }
+    }
+
+    /// Loads the shared libraries in `mappings`. `executable` is skipped, since its unwind
+    /// information comes with its debug info.
+    pub fn shared_libraries(mappings: &[Mapping], executable: &str) -> Vec<Module> {
+        let executable = fs::canonicalize(executable).unwrap_or_default();
+        // (path, start, end, offset of the first mapping) for each file, in the order they appear
+        let mut files: Vec<(&str, usize, usize, usize)> = Vec::new();
+        for mapping in mappings {
+            let path = mapping.path.as_str();
+            match files.iter_mut().find(|file| file.0 == path) {
+                Some(file) => file.2 = file.2.max(mapping.end),
+                None => files.push((path, mapping.start, mapping.end, mapping.offset)),
+            }
+        }
+        files
+            .into_iter()
+            .filter(|(path, ..)| fs::canonicalize(path).unwrap_or_default() != executable)
+            .filter_map(|(path, start, end, offset)| {
+                let data = fs::read(path).ok()?;
+                let object = object::File::parse(&*data).ok()?;
+                let module = Module::from_object(path, &object, start, end, 0);
+                let bias = module.bias_at(start, offset);
+                Some(Module { bias, ..module })
+            })
+            .collect()
+    }
+
+    /// Moves the module to wherever the inferior loaded its file, according to `mappings`. Only
+    /// position-independent executables move; others are always loaded where they were linked.
+    pub fn relocate(&mut self, mappings: &[Mapping]) {
+        let path = fs::canonicalize(&self.path).unwrap_or_default();
+        let mapping = mappings
+            .iter()
+            .find(|mapping| fs::canonicalize(&mapping.path).unwrap_or_default() == path);
+        if let Some(mapping) = mapping {
+            let bias = self.bias_at(mapping.start, mapping.offset);
+            self.start = self.start - self.bias + bias;
+            self.end = self.end - self.bias + bias;
+            self.bias = bias;
+        }
+    }
+
+    /// Works out the load bias from one mapping of the file: the segment it maps was linked at
+    /// an address as far into its page as its file offset is.
+    fn bias_at(&self, start: usize, offset: usize) -> usize {
+        self.segments
+            .iter()
+            .find(|(_, file_offset)| file_offset / PAGE_SIZE * PAGE_SIZE == offset)
+            .and_then(|(address, file_offset)| (start + file_offset - offset).checked_sub(*address))
+            .unwrap_or(start - offset)
+    }
+
+    pub fn contains(&self, pc: usize) -> bool {
+        pc >= self.start && pc < self.end
+    }
+
+    /// Names the ELF symbol containing `pc`, for code without debug info.
+    pub fn symbol_for(&self, pc: usize) -> Option<&str> {
+        let addr = pc.checked_sub(self.bias)?;
+        self.symbols
+            .iter()
+            .filter(|(start, size, _)| addr >= *start && addr < start + (*size).max(1))
+            .map(|(_, _, name)| name.as_str())
+            .next()
+    }
+
+    /// Finds the unwind row for `pc`, trying .eh_frame before .debug_frame.
+    fn find_row(&self, pc: usize) -> Option<Row> {
+        let addr = pc.checked_sub(self.bias)? as u64;
+        let bases = BaseAddresses::default()
+            .set_eh_frame(self.eh_frame_addr)
+            .set_text(self.text_addr);
+        let mut ctx = UninitializedUnwindContext::new();
+        let eh_frame = EhFrame::new(&self.eh_frame, LittleEndian);
+        if let Ok(row) =
+            eh_frame.unwind_info_for_address(&bases, &mut ctx, addr, EhFrame::cie_from_offset)
+        {
+            return Self::convert_row(row.cfa(), |reg| row.register(reg));
+        }
+        let debug_frame = DebugFrame::new(&self.debug_frame, LittleEndian);
+        let row = debug_frame
+            .unwind_info_for_address(&bases, &mut ctx, addr, DebugFrame::cie_from_offset)
+            .ok()?;
+        Self::convert_row(row.cfa(), |reg| row.register(reg))
+    }
+
+    /// Keeps the parts of a gimli row we understand. CFAs computed by DWARF expressions are
+    /// only used in signal trampolines and PLT stubs, so we give up on those.
+    fn convert_row<R: gimli::Reader>(
+        cfa: &CfaRule<R>,
+        register: impl Fn(Register) -> RegisterRule<R>,
+    ) -> Option<Row> {
+        let (cfa_register, cfa_offset) = match cfa {
+            CfaRule::RegisterAndOffset { register, offset } => (register.0, *offset),
+            _ => return None,
+        };
+        if cfa_register > RETURN_ADDRESS {
+            return None;
+        }
+        let saved = (0..=RETURN_ADDRESS)
+            .filter_map(|reg| match register(Register(reg)) {
+                RegisterRule::Offset(offset) => Some((reg, offset)),
+                _ => None,
+            })
+            .collect();
+        Some(Row {
+            cfa_register,
+            cfa_offset,
+            saved,
+        })
+    }
+}
+
+/// Unwinds at most `limit` frames of the stack starting from `regs`, innermost frame first.
+/// `read_word` reads a word of inferior memory. Code not covered by any module's CFI is unwound
+/// by following the rbp chain.
+pub fn unwind(
+    regs: libc::user_regs_struct,
+    modules: &[&Module],
+    read_word: &dyn Fn(usize) -> Option<usize>,
+    limit: usize,
+) -> Vec<Frame> {
+    let mut frames = Vec::new();
+    let mut frame = Frame {
+        regs,
+        cfa: 0,
+        caller: false,
+    };
+    while frames.len() < limit {
+        let pc = frame.lookup_pc();
+        let row = modules
+            .iter()
+            .filter(|module| module.contains(pc))
+            .find_map(|module| module.find_row(pc));
+        let mut caller_regs = frame.regs;
+        let done = match row {
+            Some(row) => {
+                let base = registers::get(&frame.regs, DWARF_NAMES[row.cfa_register as usize]);
+                frame.cfa = (base.unwrap_or(0) as i64 + row.cfa_offset) as usize;
+                let mut done = !row.saved.iter().any(|(reg, _)| *reg == RETURN_ADDRESS);
+                for (reg, offset) in &row.saved {
+                    match read_word((frame.cfa as i64 + offset) as usize) {
+                        Some(value) => {
+                            registers::set(
+                                &mut caller_regs,
+                                DWARF_NAMES[*reg as usize],
+                                value as u64,
+                            );
+                        }
+                        None => done = true,
+                    }
+                }
+                done
+            }
+            None => {
+                let rbp = frame.regs.rbp as usize;
+                frame.cfa = rbp + 16;
+                match (read_word(rbp + 8), read_word(rbp)) {
+                    (Some(ret_addr), Some(saved_rbp)) if rbp != 0 => {
+                        caller_regs.rip = ret_addr as u64;
+                        caller_regs.rbp = saved_rbp as u64;
+                        false
+                    }
+                    _ => true,
+                }
+            }
+        };
+        caller_regs.rsp = frame.cfa as u64;
+        let sp = frame.regs.rsp;
+        frames.push(frame);
+        // The stack grows down, so a caller's frame must be above its callee's
+        if done || caller_regs.rip == 0 || caller_regs.rsp <= sp {
+            break;
+        }
+        frame = Frame {
+            regs: caller_regs,
+            cfa: 0,
+            caller: true,
+        };
+    }
+    frames
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    /// Where the module was linked, and where it was loaded
+    const FUNCTION: usize = 0x1000;
+    const BIAS: usize = 0x5555_5555_4000;
+    const STACK: usize = 0x7ffe_0000;
+
+    /// .eh_frame for a function at FUNCTION with the usual prologue:
+    ///     +0 push rbp; +1 mov rbp, rsp; +4 body ... +0x20
+    fn eh_frame() -> Vec<u8> {
+        let mut data = vec![
+            // CIE: length 20, id 0, version 1, no augmentation, code alignment 1, data
+            // alignment -8, return address in r16
+            20, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0x01, 0x78, 0x10,
+            // DW_CFA_def_cfa rsp+8; DW_CFA_offset r16 at cfa-8; DW_CFA_nop padding
+            0x0c, 0x07, 0x08, 0x90, 0x01, 0, 0, 0, 0, 0, 0,
+            // FDE: length 28, pointer back to the CIE
+            28, 0, 0, 0, 28, 0, 0, 0,
+        ];
+        data.extend_from_slice(&(FUNCTION as u64).to_le_bytes());
+        data.extend_from_slice(&0x20u64.to_le_bytes());
+        data.extend_from_slice(&[
+            // DW_CFA_advance_loc 1; DW_CFA_def_cfa_offset 16; DW_CFA_offset rbp at cfa-16
+            0x41, 0x0e, 0x10, 0x86, 0x02,
+            // DW_CFA_advance_loc 3; DW_CFA_def_cfa_register rbp
+            0x43, 0x0d, 0x06,
+        ]);
+        // Terminator
+        data.extend_from_slice(&[0, 0, 0, 0]);
+        data
+    }
+
+    fn loaded_at(bias: usize) -> Module {
+        Module {
+            path: String::new(),
+            start: FUNCTION + bias,
+            end: FUNCTION + 0x20 + bias,
+            bias,
+            segments: vec![(0, 0), (0x1000, 0x1000), (0x3df0, 0x2df0)],
+            eh_frame: eh_frame(),
+            eh_frame_addr: 0x2000,
+            debug_frame: Vec::new(),
+            text_addr: FUNCTION as u64,
+            symbols: vec![(FUNCTION, 0x20, "func".to_string())],
+        }
+    }
+
+    fn regs_at(rip: usize, rsp: usize, rbp: usize) -> libc::user_regs_struct {
+        let mut regs: libc::user_regs_struct = unsafe { std::mem::zeroed() };
+        regs.rip = rip as u64;
+        regs.rsp = rsp as u64;
+        regs.rbp = rbp as u64;
+        regs
+    }
+
+    #[test]
+    fn rows_follow_the_prologue() {
+        let module = loaded_at(BIAS);
+        let row = module.find_row(BIAS + FUNCTION).unwrap();
+        assert_eq!((row.cfa_register, row.cfa_offset), (7, 8));
+        assert_eq!(row.saved, vec![(16, -8)]);
+        let row = module.find_row(BIAS + FUNCTION + 1).unwrap();
+        assert_eq!((row.cfa_register, row.cfa_offset), (7, 16));
+        assert_eq!(row.saved, vec![(6, -16), (16, -8)]);
+        let row = module.find_row(BIAS + FUNCTION + 0x10).unwrap();
+        assert_eq!((row.cfa_register, row.cfa_offset), (6, 16));
+        // Link-time addresses mean nothing once the module has moved
+        assert!(module.find_row(FUNCTION).is_none());
+        assert!(module.find_row(BIAS + FUNCTION + 0x20).is_none());
+        assert_eq!(module.symbol_for(BIAS + FUNCTION + 4), Some("func"));
+    }
+
+    #[test]
+    fn unwinds_through_cfi_then_frame_pointers() {
+        let module = loaded_at(BIAS);
+        // func's frame at STACK, called from code with no CFI whose frame is at STACK + 0x40
+        let caller_pc = 0x4000_1234;
+        let memory: HashMap<usize, usize> = vec![
+            (STACK, STACK + 0x40),
+            (STACK + 8, caller_pc),
+            (STACK + 0x40, 0),
+            (STACK + 0x48, 0),
+        ]
+        .into_iter()
+        .collect();
+        let read_word = |addr| memory.get(&addr).cloned();
+        let regs = regs_at(BIAS + FUNCTION + 0x10, STACK - 0x20, STACK);
+        let frames = unwind(regs, &[&module], &read_word, MAX_FRAMES);
+        assert_eq!(frames.len(), 2);
+        assert_eq!((frames[0].cfa, frames[0].caller), (STACK + 16, false));
+        assert_eq!(frames[1].pc(), caller_pc);
+        assert!(frames[1].caller);
+        assert_eq!(frames[1].regs.rsp as usize, STACK + 16);
+        assert_eq!(frames[1].regs.rbp as usize, STACK + 0x40);
+        assert_eq!(frames[1].cfa, STACK + 0x50);
+
+        // At the first instruction, rbp still belongs to the caller and must not be followed
+        let memory: HashMap<usize, usize> = vec![(STACK, caller_pc)].into_iter().collect();
+        let read_word = |addr| memory.get(&addr).cloned();
+        let regs = regs_at(BIAS + FUNCTION, STACK, 0);
+        let frames = unwind(regs, &[&module], &read_word, 1);
+        assert_eq!(frames[0].cfa, STACK + 8);
+        let frames = unwind(regs, &[&module], &read_word, MAX_FRAMES);
+        assert_eq!(frames[1].pc(), caller_pc);
+        assert_eq!(frames[1].regs.rbp, 0);
+    }
+
+    #[test]
+    fn load_bias_from_mappings() {
+        let module = loaded_at(0);
+        // Non-PIE executables are mapped where they were linked
+        let linked = Module { segments: vec![(0x400000, 0), (0x401000, 0x1000)], ..loaded_at(0) };
+        assert_eq!(linked.bias_at(0x400000, 0), 0);
+        assert_eq!(linked.bias_at(0x401000, 0x1000), 0);
+        // Any mapping of a PIE gives the same bias, even one starting before its segment
+        assert_eq!(module.bias_at(BIAS, 0), BIAS);
+        assert_eq!(module.bias_at(BIAS + 0x1000, 0x1000), BIAS);
+        assert_eq!(module.bias_at(BIAS + 0x3000, 0x2000), BIAS);
+
+        let path = std::env::current_exe().unwrap().to_string_lossy().into_owned();
+        let mapping = |path: &str| Mapping {
+            path: path.to_string(),
+            start: BIAS,
+            end: BIAS + 0x1000,
+            offset: 0,
+        };
+        let mut module = Module { path: path.clone(), ..module };
+        module.relocate(&[mapping("/nonexistent"), mapping(&path)]);
+        assert!(module.contains(BIAS + FUNCTION) && !module.contains(FUNCTION));
+        assert!(module.find_row(BIAS + FUNCTION + 0x10).is_some());
+    }
+}
@@ -0,0 +1,270 @@
+use crate::dwarf_data::{Encoding, Type, TypeKind, Types};
+use std::convert::TryInto;
+
+/// How many elements of an array are printed before the rest are elided, like gdb's default
+/// `print elements` limit.
+const MAX_ELEMENTS: usize = 200;
+
+/// Formats the raw bytes of a variable according to its DWARF type. Structs, unions and arrays
+/// are printed with their members and elements, e.g. `{x = 1, next = 0x0}`; `types` is used to
+/// look up struct members.
+pub fn format_value(entity_type: &Type, bytes: &[u8], types: &Types) -> String {
+    let entity_type = entity_type.resolve();
+    if bytes.len() != entity_type.size {
+        return "<unreadable>".to_string();
+    }
+    match &entity_type.kind {
+        TypeKind::Base(encoding) => format_base(*encoding, bytes),
+        TypeKind::Void => "void".to_string(),
+        TypeKind::Pointer(_) => format!("{:#x}", read_unsigned(bytes)),
+        TypeKind::Array(element, count) => format_array(element, count.unwrap_or(0), bytes, types),
+        TypeKind::Struct(id) | TypeKind::Union(id) => {
+            let members: Vec<String> = types
+                .members(*id)
+                .iter()
+                .map(|member| {
+                    let value = match member.extract(bytes) {
+                        Some(bytes) => format_value(&member.member_type, &bytes, types),
+                        None => "<unreadable>".to_string(),
+                    };
+                    format!("{} = {}", member.name, value)
+                })
+                .collect();
+            format!("{{{}}}", members.join(", "))
+        }
+        TypeKind::Enum(enumerators, _) => {
+            let value = to_integer(entity_type, bytes);
+            match enumerators.iter().find(|(_, enumerator)| *enumerator == value) {
+                Some((name, _)) => name.clone(),
+                None => value.to_string(),
+            }
+        }
+        TypeKind::Typedef(_) | TypeKind::Const(_) | TypeKind::Volatile(_) => {
+            unreachable!("resolve() looks through typedefs and qualifiers")
+        }
+    }
+}
+
+fn format_base(encoding: Encoding, bytes: &[u8]) -> String {
+    match (encoding, bytes.len()) {
+        (Encoding::SignedChar, 1) | (Encoding::UnsignedChar, 1) => {
+            let c = bytes[0];
+            let number = if encoding == Encoding::UnsignedChar {
+                c.to_string()
+            } else {
+                (c as i8).to_string()
+            };
+            if c.is_ascii_graphic() || c == b' ' {
+                format!("{} '{}'", number, c as char)
+            } else {
+                format!("{} '\\x{:02x}'", number, c)
+            }
+        }
+        (Encoding::Boolean, _) => (read_unsigned(bytes) != 0).to_string(),
+        (Encoding::Float, 4) => f32::from_le_bytes(bytes.try_into().unwrap()).to_string(),
+        (Encoding::Float, 8) => f64::from_le_bytes(bytes.try_into().unwrap()).to_string(),
+        (Encoding::Signed, 1..=8) | (Encoding::SignedChar, 1..=8) => {
+            read_signed(bytes).to_string()
+        }
+        (Encoding::Unsigned, 1..=8) | (Encoding::UnsignedChar, 1..=8) => {
+            read_unsigned(bytes).to_string()
+        }
+        _ => hex_dump(bytes),
+    }
+}
+
+/// Formats an array. Arrays of chars are printed as strings, up to the first NUL.
+fn format_array(element: &Type, count: usize, bytes: &[u8], types: &Types) -> String {
+    let is_char = matches!(
+        element.resolve().kind,
+        TypeKind::Base(Encoding::SignedChar) | TypeKind::Base(Encoding::UnsignedChar)
+    );
+    if is_char && element.size == 1 {
+        let text: String = bytes
+            .iter()
+            .take_while(|c| **c != 0)
+            .map(|c| match *c {
+                b'"' => "\\\"".to_string(),
+                b'\\' => "\\\\".to_string(),
+                c if c.is_ascii_graphic() || c == b' ' => (c as char).to_string(),
+                c => format!("\\x{:02x}", c),
+            })
+            .collect();
+        return format!("\"{}\"", text);
+    }
+    if element.size == 0 {
+        return "{}".to_string();
+    }
+    let mut elements: Vec<String> = bytes
+        .chunks(element.size)
+        .take(count.min(MAX_ELEMENTS))
+        .map(|chunk| format_value(element, chunk, types))
+        .collect();
+    if count > MAX_ELEMENTS {
+        elements.push("...".to_string());
+    }
+    format!("{{{}}}", elements.join(", "))
+}
+
+fn hex_dump(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// The register class of an eightbyte of a value in the x86-64 System V calling convention.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RegClass {
+    /// Passed in general-purpose registers (rax and rdx for return values)
+    Integer,
+    /// Passed in the low halves of vector registers (xmm0 and xmm1 for return values)
+    Sse,
+}
+
+/// Classifies a value of `entity_type` the way the calling convention does, giving the class of
+/// each of its eightbytes (None for padding). Returns None for values passed in memory, and for
+/// long doubles, which live on the x87 stack.
+pub fn classify(entity_type: &Type, types: &Types) -> Option<Vec<Option<RegClass>>> {
+    let size = entity_type.resolve().size;
+    if size == 0 || size > 16 {
+        return None;
+    }
+    let mut classes = vec![None; (size + 7) / 8];
+    classify_at(entity_type, 0, types, &mut classes)?;
+    Some(classes)
+}
+
+/// Merges the classes of the scalars in `entity_type`, which starts `offset` bytes into the
+/// value, into `classes`. An eightbyte holding any integer is of class Integer.
+fn classify_at(
+    entity_type: &Type,
+    offset: usize,
+    types: &Types,
+    classes: &mut [Option<RegClass>],
+) -> Option<()> {
+    let entity_type = entity_type.resolve();
+    let class = match &entity_type.kind {
+        TypeKind::Base(Encoding::Float) if entity_type.size > 8 => return None,
+        TypeKind::Base(Encoding::Float) => RegClass::Sse,
+        TypeKind::Base(_) | TypeKind::Pointer(_) | TypeKind::Enum(..) => RegClass::Integer,
+        TypeKind::Void => return None,
+        TypeKind::Array(element, count) => {
+            for i in 0..count.unwrap_or(0) {
+                classify_at(element, offset + i * element.size, types, classes)?;
+            }
+            return Some(());
+        }
+        TypeKind::Struct(id) | TypeKind::Union(id) => {
+            for member in types.members(*id) {
+                classify_at(&member.member_type, offset + member.offset, types, classes)?;
+            }
+            return Some(());
+        }
+        TypeKind::Typedef(_) | TypeKind::Const(_) | TypeKind::Volatile(_) => {
+            unreachable!("resolve() looks through typedefs and qualifiers")
+        }
+    };
+    // Scalars that straddle two eightbytes are unaligned, and so passed in memory
+    let eightbyte = classes.get_mut(offset / 8)?;
+    if (offset + entity_type.size.max(1) - 1) / 8 != offset / 8 {
+        return None;
+    }
+    if *eightbyte != Some(RegClass::Integer) {
+        *eightbyte = Some(class);
+    }
+    Some(())
+}
+
+/// Converts a scalar value to an integer, honouring its signedness, for use in expressions.
+pub fn to_integer(entity_type: &Type, bytes: &[u8]) -> i64 {
+    if !entity_type.is_signed() {
+        read_unsigned(bytes) as i64
+    } else {
+        read_signed(bytes)
+    }
+}
+
+/// Interprets up to 8 little-endian bytes as an unsigned integer.
+pub fn read_unsigned(bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .take(8)
+        .rev()
+        .fold(0, |acc, byte| (acc << 8) | *byte as u64)
+}
+
+/// Interprets up to 8 little-endian bytes as a sign-extended integer.
+pub fn read_signed(bytes: &[u8]) -> i64 {
+    let len = bytes.len().min(8);
+    if len == 0 {
+        return 0;
+    }
+    let shift = 64 - 8 * len;
+    ((read_unsigned(bytes) << shift) as i64) >> shift
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dwarf_data::Member;
+
+    fn make_type(name: &str, size: usize, kind: TypeKind) -> Type {
+        Type { name: name.to_string(), size, kind }
+    }
+
+    fn base(name: &str, size: usize) -> Type {
+        make_type(name, size, TypeKind::Base(Encoding::from_name(name, size)))
+    }
+
+    /// A struct with DIE offset `id` and members at the given offsets, added to `types`.
+    fn structure(types: &mut Types, id: usize, members: Vec<(usize, Type)>) -> Type {
+        let end = members.iter().map(|(offset, t)| offset + t.size).max().unwrap_or(0);
+        let members = members
+            .into_iter()
+            .map(|(offset, member_type)| Member {
+                name: String::new(),
+                offset,
+                member_type,
+                bit_field: None,
+            })
+            .collect();
+        types.members.insert(id, members);
+        make_type(&format!("struct s{}", id), (end + 7) / 8 * 8, TypeKind::Struct(id))
+    }
+
+    #[test]
+    fn classify_scalars() {
+        let types = Types::default();
+        let integer = Some(vec![Some(RegClass::Integer)]);
+        let sse = Some(vec![Some(RegClass::Sse)]);
+        assert_eq!(classify(&base("int", 4), &types), integer);
+        assert_eq!(classify(&base("double", 8), &types), sse);
+        assert_eq!(classify(&base("float", 4), &types), sse);
+        assert_eq!(classify(&base("long double", 16), &types), None);
+        let pointer = make_type("int *", 8, TypeKind::Pointer(Box::new(base("int", 4))));
+        assert_eq!(classify(&pointer, &types), integer);
+        assert_eq!(classify(&make_type("void", 0, TypeKind::Void), &types), None);
+    }
+
+    #[test]
+    fn classify_aggregates() {
+        let mut types = Types::default();
+        // {float, float} fits one SSE eightbyte; {float, int} shares one with an integer
+        let floats = structure(&mut types, 1, vec![(0, base("float", 4)), (4, base("float", 4))]);
+        assert_eq!(classify(&floats, &types), Some(vec![Some(RegClass::Sse)]));
+        let mixed = structure(&mut types, 2, vec![(0, base("float", 4)), (4, base("int", 4))]);
+        assert_eq!(classify(&mixed, &types), Some(vec![Some(RegClass::Integer)]));
+        let two = structure(&mut types, 3, vec![(0, base("double", 8)), (8, base("long", 8))]);
+        let expected = vec![Some(RegClass::Sse), Some(RegClass::Integer)];
+        assert_eq!(classify(&two, &types), Some(expected));
+
+        // Anything over 16 bytes is returned in memory
+        let array = make_type("long [3]", 24, TypeKind::Array(Box::new(base("long", 8)), Some(3)));
+        let big = structure(&mut types, 4, vec![(0, array)]);
+        assert_eq!(classify(&big, &types), None);
+        let typedef = make_type("pair_t", 16, TypeKind::Typedef(Box::new(two)));
+        assert_eq!(classify(&typedef, &types).map(|classes| classes.len()), Some(2));
+    }
+}
@@ -0,0 +1,493 @@
+mod common;
+
+use common::{build_pie_sample, build_sample, crate_path, target_bin_path};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::time::Duration;
+
+/// Runs `sample` under `deet --batch` with the given scripts.
+fn run_batch(sample: &str, scripts: &[&str]) -> Output {
+    run_batch_with(&build_sample(sample), None, scripts)
+}
+
+/// Runs `deet --batch` on `binary`, or on `binary` and `core`, with the given scripts.
+fn run_batch_with(binary: &Path, core: Option<&Path>, scripts: &[&str]) -> Output {
+    let mut cmd = Command::new(target_bin_path());
+    cmd.arg("--batch");
+    for script in scripts {
+        cmd.arg("-x").arg(crate_path(script));
+    }
+    cmd.arg(binary);
+    if let Some(core) = core {
+        cmd.arg(core);
+    }
+    cmd.current_dir(env!("CARGO_MANIFEST_DIR"))
+        .output()
+        .expect("Could not execute deet binary")
+}
+
+/// Runs `binary` in a directory of its own until it dumps core, and returns the core's path.
+/// Returns None if the system doesn't write cores to the working directory (see core(5)).
+fn dump_core(binary: &Path) -> Option<PathBuf> {
+    let pattern = std::fs::read_to_string("/proc/sys/kernel/core_pattern").ok()?;
+    if pattern.starts_with('|') || pattern.contains('/') {
+        return None;
+    }
+    let dir = binary.with_extension("core-dir");
+    std::fs::create_dir_all(&dir).expect("Could not create scratch directory");
+    Command::new("sh")
+        .arg("-c")
+        .arg("ulimit -c unlimited && exec \"$0\"")
+        .arg(binary)
+        .current_dir(&dir)
+        .output()
+        .expect("Could not run sample");
+    std::fs::read_dir(&dir)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .find(|path| {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            name.starts_with("core")
+        })
+}
+
+#[test]
+fn test_breakpoint_script() {
+    let output = run_batch("function_calls", &["tests/scripts/function_calls.deet"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Set breakpoint 0 at"), "{}", stdout);
+    assert!(stdout.contains("a = 42"), "{}", stdout);
+    assert!(stdout.contains("b = 5"), "{}", stdout);
+    assert!(stdout.contains("#1  "), "{}", stdout);
+    assert!(stdout.contains("Child exited (status 0)"), "{}", stdout);
+    assert_eq!(output.status.code(), Some(0));
+}
+
+/// Disabled and deleted breakpoints don't stop the program, and `info breakpoints` shows the
+/// state and hit count of the rest.
+#[test]
+fn test_manage_breakpoints() {
+    let output = run_batch("function_calls", &["tests/scripts/breakpoints.deet"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Deleted breakpoint 0\n"), "{}", stdout);
+    assert!(stdout.contains("No breakpoint number 7.\n"), "{}", stdout);
+    assert!(stdout.contains("Num  Enb  Address             Hits  What\n"), "{}", stdout);
+    assert!(stdout.contains("\n1    n    0x"), "{}", stdout);
+    assert!(stdout.contains("  0     in func2 at "), "{}", stdout);
+    assert!(!stdout.contains(" in func1 at "), "{}", stdout);
+    assert_eq!(stdout.matches("Child stopped").count(), 1, "{}", stdout);
+    assert!(stdout.contains("=>* 6\t    printf(\"Hello from func3! %d\\n\", a);"), "{}", stdout);
+    assert!(stdout.contains("\n1    y    0x"), "{}", stdout);
+    assert!(stdout.contains("\n2    n    0x"), "{}", stdout);
+    assert!(stdout.contains("  1     in func3 at "), "{}", stdout);
+    assert_eq!(stdout.matches("Hello from func3! 100").count(), 2, "{}", stdout);
+    assert!(stdout.contains("Child exited (status 0)"), "{}", stdout);
+}
+
+/// `break file.c:line` and `break line` stop at the first instruction of the line, and lines
+/// without code or in unknown files are rejected.
+#[test]
+fn test_break_on_lines() {
+    let output = run_batch("function_calls", &["tests/scripts/break_lines.deet"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let no_code = "Line 8 in file \"samples/function_calls.c\" has no code.";
+    assert!(stdout.contains(no_code), "{}", stdout);
+    assert!(stdout.contains("No source file named nosuch.c."), "{}", stdout);
+    assert!(stdout.contains("Line 500 in file \"function_calls.c\" has no code."), "{}", stdout);
+    assert_eq!(stdout.matches("Set breakpoint").count(), 2, "{}", stdout);
+    assert!(stdout.contains("function_calls.c:12\n    11\t"), "{}", stdout);
+    assert!(stdout.contains("=>* 12\t    printf(\"sum = %d\\n\", sum);"), "{}", stdout);
+    // `print sum` at the breakpoint, before the program prints it
+    assert!(stdout.contains("func3(100);\nsum = 47\n"), "{}", stdout);
+    assert!(stdout.contains("=>* 19\t    func3(100);"), "{}", stdout);
+    assert!(stdout.contains("Child exited (status 0)"), "{}", stdout);
+}
+
+/// `finish` reads return values from the registers the calling convention uses for their type.
+#[test]
+fn test_finish_return_values() {
+    let output = run_batch("returns", &["tests/scripts/returns.deet"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Value returned is 1.5\n"), "{}", stdout);
+    assert!(stdout.contains("Value returned is 2.25\n"), "{}", stdout);
+    assert!(stdout.contains("Value returned is {first = 3, second = 4}"), "{}", stdout);
+    assert!(stdout.contains("Value returned is {weight = 0.5, count = 7}"), "{}", stdout);
+    assert!(stdout.contains("Value returned has type struct big (not supported)"), "{}", stdout);
+    assert!(stdout.contains("Child exited (status 0)"), "{}", stdout);
+}
+
+/// The exit status is the inferior's, with 128 added to the number of a fatal signal.
+#[test]
+fn test_exit_status() {
+    let output = run_batch("segfault", &["tests/scripts/segfault.deet"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Child stopped (signal SIGSEGV)"), "{}", stdout);
+    assert!(stdout.contains("func2"), "{}", stdout);
+    assert_eq!(output.status.code(), Some(128 + 11));
+}
+
+/// `frame`, `up` and `down` select the frame that `print` looks at, and stop at either end of
+/// the stack.
+#[test]
+fn test_select_frames() {
+    let output = run_batch("function_calls", &["tests/scripts/frames.deet"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains(" in func2 (a=42, b=5) at "), "{}", stdout);
+    assert!(stdout.contains("b = 5\n#2  0x"), "{}", stdout);
+    assert!(stdout.contains(" in func1 (a=42) at "), "{}", stdout);
+    assert!(stdout.contains("a = 42\n#3  0x"), "{}", stdout);
+    assert!(stdout.contains(" in main () at "), "{}", stdout);
+    let top = "Initial frame selected; you cannot go up.\n#0  func3 (a=100)";
+    assert!(stdout.contains(top), "{}", stdout);
+    assert!(stdout.contains("a = 100\n"), "{}", stdout);
+    let bottom = "Bottom (innermost) frame selected; you cannot go down.";
+    assert!(stdout.contains(bottom), "{}", stdout);
+    assert!(stdout.contains("No frame at level 7.\n#0  func3 (a=100)"), "{}", stdout);
+    assert!(stdout.contains("Child exited (status 0)"), "{}", stdout);
+}
+
+/// `info registers` shows the selected frame's registers, with rip as an offset into its
+/// function, and `set $reg` writes one back.
+#[test]
+fn test_registers() {
+    let output = run_batch("function_calls", &["tests/scripts/registers.deet"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let rip_line = |func: &str| {
+        stdout.lines().any(|line| line.starts_with("rip ") && line.contains(func))
+    };
+    assert!(rip_line(" <func2+"), "{}", stdout);
+    assert!(rip_line(" <func1+"), "{}", stdout);
+    assert!(stdout.contains("\neflags         0x"), "{}", stdout);
+    assert!(stdout.contains("\nrax            0x4d2              1234\n"), "{}", stdout);
+    assert!(stdout.contains("Invalid register `bogus'"), "{}", stdout);
+    assert!(stdout.contains("Child exited (status 0)"), "{}", stdout);
+}
+
+/// `deet -p` attaches to a program that is already running, and `detach` lets it carry on to
+/// the end.
+#[test]
+fn test_attach_and_detach() {
+    let binary = build_sample("sleepy_print");
+    let program = Command::new(&binary)
+        .arg("2")
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("Could not run sample");
+    let pid = program.id();
+    // Let it get as far as its first sleep
+    std::thread::sleep(Duration::from_millis(300));
+    let output = Command::new(target_bin_path())
+        .args(&["--batch", "-x"])
+        .arg(crate_path("tests/scripts/attach.deet"))
+        .arg("-p")
+        .arg(pid.to_string())
+        .output()
+        .expect("Could not execute deet binary");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let program = program.wait_with_output().expect("Could not wait for sample");
+    if stdout.contains("Could not attach to process") {
+        eprintln!("Skipping test_attach_and_detach: ptrace_scope forbids attaching");
+        return;
+    }
+    assert!(stdout.contains(&format!("Attached to process {}", pid)), "{}", stdout);
+    assert!(stdout.contains(" in main (argc=2, "), "{}", stdout);
+    assert!(stdout.contains("Detached from process "), "{}", stdout);
+    assert!(program.status.success(), "{}", stdout);
+    assert_eq!(String::from_utf8_lossy(&program.stdout), "0\n1\n");
+}
+
+/// By default a signal stops the program and is delivered when it continues.
+#[test]
+fn test_signal_stops_and_passes() {
+    let output = run_batch("signals", &["tests/scripts/signals.deet"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("SIGUSR1       Yes\tYes\tYes"), "{}", stdout);
+    assert_eq!(stdout.matches("Child stopped (signal SIGUSR1)").count(), 2, "{}", stdout);
+    assert!(stdout.contains("handled 2\n"), "{}", stdout);
+    assert!(stdout.contains("Child exited (status 0)"), "{}", stdout);
+}
+
+/// `handle SIGUSR1 nostop` only reports the signal, which still reaches the program.
+#[test]
+fn test_signal_nostop() {
+    let output = run_batch("signals", &["tests/scripts/signals_nostop.deet"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("SIGUSR1       No\tYes\tYes"), "{}", stdout);
+    assert_eq!(stdout.matches("Child received signal SIGUSR1").count(), 2, "{}", stdout);
+    assert!(!stdout.contains("Child stopped"), "{}", stdout);
+    assert!(stdout.contains("handled 2\n"), "{}", stdout);
+    assert!(stdout.contains("Child exited (status 0)"), "{}", stdout);
+}
+
+/// `handle SIGUSR1 nopass` still stops, but the program never sees the signal.
+#[test]
+fn test_signal_nopass() {
+    let output = run_batch("signals", &["tests/scripts/signals_nopass.deet"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("SIGUSR1       Yes\tYes\tNo"), "{}", stdout);
+    assert_eq!(stdout.matches("Child stopped (signal SIGUSR1)").count(), 2, "{}", stdout);
+    assert!(stdout.contains("handled 0\n"), "{}", stdout);
+    assert!(stdout.contains("Child exited (status 0)"), "{}", stdout);
+}
+
+/// Breakpoints are hit in worker threads, which `info threads` and `thread N` show alongside
+/// the main thread, and a forked child is detached and left to run on its own.
+#[test]
+fn test_threads_and_fork() {
+    let output = run_batch("threads", &["tests/scripts/threads.deet"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("[New Thread "), "{}", stdout);
+    assert!(stdout.contains("[Switching to thread 2 (Thread "), "{}", stdout);
+    assert!(stdout.contains("\n  1    Thread "), "{}", stdout);
+    assert!(stdout.contains("\n* 2    Thread "), "{}", stdout);
+    assert!(stdout.contains("id = 0\n[Switching to thread 1 (Thread "), "{}", stdout);
+    // The main thread is either still in main or already waiting in pthread_join
+    assert!(stdout.contains("main () at "), "{}", stdout);
+    assert!(stdout.contains("[Switching to thread 3 (Thread "), "{}", stdout);
+    assert!(stdout.contains("id = 1\n"), "{}", stdout);
+    assert!(stdout.contains("\n* 3    Thread "), "{}", stdout);
+    assert!(stdout.contains("results 10 20\n"), "{}", stdout);
+    assert!(stdout.contains("[Detaching after fork from child process "), "{}", stdout);
+    assert!(stdout.contains("child says hello\n"), "{}", stdout);
+    assert!(stdout.contains("child exited with 7\n"), "{}", stdout);
+    assert!(stdout.contains("Child exited (status 0)"), "{}", stdout);
+    assert_eq!(output.status.code(), Some(0));
+}
+
+/// A position-independent executable is unwound at wherever it was loaded, so a crash without
+/// frame pointers still shows every function on the way up from main.
+#[test]
+fn test_pie_backtrace() {
+    let binary = build_pie_sample("segfault");
+    let output = run_batch_with(&binary, None, &["tests/scripts/segfault.deet"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Child stopped (signal SIGSEGV)"), "{}", stdout);
+    assert!(stdout.contains("#0  0x"), "{}", stdout);
+    assert!(stdout.contains(" in func2 ()\n#1  "), "{}", stdout);
+    assert!(stdout.contains(" in func1 ()\n#2  "), "{}", stdout);
+    assert!(stdout.contains(" in main ()\n"), "{}", stdout);
+    assert_eq!(output.status.code(), Some(128 + 11));
+}
+
+#[test]
+fn test_source() {
+    let output = run_batch("function_calls", &["tests/scripts/source.deet"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("a = 42"), "{}", stdout);
+    assert!(stdout.contains("Child exited (status 0)"), "{}", stdout);
+    assert_eq!(output.status.code(), Some(0));
+}
+
+/// A script that sources itself is stopped at the nesting limit instead of recursing forever.
+#[test]
+fn test_source_nesting_limit() {
+    let output = run_batch("function_calls", &["tests/scripts/source_self.deet"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(stdout.matches("No breakpoints.").count(), 16, "{}", stdout);
+    assert!(stdout.contains("Maximum source nesting depth (16) exceeded."), "{}", stdout);
+    assert_eq!(output.status.code(), Some(0));
+}
+
+/// A breakpoint's command list runs on every hit, and can call user-defined commands.
+#[test]
+fn test_breakpoint_commands() {
+    let output = run_batch("function_calls", &["tests/scripts/commands.deet"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(stdout.matches("a = 100").count(), 2, "{}", stdout);
+    assert!(stdout.contains("Child exited (status 0)"), "{}", stdout);
+    assert_eq!(output.status.code(), Some(0));
+}
+
+/// `print` evaluates C expressions, following pointers and struct members through memory.
+#[test]
+fn test_print_expressions() {
+    let output = run_batch("structs", &["tests/scripts/expressions.deet"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("list->next->value = 7"), "{}", stdout);
+    assert!(stdout.contains("(*where).x + where->y * 10 = 21"), "{}", stdout);
+    assert!(stdout.contains("numbers[2] - numbers[1] = 10"), "{}", stdout);
+    assert!(stdout.contains("*(numbers + 4) = 50"), "{}", stdout);
+    assert!(stdout.contains("&numbers[3] - numbers = 3"), "{}", stdout);
+    assert!(stdout.contains("origin.x * origin.y % 5 = 2"), "{}", stdout);
+    assert!(stdout.contains("(char) 65 = 65 'A'"), "{}", stdout);
+    assert!(stdout.contains("list->next->next = 0x0"), "{}", stdout);
+    assert!(stdout.contains("There is no member named z."), "{}", stdout);
+    assert!(stdout.contains("Attempt to take contents of a non-pointer value."), "{}", stdout);
+    assert!(stdout.contains("Child exited (status 0)"), "{}", stdout);
+}
+
+/// Structs, arrays and enums are printed with their members, elements and enumerator names.
+#[test]
+fn test_print_nested_values() {
+    let output = run_batch("structs", &["tests/scripts/expressions.deet"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("*where = {x = 1, y = 2}"), "{}", stdout);
+    assert!(stdout.contains("numbers = {10, 20, 30, 40, 50}"), "{}", stdout);
+    assert!(stdout.contains("grid = {{1, 2}, {3, 4}}"), "{}", stdout);
+    assert!(stdout.contains("label = \"origin\""), "{}", stdout);
+    assert!(stdout.contains("heading = EAST"), "{}", stdout);
+    assert!(stdout.contains("heading == EAST = 1"), "{}", stdout);
+    assert!(stdout.contains("(enum direction) 4 = SOUTH"), "{}", stdout);
+    assert!(stdout.contains("Child exited (status 0)"), "{}", stdout);
+}
+
+/// Runaway recursion in user-defined commands is stopped, and the script carries on.
+#[test]
+fn test_user_command_depth_limit() {
+    let output = run_batch("function_calls", &["tests/scripts/recursion.deet"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Max user call depth exceeded -- command aborted."), "{}", stdout);
+    assert!(stdout.contains("No breakpoints."), "{}", stdout);
+    assert_eq!(output.status.code(), Some(0));
+}
+
+/// Union members overlap, qualified members print like plain ones, and bit-fields are shifted
+/// and sign-extended out of their storage unit.
+#[test]
+fn test_print_unions_and_bit_fields() {
+    let output = run_batch("structs", &["tests/scripts/members.deet"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("answer = {integer = 42, bytes = \"*\"}"), "{}", stdout);
+    assert!(stdout.contains("answer.bytes[0] = 42 '*'"), "{}", stdout);
+    assert!(stdout.contains("config = {id = 7, counter = 3, name = 0x"), "{}", stdout);
+    assert!(
+        stdout.contains("enabled = 1, level = -3, mode = 5, tag = 120 'x', wide = 123456789012}"),
+        "{}",
+        stdout
+    );
+    assert!(stdout.contains("config.id + config.counter = 10"), "{}", stdout);
+    assert!(stdout.contains("config.level * 2 = -6"), "{}", stdout);
+    assert!(stdout.contains("config.wide = 123456789012"), "{}", stdout);
+    assert!(
+        stdout.contains("Attempt to take address of value not located in memory."),
+        "{}",
+        stdout
+    );
+    assert!(stdout.contains("Child exited (status 0)"), "{}", stdout);
+}
+
+/// `x` dumps memory in the requested format and unit size, labelling each line with the
+/// symbol it falls in.
+#[test]
+fn test_examine_memory() {
+    let output = run_batch("structs", &["tests/scripts/examine.deet"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("No running program!\nSet breakpoint 0"), "{}", stdout);
+    assert!(stdout.contains(" <numbers>:\t10\t20\t30\t40\n0x"), "{}", stdout);
+    assert!(stdout.contains(" <numbers+16>:\t50\n"), "{}", stdout);
+    let bytes = " <numbers>:\t0x0a\t0x00\t0x00\t0x00\t0x14\t0x00\t0x00\t0x00\n";
+    assert!(stdout.contains(bytes), "{}", stdout);
+    let giants = " <numbers>:\t0x000000140000000a\t0x000000280000001e\n";
+    assert!(stdout.contains(giants), "{}", stdout);
+    assert!(stdout.contains(" <numbers>:\t10\t0\t20\n"), "{}", stdout);
+    let binary = " <numbers+4>:\t00000000000000000000000000010100\n";
+    assert!(stdout.contains(binary), "{}", stdout);
+    assert!(stdout.contains(" <label>:\t\"origin\"\n"), "{}", stdout);
+    assert!(stdout.contains(" <label+2>:\t\"igin\"\n"), "{}", stdout);
+    assert!(stdout.contains(" <label>:\t111 'o'\n"), "{}", stdout);
+    assert!(stdout.contains("\n0x7ff"), "{}", stdout);
+    assert!(stdout.contains("Cannot access memory at address 0x0\n"), "{}", stdout);
+    assert!(stdout.contains("Child exited (status 0)"), "{}", stdout);
+}
+
+/// `list` shows ten lines around main, a function or a line, and each `list` after that shows
+/// the next ten.
+#[test]
+fn test_list_source() {
+    let output = run_batch("function_calls", &["tests/scripts/list.deet"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("\n    18\t    func2(a, global);\n"), "{}", stdout);
+    let end = "    25\t}\nLine number 26 out of range; \"";
+    assert!(stdout.contains(end), "{}", stdout);
+    assert!(stdout.contains("function_calls.c\" has 25 lines.\n    4\t\n"), "{}", stdout);
+    assert!(stdout.contains("    13\t    func3(100);\n    14\t}\n"), "{}", stdout);
+    assert!(stdout.contains("    23\tint main() {\n    1\t#include <stdio.h>\n"), "{}", stdout);
+    let nosuch = "was called\\n\", a, b);\nFunction \"nosuch\" not defined.\n";
+    assert!(stdout.contains(nosuch), "{}", stdout);
+    assert_eq!(stdout.matches("    1\t#include <stdio.h>\n").count(), 2, "{}", stdout);
+    assert_eq!(stdout.matches("=>* 6\t").count(), 2, "{}", stdout);
+    assert!(stdout.contains("Child exited (status 0)"), "{}", stdout);
+}
+
+/// Globals can be watched before `run`, and the watchpoint is armed once the program starts.
+#[test]
+fn test_watch_global_before_run() {
+    let output = run_batch("function_calls", &["tests/scripts/watch_global.deet"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Hardware read watchpoint 0: global"), "{}", stdout);
+    assert!(stdout.contains("Value = 5"), "{}", stdout);
+    assert!(stdout.contains("func2(a, global);"), "{}", stdout);
+    assert!(stdout.contains("Child exited (status 0)"), "{}", stdout);
+    assert_eq!(output.status.code(), Some(0));
+}
+
+/// A watchpoint on a local is deleted when its frame returns, and stops the program there.
+#[test]
+fn test_watch_local_out_of_scope() {
+    let output = run_batch("function_calls", &["tests/scripts/watch_local.deet"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("New value = 47"), "{}", stdout);
+    assert!(
+        stdout.contains(
+            "Watchpoint 1 deleted because the program has left the block in\n\
+             which its expression is valid."
+        ),
+        "{}",
+        stdout
+    );
+    assert!(stdout.contains("=>  19\t    func3(100);"), "{}", stdout);
+    assert!(!stdout.contains("hw watchpoint"), "{}", stdout);
+    assert_eq!(stdout.matches("Hello from func3!").count(), 2, "{}", stdout);
+    assert_eq!(output.status.code(), Some(0));
+}
+
+/// In optimized code, variables are read from registers (including ones a caller saved) and
+/// location lists, and ones that no longer exist are reported as optimized out. Where the
+/// compiler keeps each variable is up to it, so most of them may be optimized out, but a value
+/// that is shown must be the right one.
+#[test]
+fn test_optimized_variables() {
+    let output = run_batch("optimized", &["tests/scripts/optimized.deet"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let shows = |name: &str, value: &str| {
+        stdout.contains(&format!("{} = {}", name, value))
+            || stdout.contains(&format!("{} = <optimized out>", name))
+    };
+    assert!(shows("n", "4"), "{}", stdout);
+    assert!(shows("values", "0x"), "{}", stdout);
+    assert!(shows("scale", "4"), "{}", stdout);
+    // n and scale are in registers and location lists at the breakpoints, and not both of them
+    // can be gone already
+    assert!(stdout.contains("n = 4") || stdout.contains("scale = 4"), "{}", stdout);
+    assert!(stdout.contains("#0  sum ("), "{}", stdout);
+    assert!(stdout.contains("#0  combine ("), "{}", stdout);
+    assert_eq!(stdout.matches(" in main (").count(), 2, "{}", stdout);
+    assert!(!stdout.contains("<unreadable>") && !stdout.contains("<error"), "{}", stdout);
+    assert!(stdout.contains("combine 36\n30 36\n"), "{}", stdout);
+    assert!(stdout.contains("Child exited (status 0)"), "{}", stdout);
+}
+
+/// `deet <binary> <core>` shows where a crashed program died, and its variables and registers.
+#[test]
+fn test_core_dump() {
+    let binary = build_sample("segfault");
+    let core = match dump_core(&binary) {
+        Some(core) => core,
+        None => {
+            eprintln!("Skipping test_core_dump: cores aren't written to the working directory");
+            return;
+        }
+    };
+    let output = run_batch_with(&binary, Some(&core), &["tests/scripts/core.deet"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Core was generated by `"), "{}", stdout);
+    assert!(stdout.contains("Program terminated with signal SIGSEGV."), "{}", stdout);
+    assert!(stdout.contains("#0  func2 (a=2) at "), "{}", stdout);
+    assert!(stdout.contains(" in func1 (a=42) at "), "{}", stdout);
+    assert!(stdout.contains(" in main () at "), "{}", stdout);
+    assert!(stdout.contains("<func2"), "{}", stdout);
+    assert!(stdout.contains("a = 2"), "{}", stdout);
+    assert!(stdout.contains("a = 42"), "{}", stdout);
+    assert!(stdout.contains("* 1    LWP "), "{}", stdout);
+}
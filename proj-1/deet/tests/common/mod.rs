@@ -0,0 +1,53 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static NEXT_SAMPLE: AtomicUsize = AtomicUsize::new(0);
+
+/// Samples the Makefile compiles with optimization rather than -O0.
+const OPTIMIZED_SAMPLES: [&str; 1] = ["optimized"];
+
+pub fn target_bin_path() -> PathBuf {
+    let mut path = std::env::current_exe().expect("Could not get current test executable path");
+    path.pop();
+    path.pop();
+    path.push("deet");
+    path
+}
+
+/// Returns the path of a file in the crate, such as "tests/scripts/x.deet".
+#[allow(dead_code)]
+pub fn crate_path(path: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join(path)
+}
+
+/// Compiles one of the sample programs into a scratch directory with the same flags as the
+/// Makefile, and returns the path of the binary.
+pub fn build_sample(name: &str) -> PathBuf {
+    let opt = if OPTIMIZED_SAMPLES.contains(&name) { "-O2" } else { "-O0" };
+    compile(name, &[opt, "-no-pie", "-fno-omit-frame-pointer"])
+}
+
+/// Compiles a sample as a position-independent executable without frame pointers, so that it
+/// is loaded at a random address and can only be unwound with its call frame information.
+#[allow(dead_code)]
+pub fn build_pie_sample(name: &str) -> PathBuf {
+    compile(name, &["-O0", "-pie", "-fPIE", "-fomit-frame-pointer"])
+}
+
+fn compile(name: &str, flags: &[&str]) -> PathBuf {
+    let out_dir = std::env::temp_dir().join(format!("deet-tests-{}", std::process::id()));
+    std::fs::create_dir_all(&out_dir).expect("Could not create scratch directory");
+    // Tests run in parallel, so each gets its own copy
+    let binary = out_dir.join(format!("{}-{}", name, NEXT_SAMPLE.fetch_add(1, Ordering::SeqCst)));
+    let status = Command::new("cc")
+        .args(flags)
+        .args(&["-g", "-gdwarf-4", "-o"])
+        .arg(&binary)
+        .arg(format!("samples/{}.c", name))
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .status()
+        .expect("Could not run cc");
+    assert!(status.success(), "Could not compile samples/{}.c", name);
+    binary
+}
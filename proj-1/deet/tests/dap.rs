@@ -0,0 +1,164 @@
+mod common;
+
+use common::{build_sample, crate_path, target_bin_path};
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+
+/// A `deet --dap` process, and just enough of an editor to script a session with it.
+struct DapClient {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+    next_seq: u64,
+    /// Events received so far, in order
+    events: Vec<Value>,
+}
+
+impl DapClient {
+    fn new() -> DapClient {
+        let mut child = Command::new(target_bin_path())
+            .arg("--dap")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("Could not execute deet binary");
+        DapClient {
+            stdin: child.stdin.take().unwrap(),
+            stdout: BufReader::new(child.stdout.take().unwrap()),
+            child,
+            next_seq: 1,
+            events: Vec::new(),
+        }
+    }
+
+    fn read_message(&mut self) -> Value {
+        let mut length = None;
+        loop {
+            let mut header = String::new();
+            self.stdout.read_line(&mut header).expect("Could not read deet output");
+            assert!(!header.is_empty(), "deet closed its output");
+            let header = header.trim();
+            if header.is_empty() {
+                break;
+            }
+            length = Some(header.trim_start_matches("Content-Length:").trim().parse().unwrap());
+        }
+        let mut body = vec![0; length.expect("Message without Content-Length")];
+        self.stdout.read_exact(&mut body).unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    /// Sends a request and returns its response, keeping any events that come before it.
+    fn request(&mut self, command: &str, arguments: Value) -> Value {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        let mut request = json!({"seq": seq, "type": "request", "command": command});
+        request["arguments"] = arguments;
+        let body = request.to_string();
+        write!(self.stdin, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+        self.stdin.flush().unwrap();
+        loop {
+            let message = self.read_message();
+            if message["type"] == "event" {
+                self.events.push(message);
+            } else {
+                assert_eq!(message["request_seq"], seq);
+                assert_eq!(message["command"], command);
+                return message;
+            }
+        }
+    }
+
+    /// Waits for the event `name`, returning it and the events that came before it.
+    fn wait_for_event(&mut self, name: &str) -> (Value, Vec<Value>) {
+        loop {
+            if let Some(index) = self.events.iter().position(|event| event["event"] == name) {
+                let before: Vec<Value> = self.events.drain(..=index).collect();
+                let event = before[index].clone();
+                return (event, before[..index].to_vec());
+            }
+            let message = self.read_message();
+            assert_eq!(message["type"], "event", "Unexpected message {}", message);
+            self.events.push(message);
+        }
+    }
+}
+
+impl Drop for DapClient {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Concatenates the `output` events of one category.
+fn output(events: &[Value], category: &str) -> String {
+    events
+        .iter()
+        .filter(|event| event["event"] == "output" && event["body"]["category"] == category)
+        .map(|event| event["body"]["output"].as_str().unwrap())
+        .collect()
+}
+
+/// Stops at a breakpoint, inspects the stack and variables, then runs to the end: what the
+/// debugger and the program print must come before the events they lead up to.
+#[test]
+fn test_dap_session() {
+    let binary = build_sample("function_calls");
+    let source = crate_path("samples/function_calls.c");
+    let mut client = DapClient::new();
+
+    let response = client.request("initialize", json!({"adapterID": "deet"}));
+    assert_eq!(response["success"], true);
+    let response = client.request("launch", json!({ "program": binary }));
+    assert_eq!(response["success"], true, "{}", response);
+    client.wait_for_event("initialized");
+
+    let response = client.request(
+        "setBreakpoints",
+        json!({"source": {"path": source}, "breakpoints": [{"line": 11}]}),
+    );
+    assert_eq!(response["body"]["breakpoints"][0]["verified"], true);
+    assert_eq!(client.request("configurationDone", json!({}))["success"], true);
+    let (stopped, before) = client.wait_for_event("stopped");
+    assert_eq!(stopped["body"]["reason"], "breakpoint");
+    assert!(output(&before, "console").contains("function_calls.c:11"));
+
+    let response = client.request("stackTrace", json!({"threadId": stopped["body"]["threadId"]}));
+    let frames = response["body"]["stackFrames"].as_array().unwrap();
+    let names: Vec<&str> = frames.iter().map(|frame| frame["name"].as_str().unwrap()).collect();
+    assert_eq!(names, ["func2", "func1", "main"]);
+    assert_eq!(frames[0]["line"], 11);
+    assert_eq!(frames[1]["line"], 18);
+
+    let response = client.request("scopes", json!({"frameId": 0}));
+    let locals = response["body"]["scopes"][0]["variablesReference"].clone();
+    let response = client.request("variables", json!({ "variablesReference": locals }));
+    let variables = response["body"]["variables"].as_array().unwrap();
+    assert_eq!(variables[0], json!({"name": "a", "value": "42", "variablesReference": 0}));
+    assert_eq!(variables[1], json!({"name": "b", "value": "5", "variablesReference": 0}));
+
+    assert_eq!(client.request("continue", json!({"threadId": 1}))["success"], true);
+    let (exited, before) = client.wait_for_event("exited");
+    assert_eq!(exited["body"]["exitCode"], 0);
+    assert!(output(&before, "stdout").contains("sum = 47\n"));
+    client.wait_for_event("terminated");
+
+    assert_eq!(client.request("disconnect", json!({}))["success"], true);
+    assert!(client.child.wait().unwrap().success());
+}
+
+/// A program that cannot be loaded fails the launch rather than ending the session.
+#[test]
+fn test_dap_launch_failure() {
+    let mut client = DapClient::new();
+    client.request("initialize", json!({}));
+    let response = client.request("launch", json!({"program": "/nonexistent/program"}));
+    assert_eq!(response["success"], false);
+    assert_eq!(response["message"], "Could not open file /nonexistent/program");
+
+    let response = client.request("stackTrace", json!({"threadId": 1}));
+    assert_eq!(response["success"], false);
+    assert_eq!(client.request("disconnect", json!({}))["success"], true);
+}
@@ -0,0 +1,134 @@
+mod common;
+
+use common::{build_sample, target_bin_path};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::process::{Child, ChildStdout, Command, Stdio};
+
+/// Looks up the address of a function in `binary`'s symbol table.
+fn symbol_address(binary: &Path, symbol: &str) -> usize {
+    let output = Command::new("nm").arg(binary).output().expect("Could not run nm");
+    let symbols = String::from_utf8_lossy(&output.stdout);
+    let line = symbols
+        .lines()
+        .find(|line| line.split_whitespace().nth(2) == Some(symbol))
+        .expect("Symbol not found");
+    usize::from_str_radix(line.split_whitespace().next().unwrap(), 16).unwrap()
+}
+
+/// A deet gdbserver, and a connection to it that speaks just enough RSP to script a session.
+struct GdbServer {
+    child: Child,
+    /// The inferior shares deet's stdout, so this has to stay open for it to print
+    _stdout: BufReader<ChildStdout>,
+    stream: TcpStream,
+}
+
+impl GdbServer {
+    fn new(binary: &Path) -> GdbServer {
+        let mut child = Command::new(target_bin_path())
+            .arg("--gdbserver")
+            .arg("127.0.0.1:0")
+            .arg(binary)
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("Could not execute deet binary");
+        let mut stdout = BufReader::new(child.stdout.take().unwrap());
+        let mut line = String::new();
+        stdout.read_line(&mut line).expect("Could not read deet output");
+        assert!(line.starts_with("Listening on "), "deet did not report its address");
+        let address = line.trim().trim_start_matches("Listening on ").to_string();
+        let stream = TcpStream::connect(&address).expect("Could not connect to deet");
+        GdbServer {
+            child,
+            _stdout: stdout,
+            stream,
+        }
+    }
+
+    /// Sends a packet and returns the body of the reply.
+    fn request(&mut self, body: &str) -> String {
+        let checksum = body.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+        let packet = format!("${}#{:02x}", body, checksum);
+        self.stream.write_all(packet.as_bytes()).unwrap();
+
+        let mut byte = [0u8];
+        self.stream.read_exact(&mut byte).unwrap();
+        assert_eq!(byte[0], b'+', "deet did not acknowledge {}", packet);
+        self.stream.read_exact(&mut byte).unwrap();
+        assert_eq!(byte[0], b'$');
+        let mut reply = Vec::new();
+        loop {
+            self.stream.read_exact(&mut byte).unwrap();
+            if byte[0] == b'#' {
+                break;
+            }
+            reply.push(byte[0]);
+        }
+        let mut reply_checksum = [0u8; 2];
+        self.stream.read_exact(&mut reply_checksum).unwrap();
+        let expected = reply.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        assert_eq!(std::str::from_utf8(&reply_checksum).unwrap(), format!("{:02x}", expected));
+        self.stream.write_all(b"+").unwrap();
+        String::from_utf8(reply).unwrap()
+    }
+}
+
+impl Drop for GdbServer {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// rip is the 17th register in the `g` packet, after 16 8-byte general purpose registers.
+fn rip(registers: &str) -> usize {
+    let bytes: Vec<u8> = (0..8)
+        .map(|i| u8::from_str_radix(&registers[256 + 2 * i..258 + 2 * i], 16).unwrap())
+        .collect();
+    let mut rip = [0u8; 8];
+    rip.copy_from_slice(&bytes);
+    u64::from_le_bytes(rip) as usize
+}
+
+/// Sets a breakpoint, runs to it, and checks that the client sees the stop at the breakpoint's
+/// address with the original code in memory, then runs to the end of the program.
+#[test]
+fn test_breakpoint_session() {
+    let binary = build_sample("function_calls");
+    let func1 = symbol_address(&binary, "func1");
+    let mut server = GdbServer::new(&binary);
+
+    assert!(server.request("qSupported:swbreak+").contains("PacketSize="));
+    assert_eq!(server.request("?"), "S05");
+    let original = server.request(&format!("m{:x},1", func1));
+    assert_eq!(original.len(), 2);
+
+    assert_eq!(server.request(&format!("Z0,{:x},1", func1)), "OK");
+    assert_eq!(server.request("c"), "S05");
+    let registers = server.request("g");
+    assert_eq!(rip(&registers), func1);
+    // The breakpoint's 0xcc is hidden from the client
+    assert_eq!(server.request(&format!("m{:x},1", func1)), original);
+
+    // Writing the registers back unchanged is accepted
+    assert_eq!(server.request(&format!("G{}", registers)), "OK");
+    assert_eq!(server.request("s"), "S05");
+    assert_ne!(rip(&server.request("g")), func1);
+
+    assert_eq!(server.request(&format!("z0,{:x},1", func1)), "OK");
+    assert_eq!(server.request("c"), "W00");
+}
+
+/// Writes to memory are visible to later reads.
+#[test]
+fn test_memory_write() {
+    let binary = build_sample("function_calls");
+    let func1 = symbol_address(&binary, "func1");
+    let mut server = GdbServer::new(&binary);
+
+    assert_eq!(server.request(&format!("M{:x},2:9090", func1)), "OK");
+    assert_eq!(server.request(&format!("m{:x},2", func1)), "9090");
+    assert_eq!(server.request("vMustReplyEmpty"), "");
+}
@@ -0,0 +1,96 @@
+mod common;
+
+use common::{build_sample, crate_path, target_bin_path};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::unix::io::FromRawFd;
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Waits until `output` has `count` occurrences of `text`, and returns all of it.
+fn wait_for(output: &Mutex<String>, text: &str, count: usize) -> String {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        let output = output.lock().unwrap().clone();
+        if output.matches(text).count() >= count {
+            return output;
+        }
+        assert!(Instant::now() < deadline, "Timed out waiting for {:?}:\n{}", text, output);
+        std::thread::sleep(Duration::from_millis(20));
+    }
+}
+
+/// Ctrl-C on a real terminal signals the whole foreground process group, so the program gets a
+/// SIGINT of its own along with the debugger's. That must not stop it again on `continue`.
+#[test]
+fn test_ctrl_c_on_terminal() {
+    let binary = build_sample("spin");
+    let (mut master, mut slave) = (0, 0);
+    let ret = unsafe {
+        libc::openpty(
+            &mut master,
+            &mut slave,
+            std::ptr::null_mut(),
+            std::ptr::null(),
+            std::ptr::null(),
+        )
+    };
+    assert_eq!(ret, 0, "Could not open a pseudo-terminal");
+    let mut master = unsafe { File::from_raw_fd(master) };
+    let slave = unsafe { File::from_raw_fd(slave) };
+    let mut cmd = Command::new(target_bin_path());
+    cmd.args(&["--batch", "-x"])
+        .arg(crate_path("tests/scripts/interrupt.deet"))
+        .arg(binary)
+        .stdin(Stdio::from(slave.try_clone().unwrap()))
+        .stdout(Stdio::from(slave.try_clone().unwrap()))
+        .stderr(Stdio::from(slave));
+    // Give deet the terminal as its controlling terminal, in a session of its own
+    unsafe {
+        cmd.pre_exec(|| {
+            if libc::setsid() < 0 || libc::ioctl(0, libc::TIOCSCTTY, 0) < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let mut child = cmd.spawn().expect("Could not execute deet binary");
+    drop(cmd);
+
+    let output = Arc::new(Mutex::new(String::new()));
+    let reader = {
+        let output = output.clone();
+        let mut master = master.try_clone().unwrap();
+        std::thread::spawn(move || {
+            let mut buf = [0; 4096];
+            // Reading fails once deet and the program have closed the terminal
+            while let Ok(len) = master.read(&mut buf) {
+                if len == 0 {
+                    break;
+                }
+                output.lock().unwrap().push_str(&String::from_utf8_lossy(&buf[..len]));
+            }
+        })
+    };
+
+    wait_for(&output, "spinning", 1);
+    master.write_all(b"\x03").unwrap();
+    wait_for(&output, "i = ", 1);
+    // Let the loop run for a while after `continue`
+    std::thread::sleep(Duration::from_millis(300));
+    master.write_all(b"\x03").unwrap();
+    let stdout = wait_for(&output, "i = ", 2);
+    assert!(child.wait().unwrap().success(), "{}", stdout);
+    drop(master);
+    let _ = reader.join();
+
+    assert_eq!(stdout.matches("Child stopped (signal SIGINT)").count(), 2, "{}", stdout);
+    let values: Vec<u64> = stdout
+        .lines()
+        .filter(|line| line.starts_with("i = "))
+        .map(|line| line[4..].trim().parse().unwrap())
+        .collect();
+    assert!(values[1] > values[0], "{}", stdout);
+}